@@ -0,0 +1,229 @@
+// Tracks the memory that each module has allocated and not freed, so that reloads
+// which leak are visible.
+//
+// `malloc`, `realloc` and `free` in the prelude go through the tracking shims in
+// c_interface.rs, and pass the address that they will return to. That address is in
+// the code of whichever module called them, which is found from the address range of
+// every loaded unit's functions. Polymorphic instances are shared by all the modules
+// that use them, so they count as modules of their own, except that `alloc` and
+// `realloc` pass on the address of their own caller.
+//
+// A module's allocations are still reported after it's unloaded, until they're freed.
+// Only allocations made on the thread that compiled the code are tracked.
+//
+// A module can also replace the allocator used for its allocations, with
+// `set_allocator(alloc, free)`, so that it can allocate from an arena that it resets
+// every frame. This covers the module's own calls to `malloc`, `alloc` and `realloc`,
+// but not the allocations made inside polymorphic functions, like those of `list(T)`,
+// or the prelude's functions. Allocations that the allocator makes itself go to
+// `malloc64`, and its allocations are freed with its `free`, even if the module has
+// switched allocator since. Once the module is unloaded they're forgotten when freed,
+// rather than freed.
+
+use crate::c_interface::{malloc64, realloc64, free, memcpy};
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::cmp::min;
+
+/// The memory that a module has allocated and not freed
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModuleMemory {
+  pub module : String,
+  pub unloaded : bool,
+  pub allocations : u64,
+  pub bytes : u64,
+}
+
+/// An allocator that a module has registered, as the functions that implement it
+#[derive(Clone, Copy)]
+pub struct Allocator {
+  pub alloc : extern "C" fn(u64) -> *mut u8,
+  pub free : extern "C" fn(*mut u8),
+}
+
+#[derive(Clone, Copy)]
+struct Allocation {
+  unit : u64,
+  size : u64,
+  /// The unit whose allocator made it, and that allocator, if it wasn't made by `malloc64`
+  allocator : Option<(u64, Allocator)>,
+}
+
+#[derive(Default)]
+struct Allocations {
+  /// The address range of every loaded function, with the unit that owns it, in order
+  functions : Vec<(usize, usize, u64)>,
+  names : HashMap<u64, String>,
+  live : HashMap<usize, Allocation>,
+  allocators : HashMap<u64, Allocator>,
+  unloaded : HashSet<u64>,
+}
+
+thread_local! {
+  static ALLOCATIONS : RefCell<Allocations> = RefCell::new(Allocations::default());
+  /// Set while a module's allocator runs, so that its own allocations use `malloc64`
+  static IN_ALLOCATOR : Cell<bool> = Cell::new(false);
+}
+
+/// Records the address ranges of functions that belong to a unit
+pub fn add_functions(unit : u64, name : &str, ranges : Vec<(usize, usize)>) {
+  ALLOCATIONS.with(|a| {
+    let a = &mut *a.borrow_mut();
+    a.names.insert(unit, name.to_string());
+    a.functions.extend(ranges.into_iter().map(|(start, end)| (start, end, unit)));
+    a.functions.sort_unstable();
+  });
+}
+
+/// Forgets a unit's functions and allocator. Its allocations stay until they're freed.
+/// The unloaded units whose allocations have all been freed are forgotten too, so
+/// that reloading doesn't pile them up.
+pub fn remove_unit(unit : u64) {
+  ALLOCATIONS.with(|a| {
+    let a = &mut *a.borrow_mut();
+    a.functions.retain(|f| f.2 != unit);
+    a.allocators.remove(&unit);
+    a.unloaded.insert(unit);
+    let live_units : HashSet<u64> = a.live.values().map(|l| l.unit).collect();
+    let freed : Vec<u64> = a.unloaded.iter().cloned().filter(|u| !live_units.contains(u)).collect();
+    for u in freed {
+      a.unloaded.remove(&u);
+      a.names.remove(&u);
+    }
+  });
+}
+
+/// The unit that owns the function containing a code address, or zero
+fn owner(a : &Allocations, address : usize) -> u64 {
+  let i = match a.functions.binary_search_by_key(&address, |f| f.0) {
+    Ok(i) => i,
+    Err(0) => return 0,
+    Err(i) => i - 1,
+  };
+  let (_, end, unit) = a.functions[i];
+  if address < end { unit } else { 0 }
+}
+
+/// How many unloaded units are remembered, because some of their allocations are live
+pub fn unloaded_units() -> usize {
+  ALLOCATIONS.with(|a| a.borrow().unloaded.len())
+}
+
+/// The unit whose compiled code contains an address, or zero
+pub fn unit_containing(address : usize) -> u64 {
+  ALLOCATIONS.with(|a| owner(&*a.borrow(), address))
+}
+
+/// Sets the allocator of the module that contains `caller`, or goes back to
+/// `malloc64` if there isn't one
+pub fn set_allocator(caller : usize, allocator : Option<Allocator>) {
+  ALLOCATIONS.with(|a| {
+    let a = &mut *a.borrow_mut();
+    let unit = owner(a, caller);
+    match allocator {
+      Some(allocator) => a.allocators.insert(unit, allocator),
+      None => a.allocators.remove(&unit),
+    };
+  });
+}
+
+/// Runs part of a module's allocator, so that allocations it makes itself don't
+/// come back to it
+fn in_allocator<T>(f : impl FnOnce() -> T) -> T {
+  let previous = IN_ALLOCATOR.with(|i| i.replace(true));
+  let v = f();
+  IN_ALLOCATOR.with(|i| i.set(previous));
+  v
+}
+
+/// The unit that owns `caller`, and its allocator if it should be used
+fn allocator_for(caller : usize) -> (u64, Option<Allocator>) {
+  ALLOCATIONS.with(|a| {
+    let a = &*a.borrow();
+    let unit = owner(a, caller);
+    if IN_ALLOCATOR.with(|i| i.get()) {
+      return (unit, None);
+    }
+    (unit, a.allocators.get(&unit).cloned())
+  })
+}
+
+fn record(ptr : *mut u8, allocation : Allocation) {
+  if !ptr.is_null() {
+    ALLOCATIONS.with(|a| a.borrow_mut().live.insert(ptr as usize, allocation));
+  }
+}
+
+/// Allocates memory for the module that contains `caller`
+pub fn allocate(size : u64, caller : usize) -> *mut u8 {
+  let (unit, allocator) = allocator_for(caller);
+  let ptr = match allocator {
+    Some(al) => in_allocator(|| (al.alloc)(size)),
+    None => malloc64(size),
+  };
+  record(ptr, Allocation { unit, size, allocator: allocator.map(|al| (unit, al)) });
+  ptr
+}
+
+/// Resizes an allocation for the module that contains `caller`. If either the old
+/// or the new allocation belongs to a module's allocator, the contents are copied.
+pub fn reallocate(ptr : *mut u8, size : u64, caller : usize) -> *mut u8 {
+  let old = ALLOCATIONS.with(|a| a.borrow().live.get(&(ptr as usize)).cloned());
+  let (unit, allocator) = allocator_for(caller);
+  let from_allocator = old.map(|o| o.allocator.is_some()).unwrap_or(false);
+  // memory that wasn't tracked came from `malloc64`, and its size isn't known
+  let untracked = !ptr.is_null() && old.is_none();
+  if untracked || (allocator.is_none() && !from_allocator) {
+    let p = realloc64(ptr, size);
+    if !p.is_null() {
+      deallocated(ptr);
+      record(p, Allocation { unit, size, allocator: None });
+    }
+    return p;
+  }
+  let p = allocate(size, caller);
+  if let (false, Some(old)) = (p.is_null(), old) {
+    unsafe { memcpy(p, ptr, min(old.size, size) as usize) };
+    deallocate(ptr);
+  }
+  p
+}
+
+/// Frees an allocation, with the allocator that made it
+pub fn deallocate(ptr : *mut u8) {
+  let allocation = deallocated(ptr);
+  match allocation.and_then(|a| a.allocator) {
+    Some((unit, al)) => {
+      if !ALLOCATIONS.with(|a| a.borrow().unloaded.contains(&unit)) {
+        in_allocator(|| (al.free)(ptr));
+      }
+    }
+    None => unsafe { free(ptr) },
+  }
+}
+
+/// Stops tracking an allocation
+fn deallocated(ptr : *mut u8) -> Option<Allocation> {
+  ALLOCATIONS.with(|a| a.borrow_mut().live.remove(&(ptr as usize)))
+}
+
+/// The live allocations of each module, largest first
+pub fn report() -> Vec<ModuleMemory> {
+  ALLOCATIONS.with(|a| {
+    let a = &*a.borrow();
+    let mut totals : HashMap<u64, (u64, u64)> = HashMap::new();
+    for l in a.live.values() {
+      let t = totals.entry(l.unit).or_insert((0, 0));
+      t.0 += 1;
+      t.1 += l.size;
+    }
+    let mut report : Vec<ModuleMemory> = totals.into_iter().map(|(unit, (allocations, bytes))| {
+      let module = a.names.get(&unit).cloned().unwrap_or_else(|| "<unknown>".to_string());
+      let unloaded = a.unloaded.contains(&unit);
+      ModuleMemory { module, unloaded, allocations, bytes }
+    }).collect();
+    report.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.module.cmp(&b.module)));
+    report
+  })
+}
@@ -0,0 +1,840 @@
+// Checks over the nodes of a unit. Most of these don't stop code from compiling;
+// they only produce warnings. Each warning belongs to a lint, and lints
+// can be turned off for a module with `pragma allow(lint_name)`.
+//
+// Assignments to immutable locals (declared with `let` rather than `var`)
+// are errors, and are found here too.
+//
+// A unit can also opt in to requiring `unsafe { ... }` around operations that
+// can break memory safety, with `pragma require_unsafe`.
+//
+// Statics are initialised as part of the unit's top-level code. A static whose
+// initialiser reads a static that comes after it (directly, or by calling functions
+// in the same unit) would see zeroed memory, so the statics are put in the order of
+// their dependencies first, and a cycle between initialisers is an error. A `lazy
+// static` is initialised when it's first used, so its position doesn't matter, but
+// the statics that its initialiser reads still have to be ready by then.
+//
+// The dataflow analysis only tracks local variables, and is deliberately simple.
+// Anything that it can't see through (like passing a variable's address to a
+// function) clears what it knows about that variable, to avoid a flood of false
+// positives.
+
+use crate::common::{UnitId, RefStr};
+use crate::error::{Error, ErrorContent, Severity, TextLocation, error_raw, warning_raw};
+use crate::structure::{Nodes, NodeId, Content, LabelId, Reference, ReferenceId, VarScope, TypeKind, PrimitiveVal};
+use crate::intrinsics::UNSAFE_ZERO_INIT;
+use crate::code_store::CodeStore;
+use crate::types::{TypeMapping, SymbolInit, SymbolId, SymbolDefinition, TypeContent, MethodReceiver};
+use crate::graph::{self, DirectedGraph};
+
+use std::collections::{HashMap, HashSet, BTreeSet};
+
+pub static UNINITIALISED : &'static str = "uninitialised";
+pub static NULL_POINTER : &'static str = "null_pointer";
+pub static DOUBLE_FREE : &'static str = "double_free";
+pub static UNUSED_VARIABLES : &'static str = "unused_variables";
+pub static UNREACHABLE_CODE : &'static str = "unreachable_code";
+pub static UNUSED_IMPORTS : &'static str = "unused_imports";
+pub static UNSAFE_OPERATIONS : &'static str = "unsafe_operations";
+pub static RAW_UNION_ACCESS : &'static str = "raw_union_access";
+/// Found while structuring, rather than by the checks in this file
+pub static SHADOWED_GLOBALS : &'static str = "shadowed_globals";
+
+/// `pragma allow(warnings)` turns off every lint
+static ALL_LINTS : &'static str = "warnings";
+
+static LINTS : &'static [&'static str] = &[
+  UNINITIALISED, NULL_POINTER, DOUBLE_FREE,
+  UNUSED_VARIABLES, UNREACHABLE_CODE, UNUSED_IMPORTS, UNSAFE_OPERATIONS,
+  RAW_UNION_ACCESS, SHADOWED_GLOBALS,
+];
+
+pub type Warning = (&'static str, Error);
+
+/// What might be true of a variable at some point in the code
+#[derive(Clone, Copy, PartialEq, Default)]
+struct VarState {
+  maybe_uninit : bool,
+  maybe_null : bool,
+  maybe_freed : bool,
+}
+
+impl VarState {
+  fn join(self, other : Self) -> Self {
+    VarState {
+      maybe_uninit: self.maybe_uninit || other.maybe_uninit,
+      maybe_null: self.maybe_null || other.maybe_null,
+      maybe_freed: self.maybe_freed || other.maybe_freed,
+    }
+  }
+}
+
+#[derive(Clone, PartialEq)]
+struct State {
+  reachable : bool,
+  vars : HashMap<ReferenceId, VarState>,
+}
+
+impl State {
+  fn unreachable() -> Self {
+    State { reachable: false, vars: HashMap::new() }
+  }
+
+  fn join(&self, other : &State) -> State {
+    if !self.reachable { return other.clone() }
+    if !other.reachable { return self.clone() }
+    let mut vars = self.vars.clone();
+    for (id, v) in other.vars.iter() {
+      let joined = vars.get(id).map(|x| x.join(*v)).unwrap_or(*v);
+      vars.insert(*id, joined);
+    }
+    State { reachable: true, vars }
+  }
+}
+
+struct Analysis<'l> {
+  nodes : &'l Nodes,
+  mapping : &'l TypeMapping,
+  breaks : HashMap<LabelId, State>,
+  warnings : Vec<Warning>,
+}
+
+/// Returns warnings for reads of uninitialised locals, dereferences of pointers
+/// that came from `malloc` and haven't been checked, pointers that may be
+/// freed twice, and code that can never run.
+pub fn dataflow_warnings(nodes : &Nodes, mapping : &TypeMapping) -> Vec<Warning> {
+  let mut a = Analysis { nodes, mapping, breaks: HashMap::new(), warnings: vec![] };
+  for node in nodes.nodes.values() {
+    if let Content::FunctionDefinition{ body, .. } = &node.content {
+      let mut state = State { reachable: true, vars: HashMap::new() };
+      a.visit(*body, &mut state);
+    }
+  }
+  let mut warnings = a.warnings;
+  warnings.sort_by_key(|w| w.1.location);
+  warnings.dedup();
+  warnings
+}
+
+/// Returns warnings for locals and function arguments that are never used.
+/// Names starting with an underscore are ignored.
+pub fn unused_variable_warnings(nodes : &Nodes) -> Vec<Warning> {
+  let mut used = HashSet::new();
+  for node in nodes.nodes.values() {
+    if let Content::Reference{ refers_to: Some(id), .. } = &node.content {
+      used.insert(*id);
+    }
+  }
+  let mut warnings = vec![];
+  let mut check = |r : &Reference, kind : &str| {
+    // generated variables start with '@'
+    let ignored = r.name.starts_with('_') || r.name.starts_with('@');
+    if !ignored && !used.contains(&r.id) {
+      let w = warning_raw(r.loc, format!("unused {} '{}'", kind, r.name));
+      warnings.push((UNUSED_VARIABLES, w));
+    }
+  };
+  for node in nodes.nodes.values() {
+    match &node.content {
+      Content::VariableInitialise{ name, var_scope: VarScope::Local, .. } => check(name, "variable"),
+      Content::FunctionDefinition{ args, .. } => {
+        for (arg, _) in args { check(arg, "argument") }
+      }
+      _ => (),
+    }
+  }
+  warnings.sort_by_key(|w| w.1.location);
+  warnings
+}
+
+/// Finds operations outside of `unsafe` blocks that dereference a raw pointer
+/// (including field access through one), cast to or from a pointer, or use a
+/// pointer returned by a cbind. Nothing is checked unless the unit contains
+/// `pragma require_unsafe`, which makes these errors, or
+/// `pragma require_unsafe(warn)`, which makes them warnings.
+pub fn unsafe_operation_warnings(nodes : &Nodes, code_store : &CodeStore, unit_id : UnitId)
+  -> Result<Vec<Warning>, Error>
+{
+  let mut severity = None;
+  for p in nodes.pragmas.iter().filter(|p| p.name.as_ref() == "require_unsafe") {
+    let args : Vec<&str> = p.args.iter().map(|a| a.as_ref()).collect();
+    severity = match args.as_slice() {
+      [] | ["error"] => Some(Severity::Error),
+      ["warn"] => Some(Severity::Warning),
+      _ => return Err(error_raw(p.loc, "expected 'pragma require_unsafe' or 'pragma require_unsafe(warn)'")),
+    };
+  }
+  let severity = match severity {
+    Some(s) => s,
+    None => return Ok(vec![]),
+  };
+  let mut check = UnsafeCheck {
+    nodes, code_store, mapping: code_store.type_mapping(unit_id), operations: vec![],
+  };
+  check.visit(nodes.root, false);
+  let mut operations = check.operations;
+  operations.sort_by_key(|(loc, _)| *loc);
+  if severity == Severity::Error {
+    if operations.is_empty() {
+      return Ok(vec![]);
+    }
+    let errors = operations.into_iter().map(|(loc, s)| error_raw(loc, s)).collect();
+    let c = ErrorContent::InnerErrors("unsafe operations outside of an unsafe block".into(), errors);
+    return Err(error_raw(nodes.root().loc, c));
+  }
+  Ok(operations.into_iter().map(|(loc, s)| (UNSAFE_OPERATIONS, warning_raw(loc, s))).collect())
+}
+
+/// Finds reads of the fields of untagged unions outside of `unsafe` blocks. Nothing
+/// records which field a union holds, so reading the wrong one silently reinterprets
+/// its bytes; a `tagged union` read with `match` can't. Writing a field is fine.
+pub fn raw_union_access_warnings(nodes : &Nodes, code_store : &CodeStore, unit_id : UnitId) -> Vec<Warning> {
+  let mapping = code_store.type_mapping(unit_id);
+  let mut in_unsafe = HashSet::new();
+  let mut stack : Vec<NodeId> = nodes.unsafe_blocks.iter().cloned().collect();
+  while let Some(n) = stack.pop() {
+    if in_unsafe.insert(n) {
+      stack.extend(nodes.node(n).content.children());
+    }
+  }
+  // the field accesses that are assigned to, including those that contain them
+  let mut written = HashSet::new();
+  for node in nodes.nodes.values() {
+    if let Content::Assignment{ assignee, .. } = &node.content {
+      let mut n = *assignee;
+      while let Content::FieldAccess{ container, .. } = &nodes.node(n).content {
+        written.insert(n);
+        n = *container;
+      }
+    }
+  }
+  let mut warnings = vec![];
+  for (id, node) in nodes.nodes.iter() {
+    if let Content::FieldAccess{ container, field } = &node.content {
+      if in_unsafe.contains(id) || written.contains(id) {
+        continue;
+      }
+      let mut t = match mapping.node_type.get(container) {
+        Some(t) => t,
+        None => continue,
+      };
+      while let Some(inner) = t.ptr() {
+        t = inner;
+      }
+      if let TypeContent::Def(name, def_unit) = &t.content {
+        let def = code_store.types(*def_unit).find_type_def(name);
+        if def.map(|d| d.kind == TypeKind::Union).unwrap_or(false) {
+          let s = format!("read of field '{}' of untagged union '{}' outside of an unsafe block", field.name, t);
+          warnings.push((RAW_UNION_ACCESS, warning_raw(node.loc, s)));
+        }
+      }
+    }
+  }
+  warnings.sort_by_key(|w| w.1.location);
+  warnings
+}
+
+/// Finds assignments to locals that were declared with `let`, including
+/// assignments to their fields. Assigning to a field through a pointer is fine,
+/// because it's the pointee being changed rather than the local. The types of
+/// polymorphic function bodies aren't known here, so only direct assignments
+/// to locals are checked in them.
+pub fn mutability_errors(nodes : &Nodes, code_store : &CodeStore, unit_id : UnitId)
+  -> Result<(), Error>
+{
+  let mapping = code_store.type_mapping(unit_id);
+  let mut errors = vec![];
+  for node in nodes.nodes.values() {
+    if let Content::Assignment{ assignee, .. } = &node.content {
+      let mut n = *assignee;
+      let mut field = None;
+      loop {
+        match &nodes.node(n).content {
+          Content::FieldAccess{ container, field: f } => {
+            let through_pointer =
+              mapping.node_type.get(container).map(|t| t.ptr().is_some()).unwrap_or(true);
+            if through_pointer { break }
+            field = Some(f.name.clone());
+            n = *container;
+          }
+          Content::Reference{ name, refers_to: Some(id) } => {
+            if !nodes.mutable_locals.contains(id) {
+              let def = nodes.symbol(*id);
+              let target = match &field {
+                Some(f) => format!("field '{}' of immutable variable '{}'", f, name),
+                None => format!("immutable variable '{}'", name),
+              };
+              let m = format!(
+                "cannot assign to {}\n   '{}' is defined at {}; declare it with `var` to make it mutable",
+                target, name, def.loc);
+              errors.push(error_raw(node.loc, m));
+            }
+            break;
+          }
+          _ => break,
+        }
+      }
+    }
+  }
+  errors.sort_by_key(|e| e.location);
+  if errors.len() > 1 {
+    let c = ErrorContent::InnerErrors("assignments to immutable variables".into(), errors);
+    return Err(error_raw(nodes.root().loc, c));
+  }
+  match errors.pop() {
+    Some(e) => Err(e),
+    None => Ok(()),
+  }
+}
+
+fn static_initialiser(def : &SymbolDefinition) -> Option<NodeId> {
+  match def.initialiser {
+    SymbolInit::Expression(value) | SymbolInit::Lazy(value) => Some(value),
+    _ => None,
+  }
+}
+
+/// Moves the initialisers of the unit's statics so that each one runs after the
+/// statics that its initialiser depends on. A static that's needed by an earlier one
+/// is moved up to just before it. Returns an error if initialisers depend on each
+/// other in a cycle, or if a static can't be moved up, because it's in a different
+/// block, or because its initialiser uses a local that's defined in between.
+pub fn order_static_initialisers(code_store : &mut CodeStore, unit_id : UnitId)
+  -> Result<(), Error>
+{
+  let reordered = static_initialiser_order(code_store, unit_id)?;
+  let nodes = code_store.nodes.get_mut(&unit_id).unwrap();
+  for (block, children) in reordered {
+    nodes.nodes.get_mut(&block).unwrap().content = Content::Block(children);
+  }
+  Ok(())
+}
+
+/// The blocks whose statements have to be reordered, with their new order
+fn static_initialiser_order(code_store : &CodeStore, unit_id : UnitId)
+  -> Result<Vec<(NodeId, Vec<NodeId>)>, Error>
+{
+  let nodes = code_store.nodes(unit_id);
+  let mapping = code_store.type_mapping(unit_id);
+  let mut statics : Vec<&SymbolDefinition> =
+    code_store.types(unit_id).symbols.values()
+    .filter(|def| static_initialiser(def).is_some())
+    .collect();
+  statics.sort_by_key(|def| def.loc);
+  let index : HashMap<SymbolId, usize> =
+    statics.iter().enumerate().map(|(i, def)| (def.id, i)).collect();
+  let mut g = DirectedGraph::default();
+  for def in statics.iter() {
+    let mut reads = BTreeSet::new();
+    if let Some(value) = static_initialiser(def) {
+      let mut visited_functions = HashSet::new();
+      let mut stack = vec![value];
+      while let Some(n) = stack.pop() {
+        if let Some(id) = mapping.symbol_references.get(&n) {
+          if let Some(&i) = index.get(id) {
+            reads.insert(i);
+          }
+          else if id.uid == unit_id && visited_functions.insert(*id) {
+            // the function might be called, so its body counts as part of the initialiser
+            if let SymbolInit::Function(f) = &code_store.symbol_def(*id).initialiser {
+              stack.push(f.body);
+            }
+          }
+        }
+        match &nodes.node(n).content {
+          // closures defined in the initialiser are found if they are referenced by name
+          Content::FunctionDefinition{ .. } => (),
+          c => stack.extend(c.children()),
+        }
+      }
+    }
+    g.vertex_edges.push(reads.into_iter().collect());
+  }
+  let mut errors = vec![];
+  for component in graph::get_strongly_connected_components(&g) {
+    let self_cycle = component.len() == 1 && g.edges(component[0]).contains(&component[0]);
+    if component.len() > 1 || self_cycle {
+      let mut component = component;
+      component.sort();
+      let names : Vec<_> = component.iter().map(|&i| format!("'{}'", statics[i].name)).collect();
+      let m = format!("cycle in the initialisers of statics {}", names.join(", "));
+      errors.push(error_raw(statics[component[0]].loc, m));
+    }
+  }
+  let mut reordered = vec![];
+  if errors.is_empty() {
+    let is_lazy = |i : usize| if let SymbolInit::Lazy(_) = statics[i].initialiser { true } else { false };
+    // the statics that have to be initialised first, looking through the lazy
+    // statics that are initialised along the way
+    let needs : Vec<Vec<usize>> = (0..statics.len()).map(|i| {
+      let mut needs = BTreeSet::new();
+      let mut stack = vec![i];
+      let mut visited = HashSet::new();
+      while let Some(k) = stack.pop() {
+        for &j in g.edges(k) {
+          if !visited.insert(j) { continue }
+          if is_lazy(j) { stack.push(j) }
+          else { needs.insert(j); }
+        }
+      }
+      needs.into_iter().collect()
+    }).collect();
+    let mut static_nodes = HashMap::new();
+    for (i, def) in statics.iter().enumerate() {
+      if !is_lazy(i) {
+        static_nodes.insert(mapping.symbol_def_nodes[&def.id], i);
+      }
+    }
+    let mut blocks : Vec<(NodeId, &Vec<NodeId>)> =
+      nodes.nodes.iter()
+      .filter_map(|(&id, n)| match &n.content {
+        Content::Block(children) if children.iter().any(|c| static_nodes.contains_key(c)) => Some((id, children)),
+        _ => None,
+      })
+      .collect();
+    blocks.sort_by_key(|(id, _)| nodes.node(*id).loc);
+    for (block, children) in blocks {
+      let mut o = StaticOrder {
+        nodes, statics: &statics, needs: &needs, static_nodes: &static_nodes,
+        children, placed: HashSet::new(), order: vec![], errors: &mut errors,
+      };
+      for &c in children.iter() {
+        o.place(c);
+      }
+      if o.order != *children {
+        reordered.push((block, o.order));
+      }
+    }
+  }
+  errors.sort_by_key(|e| e.location);
+  if errors.len() > 1 {
+    let c = ErrorContent::InnerErrors("static initialisation order".into(), errors);
+    return Err(error_raw(nodes.root().loc, c));
+  }
+  match errors.pop() {
+    Some(e) => Err(e),
+    None => Ok(reordered),
+  }
+}
+
+/// Puts the statements of a block in order, one at a time
+struct StaticOrder<'l> {
+  nodes : &'l Nodes,
+  statics : &'l [&'l SymbolDefinition],
+  needs : &'l [Vec<usize>],
+  /// The index of each (non-lazy) static, by the node that initialises it
+  static_nodes : &'l HashMap<NodeId, usize>,
+  children : &'l [NodeId],
+  placed : HashSet<NodeId>,
+  order : Vec<NodeId>,
+  errors : &'l mut Vec<Error>,
+}
+
+impl <'l> StaticOrder<'l> {
+  fn place(&mut self, n : NodeId) {
+    if self.placed.contains(&n) {
+      return;
+    }
+    if let Some(&i) = self.static_nodes.get(&n) {
+      for &j in self.needs[i].iter() {
+        let dependency = *self.static_nodes.iter().find(|(_, &k)| k == j).unwrap().0;
+        if self.placed.contains(&dependency) || self.statics[j].loc < self.statics[i].loc {
+          continue;
+        }
+        if !self.children.contains(&dependency) {
+          let m = format!(
+            "static '{}' is initialised before '{}', which its initialiser depends on\n   '{}' is defined at {}",
+            self.statics[i].name, self.statics[j].name, self.statics[j].name, self.statics[j].loc);
+          self.errors.push(error_raw(self.statics[i].loc, m));
+          continue;
+        }
+        if let Some(local) = self.local_defined_before(dependency) {
+          let m = format!(
+            "static '{}' has to be initialised before '{}', but its initialiser uses '{}', which is defined in between",
+            self.statics[j].name, self.statics[i].name, local);
+          self.errors.push(error_raw(self.statics[j].loc, m));
+          continue;
+        }
+        self.place(dependency);
+      }
+    }
+    self.placed.insert(n);
+    self.order.push(n);
+  }
+
+  /// A local that the initialiser of `dependency` uses, which wouldn't be defined yet
+  /// if it was moved up to the current position
+  fn local_defined_before(&self, dependency : NodeId) -> Option<RefStr> {
+    let skipped : HashMap<ReferenceId, RefStr> =
+      self.children.iter()
+      .take_while(|&&c| c != dependency)
+      .filter(|c| !self.placed.contains(c))
+      .filter_map(|&c| match &self.nodes.node(c).content {
+        Content::VariableInitialise{ name, var_scope: VarScope::Local, .. } => Some((name.id, name.name.clone())),
+        _ => None,
+      })
+      .collect();
+    let mut stack = vec![dependency];
+    while let Some(n) = stack.pop() {
+      match &self.nodes.node(n).content {
+        Content::Reference{ refers_to: Some(r), .. } => {
+          if let Some(name) = skipped.get(r) {
+            return Some(name.clone());
+          }
+        }
+        Content::FunctionDefinition{ .. } => (),
+        c => stack.extend(c.children()),
+      }
+    }
+    None
+  }
+}
+
+struct UnsafeCheck<'l> {
+  nodes : &'l Nodes,
+  code_store : &'l CodeStore,
+  mapping : &'l TypeMapping,
+  operations : Vec<(TextLocation, String)>,
+}
+
+impl <'l> UnsafeCheck<'l> {
+  fn visit(&mut self, n : NodeId, in_unsafe : bool) {
+    let in_unsafe = in_unsafe || self.nodes.unsafe_blocks.contains(&n);
+    if !in_unsafe {
+      if let Some(s) = self.unsafe_operation(n) {
+        self.operations.push((self.nodes.node(n).loc, s));
+      }
+    }
+    for c in self.nodes.node(n).content.children() {
+      self.visit(c, in_unsafe);
+    }
+  }
+
+  fn raw_pointer(&self, n : NodeId) -> bool {
+    self.mapping.node_type.get(&n).map(|t| t.ptr().is_some()).unwrap_or(false)
+  }
+
+  fn called_function(&self, function : NodeId) -> Option<&'l str> {
+    let id = self.mapping.symbol_references.get(&function)?;
+    Some(self.code_store.symbol_def(*id).name.as_ref())
+  }
+
+  /// Indexing an array produces a pointer that is dereferenced straight away, which is fine
+  fn array_element(&self, n : NodeId) -> bool {
+    if let Content::FunctionCall{ function, args } = &self.nodes.node(n).content {
+      if let (Some("Index"), [container, _]) = (self.called_function(*function), args.as_slice()) {
+        return !self.raw_pointer(*container);
+      }
+    }
+    false
+  }
+
+  fn unsafe_operation(&self, n : NodeId) -> Option<String> {
+    match &self.nodes.node(n).content {
+      Content::FunctionCall{ function, args } => {
+        let id = self.mapping.symbol_references.get(function)?;
+        let def = self.code_store.symbol_def(*id);
+        if let ("*", [p]) = (def.name.as_ref(), args.as_slice()) {
+          if self.raw_pointer(*p) && !self.array_element(*p) {
+            return Some("dereference of a raw pointer".into());
+          }
+        }
+        if let SymbolInit::CBind = def.initialiser {
+          if self.raw_pointer(n) {
+            return Some(format!("use of a pointer returned by cbind '{}'", def.name));
+          }
+        }
+        None
+      }
+      Content::FieldAccess{ container, field } if self.raw_pointer(*container) => {
+        Some(format!("access of field '{}' through a raw pointer", field.name))
+      }
+      Content::Convert{ from_value, .. } => {
+        let from = self.mapping.node_type.get(from_value)?;
+        let into = self.mapping.node_type.get(&n)?;
+        if from != into && (from.pointer() || into.pointer()) {
+          return Some(format!("cast from {} to {}", from, into));
+        }
+        None
+      }
+      _ => None,
+    }
+  }
+}
+
+/// Removes warnings for lints that the module has turned off. Returns an
+/// error if a pragma names a lint that doesn't exist.
+pub fn suppress_warnings(nodes : &Nodes, warnings : Vec<Warning>) -> Result<Vec<Error>, Error> {
+  let mut allowed = HashSet::new();
+  for p in nodes.pragmas.iter().filter(|p| p.name.as_ref() == "allow") {
+    for lint in p.args.iter() {
+      let lint = lint.as_ref();
+      if lint != ALL_LINTS && !LINTS.contains(&lint) {
+        return Err(error_raw(p.loc, format!("unknown lint '{}'", lint)));
+      }
+      allowed.insert(lint);
+    }
+  }
+  if allowed.contains(ALL_LINTS) {
+    return Ok(vec![]);
+  }
+  Ok(warnings.into_iter().filter(|(lint, _)| !allowed.contains(lint)).map(|(_, w)| w).collect())
+}
+
+impl <'l> Analysis<'l> {
+
+  /// If the node refers to a local variable (ignoring casts), returns its id
+  fn local_var(&self, n : NodeId) -> Option<ReferenceId> {
+    match &self.nodes.node(n).content {
+      Content::Reference{ refers_to, .. } => *refers_to,
+      Content::Convert{ from_value, .. } => self.local_var(*from_value),
+      _ => None,
+    }
+  }
+
+  /// If the node is a call to a named function, returns the name and arguments
+  fn named_call(&self, n : NodeId) -> Option<(&'l str, &'l [NodeId])> {
+    let nodes = self.nodes;
+    match &nodes.node(n).content {
+      Content::FunctionCall{ function, args } => {
+        if let Content::Reference{ name, refers_to: None } = &nodes.node(*function).content {
+          return Some((name.as_ref(), args.as_slice()));
+        }
+        None
+      }
+      Content::Convert{ from_value, .. } => self.named_call(*from_value),
+      _ => None,
+    }
+  }
+
+  /// The state of a variable right after it has been set to this value
+  fn initial_state(&self, value : NodeId) -> VarState {
+    let mut v = VarState::default();
+    match self.named_call(value) {
+      Some((name, [])) if name == UNSAFE_ZERO_INIT => v.maybe_uninit = true,
+      Some(("malloc", [_])) | Some(("malloc64", [_])) => v.maybe_null = true,
+      _ => (),
+    }
+    v
+  }
+
+  fn warning(&mut self, lint : &'static str, n : NodeId, message : String) {
+    let loc = self.nodes.node(n).loc;
+    self.warnings.push((lint, warning_raw(loc, message)));
+  }
+
+  fn var_name(&self, id : ReferenceId) -> &'l str {
+    self.nodes.symbol(id).name.as_ref()
+  }
+
+  fn check_deref(&mut self, n : NodeId, pointer : NodeId, state : &mut State) {
+    if let Some(id) = self.local_var(pointer) {
+      if let Some(v) = state.vars.get_mut(&id) {
+        if v.maybe_null {
+          // only warn once per variable
+          v.maybe_null = false;
+          let name = self.var_name(id);
+          self.warning(NULL_POINTER, n, format!("'{}' may be null here, because it came from malloc and was never checked", name));
+        }
+      }
+    }
+  }
+
+  /// Any variable mentioned in a condition is assumed to have been checked
+  fn mark_checked(&self, n : NodeId, state : &mut State) {
+    if let Some(id) = self.local_var(n) {
+      if let Some(v) = state.vars.get_mut(&id) {
+        v.maybe_null = false;
+      }
+    }
+    for c in self.nodes.node(n).content.children() {
+      self.mark_checked(c, state);
+    }
+  }
+
+  fn visit_condition(&mut self, condition : NodeId, state : &mut State) {
+    self.visit(condition, state);
+    self.mark_checked(condition, state);
+  }
+
+  fn visit(&mut self, n : NodeId, state : &mut State) {
+    if !state.reachable {
+      return;
+    }
+    let nodes = self.nodes;
+    let node = nodes.node(n);
+    match &node.content {
+      Content::FunctionDefinition{..} => {
+        // analysed separately
+      }
+      Content::VariableInitialise{ name, value, .. } => {
+        self.visit(*value, state);
+        let v = self.initial_state(*value);
+        state.vars.insert(name.id, v);
+      }
+      Content::Assignment{ assignee, value } => {
+        self.visit(*value, state);
+        match &nodes.node(*assignee).content {
+          Content::Reference{ refers_to: Some(id), .. } => {
+            let v = self.initial_state(*value);
+            state.vars.insert(*id, v);
+          }
+          Content::FieldAccess{ container, .. } => {
+            // writing a field of a struct counts as initialising it
+            if let Some(id) = self.local_var(*container) {
+              if let Some(v) = state.vars.get_mut(&id) {
+                v.maybe_uninit = false;
+              }
+            }
+            self.visit(*assignee, state);
+          }
+          _ => self.visit(*assignee, state),
+        }
+      }
+      Content::Reference{ refers_to: Some(id), .. } => {
+        if let Some(v) = state.vars.get_mut(id) {
+          if v.maybe_uninit {
+            v.maybe_uninit = false;
+            let name = self.var_name(*id);
+            self.warning(UNINITIALISED, n, format!("'{}' may be used before it is initialised", name));
+          }
+        }
+      }
+      Content::FieldAccess{ container, .. } => {
+        // fields are accessed through pointers without an explicit dereference
+        self.check_deref(n, *container, state);
+        self.visit(*container, state);
+      }
+      Content::FunctionCall{ function, args } => {
+        // the receiver of `x.f()` is treated like `&x` or `*x` if it's passed that way
+        match self.mapping.method_receivers.get(&n) {
+          Some(MethodReceiver::Reference) => {
+            if let Some(id) = self.local_var(args[0]) {
+              state.vars.remove(&id);
+              self.visit(*function, state);
+              for a in &args[1..] {
+                self.visit(*a, state);
+              }
+              return;
+            }
+          }
+          Some(MethodReceiver::Dereference(_)) => self.check_deref(n, args[0], state),
+          _ => (),
+        }
+        match self.named_call(n) {
+          Some(("&", [e])) => {
+            // the variable might be written through the pointer, so forget about it
+            if let Some(id) = self.local_var(*e) {
+              state.vars.remove(&id);
+              return;
+            }
+          }
+          Some(("*", [p])) | Some(("Index", [p, _])) => {
+            self.check_deref(n, *p, state);
+          }
+          Some(("free", [p])) | Some(("dealloc", [p])) => {
+            if let Some(id) = self.local_var(*p) {
+              if let Some(v) = state.vars.get(&id) {
+                if v.maybe_freed {
+                  let name = self.var_name(id);
+                  self.warning(DOUBLE_FREE, n, format!("'{}' may already have been freed", name));
+                }
+              }
+              let v = state.vars.entry(id).or_insert(VarState::default());
+              v.maybe_freed = true;
+              return;
+            }
+          }
+          _ => (),
+        }
+        self.visit(*function, state);
+        for a in args {
+          self.visit(*a, state);
+        }
+      }
+      Content::Block(statements) => {
+        for &s in statements {
+          if !state.reachable {
+            // loop bodies that `continue` get a trailing void, which isn't the user's code
+            if !matches!(nodes.node(s).content, Content::Literal(PrimitiveVal::Void)) {
+              self.warning(UNREACHABLE_CODE, s, "unreachable code".into());
+            }
+            break;
+          }
+          self.visit(s, state);
+        }
+      }
+      Content::IfThen{ condition, then_branch } => {
+        self.visit_condition(*condition, state);
+        let mut then_state = state.clone();
+        self.visit(*then_branch, &mut then_state);
+        *state = state.join(&then_state);
+      }
+      Content::IfThenElse{ condition, then_branch, else_branch } => {
+        self.visit_condition(*condition, state);
+        let mut then_state = state.clone();
+        self.visit(*then_branch, &mut then_state);
+        self.visit(*else_branch, state);
+        *state = state.join(&then_state);
+      }
+      Content::Switch{ value, cases, default, .. } => {
+        self.visit(*value, state);
+        // without an `else`, the value might not match any of the cases
+        let mut joined = if default.is_some() { State::unreachable() } else { state.clone() };
+        for &body in cases.iter().map(|(_, b)| b).chain(default.iter()) {
+          let mut case_state = state.clone();
+          self.visit(body, &mut case_state);
+          joined = joined.join(&case_state);
+        }
+        *state = joined;
+      }
+      Content::ShortCircuit{ left, right, .. } => {
+        // the right operand only runs sometimes, after the left one has been tested
+        self.visit_condition(*left, state);
+        let mut right_state = state.clone();
+        self.visit(*right, &mut right_state);
+        *state = state.join(&right_state);
+      }
+      Content::While{ condition, body } => {
+        // iterate until nothing changes; this terminates because the states only grow
+        let endless = nodes.endless_loops.contains(&n);
+        loop {
+          let mut loop_state = state.clone();
+          self.visit_condition(*condition, &mut loop_state);
+          let exit_state = loop_state.clone();
+          self.visit(*body, &mut loop_state);
+          let joined = exit_state.join(&loop_state).join(state);
+          if joined == *state {
+            // a `loop` is only left by breaking out of it
+            *state = if endless { State::unreachable() } else { exit_state };
+            break;
+          }
+          *state = joined;
+        }
+      }
+      Content::Label{ label, body } => {
+        self.visit(*body, state);
+        if let Some(s) = self.breaks.remove(label) {
+          *state = state.join(&s);
+        }
+      }
+      Content::BreakToLabel{ label, return_value } => {
+        if let Some(v) = return_value {
+          self.visit(*v, state);
+        }
+        let s = match self.breaks.get(label) {
+          Some(s) => s.join(state),
+          None => state.clone(),
+        };
+        self.breaks.insert(*label, s);
+        *state = State::unreachable();
+      }
+      content => {
+        for c in content.children() {
+          self.visit(c, state);
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,159 @@
+// A small audio mixer for the language, built on SDL's audio device.
+//
+// Sounds are decoded and converted to the device format when they are loaded, so
+// the mixer only has to add samples together. The language refers to sounds and
+// playing voices by id, rather than by pointer, so the handles are plain `Copy`
+// values and a stale handle is harmless (it just doesn't refer to anything).
+//
+// SDL contexts can't leave the thread that created them, so the audio state is
+// thread-local, and it is created the first time a sound is loaded.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use sdl2::audio::{AudioCallback, AudioCVT, AudioDevice, AudioFormat, AudioSpecDesired, AudioSpecWAV};
+use sdl2::{AudioSubsystem, Sdl};
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[repr(C)]
+pub struct SoundHandle {
+  pub id : u64,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[repr(C)]
+pub struct VoiceHandle {
+  pub id : u64,
+}
+
+struct Voice {
+  id : u64,
+  sound : SoundHandle,
+  samples : Arc<Vec<f32>>,
+  position : usize,
+  volume : f32,
+  looping : bool,
+}
+
+/// Runs on SDL's audio thread
+struct Mixer {
+  voices : Vec<Voice>,
+  master_volume : f32,
+}
+
+impl AudioCallback for Mixer {
+  type Channel = f32;
+
+  fn callback(&mut self, out : &mut [f32]) {
+    for s in out.iter_mut() {
+      *s = 0.0;
+    }
+    for v in self.voices.iter_mut() {
+      let volume = v.volume * self.master_volume;
+      for s in out.iter_mut() {
+        if v.position >= v.samples.len() {
+          if !v.looping || v.samples.is_empty() {
+            break;
+          }
+          v.position = 0;
+        }
+        *s += v.samples[v.position] * volume;
+        v.position += 1;
+      }
+    }
+    self.voices.retain(|v| v.looping || v.position < v.samples.len());
+  }
+}
+
+pub struct Audio {
+  _sdl : Sdl,
+  _subsystem : AudioSubsystem,
+  device : AudioDevice<Mixer>,
+  sounds : HashMap<SoundHandle, Arc<Vec<f32>>>,
+  next_id : u64,
+}
+
+impl Audio {
+  fn new() -> Result<Audio, String> {
+    let sdl = sdl2::init()?;
+    let subsystem = sdl.audio()?;
+    let desired = AudioSpecDesired { freq: Some(44100), channels: Some(2), samples: None };
+    let device = subsystem.open_playback(None, &desired, |_spec| {
+      Mixer { voices: vec![], master_volume: 1.0 }
+    })?;
+    device.resume();
+    Ok(Audio { _sdl: sdl, _subsystem: subsystem, device, sounds: HashMap::new(), next_id: 0 })
+  }
+
+  fn next_id(&mut self) -> u64 {
+    self.next_id += 1;
+    self.next_id
+  }
+
+  pub fn load_wav(&mut self, path : &str) -> Result<SoundHandle, String> {
+    let wav = AudioSpecWAV::load_wav(path)
+      .map_err(|e| format!("failed to load sound '{}': {}", path, e))?;
+    let spec = self.device.spec();
+    let cvt = AudioCVT::new(
+      wav.format, wav.channels, wav.freq,
+      AudioFormat::f32_sys(), spec.channels, spec.freq)
+      .map_err(|e| format!("failed to convert sound '{}': {}", path, e))?;
+    let bytes = cvt.convert(wav.buffer().to_vec());
+    let samples = bytes.chunks_exact(4)
+      .map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+      .collect();
+    let sound = SoundHandle { id: self.next_id() };
+    self.sounds.insert(sound, Arc::new(samples));
+    Ok(sound)
+  }
+
+  /// Stops any voices that are still playing the sound
+  pub fn unload(&mut self, sound : SoundHandle) {
+    if self.sounds.remove(&sound).is_some() {
+      self.device.lock().voices.retain(|v| v.sound != sound);
+    }
+  }
+
+  pub fn play(&mut self, sound : SoundHandle, volume : f32, looping : bool) -> Option<VoiceHandle> {
+    let samples = self.sounds.get(&sound)?.clone();
+    let id = self.next_id();
+    let voice = Voice { id, sound, samples, position: 0, volume, looping };
+    self.device.lock().voices.push(voice);
+    Some(VoiceHandle { id })
+  }
+
+  pub fn stop(&mut self, voice : VoiceHandle) {
+    self.device.lock().voices.retain(|v| v.id != voice.id);
+  }
+
+  pub fn is_playing(&mut self, voice : VoiceHandle) -> bool {
+    self.device.lock().voices.iter().any(|v| v.id == voice.id)
+  }
+
+  pub fn set_volume(&mut self, voice : VoiceHandle, volume : f32) {
+    let mut mixer = self.device.lock();
+    if let Some(v) = mixer.voices.iter_mut().find(|v| v.id == voice.id) {
+      v.volume = volume;
+    }
+  }
+
+  pub fn set_master_volume(&mut self, volume : f32) {
+    self.device.lock().master_volume = volume;
+  }
+}
+
+thread_local! {
+  static AUDIO : RefCell<Option<Audio>> = RefCell::new(None);
+}
+
+/// Runs `f` with the audio state, opening the audio device if it isn't open yet
+pub fn with_audio<R>(f : impl FnOnce(&mut Audio) -> R) -> Result<R, String> {
+  AUDIO.with(|a| {
+    let mut a = a.borrow_mut();
+    if a.is_none() {
+      *a = Some(Audio::new().map_err(|e| format!("failed to open audio device: {}", e))?);
+    }
+    Ok(f(a.as_mut().unwrap()))
+  })
+}
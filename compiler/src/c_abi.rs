@@ -0,0 +1,158 @@
+// Classifies how aggregate (struct and array) arguments and return values are
+// passed to C functions on the host platform.
+//
+// LLVM leaves this to the frontend. Passing a first-class struct value to a
+// function only matches the C calling convention by coincidence, so calls to
+// `cbind` functions that take or return structs go through a wrapper that lowers
+// them into whatever the platform expects. Scalars, pointers and vectors are
+// always passed directly.
+//
+// Win64: aggregates of 1, 2, 4 or 8 bytes are passed as an integer of that size.
+// Anything else is passed as a pointer to a copy, and returned through a hidden
+// pointer argument (sret).
+//
+// System V (x86-64 Linux and macOS): aggregates over 16 bytes are passed in memory
+// (byval) and returned through sret. Smaller ones are split into eightbytes, which
+// are each passed in an SSE register if they only contain floats, and in an integer
+// register otherwise. I don't handle running out of registers, where C would spill
+// the whole aggregate to the stack.
+//
+// Calling conventions other than C only mean something on 32-bit x86. On x86-64,
+// LLVM treats stdcall and fastcall functions as ordinary C functions, which is what
+// C compilers do too, so bindings written for 32-bit Windows still work.
+
+use inkwell::context::Context;
+use inkwell::targets::TargetData;
+use inkwell::types::BasicTypeEnum;
+
+use crate::structure::CallingConvention;
+
+#[derive(Clone, Copy)]
+pub enum PassAs {
+  /// Passed the same way as in the language
+  Direct,
+  /// Reinterpreted (through memory) as a different type of the same size
+  Coerce(BasicTypeEnum),
+  /// A pointer to a copy. `byval` is true if the copy is made by the callee's
+  /// side of the convention (System V), rather than by the caller (Win64).
+  Indirect{ byval : bool },
+}
+
+/// The LLVM id of a calling convention
+pub fn calling_convention_id(convention : CallingConvention) -> u32 {
+  match convention {
+    CallingConvention::C => 0,
+    CallingConvention::Stdcall => 64,
+    CallingConvention::Fastcall => 65,
+  }
+}
+
+fn is_aggregate(t : BasicTypeEnum) -> bool {
+  match t {
+    BasicTypeEnum::StructType(_) | BasicTypeEnum::ArrayType(_) => true,
+    _ => false,
+  }
+}
+
+/// Returns true if none of the types need lowering, so that the function can be
+/// declared as it is
+pub fn is_trivial(args : &[BasicTypeEnum], return_type : Option<BasicTypeEnum>) -> bool {
+  !args.iter().cloned().chain(return_type).any(is_aggregate)
+}
+
+pub fn classify_arg(context : &Context, td : &TargetData, t : BasicTypeEnum) -> PassAs {
+  if !is_aggregate(t) {
+    return PassAs::Direct;
+  }
+  let size = td.get_abi_size(&t);
+  if cfg!(windows) {
+    match size {
+      1 | 2 | 4 | 8 => PassAs::Coerce(context.custom_width_int_type(size as u32 * 8).into()),
+      _ => PassAs::Indirect{ byval: false },
+    }
+  }
+  else {
+    match sysv_coerced_type(context, td, t, size) {
+      Some(c) => PassAs::Coerce(c),
+      None => PassAs::Indirect{ byval: true },
+    }
+  }
+}
+
+/// `Indirect` means that the result is written through a hidden first argument
+pub fn classify_return(context : &Context, td : &TargetData, t : BasicTypeEnum) -> PassAs {
+  match classify_arg(context, td, t) {
+    PassAs::Indirect{ .. } => PassAs::Indirect{ byval: false },
+    c => c,
+  }
+}
+
+/// The scalar leaves of a type, with their byte offsets
+fn flatten(td : &TargetData, t : BasicTypeEnum, offset : u64, out : &mut Vec<(u64, BasicTypeEnum)>) {
+  match t {
+    BasicTypeEnum::StructType(st) => {
+      for (i, f) in st.get_field_types().into_iter().enumerate() {
+        let field_offset = td.offset_of_element(&st, i as u32).unwrap();
+        flatten(td, f, offset + field_offset, out);
+      }
+    }
+    BasicTypeEnum::ArrayType(at) => {
+      let element = at.get_element_type();
+      let stride = td.get_abi_size(&element);
+      for i in 0..at.len() as u64 {
+        flatten(td, element, offset + i * stride, out);
+      }
+    }
+    _ => out.push((offset, t)),
+  }
+}
+
+fn sysv_coerced_type(context : &Context, td : &TargetData, t : BasicTypeEnum, size : u64)
+  -> Option<BasicTypeEnum>
+{
+  if size > 16 || size == 0 {
+    return None;
+  }
+  let mut leaves = vec![];
+  flatten(td, t, 0, &mut leaves);
+  // packed structs can have fields that aren't aligned, which go in memory
+  if leaves.iter().any(|(o, t)| o % td.get_abi_alignment(t) as u64 != 0) {
+    return None;
+  }
+  // A lone SIMD vector goes in one SSE register. Vectors mixed with other fields
+  // aren't classified properly, so they're passed in memory.
+  if leaves.iter().any(|(_, t)| match t { BasicTypeEnum::VectorType(_) => true, _ => false }) {
+    return match leaves.as_slice() {
+      [(_, v)] => Some(*v),
+      _ => None,
+    };
+  }
+  let mut eightbytes = vec![];
+  for start in (0..size).step_by(8) {
+    let width = (size - start).min(8);
+    let in_range : Vec<_> =
+      leaves.iter().filter(|(o, _)| *o >= start && *o < start + 8).map(|(_, t)| *t).collect();
+    let all_float = in_range.iter().all(|t| match t {
+      BasicTypeEnum::FloatType(_) => true,
+      _ => false,
+    });
+    let part : BasicTypeEnum =
+      if all_float && !in_range.is_empty() {
+        match (in_range.as_slice(), width) {
+          ([single], _) => *single,
+          (_, 8) => context.f32_type().vec_type(2).into(),
+          _ => context.f32_type().into(),
+        }
+      }
+      else {
+        context.custom_width_int_type(width as u32 * 8).into()
+      };
+    eightbytes.push(part);
+  }
+  if eightbytes.len() == 1 {
+    Some(eightbytes[0])
+  }
+  else {
+    Some(context.struct_type(&eightbytes, false).into())
+  }
+}
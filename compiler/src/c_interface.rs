@@ -2,21 +2,33 @@
 
 use crate::common::*;
 use crate::{lexer, parser};
-use crate::compiler::Compiler;
+use crate::compiler::{Compiler, Val};
+use crate::error::{Error, TextLocation, error_raw};
+use crate::macros;
+use crate::probes;
+use crate::pointers;
+use crate::allocations;
+use crate::safepoint;
+use crate::metering;
+use crate::recovery;
 use crate::expr::{Expr, ExprContent};
+use crate::sdl_bindings::register_sdl_symbols;
+use crate::handles::with_handles;
+use crate::shutdown;
 
 use std::fs::File;
 use std::io::Read;
-use std::ffi::CString;
 use std::collections::HashMap;
-use std::path::Path;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
 use std::fmt;
 use std::mem::ManuallyDrop;
+use std::cell::Cell;
 use std::time::{Instant, Duration};
-use std::sync::mpsc::{channel, TryRecvError, Receiver};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use notify::{Watcher, RecursiveMode, watcher, DebouncedEvent, ReadDirectoryChangesWatcher};
-use libloading::{Library, Symbol};
 
 use std::{thread, time};
 
@@ -138,28 +150,132 @@ impl fmt::Debug for SStr {
   }
 }
 
-#[cfg(not(debug_assertions))]
-static MODE : &'static str = "release";
-#[cfg(debug_assertions)]
-static MODE : &'static str = "debug";
-
-#[cfg(not(test))]
-static ROOT : &'static str = "";
-#[cfg(test)]
-static ROOT : &'static str = "../";
-
 #[no_mangle]
 static TEST_GLOBAL : i64 = 47;
 
 extern {
   pub fn malloc(size: usize) -> *mut u8;
   pub fn free(ptr: *mut u8);
+  pub fn realloc(ptr: *mut u8, size: usize) -> *mut u8;
   pub fn memcpy(dest : *mut u8, src: *const u8, count : usize) -> *mut u8;
 }
 
+static ALLOCATED_BYTES : AtomicU64 = AtomicU64::new(0);
+
+/// The total number of bytes that the language has asked for through `malloc64`
+/// and `realloc64`, for allocation statistics. Frees aren't counted.
+pub fn allocated_bytes() -> u64 {
+  ALLOCATED_BYTES.load(Ordering::Relaxed)
+}
+
+#[no_mangle]
+pub extern "C" fn malloc64(size : u64) -> *mut u8 {
+  ALLOCATED_BYTES.fetch_add(size, Ordering::Relaxed);
+  unsafe { malloc(size as usize) }
+}
+
+#[no_mangle]
+pub extern "C" fn realloc64(ptr : *mut u8, size : u64) -> *mut u8 {
+  ALLOCATED_BYTES.fetch_add(size, Ordering::Relaxed);
+  unsafe { realloc(ptr, size as usize) }
+}
+
+/// `malloc` in the prelude, which records the allocation against the module that
+/// contains `caller`, and uses that module's allocator if it has one
+#[no_mangle]
+pub extern "C" fn tracked_malloc(size : u64, caller : *const u8) -> *mut u8 {
+  allocations::allocate(size, caller as usize)
+}
+
+#[no_mangle]
+pub extern "C" fn tracked_realloc(ptr : *mut u8, size : u64, caller : *const u8) -> *mut u8 {
+  allocations::reallocate(ptr, size, caller as usize)
+}
+
+#[no_mangle]
+pub extern "C" fn tracked_free(ptr : *mut u8) {
+  allocations::deallocate(ptr)
+}
+
+/// Sets the allocator of the module that contains `caller`
+#[no_mangle]
+pub extern "C" fn set_allocator_for(caller : *const u8, alloc : *const u8, free : *const u8) {
+  let allocator = allocations::Allocator {
+    alloc: unsafe { std::mem::transmute(alloc) },
+    free: unsafe { std::mem::transmute(free) },
+  };
+  allocations::set_allocator(caller as usize, Some(allocator));
+}
+
+/// Sends the allocations of the module that contains `caller` back to `malloc64`
+#[no_mangle]
+pub extern "C" fn clear_allocator_for(caller : *const u8) {
+  allocations::set_allocator(caller as usize, None);
+}
+
+/// Prints the memory that each module has allocated and not freed
+#[no_mangle]
+pub extern "C" fn memory_report() {
+  println!("{:<24} {:>12} {:>12}", "module", "allocations", "bytes");
+  for m in allocations::report() {
+    let module = if m.unloaded { format!("{} (unloaded)", m.module) } else { m.module };
+    println!("{:<24} {:>12} {:>12}", module, m.allocations, m.bytes);
+  }
+}
+
+// These panics return inside a recovery boundary, and the code that called them
+// returns straight away (see recovery.rs)
+
 #[no_mangle]
 pub extern "C" fn panic(s : SStr) {
-  panic!("EXPLICIT PANIC: {}", s.as_str())
+  recovery::panic(format!("EXPLICIT PANIC: {}", s.as_str()))
+}
+
+/// Called by array indexing code that was compiled with bounds checks
+#[no_mangle]
+pub extern "C" fn index_out_of_bounds(index : i64, length : u64) {
+  recovery::panic(format!("index {} is out of bounds for an array of length {}", index, length))
+}
+
+/// Called by code that passes a closure with a context to a C function
+#[no_mangle]
+pub extern "C" fn closure_has_context() {
+  recovery::panic("tried to pass a closure that has a context to a C function".into())
+}
+
+/// Called by slicing code that was compiled with bounds checks
+#[no_mangle]
+pub extern "C" fn slice_out_of_bounds(start : i64, end : i64, length : u64) {
+  recovery::panic(format!("slice {}..{} is out of bounds for an array of length {}", start, end, length))
+}
+
+thread_local! {
+  /// The number of expectations that have failed in the call being counted, if
+  /// one is being counted
+  static EXPECTATION_FAILURES : Cell<Option<u64>> = Cell::new(None);
+}
+
+/// Runs `f`, and also returns the number of expectations that failed in it. A
+/// call that's counted inside another one only counts towards its own number.
+pub fn count_failed_expectations<T>(f : impl FnOnce() -> T) -> (T, u64) {
+  let outer = EXPECTATION_FAILURES.with(|e| e.replace(Some(0)));
+  let v = f();
+  let failed = EXPECTATION_FAILURES.with(|e| e.replace(outer)).unwrap_or(0);
+  (v, failed)
+}
+
+/// Called by `expect` and `expect_eq` in core/prelude.code, after they have
+/// printed the values involved. A panic can't unwind through JIT-compiled code,
+/// so this only counts the failure, and the code that failed carries on. Whoever
+/// called it reports the failures once it returns (see `count_failed_expectations`).
+#[no_mangle]
+pub extern "C" fn expectation_failed(loc : SStr) {
+  println!("  at {}", loc.as_str());
+  EXPECTATION_FAILURES.with(|e| {
+    if let Some(n) = e.get() {
+      e.set(Some(n + 1));
+    }
+  });
 }
 
 #[no_mangle]
@@ -187,18 +303,46 @@ pub extern "C" fn load_module(c : *mut Compiler, maybe_name : SStr, imports : SS
   let imports = imports.as_slice();
   let maybe_name = maybe_name.as_str();
   let name = if maybe_name == "" { None } else { Some(maybe_name) };
-  *out = match c.load_expr_as_module(e, name, imports) {
-    Ok((unit_id, _val)) => Some(unit_id).into(),
-    Err(_e) => {
-      println!("Failed to load module");
-      None.into()
+  let result = c.load_expr_as_module(e, name, imports);
+  *out = report_load(c, maybe_name, result).into();
+}
+
+/// Loads a code file as a module, named after its path. Unlike `load_expression`,
+/// syntax errors are reported like any other load error.
+#[no_mangle]
+pub extern "C" fn load_file_module(c : *mut Compiler, path : SStr, imports : SSlice<UnitId>, out : &mut SOption<UnitId>) {
+  let c = unsafe { &mut *c };
+  let path = path.as_str();
+  let result = std::fs::read_to_string(path)
+    .map_err(|e| error_raw(TextLocation::zero(), format!("failed to read '{}': {}", path, e)))
+    .and_then(|code| c.load_module(&code, Some(path), imports.as_slice()));
+  *out = report_load(c, path, result).into();
+}
+
+/// Prints a module's load errors, and shows them in the debug overlay until the
+/// module loads successfully. A module that failed is unloaded, so that its name
+/// can be loaded again.
+fn report_load(c : &mut Compiler, name : &str, result : Result<(UnitId, Val), Error>) -> Option<UnitId> {
+  match result {
+    Ok((unit_id, _val)) => {
+      debug_draw::clear_diagnostics(name);
+      Some(unit_id)
     }
-  };
+    Err(e) => {
+      let errors = format!("{}", e.display());
+      println!("Failed to load module {}\n{}", name, errors);
+      debug_draw::set_diagnostics(name, &errors);
+      if let Some(unit_id) = c.code_store.named_unit(name) {
+        c.unload_module(unit_id);
+      }
+      None
+    }
+  }
 }
 
 pub extern "C" fn unload_module(c : *mut Compiler, unit_id : UnitId) {
   let c = unsafe { &mut *c };
-  c.code_store.remove_unit(unit_id);
+  c.unload_module(unit_id);
 }
 
 pub extern "C" fn find_all_dependents(c : *mut Compiler, unit_id : UnitId, out : &mut SArray<UnitId>) {
@@ -207,9 +351,6 @@ pub extern "C" fn find_all_dependents(c : *mut Compiler, unit_id : UnitId, out :
   *out = SArray::new(deps);
 }
 
-// TODO: panics if there is more than one overload, because no argument types
-// are provided to narrow the search, and it would be very unsafe to return
-// the wrong one.
 #[no_mangle]
 pub extern "C" fn get_function(
   c : *mut Compiler,
@@ -219,22 +360,131 @@ pub extern "C" fn get_function(
 )
 {
   let c = unsafe { &mut *c };
-  let types = c.code_store.types(unit_id);
-  let name = name.as_str();
-  let mut i = types.symbols.values()
-    .filter(|def| def.name.as_ref() == name && def.type_tag.sig().is_some())
-    .flat_map(|def| def.codegen_name());
-  let lu = c.code_store.llvm_unit(unit_id);
-  let address =
-    i.next().and_then(|codegen_name|
-      unsafe { lu.ee.get_function_address(codegen_name) });
-  *out = if i.next().is_some() {
-    println!("two matching overloads for '{}' in get_function_address", name);
-    None.into()
+  *out = c.function_address(unit_id, name.as_str()).map(|a| a as *mut u8).into();
+}
+
+#[no_mangle]
+pub extern "C" fn subscribe_event(c : *mut Compiler, topic : SStr, payload_size : u64) -> u64 {
+  let c = unsafe { &mut *c };
+  match c.events.subscribe_raw(topic.as_str(), payload_size as usize) {
+    Ok(id) => id.inner(),
+    Err(e) => {
+      println!("{}", e);
+      0
+    }
   }
-  else {
-    address.map(|u| u as *mut u8).into()
-  };
+}
+
+#[no_mangle]
+pub extern "C" fn unsubscribe_event(c : *mut Compiler, subscription : u64) {
+  let c = unsafe { &mut *c };
+  c.events.unsubscribe(subscription.into());
+}
+
+#[no_mangle]
+pub extern "C" fn publish_event(c : *mut Compiler, topic : SStr, data : *const u8, payload_size : u64) {
+  let c = unsafe { &mut *c };
+  let payload = unsafe { std::slice::from_raw_parts(data, payload_size as usize) };
+  if let Err(e) = c.events.publish_raw(topic.as_str(), payload) {
+    println!("{}", e);
+  }
+}
+
+#[no_mangle]
+pub extern "C" fn poll_event(c : *mut Compiler, subscription : u64, out : *mut u8, payload_size : u64) -> bool {
+  let c = unsafe { &mut *c };
+  let out = unsafe { std::slice::from_raw_parts_mut(out, payload_size as usize) };
+  match c.events.poll_raw(subscription.into(), out) {
+    Ok(b) => b,
+    Err(e) => {
+      println!("{}", e);
+      false
+    }
+  }
+}
+
+#[no_mangle]
+pub extern "C" fn request_screenshot(c : *mut Compiler, path : SStr) {
+  let c = unsafe { &mut *c };
+  c.capture.request_screenshot(path.as_str());
+}
+
+#[no_mangle]
+pub extern "C" fn start_recording(c : *mut Compiler, directory : SStr) -> bool {
+  let c = unsafe { &mut *c };
+  match c.capture.start_recording(directory.as_str()) {
+    Ok(()) => true,
+    Err(e) => {
+      println!("{}", e);
+      false
+    }
+  }
+}
+
+#[no_mangle]
+pub extern "C" fn stop_recording(c : *mut Compiler) {
+  let c = unsafe { &mut *c };
+  c.capture.stop_recording();
+}
+
+/// True if the frame should be read back and passed to `save_frame`
+#[no_mangle]
+pub extern "C" fn frame_presented(c : *mut Compiler) -> bool {
+  let c = unsafe { &mut *c };
+  c.capture.frame_presented()
+}
+
+#[no_mangle]
+pub extern "C" fn save_frame(c : *mut Compiler, pixels : *const u8, width : i32, height : i32, pitch : i32) -> bool {
+  let c = unsafe { &mut *c };
+  if width < 0 || height < 0 || pitch < 0 {
+    return false;
+  }
+  let pixels = unsafe { std::slice::from_raw_parts(pixels, pitch as usize * height as usize) };
+  match c.capture.save_frame(pixels, width as u32, height as u32, pitch as u32) {
+    Ok(()) => true,
+    Err(e) => {
+      println!("{}", e);
+      false
+    }
+  }
+}
+
+/// Registers a function to call when the process shuts down cleanly. It's dropped
+/// if the unit that contains it, or the unit whose top-level code registered it, is
+/// unloaded first.
+#[no_mangle]
+pub extern "C" fn at_exit(c : *mut Compiler, f : *const u8) {
+  let c = unsafe { &mut *c };
+  c.add_exit_callback(f as usize);
+}
+
+/// Calls a function under the frame budget (see `Compiler::frame_budget`). Returns
+/// false if it ran over and its loops were interrupted, or if it panicked.
+#[no_mangle]
+pub extern "C" fn run_frame(c : *mut Compiler, f : *const u8) -> bool {
+  let c = unsafe { &mut *c };
+  let f : extern "C" fn() = unsafe { std::mem::transmute(f) };
+  c.run_frame(f)
+}
+
+/// Called when a sandboxed function is entered. True if the step budget has run out.
+#[no_mangle]
+pub extern "C" fn metered_step() -> bool {
+  metering::step()
+}
+
+/// Called at loop back-edges by code compiled with safepoints
+#[no_mangle]
+pub extern "C" fn safepoint() -> bool {
+  safepoint::safepoint()
+}
+
+/// True once Ctrl-C has been pressed in watch mode. Programs that run their own
+/// loop should check this, and return so that the process can shut down cleanly.
+#[no_mangle]
+pub extern "C" fn shutdown_requested() -> bool {
+  shutdown::requested()
 }
 
 //out : &mut SOption<UnitId>
@@ -267,27 +517,116 @@ pub extern "C" fn template_quote(e : &Expr, args : SSlice<&Expr>) -> Box<Expr> {
   Box::new(template(e, args.as_slice(), &mut 0))
 }
 
+/// A symbol that can't clash with any other name (see macros.rs)
+#[no_mangle]
+pub extern "C" fn gensym(name : SStr) -> Box<Expr> {
+  Box::new(Expr::new(ExprContent::symbol(macros::fresh_name(name.as_str())), TextLocation::zero()))
+}
+
+/// A symbol that a macro can bind without it being renamed for hygiene
+#[no_mangle]
+pub extern "C" fn unhygienic(name : SStr) -> Box<Expr> {
+  Box::new(Expr::new(ExprContent::symbol(name.as_str().into()), TextLocation::zero()))
+}
+
 #[no_mangle]
 pub extern "C" fn print_string(s : SStr) {
   print!("{}", s.as_str());
 }
 
-pub type TimerHandle = ManuallyDrop<Box<Instant>>;
+/// Converts a char index into a byte offset, clamping to the end of the string
+fn char_to_byte_offset(s : &str, char_index : u64) -> usize {
+  s.char_indices().nth(char_index as usize).map(|(i, _)| i).unwrap_or(s.len())
+}
+
+#[no_mangle]
+pub extern "C" fn string_len_chars(s : SStr) -> u64 {
+  s.as_str().chars().count() as u64
+}
+
+/// Returns a view of the chars in the range `start..end`. No allocation is done,
+/// so the result borrows from `s`.
+#[no_mangle]
+pub extern "C" fn string_slice_chars(out : &mut SStr, s : SStr, start : u64, end : u64) {
+  let st = s.as_str();
+  let start = char_to_byte_offset(st, start);
+  let end = char_to_byte_offset(st, end).max(start);
+  *out = SStr::from_str(&st[start..end]);
+}
+
+/// Finds the byte offset of the first occurrence of `pattern`
+#[no_mangle]
+pub extern "C" fn string_find(s : SStr, pattern : SStr, out : &mut SOption<u64>) {
+  *out = s.as_str().find(pattern.as_str()).map(|i| i as u64).into();
+}
+
+/// Splits `s` on a separator. The strings in the output array borrow from `s`.
+#[no_mangle]
+pub extern "C" fn string_split(out : &mut SArray<SStr>, s : SStr, separator : SStr) {
+  let s = s.as_str();
+  let parts = s.split(separator.as_str()).map(SStr::from_str).collect();
+  // write without dropping, because the old value was never a Rust allocation
+  unsafe { std::ptr::write(out, SArray::new(parts)) };
+}
 
+/// Concatenates two strings into a new heap allocation.
+/// 
+/// The runtime string representation is just a pointer and a length, so there is
+/// nowhere to store a short string inline. The best I can do without changing
+/// the representation is to avoid the allocation when one side is empty. The
+/// allocation is recorded against the module that contains `caller`.
 #[no_mangle]
-pub extern "C" fn start_timer() -> TimerHandle {
-  ManuallyDrop::new(Box::new(Instant::now()))
+pub extern "C" fn string_concat(out : &mut SStr, a : SStr, b : SStr, caller : *const u8) {
+  if a.length == 0 { *out = b; return }
+  if b.length == 0 { *out = a; return }
+  let length = (a.length + b.length) as usize;
+  unsafe {
+    let data = allocations::allocate(length as u64, caller as usize);
+    memcpy(data, a.data, a.length as usize);
+    memcpy(data.offset(a.length as isize), b.data, b.length as usize);
+    *out = SStr { data, length: length as u64 };
+  }
 }
 
 #[no_mangle]
-pub extern "C" fn drop_timer(t : TimerHandle) {
-  ManuallyDrop::into_inner(t);
+pub extern "C" fn hash_u64(v : u64) -> u64 {
+  let mut h = DefaultHasher::new();
+  h.write_u64(v);
+  h.finish()
 }
 
 #[no_mangle]
-pub extern "C" fn millis_elapsed(timer : TimerHandle) -> u64 {
+pub extern "C" fn hash_bytes(data : *const u8, length : u64) -> u64 {
+  let bytes = unsafe { std::slice::from_raw_parts(data, length as usize) };
+  let mut h = DefaultHasher::new();
+  h.write(bytes);
+  h.finish()
+}
+
+/// Runtime resources are passed to the language as handles (see handles.rs). A stale
+/// handle is reported, and the call does nothing.
+fn report_handle_error<T : Default>(r : Result<T, String>) -> T {
+  r.unwrap_or_else(|e| {
+    println!("{}", e);
+    T::default()
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn start_timer() -> u64 {
+  with_handles(|h| h.timers.insert(Instant::now()))
+}
+
+#[no_mangle]
+pub extern "C" fn drop_timer(timer : u64) {
+  report_handle_error(with_handles(|h| h.timers.remove(timer).map(|_| ())))
+}
+
+#[no_mangle]
+pub extern "C" fn millis_elapsed(timer : u64) -> u64 {
   let v = Instant::now();
-  v.duration_since(**timer).as_millis() as u64
+  report_handle_error(with_handles(|h|
+    h.timers.get(timer).map(|t| v.duration_since(*t).as_millis() as u64)))
 }
 
 pub struct FileWatcher {
@@ -295,75 +634,332 @@ pub struct FileWatcher {
   rx : Receiver<DebouncedEvent>,
 }
 
-pub type WatcherHandle = ManuallyDrop<Box<FileWatcher>>;
-
 #[no_mangle]
-pub extern "C" fn poll_watcher_event(w : WatcherHandle, path_out : &mut SOption<SStr>) {
-  let out = match w.rx.try_recv() {
-    Ok(event) => {
-      match event {
-        DebouncedEvent::Write(path) => {
-          let path : String = path.to_str().unwrap().replace("\\", "/");
-          Some(SStr::from_string(ManuallyDrop::new(path)))
-        }
-        _ => None,
-      }
-    },
-    Err(e) => match e {
-      TryRecvError::Disconnected => None,
-      TryRecvError::Empty => None,
-    },
+pub extern "C" fn poll_watcher_event(w : u64, path_out : &mut SOption<SStr>) {
+  let event = report_handle_error(with_handles(|h| h.watchers.get(w).map(|w| w.rx.try_recv().ok())));
+  let out = match event {
+    Some(DebouncedEvent::Write(path)) => {
+      let path : String = path.to_str().unwrap().replace("\\", "/");
+      Some(SStr::from_string(ManuallyDrop::new(path)))
+    }
+    _ => None,
   };
   *path_out = out.into();
 }
 
 #[no_mangle]
-pub extern "C" fn create_watcher(millisecond_interval : u64) -> WatcherHandle {
+pub extern "C" fn create_watcher(millisecond_interval : u64) -> u64 {
   let (tx, rx) = channel();
   let watcher = watcher(tx, Duration::from_millis(millisecond_interval)).unwrap();
-  ManuallyDrop::new(Box::new(FileWatcher { watcher, rx}))
+  with_handles(|h| h.watchers.insert(FileWatcher { watcher, rx }))
 }
 
 #[no_mangle]
-pub extern "C" fn drop_watcher(w : WatcherHandle) {
-  ManuallyDrop::into_inner(w);
+pub extern "C" fn drop_watcher(w : u64) {
+  report_handle_error(with_handles(|h| h.watchers.remove(w).map(|_| ())))
 }
 
 #[no_mangle]
-pub extern "C" fn watch_file(mut w : WatcherHandle, path : SStr) {
-  if w.watcher.watch(path.as_str(), RecursiveMode::Recursive).is_err() {
-    panic!("failed to watch file '{}'", path.as_str())
+pub extern "C" fn watch_file(w : u64, path : SStr) {
+  let r = with_handles(|h| h.watchers.get_mut(w).map(|w|
+    w.watcher.watch(path.as_str(), RecursiveMode::Recursive).is_ok()));
+  match r {
+    Ok(true) => (),
+    Ok(false) => panic!("failed to watch file '{}'", path.as_str()),
+    Err(e) => println!("{}", e),
   }
 }
 
 use rand::{Rng, SeedableRng, rngs::SmallRng};
 
-pub type RNGHandle = ManuallyDrop<Box<SmallRng>>;
+#[no_mangle]
+pub extern "C" fn seeded_rng(seed : u64) -> u64 {
+  with_handles(|h| h.rngs.insert(SmallRng::seed_from_u64(seed)))
+}
+
+#[no_mangle]
+pub extern "C" fn drop_seeded_rng(rng : u64) {
+  report_handle_error(with_handles(|h| h.rngs.remove(rng).map(|_| ())))
+}
+
+#[no_mangle]
+pub extern "C" fn rand_f64(rng : u64) -> f64 {
+  report_handle_error(with_handles(|h| h.rngs.get_mut(rng).map(|r| r.gen())))
+}
+
+#[no_mangle]
+pub extern "C" fn rand_u64(rng : u64) -> u64 {
+  report_handle_error(with_handles(|h| h.rngs.get_mut(rng).map(|r| r.gen())))
+}
+
+#[no_mangle]
+pub extern "C" fn list_live_handles(out : &mut SArray<SStr>) {
+  let lines =
+    with_handles(|h| h.describe_live()).into_iter()
+    .map(|l| SStr::from_string(ManuallyDrop::new(l)))
+    .collect();
+  *out = SArray::new(lines);
+}
+
+/// A seed that is different every time, taken from the clock
+#[no_mangle]
+pub extern "C" fn random_seed() -> u64 {
+  let t = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+  t.as_secs() ^ t.subsec_nanos() as u64
+}
+
+use crate::reflection;
+
+/// Called by `check` in core/check.code, with a description from `typeinfo(T)`
+#[no_mangle]
+pub extern "C" fn arbitrary_value(rng : u64, info : u64, out : *mut u8) {
+  if let Some(d) = reflection::description(info) {
+    let r = with_handles(|h| h.rngs.get_mut(rng).map(|r| unsafe { reflection::arbitrary(&d, r, out) }));
+    report_handle_error(r)
+  }
+}
+
+#[no_mangle]
+pub extern "C" fn shrink_value(info : u64, v : *const u8, n : u64, out : *mut u8) -> bool {
+  match reflection::description(info) {
+    Some(d) => unsafe { reflection::shrink(&d, v, n, out) },
+    None => false,
+  }
+}
+
+#[no_mangle]
+pub extern "C" fn print_value(info : u64, v : *const u8) {
+  if let Some(d) = reflection::description(info) {
+    print!("{}", unsafe { reflection::describe(&d.shape, v) });
+  }
+}
+
+use crate::audio::{with_audio, SoundHandle, VoiceHandle};
+
+#[no_mangle]
+pub extern "C" fn load_wav(path : SStr, out : &mut SOption<SoundHandle>) {
+  *out = match with_audio(|a| a.load_wav(path.as_str())).and_then(|r| r) {
+    Ok(sound) => Some(sound).into(),
+    Err(e) => {
+      println!("{}", e);
+      None.into()
+    }
+  };
+}
+
+#[no_mangle]
+pub extern "C" fn unload_sound(sound : SoundHandle) {
+  let _ = with_audio(|a| a.unload(sound));
+}
+
+/// Returns a handle with id 0 if the sound doesn't exist
+#[no_mangle]
+pub extern "C" fn play_sound(sound : SoundHandle, volume : f64, looping : bool) -> VoiceHandle {
+  match with_audio(|a| a.play(sound, volume as f32, looping)) {
+    Ok(Some(v)) => v,
+    Ok(None) => VoiceHandle { id: 0 },
+    Err(e) => {
+      println!("{}", e);
+      VoiceHandle { id: 0 }
+    }
+  }
+}
+
+#[no_mangle]
+pub extern "C" fn stop_sound(voice : VoiceHandle) {
+  let _ = with_audio(|a| a.stop(voice));
+}
+
+#[no_mangle]
+pub extern "C" fn is_sound_playing(voice : VoiceHandle) -> bool {
+  with_audio(|a| a.is_playing(voice)).unwrap_or(false)
+}
 
 #[no_mangle]
-pub extern "C" fn seeded_rng(seed : u64) -> RNGHandle {
-  ManuallyDrop::new(Box::new(SmallRng::seed_from_u64(seed)))
+pub extern "C" fn set_sound_volume(voice : VoiceHandle, volume : f64) {
+  let _ = with_audio(|a| a.set_volume(voice, volume as f32));
 }
 
 #[no_mangle]
-pub extern "C" fn drop_seeded_rng(rng : RNGHandle) {
-  ManuallyDrop::into_inner(rng);
+pub extern "C" fn set_master_volume(volume : f64) {
+  let _ = with_audio(|a| a.set_master_volume(volume as f32));
 }
 
+use crate::images::{with_images, SImage};
+
 #[no_mangle]
-pub extern "C" fn rand_f64(mut rng : RNGHandle) -> f64 {
-  rng.gen()
+pub extern "C" fn load_image(path : SStr, out : &mut SOption<*mut SImage>) {
+  *out = match with_images(|c| c.load(path.as_str())).and_then(|r| r) {
+    Ok(img) => Some(img).into(),
+    Err(e) => {
+      println!("{}", e);
+      None.into()
+    }
+  };
 }
 
 #[no_mangle]
-pub extern "C" fn rand_u64(mut rng : RNGHandle) -> u64 {
-  rng.gen()
+pub extern "C" fn reload_changed_images() -> u64 {
+  with_images(|c| c.reload_changed()).unwrap_or(0)
+}
+
+use crate::debug_draw;
+
+#[no_mangle]
+pub extern "C" fn debug_line(x0 : f64, y0 : f64, x1 : f64, y1 : f64, colour : u32) {
+  debug_draw::line(x0, y0, x1, y1, colour);
+}
+
+#[no_mangle]
+pub extern "C" fn debug_rect(x : f64, y : f64, w : f64, h : f64, colour : u32) {
+  debug_draw::rect(x, y, w, h, colour);
+}
+
+#[no_mangle]
+pub extern "C" fn debug_text(x : f64, y : f64, text : SStr, colour : u32) {
+  debug_draw::text(x, y, text.as_str(), colour);
+}
+
+#[no_mangle]
+pub extern "C" fn draw_debug_overlay(renderer : *mut sdl2::sys::SDL_Renderer) {
+  debug_draw::draw_overlay(renderer);
+}
+
+/// Matches `struct vec3` in core/math.code
+#[repr(C)]
+pub struct Vec3 { pub x : f64, pub y : f64, pub z : f64 }
+
+/// Matches `struct vec4` in core/math.code
+pub type Vec4 = [f64; 4];
+
+/// Matches `struct mat4` in core/math.code. Column-major, so `m[c][r]`.
+pub type Mat4 = [[f64; 4]; 4];
+
+#[no_mangle]
+pub extern "C" fn mat4_mul(out : &mut Mat4, a : &Mat4, b : &Mat4) {
+  let mut m = [[0.0; 4]; 4];
+  for c in 0..4 {
+    for r in 0..4 {
+      m[c][r] = (0..4).map(|i| a[i][r] * b[c][i]).sum();
+    }
+  }
+  *out = m;
+}
+
+#[no_mangle]
+pub extern "C" fn mat4_transform(out : &mut Vec4, m : &Mat4, v : &Vec4) {
+  let mut t = [0.0; 4];
+  for r in 0..4 {
+    t[r] = (0..4).map(|c| m[c][r] * v[c]).sum();
+  }
+  *out = t;
+}
+
+/// A rotation of `angle` radians around `axis`, which doesn't need to be normalised
+#[no_mangle]
+pub extern "C" fn mat4_rotation(out : &mut Mat4, axis : &Vec3, angle : f64) {
+  let len = (axis.x * axis.x + axis.y * axis.y + axis.z * axis.z).sqrt();
+  let (x, y, z) = (axis.x / len, axis.y / len, axis.z / len);
+  let (s, c) = angle.sin_cos();
+  let t = 1.0 - c;
+  *out = [
+    [t*x*x + c,   t*x*y + s*z, t*x*z - s*y, 0.0],
+    [t*x*y - s*z, t*y*y + c,   t*y*z + s*x, 0.0],
+    [t*x*z + s*y, t*y*z - s*x, t*z*z + c,   0.0],
+    [0.0,         0.0,         0.0,         1.0],
+  ];
+}
+
+/// Returns false, and leaves `out` untouched, if the matrix can't be inverted
+#[no_mangle]
+pub extern "C" fn mat4_inverse(out : &mut Mat4, m : &Mat4) -> bool {
+  // Gauss-Jordan elimination on the rows of [m | I]
+  let mut a = [[0.0; 8]; 4];
+  for r in 0..4 {
+    for c in 0..4 {
+      a[r][c] = m[c][r];
+    }
+    a[r][4 + r] = 1.0;
+  }
+  for col in 0..4 {
+    let pivot = (col..4).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs())).unwrap();
+    // a NaN or infinite pivot can't be divided out, so it counts as singular
+    if !(a[pivot][col].abs() >= 1e-12) || !a[pivot][col].is_finite() {
+      return false;
+    }
+    a.swap(col, pivot);
+    let p = a[col][col];
+    for v in a[col].iter_mut() { *v /= p; }
+    for r in 0..4 {
+      if r != col {
+        let f = a[r][col];
+        let pivot_row = a[col];
+        for (v, p) in a[r].iter_mut().zip(pivot_row.iter()) { *v -= f * p; }
+      }
+    }
+  }
+  for r in 0..4 {
+    for c in 0..4 {
+      out[c][r] = a[r][4 + c];
+    }
+  }
+  true
 }
 
 pub extern "C" fn print_type<T : std::fmt::Display>(t : T) {
   print!("{}", t);
 }
 
+/// Called by `probe_at` in core/prelude.code
+pub extern "C" fn probe_type<T : std::fmt::Display>(unit : i64, span : SStr, v : T) {
+  probes::record(unit as u64, span.as_str(), v.to_string());
+}
+
+#[no_mangle]
+pub extern "C" fn probe_string(unit : i64, span : SStr, v : SStr) {
+  probes::record(unit as u64, span.as_str(), format!("{:?}", v.as_str()));
+}
+
+/// The unit whose statics a pointer points into, or zero. Called by `checked_at`.
+#[no_mangle]
+pub extern "C" fn pointer_owner(p : *const u8) -> u64 {
+  pointers::owner(p as usize)
+}
+
+/// Called when a checked pointer is dereferenced
+#[no_mangle]
+pub extern "C" fn check_pointer(unit : u64, site : SStr) {
+  if let Err(e) = pointers::check(unit, site.as_str()) {
+    panic!("{}", e)
+  }
+}
+
+/// Writes the LLVM IR of a unit to `out`. Returns false if the unit isn't compiled.
+#[no_mangle]
+pub extern "C" fn dump_ir(c : *mut Compiler, unit_id : UnitId, out : &mut SStr) -> bool {
+  let c = unsafe { &mut *c };
+  if let Some(ir) = c.dump_ir(unit_id) {
+    *out = SStr::from_string(ManuallyDrop::new(ir));
+    true
+  }
+  else {
+    false
+  }
+}
+
+/// Writes the node tree of a unit to `out`. Returns false if the unit doesn't exist.
+#[no_mangle]
+pub extern "C" fn dump_nodes(c : *mut Compiler, unit_id : UnitId, out : &mut SStr) -> bool {
+  let c = unsafe { &mut *c };
+  if let Some(nodes) = c.dump_nodes(unit_id) {
+    *out = SStr::from_string(ManuallyDrop::new(nodes));
+    true
+  }
+  else {
+    false
+  }
+}
+
 #[no_mangle]
 pub extern "C" fn print_expr(e : &Expr) {
   println!("{}", e);
@@ -377,63 +973,163 @@ pub extern "C" fn expr_to_string(out : &mut SStr, e : &Expr) {
   *out = s;
 }
 
+// Expression accessors and builders. Built expressions have no location, so they take
+// the location of whatever they're spliced into by a macro or stage (see macros.rs).
+
+/// The number of children of a construct. Other expressions have none.
+#[no_mangle]
+pub extern "C" fn expr_child_count(e : &Expr) -> u64 {
+  e.children().len() as u64
+}
+
+/// A child of a construct, or null if it doesn't have one at that index
+#[no_mangle]
+pub extern "C" fn expr_child(e : &Expr, index : u64) -> *const Expr {
+  match e.children().get(index as usize) {
+    Some(c) => c,
+    None => std::ptr::null(),
+  }
+}
+
+/// Writes the name of a symbol to `out`, borrowing from the expression. Returns
+/// false if the expression isn't a symbol.
+#[no_mangle]
+pub extern "C" fn expr_symbol_name(out : &mut SStr, e : &Expr) -> bool {
+  if let Some(s) = e.try_symbol() {
+    *out = SStr::from_str(s);
+    true
+  }
+  else {
+    false
+  }
+}
+
+/// Writes the name of a construct (such as "call" or "block") to `out`, borrowing
+/// from the expression. Returns false if the expression isn't a construct.
+#[no_mangle]
+pub extern "C" fn expr_construct_name(out : &mut SStr, e : &Expr) -> bool {
+  if let Some((s, _)) = e.try_construct() {
+    *out = SStr::from_str(s);
+    true
+  }
+  else {
+    false
+  }
+}
+
+/// A construct with the given name and children
+#[no_mangle]
+pub extern "C" fn mk_construct(name : SStr, children : SSlice<&Expr>) -> Box<Expr> {
+  let children = children.as_slice().iter().map(|&e| e.clone()).collect();
+  Box::new(Expr::new(ExprContent::list(name.as_str().into(), children), TextLocation::zero()))
+}
+
+/// A call to a function by name
+#[no_mangle]
+pub extern "C" fn mk_call(function : SStr, args : SSlice<&Expr>) -> Box<Expr> {
+  let function = Expr::new(ExprContent::symbol(function.as_str().into()), TextLocation::zero());
+  let mut children = vec![function];
+  children.extend(args.as_slice().iter().map(|&e| e.clone()));
+  Box::new(Expr::new(ExprContent::list("call".into(), children), TextLocation::zero()))
+}
+
+/// `let name = value`
+#[no_mangle]
+pub extern "C" fn mk_let(name : SStr, value : &Expr) -> Box<Expr> {
+  let name = Expr::new(ExprContent::symbol(name.as_str().into()), TextLocation::zero());
+  let def = Expr::new(ExprContent::list("=".into(), vec![name, value.clone()]), TextLocation::zero());
+  Box::new(Expr::new(ExprContent::list("let".into(), vec![def]), TextLocation::zero()))
+}
+
+#[no_mangle]
+pub extern "C" fn expr_equal(a : &Expr, b : &Expr) -> bool {
+  a.structurally_equal(b)
+}
+
+#[no_mangle]
+pub extern "C" fn expr_to_pretty_string(out : &mut SStr, e : &Expr, width : u64) {
+  *out = SStr::from_string(ManuallyDrop::new(e.pretty(width as usize)));
+}
+
 /// defined for the test suite only
 #[no_mangle]
 pub extern "C" fn test_add(a : i64, b : i64) -> i64 {
   a + b
 }
 
+/// defined for the test suite only. Small enough to be passed in registers.
+#[repr(C)]
+pub struct TestPair { a : i64, b : f64 }
+
+/// defined for the test suite only. Too big to be passed in registers.
+#[repr(C)]
+pub struct TestTriple { a : i64, b : i64, c : i64 }
+
+/// defined for the test suite only
+#[no_mangle]
+pub extern "C" fn test_pair_sum(p : TestPair) -> f64 {
+  p.a as f64 + p.b
+}
+
+/// defined for the test suite only
+#[no_mangle]
+pub extern "C" fn test_make_triple(a : i64) -> TestTriple {
+  TestTriple { a, b: a * 2, c: a * 3 }
+}
+
+/// defined for the test suite only
+#[no_mangle]
+pub extern "C" fn test_triple_sum(t : TestTriple) -> i64 {
+  t.a + t.b + t.c
+}
+
 #[no_mangle]
 pub extern "C" fn thread_sleep(millis : u64) {
   let t = time::Duration::from_millis(millis);
   thread::sleep(t);
 }
 
+/// Looks for the library in the search paths. Returns zero (and prints the paths
+/// that were tried) if it can't be loaded.
 #[no_mangle]
-pub extern "C" fn load_library_c(lib_name : SStr) -> usize {
-  let lib = lib_name.as_str();
-  let deps_path = format!("{}target/{}/deps/{}.dll", ROOT, MODE, lib);
-  let local_path = format!("{}.dll", lib);
-  let paths = [deps_path.as_str(), local_path.as_str()];
-  paths.iter().cloned().flat_map(load_library).nth(0).unwrap_or(0)
+pub extern "C" fn load_library(c : *mut Compiler, lib_name : SStr) -> u64 {
+  let c = unsafe { &mut *c };
+  match c.libraries.find_and_load(lib_name.as_str()) {
+    Ok(handle) => handle,
+    Err(e) => {
+      println!("{}", e);
+      0
+    }
+  }
 }
 
-static mut SHARED_LIBRARIES : Option<HashMap<usize, (RefStr, Library)>> = None;
-static mut SHARED_LIB_HANDLE_COUNTER : usize = 0;
+#[no_mangle]
+pub extern "C" fn add_library_search_path(c : *mut Compiler, directory : SStr) {
+  let c = unsafe { &mut *c };
+  c.libraries.search_paths.push(directory.as_str().into());
+}
 
-/// TODO: This is not thread-safe!
-pub fn load_library(path : &str) -> Option<usize> {
-  let path = Path::new(path);
-  let file_name = path.file_name().unwrap().to_str().unwrap();
-  let r = Library::new(path);
-  if r.is_err() {
-    return None;
-  }
-  let lib = r.unwrap();
-  unsafe {
-    if SHARED_LIBRARIES.is_none() {
-      SHARED_LIBRARIES = Some(HashMap::new());
-    }
-    SHARED_LIB_HANDLE_COUNTER += 1;
-    let handle = SHARED_LIB_HANDLE_COUNTER;
-    SHARED_LIBRARIES.as_mut().unwrap().insert(handle, (file_name.into(), lib));
-    Some(handle)
-  }
+/// Returns null if the library or the symbol doesn't exist
+#[no_mangle]
+pub extern "C" fn load_symbol(c : *mut Compiler, lib_handle : u64, symbol_name : SStr) -> usize {
+  let c = unsafe { &mut *c };
+  c.libraries.symbol(lib_handle, symbol_name.as_str()).unwrap_or(0)
 }
 
-/// TODO: This is not thread-safe!
 #[no_mangle]
-pub extern "C" fn load_symbol(lib_handle : usize, symbol_name : SStr) -> usize {
-  let s = CString::new(symbol_name.as_str()).unwrap();
-  unsafe {
-    if SHARED_LIBRARIES.is_none() {
-      panic!();
-    }
-    let (_, lib) = SHARED_LIBRARIES.as_ref().unwrap().get(&lib_handle).unwrap();
-    let symbol: Option<Symbol<*const ()>> =
-      lib.get(s.as_bytes_with_nul()).ok();
-    symbol.map(|sym| sym.into_raw().into_raw() as usize).unwrap_or(0)
-  }
+pub extern "C" fn unload_library(c : *mut Compiler, lib_handle : u64) -> bool {
+  let c = unsafe { &mut *c };
+  c.libraries.unload(lib_handle)
+}
+
+#[no_mangle]
+pub extern "C" fn loaded_libraries(c : *mut Compiler, out : &mut SArray<SStr>) {
+  let c = unsafe { &mut *c };
+  let names =
+    c.libraries.iter()
+    .map(|(_, l)| SStr::from_string(ManuallyDrop::new(l.name.to_string())))
+    .collect();
+  *out = SArray::new(names);
 }
 
 pub struct CSymbols {
@@ -451,12 +1147,26 @@ impl CSymbols {
 
   fn populate(&mut self) {
     let sym = &mut self.local_symbol_table;
-    sym.insert("load_library".into(), (load_library_c as *const()) as usize);
+    sym.insert("load_library".into(), (load_library as *const()) as usize);
     sym.insert("load_symbol".into(), (load_symbol as *const()) as usize);
-    sym.insert("malloc64".into(), (malloc as *const()) as usize);
+    sym.insert("add_library_search_path".into(), (add_library_search_path as *const()) as usize);
+    sym.insert("unload_library".into(), (unload_library as *const()) as usize);
+    sym.insert("loaded_libraries".into(), (loaded_libraries as *const()) as usize);
+    sym.insert("malloc64".into(), (malloc64 as *const()) as usize);
     sym.insert("free".into(), (free as *const()) as usize);
+    sym.insert("realloc64".into(), (realloc64 as *const()) as usize);
+    sym.insert("tracked_malloc".into(), (tracked_malloc as *const()) as usize);
+    sym.insert("tracked_realloc".into(), (tracked_realloc as *const()) as usize);
+    sym.insert("tracked_free".into(), (tracked_free as *const()) as usize);
+    sym.insert("set_allocator_for".into(), (set_allocator_for as *const()) as usize);
+    sym.insert("clear_allocator_for".into(), (clear_allocator_for as *const()) as usize);
+    sym.insert("memory_report".into(), (memory_report as *const()) as usize);
     sym.insert("memcpy".into(), (memcpy as *const()) as usize);
     sym.insert("panic".into(), (panic as *const()) as usize);
+    sym.insert("index_out_of_bounds".into(), (index_out_of_bounds as *const()) as usize);
+    sym.insert("closure_has_context".into(), (closure_has_context as *const()) as usize);
+    sym.insert("slice_out_of_bounds".into(), (slice_out_of_bounds as *const()) as usize);
+    sym.insert("expectation_failed".into(), (expectation_failed as *const()) as usize);
     
 
     sym.insert("print_string".into(), (print_string as *const()) as usize);
@@ -466,18 +1176,65 @@ impl CSymbols {
     sym.insert("print_f64".into(), (print_type::<f64> as *const()) as usize);
     sym.insert("print_bool".into(), (print_type::<bool> as *const()) as usize);
 
+    sym.insert("probe_i64".into(), (probe_type::<i64> as *const()) as usize);
+    sym.insert("probe_u64".into(), (probe_type::<u64> as *const()) as usize);
+    sym.insert("probe_f64".into(), (probe_type::<f64> as *const()) as usize);
+    sym.insert("probe_bool".into(), (probe_type::<bool> as *const()) as usize);
+    sym.insert("probe_string".into(), (probe_string as *const()) as usize);
+    sym.insert("pointer_owner".into(), (pointer_owner as *const()) as usize);
+    sym.insert("check_pointer".into(), (check_pointer as *const()) as usize);
+
+    sym.insert("string_len_chars".into(), (string_len_chars as *const()) as usize);
+    sym.insert("string_slice_chars".into(), (string_slice_chars as *const()) as usize);
+    sym.insert("string_find".into(), (string_find as *const()) as usize);
+    sym.insert("string_split".into(), (string_split as *const()) as usize);
+    sym.insert("string_concat".into(), (string_concat as *const()) as usize);
+
+    sym.insert("hash_u64".into(), (hash_u64 as *const()) as usize);
+    sym.insert("hash_bytes".into(), (hash_bytes as *const()) as usize);
+
     sym.insert("template_quote".into(), (template_quote as *const()) as usize);
+    sym.insert("gensym".into(), (gensym as *const()) as usize);
+    sym.insert("unhygienic".into(), (unhygienic as *const()) as usize);
     sym.insert("thread_sleep".into(), (thread_sleep as *const()) as usize);
 
     sym.insert("expr_to_string".into(), (expr_to_string as *const()) as usize);
+    sym.insert("expr_child_count".into(), (expr_child_count as *const()) as usize);
+    sym.insert("expr_child".into(), (expr_child as *const()) as usize);
+    sym.insert("expr_symbol_name".into(), (expr_symbol_name as *const()) as usize);
+    sym.insert("expr_construct_name".into(), (expr_construct_name as *const()) as usize);
+    sym.insert("mk_construct".into(), (mk_construct as *const()) as usize);
+    sym.insert("mk_call".into(), (mk_call as *const()) as usize);
+    sym.insert("mk_let".into(), (mk_let as *const()) as usize);
+    sym.insert("expr_equal".into(), (expr_equal as *const()) as usize);
+    sym.insert("expr_to_pretty_string".into(), (expr_to_pretty_string as *const()) as usize);
+    sym.insert("dump_ir".into(), (dump_ir as *const()) as usize);
+    sym.insert("dump_nodes".into(), (dump_nodes as *const()) as usize);
 
     sym.insert("load_expression".into(), (load_expression as *const()) as usize);
     sym.insert("load_module".into(), (load_module as *const()) as usize);
+    sym.insert("load_file_module".into(), (load_file_module as *const()) as usize);
     sym.insert("unload_module".into(), (unload_module as *const()) as usize);
     sym.insert("find_all_dependents".into(), (find_all_dependents as *const()) as usize);
     sym.insert("get_module".into(), (get_module as *const()) as usize);
     sym.insert("get_function".into(), (get_function as *const()) as usize);
 
+    sym.insert("subscribe_event".into(), (subscribe_event as *const()) as usize);
+    sym.insert("unsubscribe_event".into(), (unsubscribe_event as *const()) as usize);
+    sym.insert("publish_event".into(), (publish_event as *const()) as usize);
+    sym.insert("poll_event".into(), (poll_event as *const()) as usize);
+
+    sym.insert("request_screenshot".into(), (request_screenshot as *const()) as usize);
+    sym.insert("start_recording".into(), (start_recording as *const()) as usize);
+    sym.insert("stop_recording".into(), (stop_recording as *const()) as usize);
+    sym.insert("frame_presented".into(), (frame_presented as *const()) as usize);
+    sym.insert("at_exit".into(), (at_exit as *const()) as usize);
+    sym.insert("shutdown_requested".into(), (shutdown_requested as *const()) as usize);
+    sym.insert("run_frame".into(), (run_frame as *const()) as usize);
+    sym.insert("safepoint".into(), (safepoint as *const()) as usize);
+    sym.insert("metered_step".into(), (metered_step as *const()) as usize);
+    sym.insert("save_frame".into(), (save_frame as *const()) as usize);
+
     sym.insert("start_timer".into(), (start_timer as *const()) as usize);
     sym.insert("drop_timer".into(), (drop_timer as *const()) as usize);
     sym.insert("millis_elapsed".into(), (millis_elapsed as *const()) as usize);
@@ -489,10 +1246,41 @@ impl CSymbols {
 
     sym.insert("seeded_rng".into(), (seeded_rng as *const()) as usize);
     sym.insert("drop_seeded_rng".into(), (drop_seeded_rng as *const()) as usize);
+    sym.insert("random_seed".into(), (random_seed as *const()) as usize);
+    sym.insert("arbitrary_value".into(), (arbitrary_value as *const()) as usize);
+    sym.insert("shrink_value".into(), (shrink_value as *const()) as usize);
+    sym.insert("print_value".into(), (print_value as *const()) as usize);
     sym.insert("rand_f64".into(), (rand_f64 as *const()) as usize);
     sym.insert("rand_u64".into(), (rand_u64 as *const()) as usize);
+    sym.insert("list_live_handles".into(), (list_live_handles as *const()) as usize);
+
+    sym.insert("mat4_mul".into(), (mat4_mul as *const()) as usize);
+    sym.insert("mat4_transform".into(), (mat4_transform as *const()) as usize);
+    sym.insert("mat4_rotation".into(), (mat4_rotation as *const()) as usize);
+    sym.insert("mat4_inverse".into(), (mat4_inverse as *const()) as usize);
+
+    sym.insert("load_wav".into(), (load_wav as *const()) as usize);
+    sym.insert("unload_sound".into(), (unload_sound as *const()) as usize);
+    sym.insert("play_sound".into(), (play_sound as *const()) as usize);
+    sym.insert("stop_sound".into(), (stop_sound as *const()) as usize);
+    sym.insert("is_sound_playing".into(), (is_sound_playing as *const()) as usize);
+    sym.insert("set_sound_volume".into(), (set_sound_volume as *const()) as usize);
+    sym.insert("set_master_volume".into(), (set_master_volume as *const()) as usize);
+
+    sym.insert("load_image".into(), (load_image as *const()) as usize);
+    sym.insert("reload_changed_images".into(), (reload_changed_images as *const()) as usize);
+
+    sym.insert("debug_line".into(), (debug_line as *const()) as usize);
+    sym.insert("debug_rect".into(), (debug_rect as *const()) as usize);
+    sym.insert("debug_text".into(), (debug_text as *const()) as usize);
+    sym.insert("draw_debug_overlay".into(), (draw_debug_overlay as *const()) as usize);
+
+    register_sdl_symbols(sym);
 
     sym.insert("test_add".into(), (test_add as *const()) as usize);
+    sym.insert("test_pair_sum".into(), (test_pair_sum as *const()) as usize);
+    sym.insert("test_make_triple".into(), (test_make_triple as *const()) as usize);
+    sym.insert("test_triple_sum".into(), (test_triple_sum as *const()) as usize);
     sym.insert("test_global".into(), (&TEST_GLOBAL as *const i64) as usize);
   }
 
@@ -0,0 +1,78 @@
+// Indirection slots for calls between units.
+//
+// A call to a function in another unit doesn't jump to the function directly. It
+// loads the function's address from a slot, and calls that. When a unit is reloaded,
+// the slots of its functions are re-pointed at the new versions, so the units that
+// depend on it pick up the new code without being recompiled (see
+// `Compiler::reload_module`).
+//
+// A slot is created the first time a unit links against the function, and lives
+// until the function's unit is unloaded. Slots are boxed, so their addresses don't
+// move when the table grows.
+//
+// The host can also hold on to a function's address, such as the `run_frame`
+// callback that panicked (see recovery.rs). `latest` maps an address that a slot was
+// moved away from to the address the slot points to now.
+
+use crate::types::SymbolId;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Default)]
+pub struct CallSlots {
+  // units are linked through a shared reference to the code store, so slots have
+  // to be created through one as well
+  slots : RefCell<HashMap<SymbolId, Box<AtomicUsize>>>,
+  /// The addresses that slots were moved away from, and the addresses they point to now
+  moved : HashMap<usize, usize>,
+}
+
+impl CallSlots {
+  /// The address of the function's slot. If the slot doesn't exist yet, it is created
+  /// and pointed at `function_address()`.
+  pub fn slot_address(&self, symbol : SymbolId, function_address : impl FnOnce() -> usize) -> usize {
+    let mut slots = self.slots.borrow_mut();
+    let slot = slots.entry(symbol).or_insert_with(|| Box::new(AtomicUsize::new(function_address())));
+    (&**slot) as *const AtomicUsize as usize
+  }
+
+  /// The address that the function's slot points to, if it has a slot
+  pub fn target(&self, symbol : SymbolId) -> Option<usize> {
+    self.slots.borrow().get(&symbol).map(|s| s.load(Ordering::SeqCst))
+  }
+
+  pub fn has_slot(&self, symbol : SymbolId) -> bool {
+    self.slots.borrow().contains_key(&symbol)
+  }
+
+  /// Moves a slot to a new version of its function, and points it at that version.
+  /// Code that was linked against the old version calls the new one from now on.
+  pub fn retarget(&mut self, from : SymbolId, to : SymbolId, function_address : usize) {
+    let slots = self.slots.get_mut();
+    if let Some(slot) = slots.remove(&from) {
+      let old_address = slot.swap(function_address, Ordering::SeqCst);
+      slots.insert(to, slot);
+      for a in self.moved.values_mut() {
+        if *a == old_address {
+          *a = function_address;
+        }
+      }
+      self.moved.insert(old_address, function_address);
+    }
+  }
+
+  /// The address of the newest version of the function at `function_address`. It's
+  /// the same address unless the function had a slot, and the slot has been moved.
+  pub fn latest(&self, function_address : usize) -> usize {
+    self.moved.get(&function_address).cloned().unwrap_or(function_address)
+  }
+
+  pub fn remove(&mut self, symbol : SymbolId) {
+    if let Some(slot) = self.slots.get_mut().remove(&symbol) {
+      let address = slot.load(Ordering::SeqCst);
+      self.moved.retain(|_, a| *a != address);
+    }
+  }
+}
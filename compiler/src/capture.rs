@@ -0,0 +1,113 @@
+// Screenshot and video capture of whatever the language code is drawing.
+//
+// The renderer is owned by the language code (see code/sdl2.code), so the compiler
+// can't read the screen on its own. Instead, the host and the language both make capture
+// requests here, and the draw loop calls `capture_frame` after presenting each frame.
+// If the frame is wanted, it reads the pixels back and hands them over, and they are
+// saved here. The paths stay on the host side, so the running program never sees
+// where the host's files are.
+//
+// Videos are recorded as numbered BMP files in a directory. They can be turned into
+// a real video with something like `ffmpeg -i frame_%06d.bmp out.mp4`.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+
+struct Recording {
+  directory : PathBuf,
+  next_frame : u64,
+}
+
+#[derive(Default)]
+pub struct FrameCapture {
+  screenshots : VecDeque<String>,
+  recording : Option<Recording>,
+  /// Paths that the current frame still needs to be saved to
+  pending : VecDeque<String>,
+}
+
+impl FrameCapture {
+  pub fn new() -> Self {
+    Default::default()
+  }
+
+  /// Saves the next frame that is presented to `path`
+  pub fn request_screenshot(&mut self, path : &str) {
+    self.screenshots.push_back(path.into());
+  }
+
+  /// Saves every frame presented from now on into `directory`, which is created
+  /// if it doesn't exist. Any recording that was already running is stopped.
+  pub fn start_recording(&mut self, directory : &str) -> Result<(), String> {
+    fs::create_dir_all(directory)
+      .map_err(|e| format!("could not create directory '{}' for recording: {}", directory, e))?;
+    self.recording = Some(Recording { directory: directory.into(), next_frame: 0 });
+    Ok(())
+  }
+
+  /// Returns the number of frames recorded, or None if nothing was being recorded
+  pub fn stop_recording(&mut self) -> Option<u64> {
+    self.recording.take().map(|r| r.next_frame)
+  }
+
+  pub fn is_recording(&self) -> bool {
+    self.recording.is_some()
+  }
+
+  /// Called once a frame has been presented. Works out where the frame should be
+  /// saved, and returns true if it should be saved anywhere.
+  pub fn frame_presented(&mut self) -> bool {
+    self.pending.clear();
+    self.pending.extend(self.screenshots.drain(..));
+    if let Some(r) = &mut self.recording {
+      let path = r.directory.join(format!("frame_{:06}.bmp", r.next_frame));
+      r.next_frame += 1;
+      self.pending.push_back(path.to_string_lossy().into());
+    }
+    !self.pending.is_empty()
+  }
+
+  /// Saves the presented frame to every file that it was wanted for. The pixels are
+  /// 32-bit BGRA (`SDL_PIXELFORMAT_ARGB8888`), with rows `pitch` bytes apart.
+  pub fn save_frame(&mut self, pixels : &[u8], width : u32, height : u32, pitch : u32) -> Result<(), String> {
+    let bmp = encode_bmp(pixels, width, height, pitch)?;
+    let mut result = Ok(());
+    for path in self.pending.drain(..) {
+      if let Err(e) = fs::write(&path, &bmp) {
+        result = Err(format!("failed to save frame to '{}': {}", path, e));
+      }
+    }
+    result
+  }
+}
+
+/// A top-down 32-bit BMP of the pixels
+fn encode_bmp(pixels : &[u8], width : u32, height : u32, pitch : u32) -> Result<Vec<u8>, String> {
+  let row = width as usize * 4;
+  if (pitch as usize) < row || pixels.len() < pitch as usize * height as usize {
+    return Err(format!("a {}x{} frame doesn't fit in {} bytes", width, height, pixels.len()));
+  }
+  let header_size = 14 + 40;
+  let image_size = row * height as usize;
+  let mut bmp = Vec::with_capacity(header_size + image_size);
+  // file header
+  bmp.extend_from_slice(b"BM");
+  bmp.extend_from_slice(&((header_size + image_size) as u32).to_le_bytes());
+  bmp.extend_from_slice(&0u32.to_le_bytes());
+  bmp.extend_from_slice(&(header_size as u32).to_le_bytes());
+  // info header, with a negative height for rows that go top to bottom
+  bmp.extend_from_slice(&40u32.to_le_bytes());
+  bmp.extend_from_slice(&(width as i32).to_le_bytes());
+  bmp.extend_from_slice(&(-(height as i32)).to_le_bytes());
+  bmp.extend_from_slice(&1u16.to_le_bytes());
+  bmp.extend_from_slice(&32u16.to_le_bytes());
+  bmp.extend_from_slice(&0u32.to_le_bytes());
+  bmp.extend_from_slice(&(image_size as u32).to_le_bytes());
+  bmp.extend_from_slice(&[0; 16]);
+  for y in 0..height as usize {
+    let start = y * pitch as usize;
+    bmp.extend_from_slice(&pixels[start..start + row]);
+  }
+  Ok(bmp)
+}
@@ -1,39 +1,114 @@
 
 use crate::{
-  common, expr, structure,
+  common, expr, structure, lexer,
   llvm_compile, types,
-  compiler,
+  compiler, error, call_slots,
 };
 use common::*;
 use expr::Expr;
+use lexer::DocComment;
 use types::{
   TypeInfo, SymbolId, Type, TypeMapping,
-  SymbolDefinition, TypeDefinition,
+  SymbolDefinition, TypeDefinition, InferenceStats, SymbolInit,
 };
 use llvm_compile::LlvmUnit;
+use call_slots::CallSlots;
 use compiler::Val;
-use structure::Nodes;
+use structure::{Nodes, TOP_LEVEL_FUNCTION_NAME};
+use error::{Error, ErrorContent, TextLocation};
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, BTreeMap, BTreeSet};
+use std::time::Duration;
+use std::fmt;
+
+/// How long each phase took to compile a unit. Codegen and linking cover the whole
+/// load, including any polymorphic instances and other units in the same group,
+/// and so do the inference counts.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct CompileMetrics {
+  pub lex : Duration,
+  pub parse : Duration,
+  pub structure : Duration,
+  pub infer : Duration,
+  pub codegen : Duration,
+  pub link : Duration,
+  pub init : Duration,
+  pub inference : InferenceStats,
+}
+
+impl CompileMetrics {
+  pub fn total(&self) -> Duration {
+    self.lex + self.parse + self.structure + self.infer + self.codegen + self.link + self.init
+  }
+}
+
+impl fmt::Display for CompileMetrics {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let ms = |d : Duration| d.as_micros() as f64 / 1000.0;
+    write!(f,
+      "total {:.2}ms (lex {:.2}, parse {:.2}, structure {:.2}, infer {:.2}, codegen {:.2}, link {:.2}, init {:.2}), ",
+      ms(self.total()), ms(self.lex), ms(self.parse), ms(self.structure), ms(self.infer),
+      ms(self.codegen), ms(self.link), ms(self.init))?;
+    let s = &self.inference;
+    write!(f, "{} constraints, {} processed in {} passes",
+      s.constraints, s.constraints_processed, s.passes)
+  }
+}
+
+/// How many loads of a named module have their function versions kept
+pub const KEPT_SYMBOL_VERSIONS : u32 = 8;
+
+/// One version of a function in a named module. A version is recorded for each of
+/// the module's functions every time the module is loaded.
+#[derive(Clone, Debug)]
+pub struct SymbolVersion {
+  pub version : u32,
+  pub unit_id : UnitId,
+  pub symbol_id : SymbolId,
+  pub type_tag : Type,
+}
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
 pub struct CodegenId(Uid);
 
 impl From<Uid> for CodegenId { fn from(v : Uid) -> Self { CodegenId(v) } }
 
+/// Where a polymorphic instance came from
+pub struct PolyInstantiation {
+  pub poly_symbol : SymbolId,
+  pub instance_type : Type,
+  /// The unit that referenced the instance. It may be another instance.
+  pub caller : UnitId,
+  pub call_site : TextLocation,
+}
+
 #[derive(Default)]
 pub struct CodeStore {
-  pub code : HashMap<UnitId, RefStr>,
-  pub names : HashMap<UnitId, RefStr>,
-  pub imports : HashSet<(UnitId, UnitId)>,
-  pub exprs : HashMap<UnitId, Expr>,
-  pub nodes : HashMap<UnitId, Nodes>,
-  pub types : HashMap<UnitId, TypeInfo>,
-  pub type_mappings : HashMap<UnitId, TypeMapping>,
-  pub codegen_mapping : HashMap<UnitId, CodegenId>,
+  pub code : BTreeMap<UnitId, RefStr>,
+  pub names : BTreeMap<UnitId, RefStr>,
+  pub imports : BTreeSet<(UnitId, UnitId)>,
+  pub exprs : BTreeMap<UnitId, Expr>,
+  /// The `##` comments in each unit's code
+  pub doc_comments : BTreeMap<UnitId, Vec<DocComment>>,
+  pub nodes : BTreeMap<UnitId, Nodes>,
+  pub types : BTreeMap<UnitId, TypeInfo>,
+  pub type_mappings : BTreeMap<UnitId, TypeMapping>,
+  pub codegen_mapping : BTreeMap<UnitId, CodegenId>,
   pub llvm_units : HashMap<CodegenId, LlvmUnit>,
-  pub vals : HashMap<UnitId, Val>,
+  pub vals : BTreeMap<UnitId, Val>,
+  pub warnings : BTreeMap<UnitId, Vec<Error>>,
   pub tombstones : HashSet<UnitId>,
+  pub metrics : BTreeMap<UnitId, CompileMetrics>,
+  /// How often the functions of units run by the vm were called
+  pub vm_call_counts : BTreeMap<UnitId, BTreeMap<SymbolId, u64>>,
+  /// The statics of units run by the vm, so that their tests can use them
+  pub vm_globals : BTreeMap<UnitId, HashMap<SymbolId, Val>>,
+  pub call_slots : CallSlots,
+  /// The versions of each named module's functions, by module name and then
+  /// function name. These outlive the units, so that reloads can be compared.
+  pub symbol_versions : BTreeMap<RefStr, BTreeMap<RefStr, Vec<SymbolVersion>>>,
+  /// How many times each named module has been loaded
+  pub module_versions : BTreeMap<RefStr, u32>,
 
   /// Map from the id of a polymorphic symbol to its various instances,
   /// and their instanced types.
@@ -41,7 +116,10 @@ pub struct CodeStore {
 
   /// Map from unit_id of a polymorphic instance to the definition
   /// that it is an instance of.
-  pub poly_parents : HashMap<UnitId, SymbolId>,
+  pub poly_parents : BTreeMap<UnitId, SymbolId>,
+
+  /// Map from unit_id of a polymorphic instance to the reference that created it
+  pub poly_instantiations : BTreeMap<UnitId, PolyInstantiation>,
 }
 
 impl CodeStore {
@@ -64,14 +142,24 @@ impl CodeStore {
     let aaa = (); // TODO: remove the source. I'm not sure if the source ID is stored anywhere yet. It's supposed to be stored in TextLocations.
     self.names.remove(&uid);
     self.exprs.remove(&uid);
+    self.doc_comments.remove(&uid);
     self.nodes.remove(&uid);
-    self.types.remove(&uid);
+    if let Some(types) = self.types.remove(&uid) {
+      for &symbol in types.symbols.keys() {
+        self.call_slots.remove(symbol);
+      }
+    }
     self.type_mappings.remove(&uid);
     if let Some(codegen_id) = self.codegen_mapping.remove(&uid) {
       let aaa = (); // TODO: I'm not convinced that this is sufficient to clean up the llvm module & its jitted binary code
       self.llvm_units.remove(&codegen_id);
     }
     self.vals.remove(&uid);
+    self.warnings.remove(&uid);
+    self.metrics.remove(&uid);
+    self.vm_call_counts.remove(&uid);
+    self.vm_globals.remove(&uid);
+    self.poly_instantiations.remove(&uid);
     if let Some(sid) = self.poly_parents.remove(&uid) {
       if let Some(map) = self.poly_instances.get_mut(&sid) {
         map.retain(|_, sid| sid.uid != uid);
@@ -79,6 +167,43 @@ impl CodeStore {
     }
   }
 
+  /// Records a new version of each of a named module's functions, and forgets the
+  /// versions from more than `KEPT_SYMBOL_VERSIONS` loads ago
+  pub fn record_symbol_versions(&mut self, unit_id : UnitId) {
+    let module = self.name(unit_id);
+    let version = {
+      let v = self.module_versions.entry(module.clone()).or_insert(0);
+      *v += 1;
+      *v
+    };
+    let history = self.symbol_versions.entry(module).or_default();
+    for def in self.types.get(&unit_id).unwrap().symbols.values() {
+      let is_function = if let SymbolInit::Function(_) = def.initialiser { true } else { false };
+      if is_function && !def.is_polymorphic() && def.name.as_ref() != TOP_LEVEL_FUNCTION_NAME {
+        history.entry(def.name.clone()).or_default().push(SymbolVersion {
+          version, unit_id, symbol_id: def.id, type_tag: def.type_tag.clone(),
+        });
+      }
+    }
+    if version > KEPT_SYMBOL_VERSIONS {
+      let oldest = version - KEPT_SYMBOL_VERSIONS;
+      for versions in history.values_mut() {
+        versions.retain(|v| v.version > oldest);
+      }
+      history.retain(|_, versions| !versions.is_empty());
+    }
+  }
+
+  /// Every recorded version of a module's function, oldest first. Overloads have
+  /// several entries with the same version number.
+  pub fn symbol_versions(&self, module : &str, name : &str) -> &[SymbolVersion] {
+    self.symbol_versions.get(module).and_then(|h| h.get(name)).map(|v| v.as_slice()).unwrap_or(&[])
+  }
+
+  pub fn warnings(&self, unit_id : UnitId) -> &[Error] {
+    self.warnings.get(&unit_id).map(|ws| ws.as_slice()).unwrap_or(&[])
+  }
+
   pub fn name(&self, unit_id : UnitId) -> RefStr {
     self.names.get(&unit_id).unwrap().clone()
   }
@@ -112,6 +237,27 @@ impl CodeStore {
     self.imports.iter().filter(move |(a, _)| *a == unit_id).map(|(_, b)| b)
   }
 
+  /// Every unit, ordered so that each one comes before the units that it imports
+  pub fn unload_order(&self) -> Vec<UnitId> {
+    let mut remaining : BTreeSet<UnitId> = self.names.keys().cloned().collect();
+    let mut order = vec![];
+    while !remaining.is_empty() {
+      let mut next : Vec<UnitId> =
+        remaining.iter().cloned()
+        .filter(|&u| !self.get_importers(u).any(|i| remaining.contains(i)))
+        .collect();
+      // imports shouldn't be cyclic, but if they are, the cycle goes all at once
+      if next.is_empty() {
+        next = remaining.iter().cloned().collect();
+      }
+      for u in next.iter() {
+        remaining.remove(u);
+      }
+      order.extend(next);
+    }
+    order
+  }
+
   pub fn get_importers<'l>(&'l self, unit_id : UnitId) -> impl Iterator<Item=&'l UnitId> {
     self.imports.iter().filter(move |(_, b)| *b == unit_id).map(|(a, _)| a)
   }
@@ -128,6 +274,35 @@ impl CodeStore {
     self.type_mappings.get(&unit_id).unwrap()
   }
 
+  /// Describes the chain of references that led to a polymorphic instance being
+  /// created, innermost first. Empty if the unit isn't a polymorphic instance.
+  pub fn instantiation_chain(&self, unit_id : UnitId) -> Vec<String> {
+    let mut chain = vec![];
+    let mut uid = unit_id;
+    while let Some(inst) = self.poly_instantiations.get(&uid) {
+      let name = &self.symbol_def(inst.poly_symbol).name;
+      let site_unit = self.names.get(&inst.call_site.source).map(|n| n.as_ref()).unwrap_or("<unknown>");
+      chain.push(format!("in '{}' instanced as {}, referenced in '{}' at {}",
+        name, inst.instance_type, site_unit, inst.call_site));
+      uid = inst.caller;
+    }
+    chain
+  }
+
+  /// Appends the instantiation chain of a unit to an error, so that errors inside
+  /// generic code say which instance they came from
+  pub fn add_instantiation_chain(&self, unit_id : UnitId, mut e : Error) -> Error {
+    let chain = self.instantiation_chain(unit_id);
+    if !chain.is_empty() {
+      let notes = chain.iter().map(|c| format!("\n   {}", c)).collect::<String>();
+      match &mut e.message {
+        ErrorContent::Message(m) => m.push_str(&notes),
+        ErrorContent::InnerErrors(m, _) => m.push_str(&notes),
+      }
+    }
+    e
+  }
+
   pub fn poly_instance(&self, poly_symbol_id : SymbolId, instance_type : &Type)
     -> Option<SymbolId>
   {
@@ -86,4 +86,23 @@ impl StringCache {
       string
     }
   }
+
+  /// Drops every string that isn't referred to from outside the cache, and returns
+  /// how many were dropped. Nothing holds on to the strings of an unloaded unit,
+  /// so collecting after an unload stops the cache growing across reloads.
+  pub fn collect(&self) -> usize {
+    let mut symbols = self.symbols.borrow_mut();
+    let before = symbols.len();
+    symbols.retain(|s| Rc::strong_count(s) > 1);
+    before - symbols.len()
+  }
+
+  pub fn string_count(&self) -> usize {
+    self.symbols.borrow().len()
+  }
+
+  /// The total length of the cached strings
+  pub fn bytes(&self) -> usize {
+    self.symbols.borrow().iter().map(|s| s.len()).sum()
+  }
 }
@@ -1,36 +1,238 @@
 
 use crate::{
   common, error, expr, c_interface, llvm_compile, code_store,
-  structure, lexer, parser, types, intrinsics, graph,
+  structure, lexer, parser, types, intrinsics, graph, features,
+  events, exports, analysis, capture, libraries, vm, macros, stages, docs, probes,
+  watchdog, metering, folding, escape, pointers, allocations, recovery,
 };
 use common::*;
 use expr::Expr;
 use c_interface::CSymbols;
-use code_store::CodeStore;
-use types::{TypeContent, PType, TypeInfo, TypeMapping };
-use llvm_compile::{LlvmCompiler, execute_function};
-use error::{Error, error, ErrorContent};
-use structure::TOP_LEVEL_FUNCTION_NAME;
+use code_store::{CodeStore, PolyInstantiation, CompileMetrics, SymbolVersion};
+use types::{Type, TypeContent, PType, TypeInfo, TypeMapping, SymbolDefinition, SymbolId, SymbolInit, TypeDefinition };
+use llvm_compile::{LlvmCompiler, SymbolLocation, execute_function};
+use error::{Error, error, error_raw, warning_raw, ErrorContent, TextLocation};
+use structure::{TOP_LEVEL_FUNCTION_NAME, InlineDef};
 use graph::DirectedGraph;
+use features::FeatureReport;
+use events::EventBus;
+use exports::ExportTable;
+use capture::FrameCapture;
+use libraries::SharedLibraries;
+use macros::MacroDef;
+use docs::ModuleDocs;
 
 use std::fmt;
-use std::collections::{VecDeque, HashSet};
+use std::collections::{VecDeque, HashSet, HashMap, BTreeMap};
+use std::time::{Instant, Duration};
 
 // TODO: Put these options somewhere more sensible
-pub static DEBUG_PRINTING_IR : bool = false;
-pub static ENABLE_IR_OPTIMISATION : bool = false;
 pub static DEBUG_PRINTING_DEPENDENCY_GRAPH : bool = false;
 pub static DEBUG_PRINTING_TYPE_INFERENCE : bool = false;
 
+/// What runs a unit's code
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+  /// JIT-compiled with LLVM
+  Llvm,
+  /// Interpreted by `vm.rs`. This only supports plain values, and the unit can't
+  /// use other units or be used by them.
+  Vm,
+}
+
+/// What `Compiler::reload_module` does when a module's ABI changes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnAbiChange {
+  /// Keep the old version, and fail with a diagnostic that lists the changes
+  Reject,
+  /// Unload every module that depends on the old version, so that they have to be
+  /// loaded again (and so recompiled) against the new one
+  UnloadDependents,
+}
+
+/// Something about a reloaded module that stops its dependents from being patched
+#[derive(Clone, Debug, PartialEq)]
+pub enum AbiChange {
+  /// A function that the dependents call is gone, or has a different type. The new
+  /// type is `None` if the function was removed, or has several overloads now.
+  Function { name : RefStr, old_type : Type, new_type : Option<Type> },
+  /// The dependents use a static, which can't move to the new version
+  Static { name : RefStr },
+  /// The module defines types, which are compiled into its dependents
+  Types,
+  /// The dependents use instances of the module's polymorphic functions
+  PolymorphicInstances,
+  /// An inline function changed, and its old body was compiled into the dependents
+  InlineFunction { name : RefStr },
+  /// The new version runs in the vm, so there's no machine code to patch the calls to
+  NotCompiled,
+}
+
+impl fmt::Display for AbiChange {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      AbiChange::Function { name, old_type, new_type: Some(t) } =>
+        write!(f, "'{}' changed from {} to {}", name, old_type, t),
+      AbiChange::Function { name, old_type, new_type: None } =>
+        write!(f, "'{}' ({}) was removed or overloaded", name, old_type),
+      AbiChange::Static { name } => write!(f, "the static '{}' is used", name),
+      AbiChange::Types => write!(f, "the module defines types"),
+      AbiChange::PolymorphicInstances => write!(f, "instances of its polymorphic functions are used"),
+      AbiChange::InlineFunction { name } => write!(f, "the inline function '{}' changed", name),
+      AbiChange::NotCompiled => write!(f, "the new version wasn't compiled to machine code"),
+    }
+  }
+}
+
+/// A function that the language registered with `at_exit`. It's dropped when the
+/// unit that registered it or the unit that contains it is unloaded, so that shutdown
+/// never calls code that has been freed.
+#[derive(Clone, Copy, Debug)]
+pub struct ExitCallback {
+  /// The unit whose top-level code registered it, if there was one
+  pub registered_by : Option<UnitId>,
+  /// The unit that the function was compiled into, if it's the language's own code
+  pub owner : Option<UnitId>,
+  pub function : usize,
+}
+
+/// The outcome of `Compiler::reload_module`
+pub struct Reload {
+  pub unit_id : UnitId,
+  pub val : Val,
+  /// True if the dependents were patched to use the new version, rather than unloaded
+  pub patched : bool,
+  pub abi_changes : Vec<AbiChange>,
+}
+
+/// How a unit is compiled. Each unit can be compiled differently, so code that
+/// rarely changes (like the core modules) can be optimised, while the code being
+/// worked on compiles quickly and is checked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompileOptions {
+  /// Run LLVM's optimisation passes over the unit's functions
+  pub optimise : bool,
+  /// Check that array indices are in bounds, and panic if they aren't
+  pub bounds_checks : bool,
+  /// Put a safepoint at each loop back-edge, so that loops can be interrupted
+  /// (by the watchdog, for one; see `Compiler::frame_budget`)
+  pub safepoints : bool,
+  /// Count the steps that the unit's code takes, and cut each evaluation of its
+  /// top-level code short after this many (see metering.rs)
+  pub sandbox : Option<u64>,
+  pub backend : Backend,
+}
+
+impl CompileOptions {
+  /// Quick to compile, with checks on
+  pub fn debug() -> Self {
+    CompileOptions { optimise: false, bounds_checks: true, safepoints: true, sandbox: None, backend: Backend::Llvm }
+  }
+
+  /// Optimised, without checks
+  pub fn release() -> Self {
+    CompileOptions { optimise: true, bounds_checks: false, safepoints: false, sandbox: None, backend: Backend::Llvm }
+  }
+
+  /// Interpreted instead of compiled
+  pub fn vm() -> Self {
+    CompileOptions { backend: Backend::Vm, ..CompileOptions::debug() }
+  }
+}
+
+impl Default for CompileOptions {
+  fn default() -> Self {
+    CompileOptions::debug()
+  }
+}
+
+/// The rendered type of every expression in a unit, by source span
+pub struct TypeReport {
+  pub types : BTreeMap<TextLocation, String>,
+}
+
+impl fmt::Display for TypeReport {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for (loc, t) in self.types.iter() {
+      writeln!(f, "{}:{}-{}:{} {}", loc.start.line, loc.start.col, loc.end.line, loc.end.col, t)?;
+    }
+    Ok(())
+  }
+}
+
+pub struct TestResult {
+  pub name : RefStr,
+  /// An error means that the test couldn't be run at all
+  pub outcome : Result<bool, Error>,
+}
+
+/// The results of running the `test "name" { ... }` blocks in a unit
+pub struct TestReport {
+  pub unit_name : RefStr,
+  pub results : Vec<TestResult>,
+}
+
+impl TestReport {
+  pub fn passed(&self) -> usize {
+    self.results.iter().filter(|r| match r.outcome { Ok(true) => true, _ => false }).count()
+  }
+
+  pub fn failed(&self) -> usize {
+    self.results.len() - self.passed()
+  }
+
+  /// Prints the results to the terminal, coloured green and red
+  pub fn print(&self) {
+    const GREEN : &str = "\x1b[32m";
+    const RED : &str = "\x1b[31m";
+    const RESET : &str = "\x1b[0m";
+    if self.results.is_empty() {
+      return;
+    }
+    println!("running {} tests in '{}'", self.results.len(), self.unit_name);
+    for r in self.results.iter() {
+      match &r.outcome {
+        Ok(true) => println!("test {} ... {}ok{}", r.name, GREEN, RESET),
+        Ok(false) => println!("test {} ... {}FAILED{}", r.name, RED, RESET),
+        Err(e) => println!("test {} ... {}FAILED{}\n  {}", r.name, RED, RESET, e.display()),
+      }
+    }
+    let colour = if self.failed() == 0 { GREEN } else { RED };
+    println!("{}{} passed, {} failed{}", colour, self.passed(), self.failed(), RESET);
+  }
+}
+
 pub struct Compiler {
   pub code_store : CodeStore,
   pub llvm_compiler : LlvmCompiler,
   pub gen : UIDGenerator,
   pub cache : StringCache,
   pub c_symbols : CSymbols,
+  pub events : EventBus,
+  pub exports : ExportTable,
+  pub capture : FrameCapture,
+  pub libraries : SharedLibraries,
+  /// Imports that the host adds to every unit, which shouldn't cause unused import warnings
+  pub implicit_imports : HashSet<UnitId>,
+  /// Used for units that are loaded without options of their own
+  pub default_options : CompileOptions,
+  /// Functions that the language registered with `at_exit`, in order
+  pub exit_callbacks : Vec<ExitCallback>,
+  /// The unit whose top-level code is running, if there is one
+  pub initialising : Option<UnitId>,
+  /// How long the top-level code of a unit that running code loads (with
+  /// `load_module`) may take, and how long each `run_frame` callback may take,
+  /// before their loops are interrupted. There's no limit if it's `None`.
+  pub frame_budget : Option<Duration>,
+  /// Old versions of modules that were patched by `reload_module`
+  pub retired : Vec<UnitId>,
   intrinsics : UnitId,
 }
 
+fn is_test_function(def : &SymbolDefinition, name : &str) -> bool {
+  def.name.as_ref() == name && def.type_tag.sig().map(|sig| sig.args.len() == 0).unwrap_or(false)
+}
+
 impl Compiler {
   pub fn new() -> Box<Compiler> {
     let mut gen = UIDGenerator::new();
@@ -43,7 +245,13 @@ impl Compiler {
     let c_symbols = CSymbols::new_populated();
     let mut c = Box::new(Compiler { 
       code_store, llvm_compiler, gen, cache,
-      c_symbols, intrinsics: intrinsics_id,
+      c_symbols, events: EventBus::new(),
+      exports: ExportTable::new(), capture: FrameCapture::new(),
+      libraries: SharedLibraries::new(),
+      implicit_imports: HashSet::new(),
+      default_options: CompileOptions::default(),
+      exit_callbacks: vec![], initialising: None, frame_budget: None, retired: vec![],
+      intrinsics: intrinsics_id,
     });
     let cptr = (&mut *c) as *mut Compiler;
     c.c_symbols.add_symbol("compiler", cptr);
@@ -56,19 +264,36 @@ impl Compiler {
     let name = name.map(|s| self.cache.get(s));
     let unit_id = self.code_store.create_unit(self.gen.next(), name);
     self.code_store.exprs.insert(unit_id, expr.clone());
-    self.load_module_from_expr_internal(unit_id, imports.iter().cloned().collect())?;
+    let options = self.default_options;
+    self.load_module_from_expr_internal(unit_id, imports.iter().cloned().collect(), options)?;
+    if name.is_some() {
+      self.code_store.record_symbol_versions(unit_id);
+    }
     let val = self.code_store.vals.get(&unit_id).unwrap().clone();
     Ok((unit_id, val))
   }
 
   pub fn load_module(&mut self, code : &str, name : Option<&str>, imports : &[UnitId])
     -> Result<(UnitId, Val), Error>
+  {
+    let options = self.default_options;
+    self.load_module_with_options(code, name, imports, options)
+  }
+
+  /// Loads a module, compiled with the given options. Any polymorphic instances
+  /// that the module needs are compiled with the same options.
+  pub fn load_module_with_options(
+    &mut self, code : &str, name : Option<&str>, imports : &[UnitId], options : CompileOptions)
+      -> Result<(UnitId, Val), Error>
   {
     let name = name.map(|s| self.cache.get(s));
     let unit_id = self.code_store.create_unit(self.gen.next(), name);
     self.code_store.code.insert(unit_id, code.into());
     self.parse(unit_id)?;
-    self.load_module_from_expr_internal(unit_id, imports.iter().cloned().collect())?;
+    self.load_module_from_expr_internal(unit_id, imports.iter().cloned().collect(), options)?;
+    if name.is_some() {
+      self.code_store.record_symbol_versions(unit_id);
+    }
     let val = self.code_store.vals.get(&unit_id).unwrap().clone();
     Ok((unit_id, val))
   }
@@ -89,68 +314,749 @@ impl Compiler {
     uids.into_iter().collect()
   }
 
+  pub fn unload_module(&mut self, unit_id : UnitId) {
+    self.remove_unit(unit_id);
+    self.refresh_exports();
+    self.cache.collect();
+  }
+
+  fn remove_unit(&mut self, unit_id : UnitId) {
+    self.code_store.remove_unit(unit_id);
+    self.exit_callbacks.retain(|c| c.registered_by != Some(unit_id) && c.owner != Some(unit_id));
+    probes::forget_unit(unit_id.inner().inner());
+    pointers::remove_unit(unit_id.inner().inner());
+    allocations::remove_unit(unit_id.inner().inner());
+  }
+
+  /// Replaces a named module with a new version of its code, compiled against the
+  /// same imports. If the units that depend on the module only call its functions,
+  /// and every one of those functions still exists with the same type, they are
+  /// kept and their calls are patched to go to the new versions. Otherwise the
+  /// module's ABI has changed, and `on_abi_change` decides what happens. Either way,
+  /// the new version's top-level code runs before the ABI is checked.
+  ///
+  /// A patched module's old version is retired rather than freed, because its code
+  /// might still be running. See `release_retired_units`.
+  pub fn reload_module(&mut self, name : &str, code : &str, on_abi_change : OnAbiChange)
+    -> Result<Reload, Error>
+  {
+    let old = self.code_store.named_unit(name).ok_or_else(||
+      error_raw(TextLocation::zero(), format!("no module called '{}' to reload", name)))?;
+    let imports : Vec<UnitId> = self.code_store.get_imports(old).cloned().collect();
+    // the old version keeps running while the new one compiles, under another name
+    self.code_store.names.insert(old, format!("{}.retired.{:?}", name, old).into());
+    let options = self.default_options;
+    let (new, val) = match self.load_module_with_options(code, Some(name), &imports, options) {
+      Ok(v) => v,
+      Err(e) => {
+        // a unit that failed to parse isn't cleaned up by the load
+        if let Some(u) = self.code_store.named_unit(name) {
+          self.remove_unit(u);
+        }
+        self.code_store.names.insert(old, self.cache.get(name));
+        return Err(e);
+      }
+    };
+    let importers : Vec<UnitId> =
+      self.code_store.get_importers(old).cloned().filter(|&u| u != new).collect();
+    let (replacements, abi_changes) = self.function_replacements(old, new, &importers);
+    if abi_changes.is_empty() {
+      for (from, to) in replacements {
+        let address = llvm_compile::function_symbol_address(&self.code_store, new, to);
+        self.code_store.call_slots.retarget(from, to, address);
+      }
+      for u in importers {
+        self.code_store.imports.remove(&(u, old));
+        self.code_store.imports.insert((u, new));
+      }
+      self.retired.push(old);
+      self.refresh_exports();
+      return Ok(Reload { unit_id: new, val, patched: true, abi_changes });
+    }
+    match on_abi_change {
+      OnAbiChange::Reject => {
+        self.remove_unit(new);
+        if let Some(versions) = self.code_store.symbol_versions.get_mut(name) {
+          for v in versions.values_mut() {
+            v.retain(|v| v.unit_id != new);
+          }
+        }
+        self.code_store.names.insert(old, self.cache.get(name));
+        let users : Vec<String> = importers.iter().map(|&u| format!("'{}'", self.code_store.name(u))).collect();
+        let mut message = format!(
+          "the ABI of '{}' changed, which would break the modules that use it ({}):", name, users.join(", "));
+        for c in abi_changes.iter() {
+          message.push_str(&format!("\n  {}", c));
+        }
+        Err(error_raw(TextLocation::zero(), message))
+      }
+      OnAbiChange::UnloadDependents => {
+        for u in self.find_all_dependents(old) {
+          if u != new {
+            self.remove_unit(u);
+          }
+        }
+        self.refresh_exports();
+        self.cache.collect();
+        Ok(Reload { unit_id: new, val, patched: false, abi_changes })
+      }
+    }
+  }
+
+  /// Pairs each function in `old` that has a call slot with its replacement in `new`,
+  /// and lists the changes that stop the importers from being patched.
+  fn function_replacements(&self, old : UnitId, new : UnitId, importers : &[UnitId])
+    -> (Vec<(SymbolId, SymbolId)>, Vec<AbiChange>)
+  {
+    let mut changes = vec![];
+    if self.code_store.codegen_mapping.get(&new).is_none() {
+      changes.push(AbiChange::NotCompiled);
+    }
+    let old_types = self.code_store.types(old);
+    // types, polymorphic instances and inline functions are compiled into the importers
+    if !old_types.type_defs.is_empty() {
+      changes.push(AbiChange::Types);
+    }
+    if old_types.symbols.keys().any(|s| self.code_store.poly_instances.get(s).map(|m| !m.is_empty()).unwrap_or(false)) {
+      changes.push(AbiChange::PolymorphicInstances);
+    }
+    let new_inline_functions = &self.code_store.nodes(new).inline_functions;
+    for def in self.code_store.nodes(old).inline_functions.iter() {
+      if !new_inline_functions.iter().any(|d| d.same_definition(def)) {
+        changes.push(AbiChange::InlineFunction { name: def.name.clone() });
+      }
+    }
+    for &u in importers {
+      if let Some(codegen_id) = self.code_store.codegen_mapping.get(&u) {
+        let lu = self.code_store.llvm_units.get(codegen_id).unwrap();
+        let locations = lu.globals_to_link.iter().map(|(_, l)| l).chain(lu.functions_to_link.iter().map(|(_, l)| l));
+        for loc in locations {
+          match loc {
+            SymbolLocation::Global(unit, s) | SymbolLocation::LazyInitialiser(unit, s) if *unit == old => {
+              let change = AbiChange::Static { name: self.code_store.symbol_def(*s).name.clone() };
+              if !changes.contains(&change) {
+                changes.push(change);
+              }
+            }
+            _ => (),
+          }
+        }
+      }
+    }
+    let module = self.code_store.name(new);
+    let mut replacements = vec![];
+    for (&from, def) in old_types.symbols.iter() {
+      if !self.code_store.call_slots.has_slot(from) {
+        continue;
+      }
+      let new_versions : Vec<&SymbolVersion> =
+        self.code_store.symbol_versions(&module, &def.name).iter().filter(|v| v.unit_id == new).collect();
+      match new_versions.iter().find(|v| v.type_tag == def.type_tag) {
+        Some(v) => replacements.push((from, v.symbol_id)),
+        None => changes.push(AbiChange::Function {
+          name: def.name.clone(),
+          old_type: def.type_tag.clone(),
+          // if it still has overloads, there's no telling which one replaced it
+          new_type: if new_versions.len() == 1 { Some(new_versions[0].type_tag.clone()) } else { None },
+        }),
+      }
+    }
+    (replacements, changes)
+  }
+
+  /// Frees the old versions of modules that were patched by `reload_module`. Only
+  /// call this when none of their code can be running, and nothing is holding on to
+  /// a pointer to one of their functions.
+  pub fn release_retired_units(&mut self) {
+    for unit_id in std::mem::replace(&mut self.retired, vec![]) {
+      self.remove_unit(unit_id);
+    }
+    self.cache.collect();
+  }
+
+  /// The functions of a vm unit that were called at least `threshold` times while its
+  /// top-level code ran, most called first
+  pub fn hot_functions(&self, unit_id : UnitId, threshold : u64) -> Vec<(RefStr, u64)> {
+    let mut hot : Vec<(RefStr, u64)> =
+      self.code_store.vm_call_counts.get(&unit_id).into_iter().flatten()
+      .filter(|(_, &n)| n >= threshold)
+      .filter(|(&s, _)| s != self.top_level_symbol(unit_id))
+      .map(|(&s, &n)| (self.code_store.symbol_def(s).name.clone(), n))
+      .collect();
+    hot.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    hot
+  }
+
+  /// How many times each function in a file was called, keyed by where the function
+  /// is defined, in source order. Only vm units count their calls, so functions that
+  /// were JIT-compiled are left out.
+  pub fn function_costs(&self, path : &str) -> Vec<(TextLocation, u64)> {
+    let mut costs = vec![];
+    for unit_id in self.units_loaded_from(path) {
+      costs.extend(
+        self.code_store.vm_call_counts.get(&unit_id).into_iter().flatten()
+        .filter(|(&s, _)| s.uid == unit_id && s != self.top_level_symbol(unit_id))
+        .map(|(&s, &n)| (self.code_store.symbol_def(s).loc, n)));
+    }
+    costs.sort();
+    costs
+  }
+
+  fn top_level_symbol(&self, unit_id : UnitId) -> SymbolId {
+    self.code_store.types(unit_id).symbols.values()
+      .find(|def| def.name.as_ref() == TOP_LEVEL_FUNCTION_NAME).unwrap().id
+  }
+
+  /// Registers a function for `shutdown` to call (see `ExitCallback`)
+  pub fn add_exit_callback(&mut self, function : usize) {
+    let owner = allocations::unit_containing(function);
+    let owner = self.code_store.names.keys().cloned().find(|u| owner != 0 && u.inner().inner() == owner);
+    self.exit_callbacks.push(ExitCallback { registered_by: self.initialising, owner, function });
+  }
+
+  /// Cleans up before the process exits. The `at_exit` callbacks run first, most
+  /// recently registered first. Then every unit is unloaded, each one before the
+  /// units it imports, so that no execution engine is dropped while code that is
+  /// linked against it is still loaded.
+  pub fn shutdown(&mut self) {
+    let callbacks : Vec<usize> = self.exit_callbacks.drain(..).map(|c| c.function).collect();
+    for f in callbacks.into_iter().rev() {
+      let f : extern "C" fn() = unsafe { std::mem::transmute(f) };
+      f();
+    }
+    if let Some(frames) = self.capture.stop_recording() {
+      println!("recorded {} frames", frames);
+    }
+    for unit_id in self.code_store.unload_order() {
+      self.code_store.remove_unit(unit_id);
+    }
+  }
+
+  /// Finds the address of a compiled function. Returns `None` if there is more
+  /// than one overload, because there are no argument types to narrow the search,
+  /// and it would be very unsafe to return the wrong one.
+  pub fn function_address(&self, unit_id : UnitId, name : &str) -> Option<usize> {
+    let types = self.code_store.types(unit_id);
+    let mut i = types.symbols.values()
+      .filter(|def| def.name.as_ref() == name && def.type_tag.sig().is_some())
+      .flat_map(|def| def.codegen_name());
+    // units run by the vm don't have any machine code
+    self.code_store.codegen_mapping.get(&unit_id)?;
+    let lu = self.code_store.llvm_unit(unit_id);
+    let address =
+      i.next().and_then(|codegen_name|
+        unsafe { lu.ee.get_function_address(codegen_name) });
+    if i.next().is_some() {
+      println!("two matching overloads for '{}' in get_function_address", name);
+      return None;
+    }
+    address.map(|a| a as usize)
+  }
+
+  /// The LLVM IR of the module that a unit was compiled into. This includes any
+  /// other units that were compiled in the same group. Returns `None` if the unit
+  /// hasn't been compiled.
+  pub fn dump_ir(&self, unit_id : UnitId) -> Option<String> {
+    let codegen_id = self.code_store.codegen_mapping.get(&unit_id)?;
+    let lu = self.code_store.llvm_units.get(codegen_id)?;
+    Some(lu.llvm_module.print_to_string().to_string())
+  }
+
+  /// The expression that a unit was parsed into
+  pub fn dump_expr(&self, unit_id : UnitId) -> Option<String> {
+    self.code_store.exprs.get(&unit_id).map(|e| format!("{}", e))
+  }
+
+  /// The nodes of a unit as an indented tree, one node per line. Each node is
+  /// labelled with its type if the unit has been typechecked.
+  pub fn dump_nodes(&self, unit_id : UnitId) -> Option<String> {
+    let nodes = self.code_store.nodes.get(&unit_id)?;
+    let mapping = self.code_store.type_mappings.get(&unit_id);
+    let mut out = String::new();
+    let mut stack = vec![(nodes.root, 0)];
+    while let Some((id, depth)) = stack.pop() {
+      let node = nodes.node(id);
+      out.push_str(&format!("{:indent$}{:?} {:?}", "", id, node.content, indent = depth * 2));
+      if let Some(t) = mapping.and_then(|m| m.node_type.get(&id)) {
+        out.push_str(&format!(" : {}", t));
+      }
+      out.push('\n');
+      for c in node.content.children().into_iter().rev() {
+        stack.push((c, depth + 1));
+      }
+    }
+    Some(out)
+  }
+
+  /// Runs an in-language test against the currently loaded units. A test called
+  /// `name` is a function called `test_name`, which takes no arguments and either
+  /// returns a bool or returns nothing. If several units define the test, the most
+  /// recently loaded one is used, so that hot-patched tests are picked up. Returns
+  /// true if the test passed.
+  /// 
+  /// TODO: a test that panics will take the whole session down with it, because
+  /// there is no way to recover from panics yet.
+  pub fn run_test(&mut self, name : &str) -> Result<bool, Error> {
+    let test_name = if name.starts_with("test_") { name.to_string() } else { format!("test_{}", name) };
+    let def =
+      self.code_store.types.values()
+      .flat_map(|types| types.symbols.values())
+      .filter(|def| is_test_function(def, &test_name))
+      .max_by_key(|def| def.unit_id)
+      .cloned();
+    match def {
+      Some(def) => self.run_test_function(name, &def),
+      None => error(TextLocation::zero(), format!("no test called '{}' is loaded", name)),
+    }
+  }
+
+  /// Runs the `test "name" { ... }` blocks defined in a unit, in the order that
+  /// they appear. Each test is run separately, so a failing test doesn't stop
+  /// the rest from running. The same panic caveat applies as for `run_test`.
+  pub fn run_tests(&mut self, unit_id : UnitId) -> TestReport {
+    let tests = self.code_store.nodes(unit_id).tests.clone();
+    let results =
+      tests.iter().map(|t| {
+        let def =
+          self.code_store.types(unit_id).symbols.values()
+          .find(|def| is_test_function(def, &t.function_name));
+        let outcome = match def.cloned() {
+          Some(def) => self.run_test_function(&t.name, &def),
+          None => error(t.loc, format!("test '{}' wasn't compiled", t.name)),
+        };
+        TestResult { name: t.name.clone(), outcome }
+      })
+      .collect();
+    TestReport { unit_name: self.code_store.name(unit_id), results }
+  }
+
+  /// The loaded units that define `test "name" { ... }` blocks, in load order
+  pub fn units_with_tests(&self) -> Vec<UnitId> {
+    let mut units : Vec<UnitId> =
+      self.code_store.nodes.iter()
+      .filter(|(_, nodes)| !nodes.tests.is_empty())
+      .map(|(&unit_id, _)| unit_id)
+      .collect();
+    units.sort();
+    units
+  }
+
+  /// The loaded units with tests that a change to the file at `path` could affect:
+  /// the units loaded from that file, and the units that depend on them
+  pub fn units_with_tests_affected_by(&mut self, path : &str) -> Vec<UnitId> {
+    let mut affected = HashSet::new();
+    for unit_id in self.units_loaded_from(path) {
+      affected.extend(self.find_all_dependents(unit_id));
+    }
+    self.units_with_tests().into_iter().filter(|u| affected.contains(u)).collect()
+  }
+
+  /// The units whose code was loaded from a file
+  fn units_loaded_from(&self, path : &str) -> Vec<UnitId> {
+    let path = match std::fs::canonicalize(path) {
+      Ok(p) => p,
+      Err(_) => return vec![],
+    };
+    self.code_store.names.iter()
+      .filter(|(_, name)| std::fs::canonicalize(name.as_ref()).ok().as_ref() == Some(&path))
+      .map(|(&unit_id, _)| unit_id)
+      .collect()
+  }
+
+  fn run_test_function(&mut self, name : &str, def : &SymbolDefinition) -> Result<bool, Error> {
+    use TypeContent::*;
+    use PType::*;
+    let returns_bool = match &def.type_tag.sig().unwrap().return_type.content {
+      Prim(Bool) => true,
+      Prim(Void) => false,
+      t => return error(TextLocation::zero(), format!("test '{}' returns {:?}, but tests should return a bool or nothing", name, t)),
+    };
+    if let Some(mut globals) = self.code_store.vm_globals.remove(&def.unit_id) {
+      // vm units can't call the prelude's `expect`, so only the return value counts
+      let result = vm::run_function(&self.code_store, self.intrinsics, def.id, &mut globals);
+      self.code_store.vm_globals.insert(def.unit_id, globals);
+      return Ok(result? != Val::Bool(false));
+    }
+    let f = def.codegen_name().unwrap();
+    let lu = self.code_store.llvm_unit(def.unit_id);
+    let ((passed, failed_expectations), panic) = recovery::recover(|| {
+      c_interface::count_failed_expectations(|| {
+        if returns_bool { execute_function::<bool>(f, lu) }
+        else { execute_function::<()>(f, lu); true }
+      })
+    });
+    if let Some(message) = panic {
+      return error(def.loc, format!("test '{}' panicked: {}", name, message));
+    }
+    // a test with failed expectations fails, even if it returned true
+    Ok(passed && failed_expectations == 0)
+  }
+
+  /// Pins a function from a named module to an export slot, and returns the
+  /// address of the slot. The slot address stays the same for the lifetime
+  /// of the process, and the slot is re-pointed whenever the module is reloaded.
+  pub fn export_function(&mut self, slot_name : &str, module_name : &str, function_name : &str)
+    -> *const usize
+  {
+    let (slot_name, module_name, function_name) =
+      (self.cache.get(slot_name), self.cache.get(module_name), self.cache.get(function_name));
+    let slot_address = self.exports.export(slot_name, module_name, function_name).slot_address();
+    self.refresh_exports();
+    slot_address
+  }
+
+  fn refresh_exports(&self) {
+    for slot in self.exports.slots() {
+      let address =
+        self.code_store.named_unit(&slot.module)
+        .and_then(|unit_id| self.function_address(unit_id, &slot.function))
+        .unwrap_or(0);
+      slot.set_target(address);
+    }
+  }
+
+  /// Typechecks some code and reports which language features it uses. The code
+  /// is never run, and none of the units created along the way are kept.
+  pub fn feature_report(&mut self, code : &str, name : &str, imports : &[UnitId])
+    -> Result<FeatureReport, Error>
+  {
+    let name = self.cache.get(name);
+    let unit_id = self.code_store.create_unit(self.gen.next(), Some(name));
+    self.code_store.code.insert(unit_id, code.into());
+    let mut new_units = vec![unit_id];
+    let result = self.typecheck_only(unit_id, imports.to_vec(), &mut new_units)
+      .map(|_| features::feature_report(&self.code_store, unit_id));
+    for uid in new_units {
+      self.code_store.remove_unit(uid);
+    }
+    result
+  }
+
+  /// Finds a type definition by name, as a unit with these imports would see it
+  pub fn find_type_def(&self, name : &str, imports : &[UnitId]) -> Option<&TypeDefinition> {
+    let mut imports = imports.to_vec();
+    imports.push(self.intrinsics);
+    imports.sort_unstable();
+    imports.iter().rev().filter_map(|u| self.code_store.types.get(u)?.find_type_def(name)).next()
+  }
+
+  /// Typechecks some code without generating any code for it, and reports the
+  /// type of every expression. Meant for editors, and for quick feedback on save.
+  /// None of the units created along the way are kept.
+  pub fn check_module(&mut self, code : &str, imports : &[UnitId]) -> Result<TypeReport, Vec<Error>> {
+    let unit_id = self.code_store.create_unit(self.gen.next(), None);
+    self.code_store.code.insert(unit_id, code.into());
+    let mut new_units = vec![unit_id];
+    let result = match self.typecheck_only(unit_id, imports.to_vec(), &mut new_units) {
+      Ok(()) => Ok(self.type_report(unit_id)),
+      Err(e) => Err(match &e.message {
+        ErrorContent::InnerErrors(_, es) => es.clone(),
+        _ => vec![e],
+      }),
+    };
+    for uid in new_units {
+      self.code_store.remove_unit(uid);
+    }
+    result
+  }
+
+  /// Typechecks some code, and collects the reference documentation for its
+  /// definitions. Like `check_module`, none of the units are kept.
+  pub fn document_module(&mut self, code : &str, title : &str, imports : &[UnitId])
+    -> Result<ModuleDocs, Vec<Error>>
+  {
+    let unit_id = self.code_store.create_unit(self.gen.next(), None);
+    self.code_store.code.insert(unit_id, code.into());
+    let mut new_units = vec![unit_id];
+    let result = match self.typecheck_only(unit_id, imports.to_vec(), &mut new_units) {
+      Ok(()) => Ok(ModuleDocs::from_unit(&self.code_store, unit_id, title)),
+      Err(e) => Err(match &e.message {
+        ErrorContent::InnerErrors(_, es) => es.clone(),
+        _ => vec![e],
+      }),
+    };
+    for uid in new_units {
+      self.code_store.remove_unit(uid);
+    }
+    result
+  }
+
+  /// Renders the type constraints of some code as a DOT graph, for debugging
+  /// inference (see `types::constraint_graph`). Nothing is kept afterwards.
+  pub fn constraint_graph(&mut self, code : &str, imports : &[UnitId]) -> Result<String, Error> {
+    let unit_id = self.code_store.create_unit(self.gen.next(), None);
+    self.code_store.code.insert(unit_id, code.into());
+    let result = self.parse(unit_id).and_then(|()| {
+      let imports = self.register_imports(unit_id, imports.to_vec());
+      self.structure(unit_id)?;
+      types::constraint_graph(unit_id, &mut self.code_store, &self.cache, &mut self.gen, imports)
+    });
+    self.code_store.remove_unit(unit_id);
+    result
+  }
+
+  fn type_report(&self, unit_id : UnitId) -> TypeReport {
+    let nodes = self.code_store.nodes(unit_id);
+    let mapping = self.code_store.type_mapping(unit_id);
+    let mut node_types : Vec<_> = mapping.node_type.iter().collect();
+    // when several nodes share a span, the first one created wins
+    node_types.sort_by_key(|(id, _)| **id);
+    let mut types = BTreeMap::new();
+    for (id, t) in node_types {
+      types.entry(nodes.node(*id).loc).or_insert_with(|| format!("{}", t));
+    }
+    TypeReport { types }
+  }
+
+  fn typecheck_only(&mut self, unit_id : UnitId, imports : Vec<UnitId>, new_units : &mut Vec<UnitId>)
+    -> Result<(), Error>
+  {
+    self.parse(unit_id)?;
+    let imports = self.register_imports(unit_id, imports);
+    self.run_stages(unit_id, &imports, self.default_options)?;
+    self.structure(unit_id)?;
+    self.typecheck(unit_id, imports, new_units)
+  }
+
+  fn register_imports(&mut self, unit_id : UnitId, mut imports : Vec<UnitId>) -> Vec<UnitId> {
+    imports.push(self.intrinsics);
+    // Remove duplicates
+    imports.sort_unstable();
+    imports.dedup();
+    for &i in imports.iter() {
+      self.code_store.add_import(unit_id, i);
+    }
+    imports
+  }
+
   fn parse(&mut self, unit_id : UnitId) -> Result<(), Error> {
     let code = self.code_store.code.get(&unit_id).unwrap();
-    let tokens =
-      lexer::lex(unit_id, &code, &self.cache)
+    let mut metrics = CompileMetrics::default();
+    let t = Instant::now();
+    let (tokens, docs) =
+      lexer::lex_with_docs(unit_id, &code, &self.cache)
       .map_err(|mut es| es.remove(0))?;
+    metrics.lex = t.elapsed();
+    let t = Instant::now();
     let expr = parser::parse(unit_id, tokens, &self.cache)?;
+    metrics.parse = t.elapsed();
     self.code_store.exprs.insert(unit_id, expr);
+    self.code_store.doc_comments.insert(unit_id, docs);
+    self.code_store.metrics.insert(unit_id, metrics);
     Ok(())
   }
 
-  fn load_module_from_expr_internal(&mut self, unit_id : UnitId, imports : Vec<UnitId>)
+  fn load_module_from_expr_internal(&mut self, unit_id : UnitId, imports : Vec<UnitId>, options : CompileOptions)
     -> Result<(), Error>
   {
-    fn inner(c : &mut Compiler, unit_id : UnitId, mut imports : Vec<UnitId>, new_units : &mut Vec<UnitId>) -> Result<(), Error> {
-      imports.push(c.intrinsics);
-      // Remove duplicates
-      imports.sort_unstable();
-      imports.dedup();
-      for &i in imports.iter() {
-        c.code_store.add_import(unit_id, i);
-      }
+    fn inner(
+      c : &mut Compiler, unit_id : UnitId, imports : Vec<UnitId>,
+      options : CompileOptions, new_units : &mut Vec<UnitId>)
+        -> Result<(), Error>
+    {
+      // lexing and parsing have already been timed, unless the unit started as an expression
+      let mut metrics = c.code_store.metrics.remove(&unit_id).unwrap_or_default();
+      let imports = c.register_imports(unit_id, imports);
+      c.run_stages(unit_id, &imports, options)?;
+      let t = Instant::now();
       c.structure(unit_id)?;
+      metrics.structure = t.elapsed();
+      let t = Instant::now();
       c.typecheck(unit_id, imports, new_units)?;
-      c.codegen(new_units.as_slice())?;
-      c.initialise(unit_id)?;
+      metrics.infer = t.elapsed();
+      for uid in new_units.iter() {
+        let s = c.code_store.type_mappings.get(uid).unwrap().stats;
+        metrics.inference.constraints += s.constraints;
+        metrics.inference.constraints_processed += s.constraints_processed;
+        metrics.inference.passes += s.passes;
+      }
+      if options.backend == Backend::Vm {
+        // the polymorphic instances would need compiling
+        if new_units.len() > 1 {
+          let loc = c.code_store.nodes(unit_id).root().loc;
+          return error(loc, "the vm backend doesn't support polymorphic functions yet");
+        }
+      }
+      else {
+        // instances of the unit's polymorphic functions share its nodes, so they get folded too
+        folding::fold_unit(&mut c.code_store, unit_id);
+        escape::mark_stack_arrays(&mut c.code_store, unit_id);
+        c.codegen(new_units.as_slice(), options, &mut metrics)?;
+      }
+      let t = Instant::now();
+      c.initialise(unit_id, options)?;
+      metrics.init = t.elapsed();
+      c.code_store.metrics.insert(unit_id, metrics);
       Ok(())
     }
     let mut new_units = vec![unit_id];
-    match inner(self, unit_id, imports, &mut new_units) {
-      Ok(()) => Ok(()),
+    match inner(self, unit_id, imports, options, &mut new_units) {
+      Ok(()) => {
+        self.refresh_exports();
+        Ok(())
+      }
       Err(e) => {
         println!("{}", self.display_error(&e));
         // If something failed to compile, delete all the new units
         for uid in new_units {
-          self.code_store.remove_unit(uid);
+          self.remove_unit(uid);
         }
         Err(e)
       }
     }
   }
 
+  /// Runs the unit's `#[stage(compile)]` functions, and splices the expressions they
+  /// return into the unit. The stage functions are always compiled with LLVM.
+  fn run_stages(&mut self, unit_id : UnitId, imports : &[UnitId], options : CompileOptions)
+    -> Result<(), Error>
+  {
+    let expr = self.code_store.exprs.get(&unit_id).unwrap().clone();
+    let stages = stages::find_stages(&expr)?;
+    if stages.is_empty() {
+      return Ok(());
+    }
+    let stage_unit = self.code_store.create_unit(self.gen.next(), None);
+    self.code_store.exprs.insert(stage_unit, stages::stage_module(&expr, &stages));
+    let options = CompileOptions { backend: Backend::Llvm, ..options };
+    self.load_module_from_expr_internal(stage_unit, imports.to_vec(), options)?;
+    let results : Result<Vec<Expr>, Error> = stages.iter().map(|s| {
+      match self.function_address(stage_unit, s.name) {
+        Some(address) => stages::run(s, address),
+        None => error(s.expr, format!("stage function '{}' has more than one definition", s.name)),
+      }
+    }).collect();
+    self.unload_module(stage_unit);
+    let module = stages::splice(&expr, &stages, results?);
+    self.code_store.exprs.insert(unit_id, module);
+    Ok(())
+  }
+
   fn structure(&mut self, unit_id : UnitId) -> Result<(), Error> {
     let expr = self.code_store.exprs.get(&unit_id).unwrap();
-    let nodes = structure::to_nodes(&mut self.gen, &self.cache, &expr)?;
+    let macro_defs = self.imported_macros(unit_id);
+    let inline_defs = self.imported_inline_functions(unit_id);
+    let docs = self.code_store.doc_comments.get(&unit_id).map(|d| d.as_slice()).unwrap_or(&[]);
+    let nodes = structure::to_nodes(&mut self.gen, &self.cache, &expr, &macro_defs, &inline_defs, docs)?;
     self.code_store.nodes.insert(unit_id, nodes);
     Ok(())
   }
 
+  /// The macros defined by the units that a unit imports. Units run by the vm have
+  /// no compiled macros to offer.
+  fn imported_macros(&self, unit_id : UnitId) -> HashMap<RefStr, MacroDef> {
+    let mut macro_defs = HashMap::new();
+    for &i in self.code_store.get_imports(unit_id) {
+      if i == self.intrinsics {
+        continue;
+      }
+      for name in self.code_store.nodes(i).macros.iter() {
+        let def =
+          self.code_store.types(i).symbols.values()
+          .find(|def| &def.name == name && def.type_tag.sig().is_some());
+        if let (Some(def), Some(address)) = (def, self.function_address(i, name)) {
+          let args = def.type_tag.sig().unwrap().args.len();
+          macro_defs.insert(name.clone(), MacroDef { name: name.clone(), unit_id: i, args, address });
+        }
+      }
+    }
+    macro_defs
+  }
+
+  /// The inline functions defined by the units that a unit imports. A function is
+  /// only inlined if its name has no other definitions in the imports, and if the unit
+  /// that defines it imports nothing that this unit doesn't, so that its body means
+  /// the same thing at the call site.
+  fn imported_inline_functions(&self, unit_id : UnitId) -> HashMap<RefStr, InlineDef> {
+    let imports : Vec<UnitId> = self.code_store.get_imports(unit_id).cloned().collect();
+    let mut definitions : HashMap<&str, usize> = HashMap::new();
+    for &i in imports.iter() {
+      for def in self.code_store.types(i).symbols.values() {
+        *definitions.entry(def.name.as_ref()).or_insert(0) += 1;
+      }
+    }
+    let mut inline_defs = HashMap::new();
+    for &i in imports.iter() {
+      if i == self.intrinsics {
+        continue;
+      }
+      let sees_same_names =
+        self.code_store.get_imports(i).all(|j| *j == self.intrinsics || imports.contains(j));
+      if !sees_same_names {
+        continue;
+      }
+      for def in self.code_store.nodes(i).inline_functions.iter() {
+        if definitions.get(def.name.as_ref()) == Some(&1) {
+          inline_defs.insert(def.name.clone(), def.clone());
+        }
+      }
+    }
+    inline_defs
+  }
+
   fn typecheck(&mut self, unit_id : UnitId, imports : Vec<UnitId>, new_units : &mut Vec<UnitId>) -> Result<(), Error> {
     types::typecheck_module(
-      unit_id, &mut self.code_store, &self.cache, &mut self.gen, imports)?;
+      unit_id, &mut self.code_store, &self.cache, &mut self.gen, imports.clone())?;
     self.typecheck_new_polymorphic_instances(unit_id, new_units)?;
+    analysis::order_static_initialisers(&mut self.code_store, unit_id)?;
+    let nodes = self.code_store.nodes(unit_id);
+    analysis::mutability_errors(nodes, &self.code_store, unit_id)?;
+    let mut warnings = nodes.warnings.clone();
+    warnings.extend(analysis::dataflow_warnings(nodes, self.code_store.type_mapping(unit_id)));
+    warnings.extend(analysis::unused_variable_warnings(nodes));
+    warnings.extend(self.unused_import_warnings(unit_id, &imports));
+    warnings.extend(analysis::unsafe_operation_warnings(nodes, &self.code_store, unit_id)?);
+    warnings.extend(analysis::raw_union_access_warnings(nodes, &self.code_store, unit_id));
+    let warnings = analysis::suppress_warnings(nodes, warnings)?;
+    self.code_store.warnings.insert(unit_id, warnings);
     Ok(())
   }
 
+  /// Warns about imports that a unit never refers to. Implicit imports are skipped.
+  fn unused_import_warnings(&self, unit_id : UnitId, imports : &[UnitId]) -> Vec<analysis::Warning> {
+    fn find_units(t : &Type, uids : &mut HashSet<UnitId>) {
+      if let TypeContent::Def(_, uid) = &t.content {
+        uids.insert(*uid);
+      }
+      for c in t.children() {
+        find_units(c, uids);
+      }
+    }
+    let mapping = self.code_store.type_mapping(unit_id);
+    let mut used = HashSet::new();
+    for id in mapping.symbol_references.values() {
+      used.insert(id.uid);
+    }
+    for (id, _) in mapping.polymorphic_references.iter() {
+      used.insert(id.uid);
+    }
+    for t in mapping.node_type.values() {
+      find_units(t, &mut used);
+    }
+    let loc = self.code_store.nodes(unit_id).root().loc;
+    imports.iter()
+      .filter(|&i| *i != self.intrinsics && !self.implicit_imports.contains(i) && !used.contains(i))
+      .map(|i| {
+        let name = self.code_store.names.get(i).map(|n| n.as_ref()).unwrap_or("<unnamed>");
+        let w = warning_raw(loc, format!("unused import '{}'", name));
+        (analysis::UNUSED_IMPORTS, w)
+      })
+      .collect()
+  }
+
   fn typecheck_new_polymorphic_instances(&mut self, calling_unit : UnitId, new_units : &mut Vec<UnitId>) -> Result<(), Error> {
     // Typecheck any new polymorphic function instances
     let mut search_queue = VecDeque::new();
     search_queue.push_back(calling_unit);
     while let Some(psid) = search_queue.pop_front() {
       let mapping = self.code_store.type_mappings.get(&psid).unwrap();
-      let polymorphic_references : Vec<_> = mapping.polymorphic_references.iter().cloned().collect();
+      // Instance units are created in the order of their first reference, so that
+      // unit ids (and the generated code) don't depend on hash set ordering
+      let mut polymorphic_references : Vec<_> = mapping.polymorphic_references.iter().cloned().collect();
+      polymorphic_references.sort_by_key(|r| {
+        let loc = mapping.polymorphic_reference_locs.get(r).cloned().unwrap_or_else(TextLocation::zero);
+        (loc, r.0)
+      });
       for (poly_symbol_id, instance_type) in polymorphic_references {
         let existing_poly_instance = self.code_store.poly_instance(poly_symbol_id, &instance_type);
         if let Some(id) = existing_poly_instance {
@@ -179,11 +1085,22 @@ impl Compiler {
           for referenced_uid in instance_type.units_referenced() {
             self.code_store.add_import(instance_unit_id, referenced_uid);
           }
+          // Record where the instance came from, for diagnostics
+          let call_site =
+            self.code_store.type_mapping(psid).polymorphic_reference_locs
+            .get(&(poly_symbol_id, instance_type.clone())).cloned()
+            .unwrap_or_else(TextLocation::zero);
+          let instantiation = PolyInstantiation {
+            poly_symbol: poly_symbol_id, instance_type: instance_type.clone(),
+            caller: psid, call_site,
+          };
+          self.code_store.poly_instantiations.insert(instance_unit_id, instantiation);
           // Typecheck the new instance
           let instance_symbol_id =
             types::typecheck_polymorphic_function_instance(
               instance_unit_id, poly_symbol_id, &instance_type, &mut self.code_store,
-              &self.cache, &mut self.gen)?;
+              &self.cache, &mut self.gen)
+            .map_err(|e| self.code_store.add_instantiation_chain(instance_unit_id, e))?;
           // Register the instance with the code store
           let instances = self.code_store.poly_instances.entry(poly_symbol_id).or_default();
           instances.insert(instance_type, instance_symbol_id);
@@ -196,7 +1113,9 @@ impl Compiler {
     Ok(())
   }
 
-  fn codegen(&mut self, new_units : &[UnitId]) -> Result<(), Error> {
+  fn codegen(&mut self, new_units : &[UnitId], options : CompileOptions, metrics : &mut CompileMetrics)
+    -> Result<(), Error>
+  {
     if DEBUG_PRINTING_DEPENDENCY_GRAPH {
       println!("units {{");
       for (i, u) in new_units.iter().cloned().enumerate() {
@@ -249,19 +1168,108 @@ impl Compiler {
       }
       // codegen group
       let codegen_id = self.gen.next().into();
-      let lu = self.llvm_compiler.compile_unit_group(codegen_id, unit_group.as_slice(), &self.code_store)?;
+      let t = Instant::now();
+      let lu = self.llvm_compiler.compile_unit_group(
+        codegen_id, unit_group.as_slice(), &self.code_store, options)?;
+      metrics.codegen += t.elapsed();
       for &unit_id in unit_group.iter() {
         self.code_store.codegen_mapping.insert(unit_id, codegen_id);
       }
       self.code_store.llvm_units.insert(codegen_id, lu);
+      let t = Instant::now();
       llvm_compile::link_unit(codegen_id, &self.code_store, &self.c_symbols);
+      let function_ranges = llvm_compile::function_ranges(&self.code_store, &unit_group);
+      for &unit_id in unit_group.iter() {
+        let ranges = llvm_compile::static_ranges(&self.code_store, unit_id);
+        pointers::add_unit(unit_id.inner().inner(), ranges);
+        let functions = function_ranges.iter().filter(|r| r.2 == unit_id).map(|r| (r.0, r.1)).collect();
+        allocations::add_functions(unit_id.inner().inner(), &self.code_store.name(unit_id), functions);
+      }
+      metrics.link += t.elapsed();
     }
     Ok(())
   }
 
-  fn initialise(&mut self, unit_id : UnitId) -> Result<(), Error> {
-    let val = self.run_top_level(unit_id)?;
-    self.code_store.vals.insert(unit_id, val);
+  fn initialise(&mut self, unit_id : UnitId, options : CompileOptions) -> Result<(), Error> {
+    let loc = self.code_store.nodes(unit_id).root().loc;
+    if options.backend == Backend::Vm {
+      let (result, _) = metering::meter(options.sandbox, || {
+        vm::run_unit(&self.code_store, self.intrinsics, unit_id)
+      });
+      let (val, calls, globals) = result?;
+      self.code_store.vals.insert(unit_id, val);
+      self.code_store.vm_call_counts.insert(unit_id, calls);
+      self.code_store.vm_globals.insert(unit_id, globals);
+      return Ok(());
+    }
+    // units loaded by code that is already running are the ones that get reloaded
+    // while a program runs, so they're held to the frame budget
+    let budget = if self.initialising.is_some() { self.frame_budget } else { None };
+    let previous = self.initialising.replace(unit_id);
+    let ((((result, exhausted), interrupted), failed_expectations), panic) = recovery::recover(|| {
+      c_interface::count_failed_expectations(|| {
+        watchdog::guard(budget, || {
+          metering::meter(options.sandbox, || {
+            self.run_top_level(unit_id).and_then(|val| {
+              self.code_store.vals.insert(unit_id, val);
+              self.run_init_blocks(unit_id)
+            })
+          })
+        })
+      })
+    });
+    self.initialising = previous;
+    if let Some(message) = panic {
+      return error(loc, format!("the top-level code panicked: {}", message));
+    }
+    if interrupted {
+      return error(loc, format!(
+        "the top-level code ran for more than {:?}, so its loops were interrupted", budget.unwrap()));
+    }
+    if exhausted {
+      return error(loc, format!(
+        "the top-level code took more than {} steps, so it was cut short", options.sandbox.unwrap()));
+    }
+    if failed_expectations > 0 {
+      return error(loc, format!("{} expectations failed in the top-level code", failed_expectations));
+    }
+    result
+  }
+
+  /// Calls a function under the frame budget. Returns false if its loops were
+  /// interrupted, or it panicked. If the function has been reloaded since `f` was
+  /// taken, the new version is called (see `CallSlots::latest`).
+  pub fn run_frame(&self, f : extern "C" fn()) -> bool {
+    let f : extern "C" fn() = unsafe { std::mem::transmute(self.code_store.call_slots.latest(f as usize)) };
+    let ((((), interrupted), failed_expectations), panic) = recovery::recover(|| {
+      c_interface::count_failed_expectations(|| watchdog::guard(self.frame_budget, || f()))
+    });
+    if interrupted {
+      println!("a frame ran for more than {:?}, so its loops were interrupted", self.frame_budget.unwrap());
+    }
+    if failed_expectations > 0 {
+      println!("{} expectations failed in a frame", failed_expectations);
+    }
+    if let Some(message) = &panic {
+      println!("a frame panicked: {}", message);
+    }
+    !interrupted && panic.is_none()
+  }
+
+  /// Runs the unit's `init { ... }` blocks, in order. They run once each time the
+  /// unit is loaded, after the top-level code, so the statics are all initialised.
+  fn run_init_blocks(&self, unit_id : UnitId) -> Result<(), Error> {
+    for name in self.code_store.nodes(unit_id).init_functions.iter() {
+      let def =
+        self.code_store.types(unit_id).symbols.values()
+        .find(|def| def.name == *name).unwrap();
+      match &def.type_tag.sig().unwrap().return_type.content {
+        TypeContent::Prim(PType::Void) | TypeContent::Prim(PType::Never) => (),
+        t => return error(def.loc, format!("init blocks shouldn't return a value, but this one returns {:?}", t)),
+      }
+      let lu = self.code_store.llvm_unit(unit_id);
+      execute_function::<()>(def.codegen_name().unwrap(), lu);
+    }
     Ok(())
   }
 
@@ -284,7 +1292,7 @@ impl Compiler {
       Prim(U32) => Val::U32(execute_function(f, lu)),
       Prim(U16) => Val::U16(execute_function(f, lu)),
       Prim(U8) => Val::U8(execute_function(f, lu)),
-      Prim(Void) => {
+      Prim(Void) | Prim(Never) => {
         execute_function::<()>(f, lu);
         Val::Void
       }
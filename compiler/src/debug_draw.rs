@@ -0,0 +1,188 @@
+// An immediate-mode debug overlay. The language adds lines, rectangles and text
+// at any point during a frame, and they are all drawn over the top of the frame
+// just before it is presented (see `present_frame` in code/sdl2.code), and then
+// forgotten.
+//
+// When a module fails to load, its errors are shown in a panel at the top of the
+// overlay. Unlike the primitives they stay until the module loads successfully,
+// so a program that keeps presenting frames shows why its new code isn't running.
+//
+// Text uses a tiny built-in 3x5 font, so that no font file has to be found.
+// Lowercase letters are drawn as uppercase.
+
+use std::cell::RefCell;
+
+use sdl2::sys;
+
+/// Colours are 0xRRGGBBAA
+type Colour = u32;
+
+enum Primitive {
+  Line { x0 : i32, y0 : i32, x1 : i32, y1 : i32, colour : Colour },
+  Rect { x : i32, y : i32, w : i32, h : i32, colour : Colour },
+  Text { x : i32, y : i32, text : String, colour : Colour },
+}
+
+thread_local! {
+  static PRIMITIVES : RefCell<Vec<Primitive>> = RefCell::new(vec![]);
+  /// Load errors, by module name
+  static DIAGNOSTICS : RefCell<Vec<(String, String)>> = RefCell::new(vec![]);
+}
+
+fn push(p : Primitive) {
+  PRIMITIVES.with(|ps| ps.borrow_mut().push(p));
+}
+
+/// Shows a module's load errors, replacing any that were shown for it before
+pub fn set_diagnostics(module : &str, errors : &str) {
+  clear_diagnostics(module);
+  DIAGNOSTICS.with(|ds| ds.borrow_mut().push((module.into(), errors.into())));
+}
+
+/// Called when a module loads successfully
+pub fn clear_diagnostics(module : &str) {
+  DIAGNOSTICS.with(|ds| ds.borrow_mut().retain(|(m, _)| m != module));
+}
+
+pub fn diagnostics() -> Vec<(String, String)> {
+  DIAGNOSTICS.with(|ds| ds.borrow().clone())
+}
+
+pub fn line(x0 : f64, y0 : f64, x1 : f64, y1 : f64, colour : Colour) {
+  push(Primitive::Line { x0: x0 as i32, y0: y0 as i32, x1: x1 as i32, y1: y1 as i32, colour });
+}
+
+pub fn rect(x : f64, y : f64, w : f64, h : f64, colour : Colour) {
+  push(Primitive::Rect { x: x as i32, y: y as i32, w: w as i32, h: h as i32, colour });
+}
+
+pub fn text(x : f64, y : f64, text : &str, colour : Colour) {
+  push(Primitive::Text { x: x as i32, y: y as i32, text: text.into(), colour });
+}
+
+/// Size of a font pixel, in screen pixels
+const FONT_SCALE : i32 = 2;
+
+/// 3x5 glyphs, row by row
+fn glyph(c : char) -> &'static str {
+  match c.to_ascii_uppercase() {
+    '0' => "####.##.##.####", '1' => ".#.##..#..#.###", '2' => "###..#####..###",
+    '3' => "###..#.##..####", '4' => "#.##.####..#..#", '5' => "####..###..####",
+    '6' => "####..####.####", '7' => "###..#..#.#..#.", '8' => "####.#####.####",
+    '9' => "####.####..####", 'A' => ".#.#.#####.##.#", 'B' => "##.#.###.#.###.",
+    'C' => ".###..#..#...##", 'D' => "##.#.##.##.###.", 'E' => "####..##.#..###",
+    'F' => "####..##.#..#..", 'G' => ".###..#.##.#.##", 'H' => "#.##.#####.##.#",
+    'I' => "###.#..#..#.###", 'J' => "..#..#..##.#.#.", 'K' => "#.##.###.#.##.#",
+    'L' => "#..#..#..#..###", 'M' => "#.########.##.#", 'N' => "##.#.##.##.##.#",
+    'O' => ".#.#.##.##.#.#.", 'P' => "##.#.###.#..#..", 'Q' => ".#.#.##.###..##",
+    'R' => "##.#.###.#.##.#", 'S' => ".###...#...###.", 'T' => "###.#..#..#..#.",
+    'U' => "#.##.##.##.####", 'V' => "#.##.##.##.#.#.", 'W' => "#.##.########.#",
+    'X' => "#.##.#.#.#.##.#", 'Y' => "#.##.#.#..#..#.", 'Z' => "###..#.#.#..###",
+    '.' => ".............#.", ',' => "..........#.#..", ':' => "....#.....#....",
+    '-' => "......###......", '+' => "....#.###.#....", '=' => "...###...###...",
+    '(' => "..#.#..#..#...#", ')' => "#...#..#..#.#..", '/' => "..#..#.#.#..#..",
+    '!' => ".#..#..#.....#.", '?' => "##...#.#.....#.", '_' => "............###",
+    '\'' => ".#..#..........", '"' => "#.##.#.........", '*' => "#.#.#.#.#......",
+    '[' => "##.#..#..#..##.", ']' => ".##..#..#..#.##", '<' => "..#.#.#...#...#",
+    '>' => "#...#...#.#.#..",
+    ' ' => "...............",
+    _ => "###############",
+  }
+}
+
+unsafe fn set_colour(r : *mut sys::SDL_Renderer, c : Colour) {
+  sys::SDL_SetRenderDrawColor(r, (c >> 24) as u8, (c >> 16) as u8, (c >> 8) as u8, c as u8);
+}
+
+unsafe fn draw_text(r : *mut sys::SDL_Renderer, x : i32, y : i32, text : &str) {
+  let (mut cx, mut cy) = (x, y);
+  for c in text.chars() {
+    if c == '\n' {
+      cx = x;
+      cy += 6 * FONT_SCALE;
+      continue;
+    }
+    for (i, b) in glyph(c).bytes().enumerate() {
+      if b == b'#' {
+        let i = i as i32;
+        let px = sys::SDL_Rect {
+          x: cx + (i % 3) * FONT_SCALE, y: cy + (i / 3) * FONT_SCALE,
+          w: FONT_SCALE, h: FONT_SCALE,
+        };
+        sys::SDL_RenderFillRect(r, &px);
+      }
+    }
+    cx += 4 * FONT_SCALE;
+  }
+}
+
+const PANEL_COLOUR : Colour = 0x202020FF;
+const ERROR_COLOUR : Colour = 0xFF5050FF;
+
+/// Breaks the diagnostics into lines that fit in `columns` characters
+fn wrap_diagnostics(diagnostics : &[(String, String)], columns : usize) -> Vec<String> {
+  let columns = columns.max(1);
+  let mut lines = vec![];
+  for (module, errors) in diagnostics {
+    lines.push(format!("failed to load {}:", if module.is_empty() { "module" } else { module }));
+    for line in errors.lines() {
+      let chars : Vec<char> = line.chars().collect();
+      for chunk in chars.chunks(columns) {
+        lines.push(chunk.iter().collect());
+      }
+    }
+  }
+  lines
+}
+
+/// Draws the load errors in a panel across the top of the screen
+unsafe fn draw_diagnostics(r : *mut sys::SDL_Renderer, diagnostics : &[(String, String)]) {
+  let (mut w, mut h) = (0, 0);
+  if sys::SDL_GetRendererOutputSize(r, &mut w, &mut h) != 0 {
+    return;
+  }
+  let margin = 2 * FONT_SCALE;
+  let (char_width, line_height) = (4 * FONT_SCALE, 6 * FONT_SCALE);
+  let mut lines = wrap_diagnostics(diagnostics, ((w - 2 * margin) / char_width) as usize);
+  lines.truncate(((h - 2 * margin) / line_height).max(0) as usize);
+  let panel = sys::SDL_Rect { x: 0, y: 0, w, h: lines.len() as i32 * line_height + 2 * margin };
+  set_colour(r, PANEL_COLOUR);
+  sys::SDL_RenderFillRect(r, &panel);
+  set_colour(r, ERROR_COLOUR);
+  draw_text(r, margin, margin, &lines.join("\n"));
+}
+
+/// Draws everything that was added this frame, and clears the buffer. Load
+/// errors are drawn on top. The renderer's draw colour is left as it was.
+pub fn draw_overlay(r : *mut sys::SDL_Renderer) {
+  let primitives = PRIMITIVES.with(|ps| std::mem::replace(&mut *ps.borrow_mut(), vec![]));
+  let diagnostics = diagnostics();
+  if primitives.is_empty() && diagnostics.is_empty() {
+    return;
+  }
+  unsafe {
+    let (mut cr, mut cg, mut cb, mut ca) = (0, 0, 0, 0);
+    sys::SDL_GetRenderDrawColor(r, &mut cr, &mut cg, &mut cb, &mut ca);
+    for p in primitives.iter() {
+      match p {
+        Primitive::Line { x0, y0, x1, y1, colour } => {
+          set_colour(r, *colour);
+          sys::SDL_RenderDrawLine(r, *x0, *y0, *x1, *y1);
+        }
+        Primitive::Rect { x, y, w, h, colour } => {
+          set_colour(r, *colour);
+          let rect = sys::SDL_Rect { x: *x, y: *y, w: *w, h: *h };
+          sys::SDL_RenderDrawRect(r, &rect);
+        }
+        Primitive::Text { x, y, text, colour } => {
+          set_colour(r, *colour);
+          draw_text(r, *x, *y, text);
+        }
+      }
+    }
+    if !diagnostics.is_empty() {
+      draw_diagnostics(r, &diagnostics);
+    }
+    sys::SDL_SetRenderDrawColor(r, cr, cg, cb, ca);
+  }
+}
@@ -0,0 +1,140 @@
+// Reference documentation for a module, generated from its definitions and their
+// `##` doc comments.
+//
+//   ## The distance between two points
+//   fun distance(a : vec2, b : vec2) => f64 { ... }
+//
+// A doc comment is one or more `##` lines directly above a function, macro, type,
+// static or cbind. Definitions without doc comments are still listed, with their
+// signatures. Tests, init blocks and intrinsics are left out.
+
+use crate::common::*;
+use crate::code_store::CodeStore;
+use crate::structure::{TypeKind, TOP_LEVEL_FUNCTION_NAME};
+use crate::types::{Type, TypeContent, SymbolDefinition, SymbolInit};
+
+use itertools::Itertools;
+
+/// One documented definition
+#[derive(Clone, Debug)]
+pub struct DocEntry {
+  pub name : RefStr,
+  pub signature : String,
+  pub doc : Option<RefStr>,
+  pub line : usize,
+}
+
+/// The documented definitions of a module, in the order they appear
+pub struct ModuleDocs {
+  pub title : String,
+  pub entries : Vec<DocEntry>,
+}
+
+/// Like the `Display` of a type, but with primitives named as they are in source
+fn type_name(t : &Type) -> String {
+  match &t.content {
+    TypeContent::Prim(p) => format!("{:?}", p).to_lowercase(),
+    TypeContent::Ptr => format!("ptr({})", type_name(t.ptr().unwrap())),
+    TypeContent::Fun(_) => {
+      let sig = t.sig().unwrap();
+      format!("fun({}) => {}", sig.args.iter().map(type_name).join(", "), type_name(sig.return_type))
+    }
+    TypeContent::Def(name, _) if !t.children.is_empty() =>
+      format!("{}({})", name, t.children.iter().map(type_name).join(", ")),
+    _ => format!("{}", t),
+  }
+}
+
+fn function_signature(def : &SymbolDefinition, keyword : &str) -> String {
+  let sig = def.type_tag.sig().unwrap();
+  let args = match &def.initialiser {
+    SymbolInit::Function(f) =>
+      f.args.iter().zip(sig.args).map(|(a, t)| format!("{} : {}", a.name, type_name(t))).join(", "),
+    _ => sig.args.iter().map(type_name).join(", "),
+  };
+  let mut s = format!("{} {}({}) => {}", keyword, def.name, args, type_name(sig.return_type));
+  if !def.type_vars.is_empty() {
+    s.push_str(&format!(" with {}", def.type_vars.iter().join(", ")));
+  }
+  s
+}
+
+impl ModuleDocs {
+  pub fn from_unit(code_store : &CodeStore, unit_id : UnitId, title : &str) -> ModuleDocs {
+    let nodes = code_store.nodes(unit_id);
+    let types = code_store.types(unit_id);
+    let mut entries = vec![];
+    for def in types.symbols.values() {
+      let hidden =
+        def.name.as_ref() == TOP_LEVEL_FUNCTION_NAME ||
+        nodes.init_functions.contains(&def.name) ||
+        nodes.tests.iter().any(|t| t.function_name == def.name);
+      if def.unit_id != unit_id || hidden {
+        continue;
+      }
+      let signature = match &def.initialiser {
+        SymbolInit::Function(_) if nodes.macros.contains(&def.name) =>
+          function_signature(def, "macro"),
+        SymbolInit::Function(_) => function_signature(def, "fun"),
+        SymbolInit::CBind => format!("cbind {} : {}", def.name, type_name(&def.type_tag)),
+        SymbolInit::Expression(_) => format!("static {} : {}", def.name, type_name(&def.type_tag)),
+        SymbolInit::Lazy(_) => format!("lazy static {} : {}", def.name, type_name(&def.type_tag)),
+        SymbolInit::Intrinsic => continue,
+      };
+      entries.push(DocEntry {
+        name: def.name.clone(), signature, doc: def.doc.clone(), line: def.loc.start.line,
+      });
+    }
+    for def in types.type_defs.values() {
+      let keyword = match def.kind {
+        TypeKind::Struct => "struct", TypeKind::Union => "union", TypeKind::TaggedUnion => "tagged union",
+      };
+      let mut name = def.name.to_string();
+      if def.is_polymorphic() {
+        name = format!("{}({})", name, def.type_vars.iter().join(", "));
+      }
+      let fields = def.fields.iter().map(|(f, t)| match def.bitfield_width(&f.name) {
+        Some(w) => format!("{} : {} : {}", f.name, type_name(t), w),
+        None => format!("{} : {}", f.name, type_name(t)),
+      }).join(", ");
+      entries.push(DocEntry {
+        name: def.name.clone(),
+        signature: format!("{} {} {{ {} }}", keyword, name, fields),
+        doc: def.doc.clone(), line: def.loc.start.line,
+      });
+    }
+    entries.sort_by_key(|e| e.line);
+    ModuleDocs { title: title.to_string(), entries }
+  }
+
+  pub fn markdown(&self) -> String {
+    let mut s = format!("# {}\n", self.title);
+    for e in self.entries.iter() {
+      s.push_str(&format!("\n## {}\n\n```\n{}\n```\n", e.name, e.signature));
+      if let Some(doc) = &e.doc {
+        s.push_str(&format!("\n{}\n", doc));
+      }
+    }
+    s
+  }
+
+  pub fn html(&self) -> String {
+    fn escape(s : &str) -> String {
+      s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+    }
+    let mut s = format!(
+      "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n",
+      escape(&self.title));
+    for e in self.entries.iter() {
+      s.push_str(&format!("<h2 id=\"{0}\">{0}</h2>\n<pre><code>{1}</code></pre>\n",
+        escape(&e.name), escape(&e.signature)));
+      if let Some(doc) = &e.doc {
+        for paragraph in doc.split("\n\n") {
+          s.push_str(&format!("<p>{}</p>\n", escape(paragraph)));
+        }
+      }
+    }
+    s.push_str("</body>\n</html>\n");
+    s
+  }
+}
@@ -0,0 +1,375 @@
+// A minimal editor for one code file, so that the live-programming loop works
+// without an external editor. `edit <file>` opens it next to the watcher: saving
+// with Ctrl+S writes the file, and the watcher restarts the program.
+//
+// If the file changes on disk while there are no unsaved edits (because it was
+// formatted on save, say), the editor reloads it.
+//
+// The arrow keys, Home and End move the caret, and highlight with Shift. Page Up
+// and Page Down move a screen at a time. Ctrl+S saves, Ctrl+Z and Ctrl+Y undo and
+// redo, and Ctrl+A, Ctrl+C, Ctrl+X and Ctrl+V select all, copy, cut and paste.
+//
+// Text is drawn with rusttype, so a monospaced TrueType font is needed. Some
+// common ones are looked for if no font is given.
+
+use std::cmp;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use sdl2::clipboard::ClipboardUtil;
+use sdl2::event::Event;
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::{BlendMode, Canvas};
+use sdl2::video::Window;
+
+use crate::font_render::{FontRenderState, LayoutAttribs};
+use crate::shutdown;
+use crate::text_edit::{TextEditorState, TextEdit, EditHistory, CaretMove, CaretMoveType, count_line_chars};
+
+/// Monospaced fonts that are usually installed, tried in order
+static DEFAULT_FONTS : &[&str] = &[
+  "C:/Windows/Fonts/consola.ttf",
+  "/System/Library/Fonts/Menlo.ttc",
+  "/usr/share/fonts/truetype/dejavu/DejaVuSansMono.ttf",
+  "/usr/share/fonts/TTF/DejaVuSansMono.ttf",
+  "/usr/share/fonts/dejavu/DejaVuSansMono.ttf",
+];
+
+const FONT_SIZE : f32 = 16.0;
+const INDENT : &str = "  ";
+/// Lines moved by one step of the mouse wheel
+const WHEEL_LINES : i32 = 3;
+
+const BACKGROUND : Color = Color { r: 39, g: 40, b: 34, a: 255 };
+const GUTTER : Color = Color { r: 30, g: 31, b: 26, a: 255 };
+const TEXT : Color = Color { r: 248, g: 248, b: 242, a: 255 };
+const LINE_NUMBER : Color = Color { r: 117, g: 113, b: 94, a: 255 };
+const HIGHLIGHT : Color = Color { r: 73, g: 72, b: 62, a: 255 };
+const CARET : Color = Color { r: 230, g: 219, b: 116, a: 255 };
+const STATUS_BAR : Color = Color { r: 20, g: 20, b: 20, a: 255 };
+
+pub fn find_font() -> Option<String> {
+  DEFAULT_FONTS.iter().find(|p| Path::new(p).exists()).map(|p| p.to_string())
+}
+
+fn modified_time(path : &str) -> Option<SystemTime> {
+  fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+struct CodeEditor {
+  path : String,
+  input : TextEditorState,
+  history : EditHistory,
+  /// The text as it was last saved or loaded
+  saved_text : String,
+  modified_time : Option<SystemTime>,
+  /// Whether the text differs from the saved text
+  modified : bool,
+  /// The first line on the screen
+  scroll : usize,
+  status : String,
+  /// Set when closing the window was refused because of unsaved edits
+  warned_about_closing : bool,
+}
+
+impl CodeEditor {
+  fn open(path : &str) -> Result<CodeEditor, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("failed to read '{}': {}", path, e))?;
+    Ok(CodeEditor {
+      path: path.to_string(),
+      input: TextEditorState::new(&text),
+      history: EditHistory::new(),
+      saved_text: text,
+      modified_time: modified_time(path),
+      modified: false,
+      scroll: 0,
+      status: String::new(),
+      warned_about_closing: false,
+    })
+  }
+
+  fn text_changed(&mut self) {
+    self.modified = self.input.buffer.to_string() != self.saved_text;
+    self.status.clear();
+    self.warned_about_closing = false;
+  }
+
+  fn apply(&mut self, edit : Option<TextEdit>) {
+    if let Some(edit) = edit {
+      self.history.apply_text_edit(&mut self.input, edit);
+      self.text_changed();
+    }
+  }
+
+  fn insert(&mut self, text : &str) {
+    let edit = self.input.insert(text.to_string());
+    self.apply(Some(edit));
+  }
+
+  fn move_caret(&mut self, move_type : CaretMoveType, highlighting : bool) {
+    self.input.move_caret(CaretMove { highlighting, move_type });
+  }
+
+  /// Copies the highlighted text. Returns false if nothing is highlighted.
+  fn copy(&mut self, clipboard : &ClipboardUtil) -> bool {
+    let s = self.input.get_highlighted_string();
+    if s.is_empty() {
+      return false;
+    }
+    if let Err(e) = clipboard.set_clipboard_text(&s) {
+      self.status = format!("failed to copy: {}", e);
+    }
+    true
+  }
+
+  fn save(&mut self) {
+    let text = self.input.buffer.to_string();
+    match fs::write(&self.path, &text) {
+      Ok(()) => {
+        self.saved_text = text;
+        self.modified = false;
+        self.modified_time = modified_time(&self.path);
+        self.status = "saved".into();
+      }
+      Err(e) => self.status = format!("failed to save: {}", e),
+    }
+  }
+
+  /// Reloads the file if it has changed on disk, unless there are unsaved edits
+  fn check_for_changes(&mut self) {
+    let t = modified_time(&self.path);
+    if t == self.modified_time {
+      return;
+    }
+    self.modified_time = t;
+    if self.modified {
+      self.status = "the file changed on disk, but there are unsaved edits".into();
+      return;
+    }
+    if let Ok(text) = fs::read_to_string(&self.path) {
+      if text != self.saved_text {
+        self.input.set_text(&text);
+        self.history.clear();
+        self.saved_text = text;
+        self.status = "reloaded".into();
+      }
+    }
+  }
+
+  /// Returns false if the window shouldn't close yet, because of unsaved edits
+  fn close(&mut self) -> bool {
+    if self.modified && !self.warned_about_closing {
+      self.status = "there are unsaved edits; close again to discard them".into();
+      self.warned_about_closing = true;
+      return false;
+    }
+    true
+  }
+
+  fn handle_key(&mut self, key : Keycode, keymod : Mod, clipboard : &ClipboardUtil, page_lines : usize) {
+    let shift = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+    let ctrl = keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD | Mod::LGUIMOD | Mod::RGUIMOD);
+    match key {
+      Keycode::Left => self.move_caret(CaretMoveType::Left, shift),
+      Keycode::Right => self.move_caret(CaretMoveType::Right, shift),
+      Keycode::Up => self.move_caret(CaretMoveType::Up, shift),
+      Keycode::Down => self.move_caret(CaretMoveType::Down, shift),
+      Keycode::Home => self.move_caret(CaretMoveType::Home, shift),
+      Keycode::End => self.move_caret(CaretMoveType::End, shift),
+      Keycode::PageUp => {
+        for _ in 0..page_lines { self.move_caret(CaretMoveType::Up, shift) }
+      }
+      Keycode::PageDown => {
+        for _ in 0..page_lines { self.move_caret(CaretMoveType::Down, shift) }
+      }
+      Keycode::Return | Keycode::KpEnter => {
+        let indentation = self.input.current_indentation();
+        self.insert(&format!("\n{}", indentation));
+      }
+      Keycode::Tab => self.insert(INDENT),
+      Keycode::Backspace => {
+        let edit = self.input.backspace();
+        self.apply(edit);
+      }
+      Keycode::Delete => {
+        let edit = self.input.delete();
+        self.apply(edit);
+      }
+      Keycode::S if ctrl => self.save(),
+      Keycode::Z if ctrl => {
+        if self.history.undo(&mut self.input) { self.text_changed() }
+      }
+      Keycode::Y if ctrl => {
+        if self.history.redo(&mut self.input) { self.text_changed() }
+      }
+      Keycode::A if ctrl => self.input.select_all(),
+      Keycode::C if ctrl => { self.copy(clipboard); }
+      Keycode::X if ctrl => {
+        if self.copy(clipboard) {
+          let edit = self.input.backspace();
+          self.apply(edit);
+        }
+      }
+      Keycode::V if ctrl => {
+        if let Ok(s) = clipboard.clipboard_text() {
+          self.insert(&s);
+        }
+      }
+      _ => (),
+    }
+  }
+
+  /// Scrolls so that the caret is on the screen
+  fn scroll_to_caret(&mut self, visible_lines : usize) {
+    let (line, _) = self.input.caret_line_col();
+    if line < self.scroll {
+      self.scroll = line;
+    }
+    else if line >= self.scroll + visible_lines {
+      self.scroll = line + 1 - visible_lines;
+    }
+  }
+}
+
+/// Where things go in the window, in pixels
+struct Layout {
+  width : u32,
+  /// Where the text starts, to the right of the line numbers
+  text_x : f32,
+  visible_lines : usize,
+  status_y : i32,
+}
+
+fn layout(canvas : &Canvas<Window>, editor : &CodeEditor, attribs : &LayoutAttribs) -> Layout {
+  let (width, height) = canvas.output_size().unwrap();
+  let status_height = attribs.advance_height.ceil() as i32 + 4;
+  let status_y = height as i32 - status_height;
+  let visible_lines = cmp::max(1, (status_y as f32 / attribs.advance_height) as usize);
+  let digits = editor.input.buffer.len_lines().to_string().len();
+  let text_x = (digits + 2) as f32 * attribs.advance_width;
+  Layout { width, text_x, visible_lines, status_y }
+}
+
+fn cell_rect(line : usize, col_start : usize, col_end : usize, l : &Layout, attribs : &LayoutAttribs) -> Rect {
+  Rect::new(
+    (l.text_x + col_start as f32 * attribs.advance_width) as i32,
+    (line as f32 * attribs.advance_height) as i32,
+    cmp::max(2, ((col_end - col_start) as f32 * attribs.advance_width) as u32),
+    attribs.advance_height.ceil() as u32)
+}
+
+fn draw(canvas : &mut Canvas<Window>, font : &mut FontRenderState, attribs : &LayoutAttribs, editor : &CodeEditor, l : &Layout) {
+  let buffer = &editor.input.buffer;
+  let first = editor.scroll;
+  let last = cmp::min(first + l.visible_lines, buffer.len_lines());
+
+  canvas.set_draw_color(BACKGROUND);
+  canvas.clear();
+  canvas.set_draw_color(GUTTER);
+  canvas.fill_rect(Rect::new(0, 0, l.text_x as u32, l.status_y as u32)).unwrap();
+
+  // highlighted text
+  if let Some(marker) = editor.input.caret.marker {
+    let (a, b) = (cmp::min(marker, editor.input.caret.pos()), cmp::max(marker, editor.input.caret.pos()));
+    canvas.set_draw_color(HIGHLIGHT);
+    for line in first..last {
+      let start = buffer.line_to_char(line);
+      let len = count_line_chars(buffer, line);
+      if b < start || a > start + len {
+        continue;
+      }
+      let col_start = cmp::max(a, start) - start;
+      // one more column shows that the newline is highlighted
+      let col_end = if b > start + len { len + 1 } else { b - start };
+      canvas.fill_rect(cell_rect(line - first, col_start, col_end, l, attribs)).unwrap();
+    }
+  }
+
+  // line numbers and text
+  let digits = buffer.len_lines().to_string().len();
+  let numbers = (first..last).map(|n| format!("{:>w$}", n + 1, w = digits)).collect::<Vec<_>>().join("\n");
+  font.draw_text(canvas, &numbers, attribs.advance_width, 0.0, LINE_NUMBER, attribs);
+  let text = buffer.slice(buffer.line_to_char(first)..buffer.line_to_char(last)).to_string();
+  font.draw_text(canvas, &text, l.text_x, 0.0, TEXT, attribs);
+
+  // caret
+  let (line, col) = editor.input.caret_line_col();
+  if line >= first && line < last {
+    canvas.set_draw_color(CARET);
+    canvas.fill_rect(cell_rect(line - first, col, col, l, attribs)).unwrap();
+  }
+
+  // status bar
+  canvas.set_draw_color(STATUS_BAR);
+  canvas.fill_rect(Rect::new(0, l.status_y, l.width, attribs.advance_height.ceil() as u32 + 4)).unwrap();
+  let status = format!("{}{}  {}:{}  {}",
+    editor.path, if editor.modified { " *" } else { "" }, line + 1, col + 1, editor.status);
+  font.draw_text(canvas, &status, attribs.advance_width, l.status_y as f32 + 2.0, TEXT, attribs);
+
+  canvas.present();
+}
+
+/// Opens the editor window, and returns when it's closed (or on Ctrl-C)
+pub fn run(path : &str, font_path : &str) -> Result<(), String> {
+  let font_data = fs::read(font_path).map_err(|e| format!("failed to read font '{}': {}", font_path, e))?;
+  let mut editor = CodeEditor::open(path)?;
+
+  let sdl = sdl2::init()?;
+  let video = sdl.video()?;
+  let window = video.window(&format!("{} - cauldron", path), 1000, 800)
+    .position_centered()
+    .resizable()
+    .allow_highdpi()
+    .build()
+    .map_err(|e| e.to_string())?;
+  let dpi_ratio = window.drawable_size().0 as f32 / window.size().0 as f32;
+  let mut canvas = window.into_canvas().accelerated().build().map_err(|e| e.to_string())?;
+  canvas.set_blend_mode(BlendMode::Blend);
+  let texture_creator = canvas.texture_creator();
+  let mut font = FontRenderState::new(&texture_creator, font_data, dpi_ratio)?;
+  let attribs = font.layout_attribs(FONT_SIZE);
+  let clipboard = video.clipboard();
+  video.text_input().start();
+  let mut events = sdl.event_pump()?;
+  let mut last_check = Instant::now();
+
+  'main: while !shutdown::requested() {
+    let l = layout(&canvas, &editor, &attribs);
+    for event in events.poll_iter() {
+      match event {
+        Event::Quit { .. } => {
+          if editor.close() {
+            break 'main;
+          }
+        }
+        Event::KeyDown { keycode: Some(k), keymod, .. } =>
+          editor.handle_key(k, keymod, &clipboard, l.visible_lines),
+        Event::TextInput { text, .. } => editor.insert(&text),
+        Event::MouseButtonDown { x, y, .. } => {
+          let line = editor.scroll + (y as f32 / attribs.advance_height) as usize;
+          let col = ((x as f32 - l.text_x) / attribs.advance_width).round().max(0.0) as usize;
+          editor.input.set_caret_line_col(line, col);
+        }
+        Event::MouseWheel { y, .. } => {
+          let scroll = editor.scroll as i32 - y * WHEEL_LINES;
+          let max_scroll = editor.input.buffer.len_lines() as i32 - 1;
+          editor.scroll = cmp::max(0, cmp::min(scroll, max_scroll)) as usize;
+          continue;
+        }
+        _ => continue,
+      }
+      editor.scroll_to_caret(l.visible_lines);
+    }
+    if last_check.elapsed() > Duration::from_millis(250) {
+      editor.check_for_changes();
+      last_check = Instant::now();
+    }
+    draw(&mut canvas, &mut font, &attribs, &editor, &l);
+    thread::sleep(Duration::from_millis(16));
+  }
+  Ok(())
+}
@@ -4,12 +4,17 @@ use crate::common::*;
 
 /// Returns an error that isn't wrapped in Result::Err
 pub fn error_raw<L : Into<TextLocation>, S : Into<ErrorContent>>(loc : L, message : S) -> Error {
-  Error { message: message.into(), location: loc.into() }
+  Error { message: message.into(), location: loc.into(), severity: Severity::Error }
 }
 
 /// Returns an error wrapped in Result::Err
 pub fn error<T, L : Into<TextLocation>, S : Into<ErrorContent>>(loc : L, message : S) -> Result<T, Error> {
-  Err(Error { message: message.into(), location: loc.into() })
+  Err(error_raw(loc, message))
+}
+
+/// Returns a warning, which is reported like an error but doesn't stop compilation
+pub fn warning_raw<L : Into<TextLocation>, S : Into<ErrorContent>>(loc : L, message : S) -> Error {
+  Error { message: message.into(), location: loc.into(), severity: Severity::Warning }
 }
 
 #[repr(C)]
@@ -97,16 +102,23 @@ impl <S : Into<String>> From<S> for ErrorContent {
   }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ErrorContent {
   Message(String),
   InnerErrors(String, Vec<Error>),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+  Error,
+  Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Error {
   pub message : ErrorContent,
   pub location : TextLocation,
+  pub severity : Severity,
 }
 
 impl Error {
@@ -121,6 +133,9 @@ pub struct UnsourcedError<'l> {
 
 impl <'l> fmt::Display for UnsourcedError<'l> {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.e.severity == Severity::Warning {
+      write!(f, "warning ")?;
+    }
     write!(f, "{}", self.e.location)?;
     match &self.e.message {
       ErrorContent::Message(m) => {
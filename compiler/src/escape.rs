@@ -0,0 +1,205 @@
+// Escape analysis for array literals, so that arrays which only live as long as the
+// function that made them go on the stack instead of the heap.
+//
+// An array literal is stack allocated if it initialises a local, and that local is
+// only ever indexed, asked for its length, copied into other locals with `let`, or
+// looped over with `for`. Anything else (passing it to a function, returning it,
+// storing it, assigning it to a `var`, or taking the address of it or of one of its
+// elements) might let it outlive the function, so the literal stays on the heap.
+// Locals are always declared in a scope inside the scope of the local they copy, so
+// copies can't outlive the original.
+//
+// Each literal gets one slot in the function's entry block, which is reused every
+// time it runs, so a literal in a loop doesn't grow the stack.
+//
+// Struct constructors don't need this, because structs are built in registers.
+// Polymorphic functions are skipped, because their instances share these nodes
+// but not their types.
+
+use crate::common::UnitId;
+use crate::structure::{Nodes, NodeId, Content, ReferenceId, VarScope};
+use crate::code_store::CodeStore;
+use crate::types::{TypeMapping, SymbolInit};
+
+use std::collections::{HashMap, HashSet};
+
+/// Finds the array literals in a unit that can be stack allocated
+pub fn mark_stack_arrays(code_store : &mut CodeStore, unit_id : UnitId) {
+  let stack_arrays = match (code_store.nodes.get(&unit_id), code_store.type_mappings.get(&unit_id)) {
+    (Some(nodes), Some(mapping)) => Escapes::new(code_store, mapping, nodes).stack_arrays(),
+    _ => return,
+  };
+  code_store.nodes.get_mut(&unit_id).unwrap().stack_arrays = stack_arrays;
+}
+
+struct Escapes<'l> {
+  code_store : &'l CodeStore,
+  mapping : &'l TypeMapping,
+  nodes : &'l Nodes,
+  parents : HashMap<NodeId, NodeId>,
+  uses : HashMap<ReferenceId, Vec<NodeId>>,
+}
+
+impl <'l> Escapes<'l> {
+  fn new(code_store : &'l CodeStore, mapping : &'l TypeMapping, nodes : &'l Nodes) -> Self {
+    let mut parents = HashMap::new();
+    let mut uses : HashMap<ReferenceId, Vec<NodeId>> = HashMap::new();
+    for (&id, node) in nodes.nodes.iter() {
+      for c in node.content.children() {
+        parents.insert(c, id);
+      }
+      if let Content::Reference{ refers_to: Some(r), .. } = &node.content {
+        uses.entry(*r).or_default().push(id);
+      }
+    }
+    Escapes { code_store, mapping, nodes, parents, uses }
+  }
+
+  fn stack_arrays(&self) -> HashSet<NodeId> {
+    let mut stack_arrays = HashSet::new();
+    for (&id, node) in self.nodes.nodes.iter() {
+      if let Content::ArrayLiteral(_) = node.content {
+        if let Some(local) = self.initialised_local(id) {
+          if !self.in_polymorphic_function(id) && !self.escapes(local) {
+            stack_arrays.insert(id);
+          }
+        }
+      }
+    }
+    stack_arrays
+  }
+
+  fn content(&self, id : NodeId) -> &'l Content {
+    &self.nodes.node(id).content
+  }
+
+  fn parent(&self, id : NodeId) -> Option<NodeId> {
+    self.parents.get(&id).cloned()
+  }
+
+  /// The local that a node is the initial value of, if it is one
+  fn initialised_local(&self, id : NodeId) -> Option<ReferenceId> {
+    match self.content(self.parent(id)?) {
+      Content::VariableInitialise{ name, value, var_scope: VarScope::Local, .. } if *value == id =>
+        Some(name.id),
+      _ => None,
+    }
+  }
+
+  fn in_polymorphic_function(&self, id : NodeId) -> bool {
+    let mut n = id;
+    while let Some(p) = self.parent(n) {
+      if let Content::FunctionDefinition{ type_vars, .. } = self.content(p) {
+        return !type_vars.is_empty();
+      }
+      n = p;
+    }
+    false
+  }
+
+  /// The name of the intrinsic that a call calls, if it calls one
+  fn called_intrinsic(&self, id : NodeId) -> Option<&'l str> {
+    let function = match self.content(id) {
+      Content::FunctionCall{ function, .. } => *function,
+      _ => return None,
+    };
+    let symbol_id = *self.mapping.symbol_references.get(&function)?;
+    let def = self.code_store.symbol_def(symbol_id);
+    match def.initialiser {
+      SymbolInit::Intrinsic => Some(def.name.as_ref()),
+      _ => None,
+    }
+  }
+
+  fn called_name(&self, id : NodeId) -> Option<&'l str> {
+    match self.content(id) {
+      Content::FunctionCall{ function, .. } => match self.content(*function) {
+        Content::Reference{ name, .. } => Some(name.as_ref()),
+        _ => None,
+      }
+      _ => None,
+    }
+  }
+
+  /// True if the array in a local, or in any of the locals it's copied into, might
+  /// outlive the function
+  fn escapes(&self, local : ReferenceId) -> bool {
+    let mut locals = vec![local];
+    let mut seen = HashSet::new();
+    while let Some(l) = locals.pop() {
+      if !seen.insert(l) {
+        continue;
+      }
+      for &u in self.uses.get(&l).map(|v| v.as_slice()).unwrap_or(&[]) {
+        match self.copied_into(u) {
+          Some(Some(copy)) => locals.push(copy),
+          Some(None) => (),
+          None => return true,
+        }
+      }
+    }
+    self.address_taken(&seen)
+  }
+
+  /// Checks a use of a local that holds an array. Returns the local it's copied
+  /// into, if it is, or nothing if the use can't let the array escape.
+  fn copied_into(&self, u : NodeId) -> Option<Option<ReferenceId>> {
+    let p = self.parent(u)?;
+    match self.content(p) {
+      Content::FieldAccess{ field, .. } if field.name.as_ref() == "length" => Some(None),
+      Content::VariableInitialise{ .. } => self.initialised_local(u).map(Some),
+      Content::FunctionCall{ args, .. } if args.first() == Some(&u) => {
+        match (self.called_intrinsic(p), args.len()) {
+          // the element pointer is dereferenced straight away
+          (Some("Index"), 2) => {
+            let deref = self.parent(p)?;
+            if self.called_intrinsic(deref) == Some("*") { Some(None) } else { None }
+          }
+          // `for x in a` keeps an iterator over the array in a hidden local
+          (None, 1) if self.called_name(p) == Some("iter") => {
+            let address = self.parent(p)?;
+            let init = self.parent(address)?;
+            match self.content(init) {
+              Content::VariableInitialise{ name, .. }
+                if self.called_intrinsic(address) == Some("&") && name.name.as_ref() == "@range_var"
+                  => Some(None),
+              _ => None,
+            }
+          }
+          _ => None,
+        }
+      }
+      _ => None,
+    }
+  }
+
+  /// True if the address of any of the locals, or of anything inside them, is taken
+  fn address_taken(&self, locals : &HashSet<ReferenceId>) -> bool {
+    for (&id, node) in self.nodes.nodes.iter() {
+      let mut n = match &node.content {
+        Content::FunctionCall{ args, .. } if args.len() == 1 && self.called_intrinsic(id) == Some("&") => args[0],
+        _ => continue,
+      };
+      loop {
+        match self.content(n) {
+          Content::FieldAccess{ container, .. } => n = *container,
+          Content::FunctionCall{ args, .. } if self.called_intrinsic(n) == Some("*") => {
+            let element = args[0];
+            match self.content(element) {
+              Content::FunctionCall{ args, .. } if self.called_intrinsic(element) == Some("Index") => n = args[0],
+              _ => break,
+            }
+          }
+          Content::Reference{ refers_to: Some(r), .. } => {
+            if locals.contains(r) {
+              return true;
+            }
+            break;
+          }
+          _ => break,
+        }
+      }
+    }
+    false
+  }
+}
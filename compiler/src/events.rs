@@ -0,0 +1,137 @@
+// A publish/subscribe event bus that is shared by host code and the language.
+//
+// Payloads are plain bytes. The language can't reflect on its types yet, so the
+// best validation available is to fix the payload size of a topic the first time
+// it is used, and reject any later publish or subscribe that disagrees.
+
+use crate::c_interface::SStr;
+
+use std::collections::{HashMap, VecDeque};
+use std::mem::ManuallyDrop;
+
+/// Published when the watcher sees an asset file (anything that isn't code) change
+pub const ASSET_CHANGED_TOPIC : &str = "asset_changed";
+
+/// Matches `struct asset_changed` in core/compiler.code
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct AssetChanged {
+  pub path : SStr,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct SubscriptionId(u64);
+
+struct Topic {
+  payload_size : usize,
+  subscribers : Vec<SubscriptionId>,
+}
+
+struct Subscription {
+  topic : String,
+  queue : VecDeque<Vec<u8>>,
+}
+
+#[derive(Default)]
+pub struct EventBus {
+  topics : HashMap<String, Topic>,
+  subscriptions : HashMap<SubscriptionId, Subscription>,
+  next_id : u64,
+}
+
+impl EventBus {
+  pub fn new() -> Self {
+    Default::default()
+  }
+
+  fn topic(&mut self, name : &str, payload_size : usize) -> Result<&mut Topic, String> {
+    let t = self.topics.entry(name.into())
+      .or_insert_with(|| Topic { payload_size, subscribers: vec![] });
+    if t.payload_size != payload_size {
+      return Err(format!(
+        "event topic '{}' has payloads of {} bytes, but {} bytes were given",
+        name, t.payload_size, payload_size));
+    }
+    Ok(t)
+  }
+
+  pub fn subscribe_raw(&mut self, topic : &str, payload_size : usize) -> Result<SubscriptionId, String> {
+    self.next_id += 1;
+    let id = SubscriptionId(self.next_id);
+    self.topic(topic, payload_size)?.subscribers.push(id);
+    let s = Subscription { topic: topic.into(), queue: VecDeque::new() };
+    self.subscriptions.insert(id, s);
+    Ok(id)
+  }
+
+  pub fn unsubscribe(&mut self, id : SubscriptionId) {
+    if let Some(s) = self.subscriptions.remove(&id) {
+      if let Some(t) = self.topics.get_mut(&s.topic) {
+        t.subscribers.retain(|&x| x != id);
+      }
+    }
+  }
+
+  pub fn publish_raw(&mut self, topic : &str, payload : &[u8]) -> Result<(), String> {
+    let subscribers = self.topic(topic, payload.len())?.subscribers.clone();
+    for id in subscribers {
+      if let Some(s) = self.subscriptions.get_mut(&id) {
+        s.queue.push_back(payload.to_vec());
+      }
+    }
+    Ok(())
+  }
+
+  /// Copies the next event into `out`, which must be the payload size of the topic
+  pub fn poll_raw(&mut self, id : SubscriptionId, out : &mut [u8]) -> Result<bool, String> {
+    let s = self.subscriptions.get_mut(&id).ok_or("invalid event subscription")?;
+    match s.queue.pop_front() {
+      Some(payload) => {
+        if payload.len() != out.len() {
+          return Err(format!(
+            "event topic '{}' has payloads of {} bytes, but tried to read {} bytes",
+            s.topic, payload.len(), out.len()));
+        }
+        out.copy_from_slice(&payload);
+        Ok(true)
+      }
+      None => Ok(false),
+    }
+  }
+
+  pub fn subscribe<T : Copy>(&mut self, topic : &str) -> Result<SubscriptionId, String> {
+    self.subscribe_raw(topic, std::mem::size_of::<T>())
+  }
+
+  pub fn publish<T : Copy>(&mut self, topic : &str, payload : &T) -> Result<(), String> {
+    let bytes = unsafe {
+      std::slice::from_raw_parts(payload as *const T as *const u8, std::mem::size_of::<T>())
+    };
+    self.publish_raw(topic, bytes)
+  }
+
+  pub fn poll<T : Copy>(&mut self, id : SubscriptionId) -> Result<Option<T>, String> {
+    let mut v : T = unsafe { std::mem::zeroed() };
+    let bytes = unsafe {
+      std::slice::from_raw_parts_mut(&mut v as *mut T as *mut u8, std::mem::size_of::<T>())
+    };
+    Ok(if self.poll_raw(id, bytes)? { Some(v) } else { None })
+  }
+}
+
+impl EventBus {
+  /// The path is leaked, because the language has no way to free it yet
+  pub fn publish_asset_changed(&mut self, path : &str) -> Result<(), String> {
+    let path = path.replace("\\", "/");
+    let event = AssetChanged { path: SStr::from_string(ManuallyDrop::new(path)) };
+    self.publish(ASSET_CHANGED_TOPIC, &event)
+  }
+}
+
+impl From<u64> for SubscriptionId {
+  fn from(v : u64) -> Self { SubscriptionId(v) }
+}
+
+impl SubscriptionId {
+  pub fn inner(self) -> u64 { self.0 }
+}
@@ -0,0 +1,65 @@
+// A table of named slots holding function addresses, for hosts that need to keep
+// hold of an address across reloads (e.g. external profilers, or callbacks that
+// are stored by a C library).
+//
+// The address of a slot never changes, and slots are never freed. Whenever a
+// module is loaded or unloaded the slots are re-pointed, so a slot always holds
+// the newest version of its function, or null if the function doesn't exist.
+
+use crate::common::*;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct ExportSlot {
+  pub module : RefStr,
+  pub function : RefStr,
+  address : &'static AtomicUsize,
+}
+
+impl ExportSlot {
+  /// The stable address of the slot itself
+  pub fn slot_address(&self) -> *const usize {
+    self.address as *const AtomicUsize as *const usize
+  }
+
+  /// The address of the function that the slot currently points to
+  pub fn target(&self) -> usize {
+    self.address.load(Ordering::SeqCst)
+  }
+
+  pub fn set_target(&self, function_address : usize) {
+    self.address.store(function_address, Ordering::SeqCst);
+  }
+}
+
+#[derive(Default)]
+pub struct ExportTable {
+  slots : HashMap<RefStr, ExportSlot>,
+}
+
+impl ExportTable {
+  pub fn new() -> Self {
+    Default::default()
+  }
+
+  /// Creates a slot, or retargets an existing slot with the same name. The
+  /// slot is null until the table is refreshed.
+  pub fn export(&mut self, name : RefStr, module : RefStr, function : RefStr) -> &ExportSlot {
+    let slot = self.slots.entry(name).or_insert_with(|| {
+      let address = Box::leak(Box::new(AtomicUsize::new(0)));
+      ExportSlot { module: module.clone(), function: function.clone(), address }
+    });
+    slot.module = module;
+    slot.function = function;
+    slot
+  }
+
+  pub fn get(&self, name : &str) -> Option<&ExportSlot> {
+    self.slots.get(name)
+  }
+
+  pub fn slots(&self) -> impl Iterator<Item=&ExportSlot> {
+    self.slots.values()
+  }
+}
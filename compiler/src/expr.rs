@@ -112,6 +112,70 @@ impl Expr {
       _ => &[],
     }
   }
+
+  /// Whether two expressions have the same shape and contents, ignoring locations
+  pub fn structurally_equal(&self, other : &Expr) -> bool {
+    use self::ExprContent::*;
+    match (&self.content, &other.content) {
+      (List(a, es_a), List(b, es_b)) => {
+        let (es_a, es_b) = (es_a.as_slice(), es_b.as_slice());
+        a.as_str() == b.as_str() && es_a.len() == es_b.len() &&
+          es_a.iter().zip(es_b).all(|(a, b)| a.structurally_equal(b))
+      }
+      (Symbol(a), Symbol(b)) | (LiteralString(a), LiteralString(b)) => a.as_str() == b.as_str(),
+      (LiteralFloat(a), LiteralFloat(b)) => a == b,
+      (LiteralInt(a), LiteralInt(b)) => a == b,
+      (LiteralBool(a), LiteralBool(b)) => a == b,
+      (LiteralUnit, LiteralUnit) => true,
+      _ => false,
+    }
+  }
+
+  /// Displays the expression on one line if it fits in `width` columns. Otherwise
+  /// each construct that doesn't fit has its children on separate, indented lines.
+  pub fn pretty(&self, width : usize) -> String {
+    fn one_line(e : &Expr) -> String {
+      match e.try_construct() {
+        Some((s, children)) => {
+          let mut out = format!("({}", s);
+          for c in children {
+            out.push(' ');
+            out.push_str(&one_line(c));
+          }
+          out.push(')');
+          out
+        }
+        None => format!("{}", e),
+      }
+    }
+    fn pretty_inner(e : &Expr, out : &mut String, indent : usize, width : usize) {
+      let line = one_line(e);
+      match e.try_construct() {
+        Some((s, children)) if indent + line.len() > width && !children.is_empty() => {
+          out.push('(');
+          out.push_str(s);
+          for c in children {
+            out.push('\n');
+            out.push_str(&" ".repeat(indent + 2));
+            pretty_inner(c, out, indent + 2, width);
+          }
+          out.push(')');
+        }
+        _ => out.push_str(&line),
+      }
+    }
+    let mut out = String::new();
+    pretty_inner(self, &mut out, 0, width);
+    out
+  }
+}
+
+/// `a.f(b)` is parsed as `f(a, b)`, but the name comes after the receiver
+pub fn is_method_call(f : &Expr, args : &[Expr]) -> bool {
+  match args.first() {
+    Some(receiver) => f.try_symbol().is_some() && f.loc.start > receiver.loc.start,
+    None => false,
+  }
 }
 
 impl fmt::Debug for Expr {
@@ -0,0 +1,128 @@
+// Reports which language features a unit uses. This is meant to help audit
+// a project before turning on stricter checks, or before porting it.
+
+use crate::common::*;
+use crate::code_store::CodeStore;
+use crate::structure::{Content, TypeKind, NodeId, VarScope, GlobalType};
+use crate::types::{SymbolInit, TypeContent};
+use crate::intrinsics::UNSAFE_ZERO_INIT;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+pub struct FeatureReport {
+  pub unit_name : RefStr,
+  /// Number of times each feature was used
+  pub features : BTreeMap<&'static str, usize>,
+  /// Number of calls to each intrinsic
+  pub intrinsics : BTreeMap<RefStr, usize>,
+  /// Every cbind that the unit declares or references
+  pub cbinds : BTreeSet<RefStr>,
+}
+
+impl FeatureReport {
+  fn feature(&mut self, name : &'static str) {
+    *self.features.entry(name).or_insert(0) += 1;
+  }
+}
+
+/// Builds a report for a unit that has been typechecked
+pub fn feature_report(code_store : &CodeStore, unit_id : UnitId) -> FeatureReport {
+  let nodes = code_store.nodes(unit_id);
+  let mapping = code_store.type_mapping(unit_id);
+  let mut report = FeatureReport {
+    unit_name: code_store.name(unit_id),
+    features: BTreeMap::new(),
+    intrinsics: BTreeMap::new(),
+    cbinds: BTreeSet::new(),
+  };
+  let is_ptr = |n : &NodeId| {
+    mapping.node_type.get(n).map(|t| t.content == TypeContent::Ptr).unwrap_or(false)
+  };
+  // indexing an array is structured as `*Index(a, i)`, which isn't a raw pointer operation
+  let is_array_index = |n : &NodeId| {
+    if let Content::FunctionCall{ function, args } = &nodes.node(*n).content {
+      let name = mapping.symbol_references.get(function).map(|&id| &code_store.symbol_def(id).name);
+      if let (Some(name), [a, _]) = (name, args.as_slice()) {
+        return name.as_ref() == "Index" && !is_ptr(a);
+      }
+    }
+    false
+  };
+  for node in nodes.nodes.values() {
+    match &node.content {
+      Content::FunctionCall{ function, args } => {
+        let def = mapping.symbol_references.get(function).map(|&id| code_store.symbol_def(id));
+        match def.map(|def| (def, &def.initialiser)) {
+          Some((def, SymbolInit::Intrinsic)) => {
+            *report.intrinsics.entry(def.name.clone()).or_insert(0) += 1;
+            match (def.name.as_ref(), args.as_slice()) {
+              ("*", [p]) if !is_array_index(p) => report.feature("raw pointer dereference"),
+              ("&", [_]) => report.feature("address-of"),
+              ("Index", [a, _]) if is_ptr(a) => report.feature("raw pointer indexing"),
+              (name, _) if name == UNSAFE_ZERO_INIT => report.feature("zero initialisation"),
+              _ => (),
+            }
+          }
+          Some((def, SymbolInit::CBind)) => {
+            report.cbinds.insert(def.name.clone());
+            report.feature("c function call");
+          }
+          Some(_) => (),
+          None => report.feature("function pointer call"),
+        }
+      }
+      Content::Convert{ from_value, .. } => {
+        if is_ptr(from_value) || is_ptr(&node.id) {
+          report.feature("pointer cast");
+        }
+      }
+      Content::CBind{ name, .. } => {
+        report.cbinds.insert(name.clone());
+        report.feature("cbind");
+      }
+      Content::TypeDefinition{ kind, type_vars, .. } => {
+        if *kind == TypeKind::Union { report.feature("union") }
+        if *kind == TypeKind::TaggedUnion { report.feature("tagged union") }
+        if type_vars.len() > 0 { report.feature("polymorphic type") }
+      }
+      Content::VariableInitialise{ var_scope: VarScope::Global(GlobalType::Lazy), .. } => {
+        report.feature("lazy static");
+      }
+      Content::FunctionDefinition{ type_vars, .. } => {
+        if type_vars.len() > 0 { report.feature("polymorphic function") }
+      }
+      Content::Quote(_) => report.feature("quote"),
+      Content::SizeOf{..} => report.feature("sizeof"),
+      Content::Switch{..} => report.feature("switch"),
+      _ => (),
+    }
+  }
+  // cbinds referenced as globals rather than called
+  for &id in mapping.symbol_references.values() {
+    let def = code_store.symbol_def(id);
+    if let (SymbolInit::CBind, None) = (&def.initialiser, def.type_tag.sig()) {
+      report.cbinds.insert(def.name.clone());
+    }
+  }
+  report
+}
+
+impl fmt::Display for FeatureReport {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "unit {}", self.unit_name)?;
+    writeln!(f, "  features:")?;
+    for (name, count) in self.features.iter() {
+      writeln!(f, "    {} ({})", name, count)?;
+    }
+    writeln!(f, "  intrinsics:")?;
+    for (name, count) in self.intrinsics.iter() {
+      writeln!(f, "    {} ({})", name, count)?;
+    }
+    writeln!(f, "  cbinds:")?;
+    for name in self.cbinds.iter() {
+      writeln!(f, "    {}", name)?;
+    }
+    Ok(())
+  }
+}
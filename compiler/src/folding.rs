@@ -0,0 +1,206 @@
+// Constant folding over the nodes of a typechecked unit, so that code compiled
+// without LLVM's optimisations doesn't do arithmetic on constants at runtime.
+//
+// Calls to the arithmetic, comparison and boolean intrinsics whose arguments are
+// all literals become literals, `if`, `while`, `switch`, `&&` and `||` with a
+// literal condition lose the branches that can't run, and `sizeof` and `alignof`
+// of primitive types become literals.
+//
+// Folded nodes keep their ids, so the types that the typechecker gave them stay
+// valid. Only what the generated code would compute exactly is folded: integers
+// wrap like they do in LLVM, and division by zero is left alone so that it fails
+// the same way at runtime. Comparisons of integers and all float arithmetic are
+// only folded for `i64` and `f64`, where the literal holds the value as it is.
+
+use crate::common::UnitId;
+use crate::structure::{Nodes, NodeId, Content, PrimitiveVal, LayoutQuery, ShortCircuitOp};
+use crate::code_store::CodeStore;
+use crate::types::{Type, TypeContent, TypeMapping, PType, SymbolInit};
+
+use std::mem::size_of;
+
+/// Folds the constants in a unit's nodes
+pub fn fold_unit(code_store : &mut CodeStore, unit_id : UnitId) {
+  let mut nodes = match code_store.nodes.remove(&unit_id) {
+    Some(nodes) => nodes,
+    None => return,
+  };
+  if let Some(mapping) = code_store.type_mappings.get(&unit_id) {
+    let root = nodes.root;
+    Folder { code_store, mapping, nodes: &mut nodes }.fold(root);
+  }
+  code_store.nodes.insert(unit_id, nodes);
+}
+
+struct Folder<'l> {
+  code_store : &'l CodeStore,
+  mapping : &'l TypeMapping,
+  nodes : &'l mut Nodes,
+}
+
+impl <'l> Folder<'l> {
+  fn fold(&mut self, id : NodeId) {
+    for c in self.nodes.node(id).content.children() {
+      self.fold(c);
+    }
+    if let Some(content) = self.folded(id) {
+      self.nodes.nodes.get_mut(&id).unwrap().content = content;
+    }
+  }
+
+  fn literal(&self, id : NodeId) -> Option<&PrimitiveVal> {
+    match &self.nodes.node(id).content {
+      Content::Literal(v) => Some(v),
+      _ => None,
+    }
+  }
+
+  fn prim_type(&self, id : NodeId) -> Option<PType> {
+    match self.mapping.node_type.get(&id)?.content {
+      TypeContent::Prim(t) => Some(t),
+      _ => None,
+    }
+  }
+
+  /// The name of the intrinsic that a node refers to, if it refers to one
+  fn intrinsic_name(&self, id : NodeId) -> Option<&str> {
+    let symbol_id = *self.mapping.symbol_references.get(&id)?;
+    let def = self.code_store.symbol_def(symbol_id);
+    match def.initialiser {
+      SymbolInit::Intrinsic => Some(def.name.as_ref()),
+      _ => None,
+    }
+  }
+
+  /// The content that a node can be replaced with, if it can be folded
+  fn folded(&self, id : NodeId) -> Option<Content> {
+    use PrimitiveVal::*;
+    match &self.nodes.node(id).content {
+      Content::FunctionCall{ function, args } => {
+        let name = self.intrinsic_name(*function)?;
+        let t = self.mapping.node_type.get(args.first()?)?;
+        let args : Option<Vec<&PrimitiveVal>> = args.iter().map(|a| self.literal(*a)).collect();
+        let v = fold_intrinsic(name, t, args?.as_slice())?;
+        Some(Content::Literal(v))
+      }
+      Content::IfThen{ condition, then_branch } => {
+        match self.literal(*condition)? {
+          Bool(true) if self.prim_type(*then_branch) == Some(PType::Void) =>
+            Some(Content::Block(vec![*then_branch])),
+          Bool(false) => Some(Content::Literal(Void)),
+          _ => None,
+        }
+      }
+      Content::IfThenElse{ condition, then_branch, else_branch } => {
+        match self.literal(*condition)? {
+          Bool(true) => Some(Content::Block(vec![*then_branch])),
+          Bool(false) => Some(Content::Block(vec![*else_branch])),
+          _ => None,
+        }
+      }
+      Content::Switch{ value, cases, default, .. } => {
+        let v = match self.literal(*value)? {
+          Int(v) => *v,
+          _ => return None,
+        };
+        let body = cases.iter().find(|(cs, _)| cs.contains(&v)).map(|(_, b)| *b).or(*default);
+        match body {
+          // without an `else` the switch is void, but the body might not be
+          Some(b) if default.is_some() || self.prim_type(b) == Some(PType::Void) =>
+            Some(Content::Block(vec![b])),
+          None => Some(Content::Literal(Void)),
+          _ => None,
+        }
+      }
+      Content::ShortCircuit{ op, left, right, .. } => {
+        match (op, self.literal(*left)?) {
+          (ShortCircuitOp::And, Bool(false)) => Some(Content::Literal(Bool(false))),
+          (ShortCircuitOp::Or, Bool(true)) => Some(Content::Literal(Bool(true))),
+          (_, Bool(_)) => Some(Content::Block(vec![*right])),
+          _ => None,
+        }
+      }
+      Content::While{ condition, .. } => {
+        match self.literal(*condition)? {
+          Bool(false) => Some(Content::Literal(Void)),
+          _ => None,
+        }
+      }
+      Content::SizeOf{ query, .. } => {
+        let t = self.mapping.sizeof_info.get(&id)?;
+        let size = primitive_size(t)?;
+        match query {
+          LayoutQuery::Size | LayoutQuery::Alignment => Some(Content::Literal(Int(size as i64))),
+          LayoutQuery::Offset(_) | LayoutQuery::BitOffset(_) | LayoutQuery::TypeInfo => None,
+        }
+      }
+      _ => None,
+    }
+  }
+}
+
+/// The size of a primitive or pointer type, which is also its alignment
+fn primitive_size(t : &Type) -> Option<usize> {
+  use PType::*;
+  let size = match t.content {
+    TypeContent::Ptr => size_of::<usize>(),
+    TypeContent::Prim(t) => match t {
+      F64 | I64 | U64 => 8,
+      F32 | I32 | U32 => 4,
+      U16 => 2,
+      U8 | Bool => 1,
+      Vec4F => 16,
+      Void | Never => return None,
+    }
+    _ => return None,
+  };
+  Some(size)
+}
+
+/// Evaluates an intrinsic on literal arguments. `t` is the type of the first argument.
+fn fold_intrinsic(name : &str, t : &Type, args : &[&PrimitiveVal]) -> Option<PrimitiveVal> {
+  use PrimitiveVal::*;
+  let i64_type = t.content == TypeContent::Prim(PType::I64);
+  let f64_type = t.content == TypeContent::Prim(PType::F64);
+  let v = match args {
+    [Int(a)] if t.int() && name == "-" => Int(a.wrapping_neg()),
+    [Float(a)] if f64_type && name == "-" => Float(-a),
+    [Bool(a)] if name == "!" => Bool(!a),
+    [Int(a), Int(b)] if t.int() => {
+      let (a, b) = (*a, *b);
+      match name {
+        "+" => Int(a.wrapping_add(b)),
+        "-" => Int(a.wrapping_sub(b)),
+        "*" => Int(a.wrapping_mul(b)),
+        _ if !i64_type => return None,
+        "/" => Int(a.checked_div(b)?),
+        "%" => Int(a.checked_rem(b)?),
+        ">" => Bool(a > b),
+        ">=" => Bool(a >= b),
+        "<" => Bool(a < b),
+        "<=" => Bool(a <= b),
+        "==" => Bool(a == b),
+        "!=" => Bool(a != b),
+        _ => return None,
+      }
+    }
+    [Float(a), Float(b)] if f64_type => {
+      let (a, b) = (*a, *b);
+      match name {
+        "+" => Float(a + b),
+        "-" => Float(a - b),
+        "*" => Float(a * b),
+        "/" => Float(a / b),
+        "%" => Float(a % b),
+        ">" => Bool(a > b),
+        ">=" => Bool(a >= b),
+        "<" => Bool(a < b),
+        "<=" => Bool(a <= b),
+        "==" => Bool(a == b),
+        _ => return None,
+      }
+    }
+    _ => return None,
+  };
+  Some(v)
+}
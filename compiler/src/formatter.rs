@@ -0,0 +1,546 @@
+// Formats code in a canonical layout.
+//
+// The code is parsed as usual and printed back out from its expression tree.
+// Comments aren't part of the tree, so the lexer keeps them to one side, and they
+// are put back between the statements that they were found between. A comment in
+// the middle of a statement moves to the line after it.
+//
+// An expression stays on one line if it fits within the line width. Otherwise
+// blocks get a line per statement, and calls and arrays get a line per argument.
+// One blank line between statements is kept.
+//
+// The formatted code is parsed again and compared with the original, so that
+// formatting can never change what a program means.
+
+use crate::common::*;
+use crate::error::{Error, error, error_raw};
+use crate::expr::{Expr, ExprContent, is_method_call};
+use crate::lexer::{self, Comment};
+use crate::parser::{self, Precedence};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FormatOptions {
+  /// Spaces per level of indentation
+  pub indent : usize,
+  /// Lines longer than this are broken up where possible
+  pub width : usize,
+}
+
+impl Default for FormatOptions {
+  fn default() -> Self {
+    FormatOptions { indent: 2, width: 100 }
+  }
+}
+
+/// Formats some code. Fails if the code doesn't parse.
+pub fn format(code : &str, options : FormatOptions) -> Result<String, Error> {
+  let cache = StringCache::new();
+  let (expr, comments) = parse(code, &cache)?;
+  let comment_count = comments.len();
+  let mut f = Formatter { comments, next_comment: 0, options, precedence: parser::precedence() };
+  let out = match f.statements(expr.children(), 0, usize::MAX) {
+    Ok(out) => out,
+    Err(Failure::Error(e)) => return Err(e),
+    Err(Failure::Multiline) => panic!("statements can always be split over lines"),
+  };
+  let same = match parse(&out, &cache) {
+    Ok((formatted, comments)) => formatted.structurally_equal(&expr) && comments.len() == comment_count,
+    Err(_) => false,
+  };
+  if !same {
+    return error(expr.loc, "the code couldn't be formatted without changing its meaning");
+  }
+  Ok(out)
+}
+
+fn parse(code : &str, cache : &StringCache) -> Result<(Expr, Vec<Comment>), Error> {
+  let (tokens, comments) =
+    lexer::lex_with_comments(no_source(), code, cache).map_err(|mut es| es.remove(0))?;
+  let expr = parser::parse(no_source(), tokens, cache)?;
+  Ok((expr, comments))
+}
+
+/// Why an expression couldn't be printed
+enum Failure {
+  /// It needs more than one line
+  Multiline,
+  Error(Error),
+}
+
+impl From<Error> for Failure {
+  fn from(e : Error) -> Self { Failure::Error(e) }
+}
+
+/// How an expression binds, for deciding where it needs parentheses
+#[derive(Clone, Copy)]
+enum Binding {
+  /// Nothing can come between its parts, as in `f(x)` or `[a, b]`
+  Closed,
+  /// An operator between two operands
+  Infix(i32),
+  /// An operator or keyword followed by an operand
+  Prefix(i32),
+}
+
+/// Constructs that the parser builds from infix operators without calls
+static SPECIAL_INFIX : &[&str] = &["=", ".", "as", "in", ":"];
+
+/// Constructs that start with a keyword
+static KEYWORDS : &[&str] = &[
+  "if", "while", "for", "unsafe", "struct", "union", "tagged", "match", "switch", "cbind", "fun", "macro", "test",
+  "pragma", "static", "lazy", "inline", "init", "let", "var", "type", "return", "stage",
+  "loop", "break", "continue",
+];
+
+fn is_operator(s : &str) -> bool {
+  s.chars().next().map(|c| !c.is_alphanumeric() && c != '_').unwrap_or(false)
+}
+
+fn spaces(n : usize) -> String {
+  " ".repeat(n)
+}
+
+/// The column that some text ends at, if it starts at `col`
+fn end_col(col : usize, s : &str) -> usize {
+  match s.rfind('\n') {
+    Some(i) => s[i + 1..].chars().count(),
+    None => col + s.chars().count(),
+  }
+}
+
+fn blank_line(out : &mut String, last_line : Option<usize>, line : usize) {
+  if let Some(l) = last_line {
+    if line > l + 1 {
+      out.push('\n');
+    }
+  }
+}
+
+fn string_literal(s : &str) -> String {
+  let mut out = String::from("\"");
+  for c in s.chars() {
+    match c {
+      '\\' => out.push_str("\\\\"),
+      '"' => out.push_str("\\\""),
+      '\n' => out.push_str("\\n"),
+      '\t' => out.push_str("\\t"),
+      '\0' => out.push_str("\\0"),
+      c => out.push(c),
+    }
+  }
+  out.push('"');
+  out
+}
+
+fn leaf(e : &Expr) -> String {
+  match &e.content {
+    ExprContent::Symbol(s) => s.as_str().to_string(),
+    ExprContent::LiteralString(s) => string_literal(s.as_str()),
+    ExprContent::LiteralFloat(f) => {
+      let s = f.to_string();
+      if s.contains('.') { s } else { format!("{}.0", s) }
+    }
+    ExprContent::LiteralInt(i) => i.to_string(),
+    ExprContent::LiteralBool(b) => b.to_string(),
+    ExprContent::LiteralUnit => "()".to_string(),
+    ExprContent::List(_, _) => panic!("expected a leaf expression"),
+  }
+}
+
+/// Whether an expression is printed inside brackets of its own
+fn is_bracketed(e : &Expr) -> bool {
+  match e.try_construct() {
+    Some(("block", _)) | Some(("array", _)) | Some(("tuple", _)) => true,
+    Some(_) => false,
+    None => true,
+  }
+}
+
+struct Formatter {
+  comments : Vec<Comment>,
+  /// The first comment that hasn't been printed yet
+  next_comment : usize,
+  options : FormatOptions,
+  precedence : Precedence,
+}
+
+impl Formatter {
+
+  /// Prints statements on their own lines, along with the comments between them.
+  /// Comments above `end_line` that haven't been printed yet go at the end.
+  fn statements(&mut self, es : &[Expr], indent : usize, end_line : usize) -> Result<String, Failure> {
+    let mut out = String::new();
+    let mut last_line = None;
+    for e in es {
+      self.comments_before(e.loc.start.line, indent, &mut out, &mut last_line);
+      blank_line(&mut out, last_line, e.loc.start.line);
+      out.push_str(&spaces(indent));
+      out.push_str(&self.expr(e, indent, indent)?);
+      last_line = Some(e.loc.end.line);
+      while let Some(c) = self.comments.get(self.next_comment) {
+        if !(c.trailing && c.line == e.loc.end.line) {
+          break;
+        }
+        out.push(' ');
+        out.push_str(&c.text);
+        self.next_comment += 1;
+      }
+      out.push('\n');
+    }
+    self.comments_before(end_line, indent, &mut out, &mut last_line);
+    Ok(out)
+  }
+
+  fn comments_before(&mut self, line : usize, indent : usize, out : &mut String, last_line : &mut Option<usize>) {
+    while let Some(c) = self.comments.get(self.next_comment) {
+      if c.line >= line {
+        break;
+      }
+      blank_line(out, *last_line, c.line);
+      out.push_str(&spaces(indent));
+      out.push_str(&c.text);
+      out.push('\n');
+      *last_line = Some(c.line + c.text.matches('\n').count());
+      self.next_comment += 1;
+    }
+  }
+
+  /// Whether there are comments inside an expression that still have to be printed
+  fn has_comments_within(&self, e : &Expr) -> bool {
+    self.comments[self.next_comment..].iter()
+      .take_while(|c| c.line < e.loc.end.line)
+      .any(|c| c.line >= e.loc.start.line)
+  }
+
+  /// Prints an expression that starts at column `col`, on a line indented by
+  /// `indent`. It goes on one line if it fits.
+  fn expr(&mut self, e : &Expr, indent : usize, col : usize) -> Result<String, Failure> {
+    match self.render(e, indent, col, true) {
+      Ok(s) => {
+        if col + s.chars().count() <= self.options.width {
+          return Ok(s);
+        }
+      }
+      Err(Failure::Multiline) => (),
+      Err(e) => return Err(e),
+    }
+    self.render(e, indent, col, false)
+  }
+
+  /// Prints a part of an expression, on one line if `flat` is set
+  fn sub(&mut self, e : &Expr, indent : usize, col : usize, flat : bool) -> Result<String, Failure> {
+    if flat {
+      self.render(e, indent, col, true)
+    }
+    else {
+      self.expr(e, indent, col)
+    }
+  }
+
+  fn operand(&mut self, e : &Expr, indent : usize, col : usize, flat : bool, parens : bool) -> Result<String, Failure> {
+    if parens {
+      Ok(format!("({})", self.sub(e, indent, col + 1, flat)?))
+    }
+    else {
+      self.sub(e, indent, col, flat)
+    }
+  }
+
+  /// `a + b` and `-a` are calls to operators
+  fn operator<'e>(&self, e : &'e Expr) -> Option<(&'e str, &'e [Expr])> {
+    if let Some(("call", es)) = e.try_construct() {
+      let (f, args) = es.split_first()?;
+      let op = f.try_symbol()?;
+      let known = match args.len() {
+        1 => self.precedence.prefix.contains_key(op),
+        2 => self.precedence.infix.contains_key(op),
+        _ => false,
+      };
+      if known && is_operator(op) {
+        return Some((op, args));
+      }
+    }
+    None
+  }
+
+  fn binding(&self, e : &Expr) -> Binding {
+    if let Some((op, args)) = self.operator(e) {
+      if args.len() == 2 {
+        return Binding::Infix(self.precedence.infix[op]);
+      }
+      return Binding::Prefix(self.precedence.prefix[op]);
+    }
+    match e.try_construct() {
+      Some((op, [_, _])) if SPECIAL_INFIX.contains(&op) => Binding::Infix(self.precedence.infix[op]),
+      Some((op, [_])) if op == "#" || op == "$" => Binding::Prefix(self.precedence.prefix[op]),
+      Some((keyword, _)) if KEYWORDS.contains(&keyword) => Binding::Prefix(self.precedence.prefix["#keyword"]),
+      _ => Binding::Closed,
+    }
+  }
+
+  /// Whether an operand on the left of an operator needs parentheses
+  fn left_parens(&self, e : &Expr, precedence : i32) -> bool {
+    match self.binding(e) {
+      Binding::Closed => false,
+      Binding::Infix(p) | Binding::Prefix(p) => p < precedence,
+    }
+  }
+
+  /// Whether an operand on the right of an operator needs parentheses. Prefix
+  /// operators and keywords take their operands with them, so only infix
+  /// operators can.
+  fn right_parens(&self, e : &Expr, precedence : i32) -> bool {
+    match self.binding(e) {
+      Binding::Infix(p) => p <= precedence,
+      _ => false,
+    }
+  }
+
+  fn render(&mut self, e : &Expr, indent : usize, col : usize, flat : bool) -> Result<String, Failure> {
+    if flat && self.has_comments_within(e) {
+      return Err(Failure::Multiline);
+    }
+    let (name, es) = match e.try_construct() {
+      Some(c) => c,
+      None => return Ok(leaf(e)),
+    };
+    if let Some((op, args)) = self.operator(e) {
+      if let [a, b] = args {
+        let p = self.precedence.infix[op];
+        let left = self.operand(a, indent, col, flat, self.left_parens(a, p))?;
+        // ranges are written `a..b`
+        let op = if op == ".." { op.to_string() } else { format!(" {} ", op) };
+        let right_col = end_col(col, &left) + op.len();
+        let right = self.operand(b, indent, right_col, flat, self.right_parens(b, p))?;
+        return Ok(format!("{}{}{}", left, op, right));
+      }
+      let a = &args[0];
+      // `- -a` would lex as `--a`
+      let parens = self.right_parens(a, self.precedence.prefix[op]) || self.operator(a).is_some();
+      let operand = self.operand(a, indent, col + op.len(), flat, parens)?;
+      return Ok(format!("{}{}", op, operand));
+    }
+    let s = match (name, es) {
+      (op, [a, b]) if SPECIAL_INFIX.contains(&op) => {
+        let p = self.precedence.infix[op];
+        let left = self.operand(a, indent, col, flat, self.left_parens(a, p))?;
+        let op = if op == "." { ".".to_string() } else { format!(" {} ", op) };
+        let right_col = end_col(col, &left) + op.len();
+        let right = self.operand(b, indent, right_col, flat, self.right_parens(b, p))?;
+        format!("{}{}{}", left, op, right)
+      }
+      (op, [a]) if op == "#" || op == "$" => {
+        format!("{}{}", op, self.operand(a, indent, col + 1, flat, !is_bracketed(a))?)
+      }
+      ("?", [a]) => {
+        let parens = self.left_parens(a, self.precedence.infix["?"]);
+        format!("{}?", self.operand(a, indent, col, flat, parens)?)
+      }
+      ("call", [f, args @ ..]) if is_method_call(f, args) => {
+        let receiver = &args[0];
+        let parens = self.left_parens(receiver, self.precedence.infix["."]);
+        let mut s = self.operand(receiver, indent, col, flat, parens)?;
+        s.push('.');
+        s.push_str(f.try_symbol().unwrap());
+        let list_col = end_col(col, &s);
+        s + &self.list("(", ")", &args[1..], indent, list_col, flat, true)?
+      }
+      ("call", [f, args @ ..]) | ("index", [f, args @ ..]) => {
+        let (open, close) = if name == "call" { ("(", ")") } else { ("[", "]") };
+        let parens = self.left_parens(f, self.precedence.infix[open]);
+        let s = self.operand(f, indent, col, flat, parens)?;
+        let list_col = end_col(col, &s);
+        s + &self.list(open, close, args, indent, list_col, flat, name == "call")?
+      }
+      ("array", es) => self.list("[", "]", es, indent, col, flat, false)?,
+      ("tuple", es) => self.list("(", ")", es, indent, col, flat, false)?,
+      ("block", es) => self.block(e, es, indent, col, flat)?,
+      ("if", [cond, then_e, rest @ ..]) => {
+        let mut s = self.keyword("if", &[cond, then_e], indent, col, flat)?;
+        if let [else_e] = rest {
+          // `else if` is parsed as an else block holding the inner `if`
+          let else_e = match else_e.try_construct() {
+            Some(("block", [inner])) if inner.loc.start == else_e.loc.start => inner,
+            _ => else_e,
+          };
+          if flat {
+            s.push(' ');
+          }
+          else {
+            s.push('\n');
+            s.push_str(&spaces(indent));
+          }
+          let else_col = end_col(col, &s);
+          s.push_str(&self.keyword("else", &[else_e], indent, else_col, flat)?);
+        }
+        s
+      }
+      ("while", [cond, body]) => self.keyword("while", &[cond, body], indent, col, flat)?,
+      ("for", [range, body]) => self.keyword("for", &[range, body], indent, col, flat)?,
+      ("struct", [n, fields]) | ("union", [n, fields]) => self.keyword(name, &[n, fields], indent, col, flat)?,
+      ("struct", [n, attributes, fields]) => {
+        let mut s = self.keyword("struct", &[n], indent, col, flat)?;
+        s.push_str(" with ");
+        for (i, a) in attributes.children().iter().enumerate() {
+          if i > 0 {
+            s.push_str(", ");
+          }
+          let attribute_col = end_col(col, &s);
+          s.push_str(&self.sub(a, indent, attribute_col, flat)?);
+        }
+        let fields_col = end_col(col, &s);
+        s.push_str(&self.keyword("", &[fields], indent, fields_col, flat)?);
+        s
+      }
+      ("test", [n, body]) => self.keyword("test", &[n, body], indent, col, flat)?,
+      ("match", [value, arms]) | ("switch", [value, arms]) => self.keyword(name, &[value, arms], indent, col, flat)?,
+      ("arm", [patterns @ .., body]) if !patterns.is_empty() => {
+        let mut s = String::new();
+        for (i, p) in patterns.iter().enumerate() {
+          if i > 0 {
+            s.push_str(", ");
+          }
+          let pattern_col = end_col(col, &s);
+          s.push_str(&self.sub(p, indent, pattern_col, flat)?);
+        }
+        s.push_str(" =>");
+        let body_col = end_col(col, &s);
+        s.push_str(&self.keyword("", &[body], indent, body_col, flat)?);
+        s
+      }
+      ("cbind", [typed, rest @ ..]) => {
+        let mut s = self.keyword("cbind", &[typed], indent, col, flat)?;
+        if let [convention] = rest {
+          let with_col = end_col(col, &s);
+          s.push_str(&self.keyword(" with", &[convention], indent, with_col, flat)?);
+        }
+        s
+      }
+      ("fun", _) | ("macro", _) => self.function(name, e, indent, col, flat)?,
+      ("return", []) | ("break", []) | ("continue", []) => name.to_string(),
+      ("break", args) | ("continue", args) => self.keyword(name, &args.iter().collect::<Vec<_>>(), indent, col, flat)?,
+      (keyword, [a]) if ["unsafe", "init", "pragma", "static", "lazy", "inline", "tagged", "type", "let", "var", "return", "loop"].contains(&keyword) =>
+        self.keyword(keyword, &[a], indent, col, flat)?,
+      ("let", [shadow, def]) | ("var", [shadow, def]) => self.keyword(name, &[shadow, def], indent, col, flat)?,
+      ("stage", [phase, def]) => {
+        if flat {
+          return Err(Failure::Multiline);
+        }
+        format!("#[stage({})]\n{}{}", leaf(phase), spaces(indent), self.expr(def, indent, indent)?)
+      }
+      _ => return Err(error_raw(e, format!("can't format a '{}' expression", name)).into()),
+    };
+    Ok(s)
+  }
+
+  /// A keyword followed by its parts, separated by spaces
+  fn keyword(&mut self, keyword : &str, parts : &[&Expr], indent : usize, col : usize, flat : bool) -> Result<String, Failure> {
+    let mut s = keyword.to_string();
+    for e in parts {
+      s.push(' ');
+      let part_col = end_col(col, &s);
+      s.push_str(&self.sub(e, indent, part_col, flat)?);
+    }
+    Ok(s)
+  }
+
+  /// Call arguments, array elements and so on. Named arguments are printed as `name: value`.
+  fn list(&mut self, open : &str, close : &str, es : &[Expr], indent : usize, col : usize, flat : bool, named : bool)
+    -> Result<String, Failure>
+  {
+    let mut s = open.to_string();
+    if flat || es.is_empty() {
+      for (i, e) in es.iter().enumerate() {
+        if i > 0 {
+          s.push_str(", ");
+        }
+        let item_col = end_col(col, &s);
+        s.push_str(&self.list_item(e, indent, item_col, flat, named)?);
+      }
+    }
+    else {
+      let inner = indent + self.options.indent;
+      for (i, e) in es.iter().enumerate() {
+        s.push('\n');
+        s.push_str(&spaces(inner));
+        s.push_str(&self.list_item(e, inner, inner, false, named)?);
+        if i + 1 < es.len() {
+          s.push(',');
+        }
+      }
+      s.push('\n');
+      s.push_str(&spaces(indent));
+    }
+    s.push_str(close);
+    Ok(s)
+  }
+
+  fn list_item(&mut self, e : &Expr, indent : usize, col : usize, flat : bool, named : bool) -> Result<String, Failure> {
+    match e.try_construct() {
+      Some((":", [n, v])) if named => {
+        let n = self.sub(n, indent, col, flat)?;
+        let value_col = end_col(col, &n) + 2;
+        Ok(format!("{}: {}", n, self.sub(v, indent, value_col, flat)?))
+      }
+      _ => self.sub(e, indent, col, flat),
+    }
+  }
+
+  fn block(&mut self, e : &Expr, es : &[Expr], indent : usize, col : usize, flat : bool) -> Result<String, Failure> {
+    if flat {
+      return match es {
+        [] => Ok("{}".to_string()),
+        [s] => Ok(format!("{{ {} }}", self.render(s, indent, col + 2, true)?)),
+        _ => Err(Failure::Multiline),
+      };
+    }
+    let inner = indent + self.options.indent;
+    let body = self.statements(es, inner, e.loc.end.line)?;
+    Ok(format!("{{\n{}{}}}", body, spaces(indent)))
+  }
+
+  /// `fun name(args) => return_type with type_vars { body }`, or a function type
+  /// without the name and body
+  fn function(&mut self, keyword : &str, e : &Expr, indent : usize, col : usize, flat : bool) -> Result<String, Failure> {
+    let mut s = keyword.to_string();
+    let mut es = e.children();
+    if let Some(name) = es.first() {
+      if name.try_construct().map(|(n, _)| n) != Some("args") {
+        s.push(' ');
+        let name_col = end_col(col, &s);
+        s.push_str(&self.sub(name, indent, name_col, flat)?);
+        es = &es[1..];
+      }
+    }
+    let args = match es.first() {
+      Some(args) => args,
+      None => return Err(error_raw(e, "expected function arguments").into()),
+    };
+    s.push('(');
+    for (i, a) in args.children().iter().enumerate() {
+      if i > 0 {
+        s.push_str(", ");
+      }
+      let arg_col = end_col(col, &s);
+      s.push_str(&self.sub(a, indent, arg_col, flat)?);
+    }
+    s.push(')');
+    for e in &es[1..] {
+      let part_col = end_col(col, &s);
+      match e.try_construct() {
+        Some(("polytypes", ts)) => {
+          s.push_str(" with ");
+          for (i, t) in ts.iter().enumerate() {
+            if i > 0 {
+              s.push_str(", ");
+            }
+            s.push_str(&self.sub(t, indent, part_col, flat)?);
+          }
+        }
+        Some(("block", _)) => s.push_str(&self.keyword("", &[e], indent, part_col, flat)?),
+        _ => s.push_str(&self.keyword(" =>", &[e], indent, part_col, flat)?),
+      }
+    }
+    Ok(s)
+  }
+}
@@ -0,0 +1,42 @@
+// Entry points for fuzzing the front end (e.g. with cargo-fuzz).
+//
+// Each one takes arbitrary bytes and runs one more stage of the pipeline than the
+// last. They are deterministic, because every call starts from a fresh string
+// cache (and a fresh compiler for inference). Bad input should come back as an
+// error, so anything that panics here is a bug.
+
+use crate::common::*;
+use crate::error::{Error, error_raw, TextLocation};
+use crate::compiler::Compiler;
+use crate::{lexer, parser, structure};
+
+use std::collections::HashMap;
+
+fn to_str(data : &[u8]) -> Result<&str, Error> {
+  std::str::from_utf8(data).map_err(|e| error_raw(TextLocation::zero(), format!("invalid utf-8: {}", e)))
+}
+
+/// Returns the number of tokens
+pub fn fuzz_lex(data : &[u8]) -> Result<usize, Vec<Error>> {
+  let code = to_str(data).map_err(|e| vec![e])?;
+  let cache = StringCache::new();
+  lexer::lex(no_source(), code, &cache).map(|tokens| tokens.len())
+}
+
+/// Lexes, parses and structures the input
+pub fn fuzz_parse(data : &[u8]) -> Result<(), Error> {
+  let code = to_str(data)?;
+  let cache = StringCache::new();
+  let tokens = lexer::lex(no_source(), code, &cache).map_err(|mut es| es.remove(0))?;
+  let expr = parser::parse(no_source(), tokens, &cache)?;
+  let mut gen = UIDGenerator::new();
+  structure::to_nodes(&mut gen, &cache, &expr, &HashMap::new(), &HashMap::new(), &[])?;
+  Ok(())
+}
+
+/// Typechecks the input on its own, without the core modules
+pub fn fuzz_infer(data : &[u8]) -> Result<(), Vec<Error>> {
+  let code = to_str(data).map_err(|e| vec![e])?;
+  let mut c = Compiler::new();
+  c.check_module(code, &[]).map(|_| ())
+}
@@ -0,0 +1,101 @@
+// Golden-output tests for whole programs.
+//
+// Each program is run in its own process (as `cauldron run <path>`), so that a
+// crash only fails that program. Its stdout, and its exit status if it failed,
+// are compared against the `.golden` file next to it. Running with `--bless`
+// writes the current output to the golden files instead.
+
+use std::fs;
+use std::default::Default;
+
+use subprocess::{Popen, PopenConfig, Redirection};
+
+/// The programs that are checked when none are given on the command line.
+/// One path per line, and lines starting with '#' are ignored.
+pub const DEFAULT_PROGRAM_LIST : &str = "code/golden_programs.txt";
+
+fn golden_path(path : &str) -> String {
+  format!("{}.golden", path)
+}
+
+fn run_program(path : &str) -> Result<String, String> {
+  let exe = std::env::current_exe().map_err(|e| e.to_string())?;
+  let exe = exe.to_str().unwrap();
+  let mut p = Popen::create(&[exe, "run", path], PopenConfig {
+    stdout: Redirection::Pipe, stderr: Redirection::Merge, ..Default::default()
+  }).map_err(|e| format!("failed to run '{}': {}", path, e))?;
+  let (out, _) = p.communicate(None).map_err(|e| format!("failed to read output of '{}': {}", path, e))?;
+  let mut out = out.unwrap_or_default().replace("\r\n", "\n");
+  let status = p.wait().map_err(|e| format!("failed to run '{}': {}", path, e))?;
+  if !status.success() {
+    out.push_str(&format!("exit status: {:?}\n", status));
+  }
+  Ok(out)
+}
+
+pub fn read_program_list(list_path : &str) -> Result<Vec<String>, String> {
+  let text = fs::read_to_string(list_path)
+    .map_err(|e| format!("failed to read program list '{}': {}", list_path, e))?;
+  Ok(text.lines()
+    .map(|l| l.trim())
+    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+    .map(|l| l.to_string())
+    .collect())
+}
+
+/// Returns true if every program matched its golden file (or if they were blessed)
+pub fn run_golden_tests(paths : &[String], bless : bool) -> bool {
+  let mut failed = 0;
+  for path in paths {
+    let out = match run_program(path) {
+      Ok(out) => out,
+      Err(e) => {
+        println!("golden {} ... FAILED\n  {}", path, e);
+        failed += 1;
+        continue;
+      }
+    };
+    let golden = golden_path(path);
+    if bless {
+      match fs::write(&golden, &out) {
+        Ok(()) => println!("golden {} ... blessed", path),
+        Err(e) => {
+          println!("golden {} ... FAILED\n  failed to write '{}': {}", path, golden, e);
+          failed += 1;
+        }
+      }
+      continue;
+    }
+    let expected = match fs::read_to_string(&golden) {
+      Ok(s) => s.replace("\r\n", "\n"),
+      Err(_) => {
+        println!("golden {} ... FAILED\n  there is no '{}' (use --bless to create it)", path, golden);
+        failed += 1;
+        continue;
+      }
+    };
+    if out == expected {
+      println!("golden {} ... ok", path);
+    }
+    else {
+      failed += 1;
+      println!("golden {} ... FAILED", path);
+      // only the first difference is shown, since later lines usually differ as a result
+      let (mut expected_lines, mut actual_lines) = (expected.lines(), out.lines());
+      for line in 1.. {
+        match (expected_lines.next(), actual_lines.next()) {
+          (None, None) => break,
+          (e, a) if e == a => continue,
+          (e, a) => {
+            println!("  line {} differs", line);
+            println!("  expected: {}", e.unwrap_or("<end of output>"));
+            println!("  actual:   {}", a.unwrap_or("<end of output>"));
+            break;
+          }
+        }
+      }
+    }
+  }
+  println!("{} programs, {} failed", paths.len(), failed);
+  failed == 0
+}
@@ -0,0 +1,115 @@
+// Generational handle tables for runtime resources that the language holds on to,
+// like timers, file watchers and random number generators.
+//
+// A handle is a slot index in the low 32 bits and the slot's generation in the high
+// 32 bits. Removing a value bumps its slot's generation, so a handle that outlives
+// its value no longer matches, and using it is an error rather than a use-after-free.
+// Generations start at 1, so zero is never a valid handle.
+
+use std::cell::RefCell;
+use std::time::Instant;
+use rand::rngs::SmallRng;
+
+use crate::c_interface::FileWatcher;
+
+struct Slot<T> {
+  generation : u32,
+  value : Option<T>,
+}
+
+pub struct HandleTable<T> {
+  /// What the values are, for error messages
+  kind : &'static str,
+  slots : Vec<Slot<T>>,
+  free : Vec<u32>,
+}
+
+impl <T> HandleTable<T> {
+  pub fn new(kind : &'static str) -> Self {
+    HandleTable { kind, slots: vec![], free: vec![] }
+  }
+
+  fn handle(index : u32, generation : u32) -> u64 {
+    ((generation as u64) << 32) | index as u64
+  }
+
+  pub fn insert(&mut self, value : T) -> u64 {
+    if let Some(index) = self.free.pop() {
+      let slot = &mut self.slots[index as usize];
+      slot.value = Some(value);
+      Self::handle(index, slot.generation)
+    }
+    else {
+      let index = self.slots.len() as u32;
+      self.slots.push(Slot { generation: 1, value: Some(value) });
+      Self::handle(index, 1)
+    }
+  }
+
+  fn slot_index(&self, handle : u64) -> Result<usize, String> {
+    let index = (handle & 0xffff_ffff) as usize;
+    let generation = (handle >> 32) as u32;
+    match self.slots.get(index) {
+      Some(slot) if slot.generation == generation && slot.value.is_some() => Ok(index),
+      _ => Err(format!("invalid or stale {} handle ({:#x})", self.kind, handle)),
+    }
+  }
+
+  pub fn get(&self, handle : u64) -> Result<&T, String> {
+    let i = self.slot_index(handle)?;
+    Ok(self.slots[i].value.as_ref().unwrap())
+  }
+
+  pub fn get_mut(&mut self, handle : u64) -> Result<&mut T, String> {
+    let i = self.slot_index(handle)?;
+    Ok(self.slots[i].value.as_mut().unwrap())
+  }
+
+  pub fn remove(&mut self, handle : u64) -> Result<T, String> {
+    let i = self.slot_index(handle)?;
+    let slot = &mut self.slots[i];
+    slot.generation = slot.generation.wrapping_add(1).max(1);
+    self.free.push(i as u32);
+    Ok(slot.value.take().unwrap())
+  }
+
+  /// The handles that are still live
+  pub fn live(&self) -> impl Iterator<Item=u64> + '_ {
+    self.slots.iter().enumerate()
+      .filter(|(_, s)| s.value.is_some())
+      .map(|(i, s)| Self::handle(i as u32, s.generation))
+  }
+}
+
+pub struct RuntimeHandles {
+  pub timers : HandleTable<Instant>,
+  pub watchers : HandleTable<FileWatcher>,
+  pub rngs : HandleTable<SmallRng>,
+}
+
+impl RuntimeHandles {
+  fn new() -> Self {
+    RuntimeHandles {
+      timers: HandleTable::new("timer"),
+      watchers: HandleTable::new("watcher"),
+      rngs: HandleTable::new("rng"),
+    }
+  }
+
+  /// One line per live handle, for tracking down leaks
+  pub fn describe_live(&self) -> Vec<String> {
+    let mut lines = vec![];
+    lines.extend(self.timers.live().map(|h| format!("timer {:#x}", h)));
+    lines.extend(self.watchers.live().map(|h| format!("watcher {:#x}", h)));
+    lines.extend(self.rngs.live().map(|h| format!("rng {:#x}", h)));
+    lines
+  }
+}
+
+thread_local! {
+  static HANDLES : RefCell<RuntimeHandles> = RefCell::new(RuntimeHandles::new());
+}
+
+pub fn with_handles<R>(f : impl FnOnce(&mut RuntimeHandles) -> R) -> R {
+  HANDLES.with(|h| f(&mut h.borrow_mut()))
+}
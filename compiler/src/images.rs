@@ -0,0 +1,149 @@
+// Image loading for the language, with hot reloading.
+//
+// Images are owned by a cache and handed to the language as pointers, so their
+// addresses stay the same when an image is reloaded. When an image file changes,
+// it's decoded again in place and its version goes up, so that code holding on to
+// the image can tell that it should upload the pixels again.
+//
+// Programs run by the watcher have their images reloaded automatically, when the
+// watcher reports the change with `:asset-changed`. Other programs can call
+// `reload_changed_images` themselves. Either way, the old pixels are kept alive
+// until the next reload, so a pointer to them that's still on the stack doesn't
+// dangle. Code that keeps the pixel pointer for longer should check the version.
+
+use crate::c_interface::SArray;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+
+use notify::{Watcher, RecursiveMode, watcher, DebouncedEvent, RecommendedWatcher};
+
+/// Matches `struct image` in core/prelude.code. The pixels are RGBA.
+#[repr(C)]
+pub struct SImage {
+  pub width : u64,
+  pub height : u64,
+  pub pixels : SArray<u8>,
+  pub version : u64,
+}
+
+fn decode(path : &Path) -> Result<(u64, u64, Vec<u8>), String> {
+  let img = image::open(path)
+    .map_err(|e| format!("failed to load image '{}': {}", path.display(), e))?
+    .to_rgba();
+  Ok((img.width() as u64, img.height() as u64, img.into_raw()))
+}
+
+pub struct ImageCache {
+  watcher : RecommendedWatcher,
+  rx : Receiver<DebouncedEvent>,
+  images : HashMap<PathBuf, Box<SImage>>,
+  /// The pixels that the last reload replaced, which code might still be using
+  retired : Vec<SArray<u8>>,
+}
+
+impl ImageCache {
+  fn new() -> Result<ImageCache, String> {
+    let (tx, rx) = channel();
+    let watcher = watcher(tx, Duration::from_millis(200))
+      .map_err(|e| format!("failed to create image watcher: {}", e))?;
+    Ok(ImageCache { watcher, rx, images: HashMap::new(), retired: vec![] })
+  }
+
+  /// Loading the same file twice returns the same image
+  pub fn load(&mut self, path : &str) -> Result<*mut SImage, String> {
+    let full_path = Path::new(path).canonicalize()
+      .map_err(|e| format!("failed to load image '{}': {}", path, e))?;
+    if let Some(img) = self.images.get_mut(&full_path) {
+      return Ok(&mut **img);
+    }
+    let (width, height, pixels) = decode(&full_path)?;
+    if let Err(e) = self.watcher.watch(&full_path, RecursiveMode::NonRecursive) {
+      println!("image '{}' won't be reloaded when it changes: {}", path, e);
+    }
+    let img = Box::new(SImage { width, height, pixels: SArray::new(pixels), version: 0 });
+    let img = self.images.entry(full_path).or_insert(img);
+    Ok(&mut **img)
+  }
+
+  /// Reloads any images that have changed since the last call, and returns how
+  /// many were reloaded. If a changed file can't be decoded (e.g. because it's
+  /// still being written), the old pixels are kept.
+  pub fn reload_changed(&mut self) -> u64 {
+    self.retired.clear();
+    self.reload_pending().len() as u64
+  }
+
+  /// Returns the paths of the images that were reloaded
+  fn reload_pending(&mut self) -> HashSet<PathBuf> {
+    let mut reloaded = HashSet::new();
+    while let Ok(event) = self.rx.try_recv() {
+      let path = match event {
+        DebouncedEvent::Write(p) | DebouncedEvent::Create(p) => p,
+        _ => continue,
+      };
+      let path = path.canonicalize().unwrap_or(path);
+      if self.reload(&path) {
+        reloaded.insert(path);
+      }
+    }
+    reloaded
+  }
+
+  /// Reloads an image, if it's loaded. Returns true if it was reloaded.
+  fn reload(&mut self, path : &Path) -> bool {
+    let img = match self.images.get_mut(path) {
+      Some(img) => img,
+      None => return false,
+    };
+    match decode(path) {
+      Ok((width, height, pixels)) => {
+        img.width = width;
+        img.height = height;
+        let old = std::mem::replace(&mut img.pixels, SArray::new(pixels));
+        self.retired.push(old);
+        img.version += 1;
+        true
+      }
+      Err(e) => {
+        println!("{}", e);
+        false
+      }
+    }
+  }
+}
+
+thread_local! {
+  static IMAGES : RefCell<Option<ImageCache>> = RefCell::new(None);
+}
+
+/// Called when the watcher reports that an asset changed, which it does while no
+/// code is running. Reloads the image at `path` if it's loaded, along with any
+/// others that changed.
+pub fn asset_changed(path : &str) {
+  IMAGES.with(|c| {
+    if let Some(c) = c.borrow_mut().as_mut() {
+      c.retired.clear();
+      let reloaded = c.reload_pending();
+      let path = Path::new(path);
+      let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+      if !reloaded.contains(&path) {
+        c.reload(&path);
+      }
+    }
+  })
+}
+
+/// Runs `f` with the image cache, creating it if it doesn't exist yet
+pub fn with_images<R>(f : impl FnOnce(&mut ImageCache) -> R) -> Result<R, String> {
+  IMAGES.with(|c| {
+    let mut c = c.borrow_mut();
+    if c.is_none() {
+      *c = Some(ImageCache::new()?);
+    }
+    Ok(f(c.as_mut().unwrap()))
+  })
+}
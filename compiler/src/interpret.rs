@@ -1,10 +1,16 @@
 
 use crate::common::*;
-use crate::error::Error;
-use crate::compiler::{Val, Compiler};
+use crate::error::{Error, error_raw, TextLocation};
+use crate::compiler::{Val, Compiler, TypeReport, CompileOptions, Backend, OnAbiChange, Reload};
+use crate::features::FeatureReport;
+use crate::docs::ModuleDocs;
+use crate::probes::{self, Probe};
+use crate::structure::TypeKind;
 
-use std::fs::File;
-use std::io::Read;
+use crate::c_interface::allocated_bytes;
+
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
 
 // TODO: fix this gross hack
 #[cfg(not(test))]
@@ -12,20 +18,162 @@ const CODE_PATH : &'static str = "code/";
 #[cfg(test)]
 const CODE_PATH : &'static str = "../code/";
 
+static CORE_MODULES : &[&str] = &["prelude", "list", "map", "math", "check", "compiler"];
+
+/// A project can choose its own prelude with a file of this name, next to its
+/// main program (see `Prelude::from_file`)
+pub static PRELUDE_FILE : &str = "prelude.list";
+
+/// The modules that an interpreter loads before anything else, in order. Each one
+/// is imported by the ones after it, so a project can layer its own prelude
+/// modules on top of the core modules, or replace them entirely.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Prelude {
+  /// The paths of the modules
+  pub modules : Vec<String>,
+}
+
+impl Prelude {
+  /// The core modules, which are in `{code_path}core/`
+  pub fn core(code_path : &str) -> Self {
+    let modules =
+      CORE_MODULES.iter().map(|m| format!("{}core/{}.code", code_path, m)).collect();
+    Prelude { modules }
+  }
+
+  /// The core modules, from the default code directory
+  pub fn default_core() -> Self {
+    Prelude::core(CODE_PATH)
+  }
+
+  /// No prelude at all. Mostly useful for tests.
+  pub fn empty() -> Self {
+    Prelude { modules: vec![] }
+  }
+
+  /// Adds a layer on top of the modules that are already in the prelude
+  pub fn with_module(mut self, path : &str) -> Self {
+    self.modules.push(path.into());
+    self
+  }
+
+  /// Reads a prelude file. It has one module path per line, relative to the file's
+  /// directory, and `core` stands for all of the core modules. Blank lines and
+  /// lines starting with `//` are skipped. An empty file means no prelude.
+  pub fn from_file(path : &str) -> Result<Self, String> {
+    let text = std::fs::read_to_string(path)
+      .map_err(|e| format!("failed to read prelude file '{}': {}", path, e))?;
+    let dir = std::path::Path::new(path).parent().unwrap_or(std::path::Path::new(""));
+    let mut prelude = Prelude::empty();
+    for line in text.lines().map(|l| l.trim()) {
+      if line.is_empty() || line.starts_with("//") {
+        continue;
+      }
+      if line == "core" {
+        prelude.modules.extend(Prelude::default_core().modules);
+      }
+      else {
+        prelude.modules.push(dir.join(line).to_string_lossy().replace("\\", "/"));
+      }
+    }
+    Ok(prelude)
+  }
+
+  /// The prelude for the program at `path`. This is the project's prelude file if
+  /// it has one, and the core modules otherwise.
+  pub fn for_program(path : &str) -> Result<Self, String> {
+    let dir = std::path::Path::new(path).parent().unwrap_or(std::path::Path::new(""));
+    let file = dir.join(PRELUDE_FILE);
+    if file.exists() {
+      Prelude::from_file(&file.to_string_lossy())
+    }
+    else {
+      Ok(Prelude::default_core())
+    }
+  }
+}
+
+/// A tiny core module that is loaded if the real core modules fail to load,
+/// so that the REPL is still usable while the problem is being fixed.
+static MINIMAL_CORE : &'static str = r#"
+cbind malloc64 : fun(size: u64) => ptr(u8)
+cbind free : fun(ptr: ptr(u8))
+cbind memcpy : fun(dest : ptr(u8), src : ptr(u8), length : u64) => ptr(u8)
+cbind panic : fun(s : ptr(string))
+cbind print_string : fun(s : ptr(string))
+cbind print_i64 : fun(v : i64)
+cbind print_u64 : fun(v : u64)
+cbind print_f64 : fun(v : f64)
+cbind print_bool : fun(v : bool)
+
+fun malloc(size) { malloc64(size) }
+fun panic(s : string) { panic(&s) }
+fun print(s : string) { print_string(&s) }
+fun print(v : i64) { print_i64(v) }
+fun print(v : u64) { print_u64(v) }
+fun print(v : f64) { print_f64(v) }
+fun print(v : bool) { print_bool(v) }
+fun println() { print("\n") }
+fun println(t : T) with T { print(t); println() }
+"#;
+
+static BENCH_FUNCTION_NAME : &str = "__bench";
+
+pub struct BenchReport {
+  pub runs : usize,
+  pub min : Duration,
+  pub median : Duration,
+  pub bytes_per_run : u64,
+}
+
+/// How a type is laid out in memory, as the compiled code sees it
+pub struct LayoutReport {
+  pub size : u64,
+  pub alignment : u64,
+  /// The byte offset of each field of a struct, except for bitfields
+  pub fields : Vec<(RefStr, u64)>,
+  /// The bit offset and width of each bitfield of a struct
+  pub bitfields : Vec<(RefStr, u64, u32)>,
+}
+
 pub struct Interpreter {
   pub c : Box<Compiler>,
   imports : Vec<UnitId>,
+  pub prelude : Prelude,
+  core_modules : Vec<UnitId>,
+  /// Set if the core modules failed to load, in which case the minimal core is used
+  pub core_error : Option<Error>,
+  /// The top-level forms that the REPL evaluated successfully, in order (see `:save`)
+  pub session : Vec<String>,
 }
 
 pub fn interpreter() -> Interpreter {
-  let c = Compiler::new();
-  let mut i = Interpreter { c, imports: vec![] };
-  
-  // loading core modules
-  if let Err(e) = i.load_core_modules() {
-    println!("Failed to load core modules.");
+  interpreter_with_prelude(Prelude::default_core())
+}
+
+pub fn interpreter_with_core_path(core_path : &str) -> Interpreter {
+  interpreter_with_prelude(Prelude::core(core_path))
+}
+
+/// An interpreter for the program at `path`, with the project's prelude. If the
+/// prelude file can't be read, the core modules are used instead.
+pub fn program_interpreter(path : &str) -> Interpreter {
+  match Prelude::for_program(path) {
+    Ok(prelude) => interpreter_with_prelude(prelude),
+    Err(e) => {
+      println!("{}", e);
+      interpreter()
+    }
   }
-  
+}
+
+pub fn interpreter_with_prelude(prelude : Prelude) -> Interpreter {
+  let c = Compiler::new();
+  let mut i = Interpreter {
+    c, imports: vec![], prelude,
+    core_modules: vec![], core_error: None, session: vec![],
+  };
+  i.load_core_modules();
   return i;
 }
 
@@ -39,23 +187,205 @@ impl Interpreter {
     Ok(self.load_module(code, Some(name))?.1)
   }
 
+  /// Runs code with the vm backend. It can't use the prelude, or anything else
+  /// that has been loaded.
+  pub fn eval_in_vm(&mut self, code : &str) -> Result<Val, Error> {
+    Ok(self.load_module_with_options(code, None, CompileOptions::vm())?.1)
+  }
+
+  /// Reports the language features used by some code, without running it
+  pub fn feature_report(&mut self, code : &str, name : &str) -> Result<FeatureReport, Error> {
+    self.c.feature_report(code, name, &self.imports)
+  }
+
+  /// See `Compiler::check_module`
+  pub fn check_module(&mut self, code : &str) -> Result<TypeReport, Vec<Error>> {
+    self.c.check_module(code, &self.imports)
+  }
+
+  /// See `Compiler::document_module`
+  pub fn document_module(&mut self, code : &str, title : &str) -> Result<ModuleDocs, Vec<Error>> {
+    self.c.document_module(code, title, &self.imports)
+  }
+
+  /// See `Compiler::constraint_graph`
+  pub fn constraint_graph(&mut self, code : &str) -> Result<String, Error> {
+    self.c.constraint_graph(code, &self.imports)
+  }
+
+  /// Unloads a named module, so that it can be loaded again with new code
+  pub fn unload_module(&mut self, name : &str) {
+    if let Some(unit_id) = self.c.code_store.named_unit(name) {
+      self.imports.retain(|&i| i != unit_id);
+      self.c.unload_module(unit_id);
+    }
+  }
+
+  /// Replaces a named module with new code. See `Compiler::reload_module`.
+  pub fn reload_module(&mut self, name : &str, code : &str, on_abi_change : OnAbiChange)
+    -> Result<Reload, Error>
+  {
+    let old = self.c.code_store.named_unit(name);
+    let reload = self.c.reload_module(name, code, on_abi_change)?;
+    // none of the interpreter's code can be running, so the old version can go now
+    self.c.release_retired_units();
+    let remaining = &self.c.code_store.names;
+    self.imports.retain(|u| Some(*u) != old && remaining.contains_key(u));
+    self.imports.push(reload.unit_id);
+    self.c.implicit_imports.insert(reload.unit_id);
+    Ok(reload)
+  }
+
+  /// The latest value of every probe that has run, with the name of its unit
+  pub fn probes(&self) -> Vec<(RefStr, Probe)> {
+    probes::latest().into_iter().map(|p| {
+      let name = self.c.code_store.names.iter()
+        .find(|(u, _)| u.inner().inner() == p.unit)
+        .map(|(_, n)| n.clone())
+        .unwrap_or_else(|| "<unknown>".into());
+      (name, p)
+    }).collect()
+  }
+
+  /// Compiles an expression into a function, and times `runs` calls to it
+  pub fn bench(&mut self, expr : &str, runs : usize) -> Result<BenchReport, Error> {
+    let runs = runs.max(1);
+    // the result is discarded, so that the function signature is always the same
+    let code = format!("fun {}() {{\n{}\n()\n}}", BENCH_FUNCTION_NAME, expr);
+    let (unit_id, _) = self.c.load_module(&code, None, &self.imports)?;
+    let address = self.c.function_address(unit_id, BENCH_FUNCTION_NAME);
+    let report = address.map(|address| {
+      let f : extern "C" fn() = unsafe { std::mem::transmute(address) };
+      let bytes_before = allocated_bytes();
+      let mut times : Vec<Duration> = (0..runs).map(|_| {
+        let t = Instant::now();
+        f();
+        t.elapsed()
+      }).collect();
+      let bytes = allocated_bytes() - bytes_before;
+      times.sort();
+      BenchReport { runs, min: times[0], median: times[runs / 2], bytes_per_run: bytes / runs as u64 }
+    });
+    self.c.unload_module(unit_id);
+    report.ok_or_else(|| error_raw(TextLocation::zero(), "failed to find the compiled benchmark function"))
+  }
+
+  /// The size, alignment and field offsets of a type, like `vertex` or `array(i64)`,
+  /// found by compiling `sizeof`, `alignof` and `offsetof` queries
+  pub fn layout(&mut self, type_expr : &str) -> Result<LayoutReport, Error> {
+    let name = type_expr.split('(').next().unwrap().trim();
+    let (fields, bitfields) : (Vec<RefStr>, Vec<(RefStr, u32)>) = self.c.find_type_def(name, &self.imports)
+      .filter(|def| def.kind == TypeKind::Struct)
+      // bitfields have no byte offset, so they're reported in bits
+      .map(|def| {
+        let fields = def.fields.iter()
+          .filter(|(f, _)| def.bitfield_width(&f.name).is_none())
+          .map(|(f, _)| f.name.clone()).collect();
+        let bitfields = def.fields.iter()
+          .filter_map(|(f, _)| def.bitfield_width(&f.name).map(|w| (f.name.clone(), w)))
+          .collect();
+        (fields, bitfields)
+      })
+      .unwrap_or_default();
+    let mut query = |code : String| -> Result<u64, Error> {
+      let (unit_id, val) = self.c.load_module(&code, None, &self.imports)?;
+      self.c.unload_module(unit_id);
+      match val {
+        Val::U64(v) => Ok(v),
+        v => Err(error_raw(TextLocation::zero(), format!("expected a u64 from '{}', found {:?}", code, v))),
+      }
+    };
+    let size = query(format!("sizeof({})", type_expr))?;
+    let alignment = query(format!("alignof({})", type_expr))?;
+    let mut offsets = vec![];
+    for f in fields {
+      let offset = query(format!("offsetof({}, {})", type_expr, f))?;
+      offsets.push((f, offset));
+    }
+    let mut bit_offsets = vec![];
+    for (f, width) in bitfields {
+      let offset = query(format!("bitoffsetof({}, {})", type_expr, f))?;
+      bit_offsets.push((f, offset, width));
+    }
+    Ok(LayoutReport { size, alignment, fields: offsets, bitfields: bit_offsets })
+  }
+
+  /// See `Compiler::export_function`
+  pub fn export_function(&mut self, slot_name : &str, module_name : &str, function_name : &str)
+    -> *const usize
+  {
+    self.c.export_function(slot_name, module_name, function_name)
+  }
+
   fn load_module(&mut self, code : &str, name : Option<&str>) -> Result<(UnitId, Val), Error> {
-    let (unit_id, val) = self.c.load_module(code, name, &self.imports)?;
-    self.imports.push(unit_id);
+    let options = self.c.default_options;
+    self.load_module_with_options(code, name, options)
+  }
+
+  fn load_module_with_options(&mut self, code : &str, name : Option<&str>, options : CompileOptions)
+    -> Result<(UnitId, Val), Error>
+  {
+    let imports : &[UnitId] = if options.backend == Backend::Vm { &[] } else { &self.imports };
+    let (unit_id, val) = self.c.load_module_with_options(code, name, imports, options)?;
+    // everything the interpreter loads is imported by later code, whether it's used or not,
+    // except for units run by the vm, which compiled code can't link against
+    if options.backend == Backend::Llvm {
+      self.imports.push(unit_id);
+      self.c.implicit_imports.insert(unit_id);
+    }
     Ok((unit_id, val))
   }
 
-  fn load_core_modules(&mut self) -> Result<(), Error> {
-    for module_name in &["prelude", "list", "compiler"] {
-      let path = format!("{}core/{}.code", CODE_PATH, module_name);
-      let mut f = File::open(&path).expect("failed to load prelude");
-      let mut code = String::new();
-      f.read_to_string(&mut code).unwrap();
-      self.load_module(&code, Some(&path))?;
+  /// Loads the prelude modules as a single transaction. If any of them fail, the ones
+  /// that did load are unloaded again, and the minimal core is loaded instead.
+  fn load_core_modules(&mut self) {
+    match self.try_load_core_modules() {
+      Ok(()) => self.core_error = None,
+      Err(e) => {
+        println!("Failed to load core modules. Falling back to a minimal core.");
+        println!("{}", e.display());
+        self.unload_core_modules();
+        match self.load_module(MINIMAL_CORE, Some("minimal_core")) {
+          Ok((unit_id, _)) => self.core_modules.push(unit_id),
+          Err(e) => println!("Failed to load the minimal core: {}", e.display()),
+        }
+        self.core_error = Some(e);
+      }
+    }
+  }
+
+  fn try_load_core_modules(&mut self) -> Result<(), Error> {
+    for path in self.prelude.modules.clone() {
+      let code = std::fs::read_to_string(&path).map_err(|e|
+        error_raw(TextLocation::zero(), format!("failed to read prelude module '{}': {}", path, e)))?;
+      // prelude modules don't change much, so they're worth optimising
+      let (unit_id, _) = self.load_module_with_options(&code, Some(&path), CompileOptions::release())?;
+      self.core_modules.push(unit_id);
     }
     Ok(())
   }
 
+  /// Unloads the core modules, and everything that depends on them
+  fn unload_core_modules(&mut self) {
+    let mut to_unload = HashSet::new();
+    for &unit_id in self.core_modules.iter() {
+      to_unload.extend(self.c.find_all_dependents(unit_id));
+    }
+    for unit_id in to_unload {
+      self.imports.retain(|&i| i != unit_id);
+      self.c.unload_module(unit_id);
+    }
+    self.core_modules.clear();
+  }
+
+  /// Reloads the core modules from disk. Everything that was loaded after
+  /// them is unloaded, because it was compiled against the old versions.
+  /// If loading fails, `core_error` is set and the minimal core is used.
+  pub fn reload_core_modules(&mut self) {
+    self.unload_core_modules();
+    self.load_core_modules();
+  }
+
   /// Calls a function that accepts an OUT pointer as an argument, in C style.
   #[allow(dead_code)]
   pub fn run_with_pointer_return<A>(
@@ -6,11 +6,35 @@ use crate::types::{
   SignatureBuilder, SymbolDefinition,
   SymbolInit, TypeDefinition,
 };
-use crate::structure::{TypeKind, Reference};
+use crate::structure::{TypeKind, Reference, StructLayout};
 use PType::*;
 use TypeContent::Polytype;
 
 pub static UNSAFE_ZERO_INIT : &'static str = "UnsafeZeroInit";
+pub static RETURN_ADDRESS : &'static str = "return_address";
+pub static SLICE_LENGTH : &'static str = "slice_length";
+
+/// The most values that a tuple like `(a, b)` can hold, which is the longest of
+/// the prelude's tuple structs
+pub const MAX_TUPLE_LENGTH : usize = 6;
+
+/// The name of the prelude's struct that holds tuples of a length, which `(A, B)`
+/// is short for (see core/prelude.code)
+pub fn tuple_type_name(length : usize) -> String {
+  format!("tup{}", length)
+}
+
+pub fn is_tuple_type(name : &str) -> bool {
+  name.strip_prefix("tup")
+    .and_then(|n| n.parse::<usize>().ok())
+    .map(|n| (2..=MAX_TUPLE_LENGTH).contains(&n))
+    .unwrap_or(false)
+}
+
+/// The name of a tuple's field, by its position
+pub fn tuple_field_name(i : usize) -> String {
+  format!("v{}", i)
+}
 
 pub fn get_intrinsics(intrinsics_id : UnitId, gen : &mut UIDGenerator, cache : &StringCache) -> TypeInfo {
   let unit_id = intrinsics_id;
@@ -40,9 +64,6 @@ pub fn get_intrinsics(intrinsics_id : UnitId, gen : &mut UIDGenerator, cache : &
       add_intrinsic(cache, gen, unit_id, &mut types, n, &[t, t], boolean);
     }
   }
-  for &n in &["&&", "||"] {
-    add_intrinsic(cache, gen, unit_id, &mut types, n, &[boolean, boolean], boolean);
-  }
   add_intrinsic(cache, gen, unit_id, &mut types, "!", &[boolean], boolean);
   
   for t in &[F64.into(), F32.into()] {
@@ -54,6 +75,32 @@ pub fn get_intrinsics(intrinsics_id : UnitId, gen : &mut UIDGenerator, cache : &
     add_intrinsic(cache, gen, unit_id, &mut types, "log", &[t], t);
   }
 
+  // Add SIMD vector operations
+  let vec4f : &Type = &Vec4F.into();
+  let f32_type : &Type = &F32.into();
+  let i64_type : &Type = &I64.into();
+  add_intrinsic(cache, gen, unit_id, &mut types, "-", &[vec4f], vec4f);
+  for &n in &["+", "-", "*", "/"] {
+    add_intrinsic(cache, gen, unit_id, &mut types, n, &[vec4f, vec4f], vec4f);
+  }
+  add_intrinsic(cache, gen, unit_id, &mut types, "vec4f", &[f32_type, f32_type, f32_type, f32_type], vec4f);
+  add_intrinsic(cache, gen, unit_id, &mut types, "splat", &[f32_type], vec4f);
+  add_intrinsic(cache, gen, unit_id, &mut types, "extract", &[vec4f, i64_type], f32_type);
+  add_intrinsic(cache, gen, unit_id, &mut types, "dot", &[vec4f, vec4f], f32_type);
+  // The indices must be literals, because they become the shuffle mask
+  add_intrinsic(cache, gen, unit_id, &mut types, "shuffle",
+    &[vec4f, i64_type, i64_type, i64_type, i64_type], vec4f);
+
+  // The address that the calling function will return to
+  add_intrinsic(cache, gen, unit_id, &mut types, RETURN_ADDRESS, &[], &Type::ptr_to(U8.into()));
+
+  // The length of a slice from `start` to `end` of something `length` long. With
+  // bounds checks on, this panics unless `start <= end <= length`.
+  let u64_type : &Type = &U64.into();
+  for index_type in &[I64.into(), I32.into(), U64.into(), U32.into()] {
+    add_intrinsic(cache, gen, unit_id, &mut types, SLICE_LENGTH, &[index_type, index_type, u64_type], u64_type);
+  }
+
   // Add polymorphic instrinsic operations
   let tvar = cache.get("A");
   let tv : Type = Polytype(tvar.clone()).into();
@@ -104,6 +151,10 @@ fn add_type_def(cache : &StringCache, gen : &mut UIDGenerator, unit_id : UnitId,
       (reference, t)
     }).collect(),
     type_vars,
+    layout: StructLayout::default(),
+    bitfields: vec![],
+    loc: TextLocation::zero(),
+    doc: None,
   };
   t.type_defs.insert(type_def.name.clone(), type_def);
 }
@@ -124,6 +175,8 @@ fn create_symbol_def(
     type_tag: sig.into(),
     initialiser: SymbolInit::Intrinsic,
     type_vars,
+    loc: TextLocation::zero(),
+    doc: None,
   }
 }
 
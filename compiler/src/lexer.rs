@@ -5,7 +5,7 @@ use std::fmt;
 
 const SYNTAX : &'static [&'static str] =
   &["==", "!=", "<=", ">=", "=>", "+=", "-=", "*=", "/=", "||",
-    "&&", "{", "}", "(", ")", "[", "]", "<", ">", ";", ":", ",",
+    "&&", "..", "{", "}", "(", ")", "[", "]", "<", ">", ";", ":", ",",
     ".", "=", "+", "-", "*", "/", "%", "?", "|", "&", "^", "!",
     "$", "'", "#"];
 
@@ -47,11 +47,31 @@ impl Token {
   }
 }
 
+/// One line of a `## ...` comment, which documents the definition below it
+#[derive(Clone, Debug)]
+pub struct DocComment {
+  pub source : SourceId,
+  pub line : usize,
+  pub text : String,
+}
+
+/// A comment of any kind. The parser ignores these, but the formatter keeps them.
+#[derive(Clone, Debug)]
+pub struct Comment {
+  pub line : usize,
+  /// The whole comment, including the `//`, `##` or `/* */`
+  pub text : String,
+  /// Whether there's code before the comment on the same line
+  pub trailing : bool,
+}
+
 struct CStream<'l> {
   source : SourceId,
   chars : Vec<char>,
   loc : StreamLocation,
   tokens : Vec<Token>,
+  docs : Vec<DocComment>,
+  comments : Vec<Comment>,
   errors : Vec<Error>,
   symbols : &'l StringCache,
   current_token : String,
@@ -78,6 +98,8 @@ impl <'l> CStream<'l> {
       chars,
       loc : StreamLocation { pos: 0, line: 1, line_start: 0 },
       tokens: vec!(),
+      docs: vec!(),
+      comments: vec!(),
       errors: vec!(),
       symbols,
       current_token: String::new(),
@@ -181,7 +203,8 @@ impl <'l> CStream<'l> {
       let start_loc = self.loc;
       self.append_char_while(&CStream::is_number);
       let literal_type =
-        if self.has_chars() && self.peek() == '.' {
+        // the `..` in `1..n` is a range, not a decimal point
+        if self.has_chars() && self.peek() == '.' && self.chars.get(self.loc.pos + 1) != Some(&'.') {
           self.append_char();
           self.append_char_while(&CStream::is_number);
           TokenType::FloatLiteral
@@ -276,24 +299,40 @@ impl <'l> CStream<'l> {
     self.raise_error(start_loc, "Unknown token".to_string())
   }
 
+  /// Whether there's code before the stream's position on the same line
+  fn after_code(&self) -> bool {
+    self.tokens.last().map(|t| t.loc.end.line == self.loc.line).unwrap_or(false)
+  }
+
   fn lex_comment(&mut self) -> bool {
-    // TODO: this doesn't handle newlines properly, so the error locations will be wrong
-    if self.peek_string("/*") {
-      self.skip_char();
-      self.skip_char();
-      self.skip_char_while(&|cs : &CStream| { !cs.peek_string("*/") });
-      self.skip_char();
-      self.skip_char();
-      return true;
+    let start = self.loc;
+    if self.skip_string("/*") {
+      while self.has_chars() && !self.peek_string("*/") {
+        if !self.handle_newline() {
+          self.skip_char();
+        }
+      }
+      // an unterminated comment runs to the end of the file
+      self.skip_string("*/");
     }
-    else if self.peek_string("//") {
-      self.skip_char_while(&|cs : &CStream| {
-        let c = cs.peek();
-        c != '\n'
-      });
-      return true;
+    // `##` is only a doc comment at the start of a line, because elsewhere it's a
+    // quote of a quote
+    else if (self.peek_string("##") && !self.after_code()) || self.peek_string("//") {
+      self.skip_char_while(&|cs : &CStream| cs.peek() != '\n');
     }
-    return false;
+    else {
+      return false;
+    }
+    let text : String = self.chars[start.pos..self.loc.pos].iter().collect();
+    let text = text.trim_end().to_string();
+    if text.starts_with("##") {
+      let doc = &text[2..];
+      let doc = if doc.starts_with(' ') { &doc[1..] } else { doc };
+      self.docs.push(DocComment { source: self.source, line: start.line, text: doc.to_string() });
+    }
+    let trailing = self.tokens.last().map(|t| t.loc.end.line == start.line).unwrap_or(false);
+    self.comments.push(Comment { line: start.line, text, trailing });
+    true
   }
 
   fn lex_syntax(&mut self) -> bool {
@@ -317,6 +356,9 @@ impl <'l> CStream<'l> {
       if c == '\\' {
         // slash pattern, e.g. \n for newline
         self.skip_char();
+        if !self.has_chars() {
+          break;
+        }
         let c = self.peek();
         match c {
           '\\' => self.current_token.push('\\'),
@@ -346,6 +388,26 @@ impl <'l> CStream<'l> {
 }
 
 pub fn lex(source : SourceId, code : &str, symbols : &StringCache) -> Result<Vec<Token>, Vec<Error>> {
+  lex_with_docs(source, code, symbols).map(|(tokens, _)| tokens)
+}
+
+/// Lexes some code, and also returns its doc comments
+pub fn lex_with_docs(source : SourceId, code : &str, symbols : &StringCache)
+  -> Result<(Vec<Token>, Vec<DocComment>), Vec<Error>>
+{
+  lex_stream(source, code, symbols).map(|cs| (cs.tokens, cs.docs))
+}
+
+/// Lexes some code, and also returns all of its comments
+pub fn lex_with_comments(source : SourceId, code : &str, symbols : &StringCache)
+  -> Result<(Vec<Token>, Vec<Comment>), Vec<Error>>
+{
+  lex_stream(source, code, symbols).map(|cs| (cs.tokens, cs.comments))
+}
+
+fn lex_stream<'l>(source : SourceId, code : &str, symbols : &'l StringCache)
+  -> Result<CStream<'l>, Vec<Error>>
+{
 
   fn lex_with_errors(cs : &mut CStream) -> Result<(), Error> {
     while cs.has_chars() {
@@ -371,7 +433,7 @@ pub fn lex(source : SourceId, code : &str, symbols : &StringCache) -> Result<Vec
     }
   }
   if cs.errors.is_empty() {
-    Ok(cs.tokens)
+    Ok(cs)
   }
   else {
     Err(cs.errors)
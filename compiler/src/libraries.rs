@@ -0,0 +1,135 @@
+// The shared libraries (dll/so files) that the language has loaded.
+//
+// The registry belongs to the compiler, and the language reaches it through the
+// compiler handle, so there is no global state to race on. Handles are plain
+// numbers that are never reused, so a stale handle finds nothing instead of
+// finding some other library. Unloading a library invalidates any symbols that
+// were loaded from it, and nothing tracks those, so the caller has to drop them.
+//
+// Libraries are looked up by name in a list of search directories, with the
+// platform's prefix and extension added (`foo` is `foo.dll`, `libfoo.so` or
+// `libfoo.dylib`).
+
+use crate::common::*;
+
+use libloading::{Library, Symbol};
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::path::Path;
+
+#[cfg(not(debug_assertions))]
+static MODE : &'static str = "release";
+#[cfg(debug_assertions)]
+static MODE : &'static str = "debug";
+
+#[cfg(not(test))]
+static ROOT : &'static str = "";
+#[cfg(test)]
+static ROOT : &'static str = "../";
+
+/// The file name of a library on this platform. Names that already have an
+/// extension are left alone.
+pub fn library_file_name(name : &str) -> String {
+  let path = Path::new(name);
+  if path.extension().is_some() {
+    return name.into();
+  }
+  let (prefix, suffix) =
+    if cfg!(windows) { ("", "dll") }
+    else if cfg!(target_os = "macos") { ("lib", "dylib") }
+    else { ("lib", "so") };
+  let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or(name);
+  let file_name = format!("{}{}.{}", prefix, file_name, suffix);
+  path.with_file_name(file_name).to_string_lossy().replace("\\", "/")
+}
+
+pub struct SharedLibrary {
+  /// The file name of the library
+  pub name : RefStr,
+  pub path : RefStr,
+  lib : Library,
+}
+
+pub struct SharedLibraries {
+  /// The directories to look for libraries in, in order. An empty string is the
+  /// working directory.
+  pub search_paths : Vec<String>,
+  libraries : BTreeMap<u64, SharedLibrary>,
+  next_handle : u64,
+}
+
+impl Default for SharedLibraries {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl SharedLibraries {
+  pub fn new() -> Self {
+    let deps_path = format!("{}target/{}/deps/", ROOT, MODE);
+    SharedLibraries {
+      search_paths: vec![deps_path, "".into()],
+      libraries: BTreeMap::new(),
+      next_handle: 0,
+    }
+  }
+
+  /// Finds a library in the search paths and loads it. A name with a directory
+  /// in it is only looked for in that directory. If the library is already
+  /// loaded from the same path (by the project manifest, for instance), the
+  /// existing handle is returned. The error lists every path that was tried.
+  pub fn find_and_load(&mut self, name : &str) -> Result<u64, String> {
+    let file_name = library_file_name(name);
+    let paths : Vec<String> =
+      if Path::new(name).parent().map(|p| p.as_os_str().is_empty()).unwrap_or(true) {
+        self.search_paths.iter().map(|dir| {
+          if dir.is_empty() || dir.ends_with('/') { format!("{}{}", dir, file_name) }
+          else { format!("{}/{}", dir, file_name) }
+        }).collect()
+      }
+      else { vec![file_name] };
+    let mut tried = vec![];
+    for path in paths.iter() {
+      if let Some((&h, _)) = self.libraries.iter().find(|(_, l)| l.path.as_ref() == path.as_str()) {
+        return Ok(h);
+      }
+      match self.load(path) {
+        Ok(handle) => return Ok(handle),
+        Err(e) => tried.push(format!("   {} ({})", path, e)),
+      }
+    }
+    Err(format!("couldn't load library '{}'. Tried:\n{}", name, tried.join("\n")))
+  }
+
+  /// Loads the library at exactly this path, and returns a handle to it
+  pub fn load(&mut self, path : &str) -> Result<u64, String> {
+    let lib = Library::new(path).map_err(|e| e.to_string())?;
+    let file_name = Path::new(path).file_name().and_then(|n| n.to_str()).unwrap_or(path);
+    self.next_handle += 1;
+    let handle = self.next_handle;
+    let l = SharedLibrary { name: file_name.into(), path: path.into(), lib };
+    self.libraries.insert(handle, l);
+    Ok(handle)
+  }
+
+  /// Returns the address of a symbol, or `None` if the library or the symbol
+  /// doesn't exist
+  pub fn symbol(&self, handle : u64, name : &str) -> Option<usize> {
+    let lib = &self.libraries.get(&handle)?.lib;
+    let name = CString::new(name).ok()?;
+    unsafe {
+      let symbol : Symbol<*const ()> = lib.get(name.as_bytes_with_nul()).ok()?;
+      Some(symbol.into_raw().into_raw() as usize)
+    }
+  }
+
+  /// Returns false if the handle didn't refer to a loaded library
+  pub fn unload(&mut self, handle : u64) -> bool {
+    self.libraries.remove(&handle).is_some()
+  }
+
+  /// The loaded libraries, in the order that they were loaded
+  pub fn iter(&self) -> impl Iterator<Item=(u64, &SharedLibrary)> {
+    self.libraries.iter().map(|(&h, l)| (h, l))
+  }
+}
@@ -6,12 +6,18 @@ use crate::error::{Error, error, error_raw, TextLocation};
 
 use crate::structure::{
   Node, NodeId, Nodes, Content, PrimitiveVal, TypeKind, ReferenceId,
-  LabelId, NodeValueType, VarScope, Reference };
+  LabelId, NodeValueType, VarScope, GlobalType, Reference, LayoutQuery, CallingConvention,
+  VariantAccess, ShortCircuitOp };
 use crate::types::{
   Type, PType, TypeDefinition, SymbolInit, SymbolId, TypeMapping,
-  SymbolDefinition, TypeInfo, TypeContent, FunctionSignature };
+  SymbolDefinition, TypeInfo, TypeContent, FunctionSignature, FieldPlacement, BitfieldLayout,
+  MethodReceiver };
 use crate::code_store::CodeStore;
 use crate::llvm_compile::SymbolLocation;
+use crate::compiler::CompileOptions;
+use crate::c_abi::{self, PassAs};
+use crate::reflection::{self, Shape, TypeDescription};
+use crate::intrinsics::{RETURN_ADDRESS, SLICE_LENGTH, is_tuple_type};
 
 use std::collections::HashMap;
 
@@ -24,7 +30,7 @@ use inkwell::attributes::{Attribute, AttributeLoc};
 use inkwell::passes::PassManager;
 use inkwell::types::{
   BasicTypeEnum, BasicType, StructType, PointerType,
-  FunctionType, IntType, FloatType };
+  FunctionType, IntType, FloatType, VectorType };
 use inkwell::values::{
   BasicValueEnum, BasicValue, FloatValue, StructValue, IntValue,
   FunctionValue, PointerValue, GlobalValue };
@@ -66,6 +72,8 @@ impl MaybeVal {
 struct GenVal {
   storage : Storage,
   value : BasicValueEnum,
+  /// A pointer into a packed struct, which can be at any address
+  unaligned : bool,
 }
 
 impl Into<MaybeVal> for GenVal {
@@ -75,11 +83,23 @@ impl Into<MaybeVal> for GenVal {
 }
 
 fn reg(value : BasicValueEnum) -> GenVal {
-  GenVal { storage: Storage::Register, value }
+  GenVal { storage: Storage::Register, value, unaligned: false }
 }
 
 fn pointer(ptr : PointerValue) -> GenVal {
-  GenVal { storage: Storage::Pointer, value: ptr.as_basic_value_enum() }
+  GenVal { storage: Storage::Pointer, value: ptr.as_basic_value_enum(), unaligned: false }
+}
+
+fn unaligned_pointer(ptr : PointerValue) -> GenVal {
+  GenVal { storage: Storage::Pointer, value: ptr.as_basic_value_enum(), unaligned: true }
+}
+
+/// The empty function that every module ends with
+pub static CODE_END : &'static str = "@code_end";
+
+/// The function that initialises a lazy static
+pub fn lazy_initialiser_name(name : &str) -> String {
+  format!("{}.lazy_init", name)
 }
 
 fn const_zero(t : BasicTypeEnum) -> BasicValueEnum {
@@ -106,9 +126,6 @@ fn name_basic_type(t : &BasicValueEnum, s : &str) {
   }
 }
 
-#[derive(Copy, Clone)]
-enum ShortCircuitOp { And, Or }
-
 /// Code generates a module
 pub struct Gen<'l> {
   context: &'l Context,
@@ -124,6 +141,8 @@ pub struct Gen<'l> {
   struct_types: HashMap<RefStr, StructType>,
 
   pm : &'l PassManager<FunctionValue>,
+
+  options : CompileOptions,
 }
 
 #[derive(Clone, Copy)]
@@ -164,6 +183,9 @@ pub struct GenFunction<'l, 'a> {
 
   /// stack of labels in scopes and their state
   labels_in_scope: Vec<(LabelId, LabelState)>,
+
+  /// How the function returns its value, if it returns a tuple that's lowered
+  tuple_return : Option<(BasicTypeEnum, PassAs)>,
 }
 
 pub struct CompileInfo<'l> {
@@ -233,6 +255,16 @@ impl <'l> TypedNode<'l> {
     self.info.mapping.sizeof_info.get(&self.node.id)
   }
 
+  /// How the receiver of a call written `x.f(...)` is passed
+  fn method_receiver(&self) -> MethodReceiver {
+    self.info.mapping.method_receivers.get(&self.node.id).cloned().unwrap_or(MethodReceiver::Value)
+  }
+
+  /// True for a closure that's passed to a C function as its bare function
+  fn is_thinned(&self) -> bool {
+    self.info.mapping.thinned_args.contains(&self.node.id)
+  }
+
   fn node_symbol_def(&self) -> Option<&SymbolDefinition> {
     let symbol_id = *self.info.mapping.symbol_references.get(&self.node.id)?;
     let def = self.info.symbol_def(symbol_id);
@@ -262,6 +294,7 @@ impl <'l> Gen<'l> {
     globals_to_link: &'l mut Vec<(GlobalValue, SymbolLocation)>,
     functions_to_link: &'l mut Vec<(FunctionValue, SymbolLocation)>,
     pm : &'l PassManager<FunctionValue>,
+    options : CompileOptions,
   )
       -> Gen<'l>
   {
@@ -272,6 +305,7 @@ impl <'l> Gen<'l> {
       functions_to_link,
       struct_types: HashMap::new(),
       pm,
+      options,
     }
   }
 
@@ -295,8 +329,7 @@ impl <'l> Gen<'l> {
             SymbolInit::CBind => {
               let symloc = SymbolLocation::CBind(def.name.clone());
               if let Some(sig) = def.type_tag.sig() {
-                let f = self.codegen_prototype(info, def.name.as_ref(), sig.return_type, None, sig.args);
-                self.functions_to_link.push((f, symloc));
+                self.codegen_cbind_prototype(info, def.name.as_ref(), sig, symloc);
               }
               else {
                 let gv = self.module.add_global(t, Some(AddressSpace::Generic), &def.name);
@@ -309,13 +342,22 @@ impl <'l> Gen<'l> {
               // let v = self.codegen_static(info.typed_node(node_id))?;
               // self.add_global(v, false, &name);
             }
+            SymbolInit::Lazy(body) => {
+              let global = self.add_global(const_zero(t), false, &def.name);
+              let value_function =
+                self.codegen_prototype(
+                  info, &format!("{}.lazy_value", def.name), &def.type_tag, None, &[]);
+              functions_to_codegen.push((value_function, &[] as &[Reference], *body, &def.type_tag, info));
+              let tuple_return = self.tuple_return(info, &def.type_tag);
+              self.codegen_lazy_initialiser(&def.name, value_function, global, tuple_return);
+            }
             SymbolInit::Function(init) => {
               let sig = def.type_tag.sig().unwrap();
               let f =
                 self.codegen_prototype(
                   info, init.name_for_codegen.as_ref(), sig.return_type,
                   Some(&init.args), sig.args);
-              functions_to_codegen.push((f, init.args.as_slice(), init.body, info));
+              functions_to_codegen.push((f, init.args.as_slice(), init.body, sig.return_type, info));
             }
             SymbolInit::Intrinsic => (),
           }
@@ -324,10 +366,17 @@ impl <'l> Gen<'l> {
     }
 
     // codegen the functions
-    for (p, args, body, info) in functions_to_codegen {
-      self.codegen_function(p, info.typed_node(body), args)?;
+    for (p, args, body, return_type, info) in functions_to_codegen {
+      self.codegen_function(p, info.typed_node(body), args, return_type)?;
     }
 
+    // An empty function after all the others, whose address is where the module's
+    // code ends (see `llvm_compile::function_ranges`)
+    let end = self.module.add_function(CODE_END, self.context.void_type().fn_type(&[], false), None);
+    let builder = self.context.create_builder();
+    builder.position_at_end(&self.context.append_basic_block(&end, "entry"));
+    builder.build_return(None);
+
     Ok(())
   }
 
@@ -354,20 +403,178 @@ impl <'l> Gen<'l> {
     function.add_attribute(i, self.context.create_string_attribute("probe-stack", "__rust_probestack"));
     function.add_attribute(i, self.context.create_string_attribute("target-cpu", "x86-64"));
 
+    let sret_offset = if let Some((_, PassAs::Indirect{ .. })) = self.tuple_return(info, return_type) {
+      let sret = self.context.create_enum_attribute(Attribute::get_named_enum_kind_id("sret"), 0);
+      function.add_attribute(AttributeLoc::Param(0), sret);
+      1
+    }
+    else { 0 };
+
     // set arguments names
     if let Some(arg_names) = arg_names {
-      for (i, arg) in function.get_param_iter().enumerate() {
-        name_basic_type(&arg, arg_names[i].name.as_ref());
+      for (arg, arg_name) in function.get_param_iter().skip(sret_offset).zip(arg_names) {
+        name_basic_type(&arg, arg_name.name.as_ref());
       }
     }
     function
   }
 
+  /// Declares a C function. If it takes or returns aggregates, the declaration is
+  /// lowered to the platform's C ABI (see c_abi.rs), and the function that's returned
+  /// is a wrapper with the language's representation of the arguments. The wrapper
+  /// has the same calling convention as the C function, because call sites only
+  /// know the convention from the function's type.
+  fn codegen_cbind_prototype(
+    &mut self,
+    info : &CompileInfo,
+    name : &str,
+    sig : FunctionSignature,
+    symloc : SymbolLocation)
+      -> FunctionValue
+  {
+    let arg_types : Vec<BasicTypeEnum> =
+      sig.args.iter().map(|t| self.to_basic_type(info, t).unwrap()).collect();
+    let return_type = self.to_basic_type(info, sig.return_type);
+    let convention = c_abi::calling_convention_id(sig.convention);
+    if c_abi::is_trivial(&arg_types, return_type) {
+      let f = self.codegen_prototype(info, name, sig.return_type, None, sig.args);
+      f.set_call_conventions(convention);
+      self.functions_to_link.push((f, symloc));
+      return f;
+    }
+    // Lower the C declaration
+    let return_class = return_type.map(|t| c_abi::classify_return(self.context, self.target_data, t));
+    let arg_classes : Vec<_> =
+      arg_types.iter().map(|t| c_abi::classify_arg(self.context, self.target_data, *t)).collect();
+    let mut c_arg_types = vec![];
+    let mut c_return_type = return_type;
+    match &return_class {
+      Some(PassAs::Coerce(t)) => c_return_type = Some(*t),
+      Some(PassAs::Indirect{ .. }) => {
+        c_arg_types.push(self.pointer_to_type(return_type).into());
+        c_return_type = None;
+      }
+      _ => (),
+    }
+    for (t, class) in arg_types.iter().zip(arg_classes.iter()) {
+      let c_arg_type = match class {
+        PassAs::Direct => *t,
+        PassAs::Coerce(c) => *c,
+        PassAs::Indirect{ .. } => self.pointer_to_type(Some(*t)).into(),
+      };
+      c_arg_types.push(c_arg_type);
+    }
+    let c_fn_type = self.function_type(c_return_type, &c_arg_types);
+    let c_function = self.module.add_function(&format!("{}.c_abi", name), c_fn_type, None);
+    c_function.set_call_conventions(convention);
+    let sret_offset = if let Some(PassAs::Indirect{ .. }) = return_class {
+      let sret = self.context.create_enum_attribute(Attribute::get_named_enum_kind_id("sret"), 0);
+      c_function.add_attribute(AttributeLoc::Param(0), sret);
+      1
+    }
+    else { 0 };
+    for (i, class) in arg_classes.iter().enumerate() {
+      if let PassAs::Indirect{ byval: true } = class {
+        let byval = self.context.create_enum_attribute(Attribute::get_named_enum_kind_id("byval"), 0);
+        c_function.add_attribute(AttributeLoc::Param(i as u32 + sret_offset), byval);
+      }
+    }
+    self.functions_to_link.push((c_function, symloc));
+
+    // Generate the wrapper, which moves values through memory to reinterpret them
+    let wrapper = self.codegen_prototype(info, name, sig.return_type, None, sig.args);
+    wrapper.set_call_conventions(convention);
+    let builder = self.context.create_builder();
+    let entry = self.context.append_basic_block(&wrapper, "entry");
+    builder.position_at_end(&entry);
+    // the wrapper returns a tuple the same way as the C function (see `tuple_return`),
+    // so the result is passed straight through
+    let passes_through = self.tuple_return(info, sig.return_type).is_some();
+    let reinterpret = |v : BasicValueEnum, t : BasicTypeEnum| {
+      self.reinterpret(&builder, v, t, &|t| builder.build_alloca(t, "abi_cast"))
+    };
+    let mut params : Vec<BasicValueEnum> = wrapper.get_param_iter().collect();
+    let mut c_args : Vec<BasicValueEnum> = vec![];
+    let sret = if let Some(PassAs::Indirect{ .. }) = return_class {
+      let p =
+        if passes_through { params.remove(0).into_pointer_value() }
+        else { builder.build_alloca(return_type.unwrap(), "sret") };
+      c_args.push(p.into());
+      Some(p)
+    }
+    else { None };
+    for (v, class) in params.into_iter().zip(arg_classes.iter()) {
+      let c_arg = match class {
+        PassAs::Direct => v,
+        PassAs::Coerce(t) => reinterpret(v, *t),
+        PassAs::Indirect{ .. } => {
+          let p = builder.build_alloca(v.get_type(), "arg_copy");
+          builder.build_store(p, v);
+          p.into()
+        }
+      };
+      c_args.push(c_arg);
+    }
+    let call = builder.build_call(c_function, &c_args, "c_call");
+    call.set_call_convention(convention);
+    let result = call.try_as_basic_value().left();
+    match (return_class, result, sret) {
+      _ if passes_through => { builder.build_return(result.as_ref().map(|v| v as &dyn BasicValue)); }
+      (_, _, Some(sret)) => {
+        let v = builder.build_load(sret, "c_result");
+        builder.build_return(Some(&v));
+      }
+      (Some(PassAs::Coerce(_)), Some(v), None) => {
+        let v = reinterpret(v, return_type.unwrap());
+        builder.build_return(Some(&v));
+      }
+      (_, Some(v), None) => { builder.build_return(Some(&v)); }
+      (_, None, None) => { builder.build_return(None); }
+    }
+    wrapper
+  }
+
+  /// Generates the function that runs a lazy static's initialiser the first time
+  /// it's called. Every use of the static calls this first.
+  fn codegen_lazy_initialiser(
+    &mut self, name : &str, value_function : FunctionValue, global : PointerValue,
+    tuple_return : Option<(BasicTypeEnum, PassAs)>)
+  {
+    let bool_type = self.context.bool_type();
+    let flag = self.add_global(bool_type.const_int(0, false).into(), false, &format!("{}.initialised", name));
+    let fn_type = self.context.void_type().fn_type(&[], false);
+    let f = self.module.add_function(&lazy_initialiser_name(name), fn_type, None);
+    let builder = self.context.create_builder();
+    let entry = self.context.append_basic_block(&f, "entry");
+    let init_block = self.context.append_basic_block(&f, "init");
+    let done_block = self.context.append_basic_block(&f, "done");
+    builder.position_at_end(&entry);
+    let initialised = builder.build_load(flag, "initialised").into_int_value();
+    builder.build_conditional_branch(initialised, &done_block, &init_block);
+    builder.position_at_end(&init_block);
+    // the flag is set first, so an initialiser that uses its own static sees zeroes
+    // instead of recursing forever
+    builder.build_store(flag, bool_type.const_int(1, false));
+    match tuple_return {
+      // the value is written straight to the global
+      Some((_, PassAs::Indirect{ .. })) => { builder.build_call(value_function, &[global.into()], "void"); }
+      _ => {
+        let value = builder.build_call(value_function, &[], "lazy_value").try_as_basic_value().left().unwrap();
+        let store_ptr = builder.build_pointer_cast(global, self.pointer_to_type(Some(value.get_type())), "lazy_value_ptr");
+        builder.build_store(store_ptr, value);
+      }
+    }
+    builder.build_unconditional_branch(&done_block);
+    builder.position_at_end(&done_block);
+    builder.build_return(None);
+  }
+
   fn codegen_function(
     &mut self,
     prototype_handle : FunctionValue,
     body : TypedNode,
-    args : &[Reference])
+    args : &[Reference],
+    return_type : &Type)
       -> Result<FunctionValue, Error>
   {
     // this function is here because Rust doesn't have a proper try/catch yet
@@ -379,8 +586,13 @@ impl <'l> Gen<'l> {
 
       genf.builder.position_at_end(&entry);
 
+      if genf.gen.options.sandbox.is_some() {
+        codegen_entry_step(genf);
+      }
+
       // set function parameters
-      for (arg_value, arg_symbol) in function.get_param_iter().zip(args) {
+      let sret_offset = if let Some((_, PassAs::Indirect{ .. })) = genf.tuple_return { 1 } else { 0 };
+      for (arg_value, arg_symbol) in function.get_param_iter().skip(sret_offset).zip(args) {
         genf.init_local_var(arg_symbol.id, &arg_symbol.name, arg_value);
       }
 
@@ -400,8 +612,10 @@ impl <'l> Gen<'l> {
       }
     }
 
+    let tuple_return = self.tuple_return(body.info, return_type);
     let builder = self.context.create_builder();
     let mut gen_function = GenFunction::new(self, builder, prototype_handle);
+    gen_function.tuple_return = tuple_return;
 
     match generate(body, args, &mut gen_function) {
       Ok(()) => Ok(prototype_handle),
@@ -444,6 +658,9 @@ impl <'l> Gen<'l> {
               Prim(U32) => self.context.i32_type().const_int(*i as u64, false).into(),
               Prim(U16) => self.context.i16_type().const_int(*i as u64, false).into(),
               Prim(U8) => self.context.i8_type().const_int(*i as u64, false).into(),
+              // an integer literal returned by an `if` whose other branch is a float
+              Prim(F64) => self.context.f64_type().const_float(*i as f64).into(),
+              Prim(F32) => self.context.f32_type().const_float(*i as f64).into(),
               _ => panic!("primitive type error {}", node.type_tag()),
             }
             
@@ -482,7 +699,7 @@ impl <'l> Gen<'l> {
     match &t.content {
       TypeContent::Prim(t) => {
         match t {
-          PType::Void => None,
+          PType::Void | PType::Never => None,
           PType::F64 => Some(self.context.f64_type().into()),
           PType::F32 => Some(self.context.f32_type().into()),
           PType::I64 => Some(self.context.i64_type().into()),
@@ -492,9 +709,10 @@ impl <'l> Gen<'l> {
           PType::U16 => Some(self.context.i16_type().into()),
           PType::U8 => Some(self.context.i8_type().into()),
           PType::Bool => Some(self.context.bool_type().into()),
+          PType::Vec4F => Some(self.context.f32_type().vec_type(4).into()),
         }
       }
-      TypeContent::Fun => {
+      TypeContent::Fun(_) => {
         let sig = t.sig().unwrap();
         let t = self.to_function_type(info, sig.args.as_ref(), &sig.return_type);
         Some(t.ptr_type(AddressSpace::Generic).into())
@@ -518,12 +736,20 @@ impl <'l> Gen<'l> {
   }
 
   fn to_function_type(&mut self, info : &CompileInfo, arg_types : &[Type], return_type : &Type) -> FunctionType {
-    let arg_types =
-      arg_types.iter().map(|t| self.to_basic_type(info, t).unwrap())
-      .collect::<Vec<BasicTypeEnum>>();
-    let arg_types = arg_types.as_slice();
-    let return_type = self.to_basic_type(info, return_type);
-    self.function_type(return_type, arg_types)
+    let mut basic_arg_types = vec![];
+    let mut basic_return_type = self.to_basic_type(info, return_type);
+    match self.tuple_return(info, return_type) {
+      Some((t, PassAs::Indirect{ .. })) => {
+        basic_arg_types.push(self.pointer_to_type(Some(t)).into());
+        basic_return_type = None;
+      }
+      Some((_, PassAs::Coerce(c))) => basic_return_type = Some(c),
+      _ => (),
+    }
+    for t in arg_types {
+      basic_arg_types.push(self.to_basic_type(info, t).unwrap());
+    }
+    self.function_type(basic_return_type, basic_arg_types.as_slice())
   }
 
   fn function_type(&self, return_type : Option<BasicTypeEnum>, arg_types : &[BasicTypeEnum])
@@ -562,6 +788,83 @@ impl <'l> Gen<'l> {
     }
   }
 
+  /// How a function returns a tuple, if it isn't returned as it is. Tuples are
+  /// returned the way the platform's C ABI returns a struct, which is in registers
+  /// if it allows and through a hidden `sret` pointer otherwise, so that functions
+  /// which return them can be called from Rust.
+  fn tuple_return(&mut self, info : &CompileInfo, t : &Type) -> Option<(BasicTypeEnum, PassAs)> {
+    match &t.content {
+      TypeContent::Def(name, _) if is_tuple_type(name) => {
+        let bt = self.to_basic_type(info, t)?;
+        match c_abi::classify_return(self.context, self.target_data, bt) {
+          PassAs::Direct => None,
+          class => Some((bt, class)),
+        }
+      }
+      _ => None,
+    }
+  }
+
+  /// The bare function of a closure that's passed to a C function. If the closure
+  /// has a context, `closure_has_context` panics instead.
+  fn codegen_thinned_closure(&mut self, closure : TypedNode, function_type : &Type)
+    -> Result<BasicValueEnum, Error>
+  {
+    let sv = self.codegen_value(closure)?.into_struct_value();
+    let def = closure.node_type_def().expect("thinned value is not a closure");
+    let placements = self.gen.field_placements(closure.info, def, closure.type_tag());
+    let field = |name : &str| {
+      let i = def.fields.iter().position(|(f, _)| f.name.as_ref() == name).unwrap();
+      placements[i].index
+    };
+    let function = self.builder.build_extract_value(sv, field("function"), "function").unwrap();
+    let has_env = self.builder.build_extract_value(sv, field("has_env"), "has_env").unwrap().into_int_value();
+    let f = self.fn_val;
+    let fail_block = self.gen.context.append_basic_block(&f, "closure_has_context");
+    let ok_block = self.gen.context.append_basic_block(&f, "thin_closure");
+    self.builder.build_conditional_branch(has_env, &fail_block, &ok_block);
+    self.builder.position_at_end(&fail_block);
+    let report = match self.gen.module.get_function("closure_has_context") {
+      Some(f) => f,
+      None => {
+        let fn_type = self.gen.context.void_type().fn_type(&[], false);
+        let f = self.gen.module.add_function("closure_has_context", fn_type, None);
+        self.gen.functions_to_link.push((f, SymbolLocation::CBind("closure_has_context".into())));
+        f
+      }
+    };
+    self.builder.build_call(report, &[], "void");
+    codegen_return_zeroes(self);
+    self.builder.position_at_end(&ok_block);
+    let t = self.gen.to_basic_type(closure.info, function_type).unwrap();
+    Ok(self.builder.build_pointer_cast(function.into_pointer_value(), t.into_pointer_type(), "thinned").into())
+  }
+
+  /// Reinterprets a value as a different type of the same size, by storing it and
+  /// loading it back. `alloca` makes the memory, for the type with the stricter
+  /// alignment.
+  fn reinterpret(
+    &self, builder : &Builder, v : BasicValueEnum, t : BasicTypeEnum,
+    alloca : &dyn Fn(BasicTypeEnum) -> PointerValue)
+      -> BasicValueEnum
+  {
+    let (alloc_type, store_type) =
+      if self.target_data.get_abi_alignment(&t) >= self.target_data.get_abi_alignment(&v.get_type()) {
+        (t, v.get_type())
+      }
+      else { (v.get_type(), t) };
+    let p = alloca(alloc_type);
+    let store_ptr = builder.build_pointer_cast(p, self.pointer_to_type(Some(store_type)), "abi_cast_ptr");
+    if store_type == v.get_type() {
+      builder.build_store(store_ptr, v);
+      builder.build_load(p, "abi_cast_val")
+    }
+    else {
+      builder.build_store(p, v);
+      builder.build_load(store_ptr, "abi_cast_val")
+    }
+  }
+
   fn pointer_to_type(&self, t : Option<BasicTypeEnum>) -> PointerType {
     if let Some(t) = t {
     use BasicTypeEnum::*;
@@ -587,53 +890,143 @@ impl <'l> Gen<'l> {
         return *t;
       }
     }
-    let field_basic_types : Vec<_> = {
-      if def.is_polymorphic() {
-        def.instanced_fields(t.children()).iter()
-          .map(|t| self.to_basic_type_no_cycle(info, t).unwrap()).collect()
-      }
-      else {
-        def.fields.iter()
-          .map(|(_, t)| self.to_basic_type_no_cycle(info, t).unwrap()).collect()
-      }
-    };
+    let field_basic_types = self.field_basic_types(info, def, t);
     let t = match def.kind {
       TypeKind::Struct => {
-        self.context.struct_type(&field_basic_types, false)
-      }
-      TypeKind::Union => {
-        let mut union_bitwidth = 0;
-        let mut bt : Option<BasicTypeEnum> = None;
-        let mut widest_alignment = 0;
-        for t in field_basic_types {
-          let alignment = self.target_data.get_preferred_alignment(&t);
-          if alignment > widest_alignment {
-            widest_alignment = alignment;
-            bt = Some(t)
-          }
-          let width = self.target_data.get_bit_size(&t);
-          if width > union_bitwidth {
-            union_bitwidth = width;
-          }
-        }
-        if let Some(t) = bt {
-          let val_bitwidth = self.target_data.get_bit_size(&t);
-          assert!(union_bitwidth >= val_bitwidth);
-          let difference = union_bitwidth - val_bitwidth;
-          assert!(difference % 8 == 0);
-          let padding = self.context.i8_type().array_type(difference as u32 / 8);
-          self.context.struct_type(&[t, padding.into()], true)
+        // bitfields after the first in a run share its field, which is an array of
+        // bytes for System V
+        let placements = self.field_placements(info, def, t);
+        let mut field_basic_types : Vec<_> =
+          field_basic_types.into_iter().zip(placements.iter()).enumerate()
+          .filter(|(i, (_, p))| *i == 0 || placements[i - 1].index != p.index)
+          .map(|(_, (t, p))| match p.storage_bytes {
+            Some(bytes) => self.context.i8_type().array_type(bytes).into(),
+            None => t,
+          })
+          .collect();
+        let mut align = def.layout.align;
+        if BitfieldLayout::host() == BitfieldLayout::SysV && !def.bitfields.is_empty() && !def.layout.packed {
+          align = Some(std::cmp::max(align.unwrap_or(1), def.bitfield_alignment() as u32));
         }
-        else {
-          let padding = self.context.i8_type().array_type(union_bitwidth as u32 / 8);
-          self.context.struct_type(&[padding.into()], true)
+        if let Some(align) = align {
+          // Vectors are aligned to their size, so a zero-length array of one raises the
+          // alignment of the struct (and pads its size) without moving any fields
+          let v = self.context.i8_type().vec_type(align);
+          field_basic_types.push(v.array_type(0).into());
         }
+        self.context.struct_type(&field_basic_types, def.layout.packed)
+      }
+      TypeKind::Union => self.union_type(field_basic_types),
+      // each variant is stored after the tag, like a `#[repr(u8)]` enum in Rust
+      TypeKind::TaggedUnion => {
+        let variants = field_basic_types.into_iter().map(|t| self.variant_type(t).into()).collect();
+        self.union_type(variants)
       }
     };
     self.struct_types.insert(def.name.clone(), t);
     return t;
   }
 
+  /// Where each field of a struct is stored, laid out the way the host's C compilers
+  /// lay out bitfields
+  fn field_placements(&mut self, info : &CompileInfo, def : &TypeDefinition, t : &Type) -> Vec<FieldPlacement> {
+    let layout = BitfieldLayout::host();
+    let field_layouts : Vec<(u64, u64)> = {
+      if def.bitfields.is_empty() || layout == BitfieldLayout::Msvc { vec![] }
+      else {
+        self.field_basic_types(info, def, t).iter()
+          .map(|t| (self.target_data.get_abi_size(t), self.target_data.get_abi_alignment(t) as u64))
+          .collect()
+      }
+    };
+    def.field_placements(layout, &field_layouts)
+  }
+
+  fn field_basic_types(&mut self, info : &CompileInfo, def : &TypeDefinition, t : &Type) -> Vec<BasicTypeEnum> {
+    if def.is_polymorphic() {
+      def.instanced_fields(t.children()).iter()
+        .map(|t| self.to_basic_type_no_cycle(info, t).unwrap()).collect()
+    }
+    else {
+      def.fields.iter()
+        .map(|(_, t)| self.to_basic_type_no_cycle(info, t).unwrap()).collect()
+    }
+  }
+
+  /// Describes a type for `typeinfo(T)`. Returns None if it isn't made of numbers
+  /// and bools, or if it has bitfields.
+  fn type_shape(&mut self, info : &CompileInfo, t : &Type) -> Option<Shape> {
+    let shape = match &t.content {
+      TypeContent::Prim(p) => match p {
+        PType::I64 => Shape::Int{ bytes: 8, signed: true },
+        PType::I32 => Shape::Int{ bytes: 4, signed: true },
+        PType::U64 => Shape::Int{ bytes: 8, signed: false },
+        PType::U32 => Shape::Int{ bytes: 4, signed: false },
+        PType::U16 => Shape::Int{ bytes: 2, signed: false },
+        PType::U8 => Shape::Int{ bytes: 1, signed: false },
+        PType::F64 => Shape::Float{ bytes: 8 },
+        PType::F32 => Shape::Float{ bytes: 4 },
+        PType::Bool => Shape::Bool,
+        PType::Void | PType::Never | PType::Vec4F => return None,
+      },
+      TypeContent::Def(name, unit_id) => {
+        let def = info.find_type_def(name, *unit_id)?;
+        if def.kind != TypeKind::Struct || !def.bitfields.is_empty() {
+          return None;
+        }
+        let field_types = {
+          if def.is_polymorphic() { def.instanced_fields(t.children()) }
+          else { def.fields.iter().map(|(_, t)| t.clone()).collect() }
+        };
+        let struct_type = self.composite_type(info, def, t);
+        let placements = self.field_placements(info, def, t);
+        let mut fields = vec![];
+        for (((f, _), field_type), p) in def.fields.iter().zip(field_types.iter()).zip(placements.iter()) {
+          let offset = self.target_data.offset_of_element(&struct_type, p.index).unwrap();
+          fields.push((f.name.to_string(), offset, self.type_shape(info, field_type)?));
+        }
+        Shape::Struct{ name: name.to_string(), fields }
+      }
+      _ => return None,
+    };
+    Some(shape)
+  }
+
+  /// A union is the field with the widest alignment, padded to the size of the largest field
+  fn union_type(&mut self, field_basic_types : Vec<BasicTypeEnum>) -> StructType {
+    let mut union_bitwidth = 0;
+    let mut bt : Option<BasicTypeEnum> = None;
+    let mut widest_alignment = 0;
+    for t in field_basic_types {
+      let alignment = self.target_data.get_preferred_alignment(&t);
+      if alignment > widest_alignment {
+        widest_alignment = alignment;
+        bt = Some(t)
+      }
+      let width = self.target_data.get_bit_size(&t);
+      if width > union_bitwidth {
+        union_bitwidth = width;
+      }
+    }
+    if let Some(t) = bt {
+      let val_bitwidth = self.target_data.get_bit_size(&t);
+      assert!(union_bitwidth >= val_bitwidth);
+      let difference = union_bitwidth - val_bitwidth;
+      assert!(difference % 8 == 0);
+      let padding = self.context.i8_type().array_type(difference as u32 / 8);
+      self.context.struct_type(&[t, padding.into()], true)
+    }
+    else {
+      let padding = self.context.i8_type().array_type(union_bitwidth as u32 / 8);
+      self.context.struct_type(&[padding.into()], true)
+    }
+  }
+
+  /// A variant of a tagged union, with its u8 tag
+  fn variant_type(&self, t : BasicTypeEnum) -> StructType {
+    self.context.struct_type(&[self.context.i8_type().into(), t], false)
+  }
+
   fn add_global(&mut self, initial_value : BasicValueEnum, is_constant : bool, name : &str) -> PointerValue {
     let gv = self.module.add_global(initial_value.get_type(), Some(AddressSpace::Generic), name);
     gv.set_initializer(&initial_value);
@@ -742,16 +1135,140 @@ fn integer_binary_ops(
   }
 }
 
-fn get_index_data_ptr(gf : &mut GenFunction, container : TypedNode, index : TypedNode) -> Result<PointerValue, Error> {
+/// Calls `index_out_of_bounds`, which panics, unless the index is less than the length.
+/// If the panic returns, so does the function.
+fn codegen_bounds_check(gf : &mut GenFunction, index : IntValue, signed : bool, length : IntValue) {
+  let i64_type = gf.gen.context.i64_type();
+  let index =
+    if index.get_type().get_bit_width() == 64 { index }
+    else if signed { gf.builder.build_int_s_extend(index, i64_type, "index") }
+    else { gf.builder.build_int_z_extend(index, i64_type, "index") };
+  // negative indices wrap around to huge ones, so one unsigned comparison is enough
+  let in_bounds = gf.builder.build_int_compare(IntPredicate::ULT, index, length, "in_bounds");
+  let f = gf.fn_val;
+  let fail_block = gf.gen.context.append_basic_block(&f, "out_of_bounds");
+  let ok_block = gf.gen.context.append_basic_block(&f, "in_bounds");
+  gf.builder.build_conditional_branch(in_bounds, &ok_block, &fail_block);
+  gf.builder.position_at_end(&fail_block);
+  let report = match gf.gen.module.get_function("index_out_of_bounds") {
+    Some(f) => f,
+    None => {
+      let fn_type = gf.gen.context.void_type().fn_type(&[i64_type.into(), i64_type.into()], false);
+      let f = gf.gen.module.add_function("index_out_of_bounds", fn_type, None);
+      gf.gen.functions_to_link.push((f, SymbolLocation::CBind("index_out_of_bounds".into())));
+      f
+    }
+  };
+  gf.builder.build_call(report, &[index.into(), length.into()], "void");
+  codegen_return_zeroes(gf);
+  gf.builder.position_at_end(&ok_block);
+}
+
+/// Returns `end - start` as a u64. With bounds checks on, calls `slice_out_of_bounds`,
+/// which panics, unless `start <= end <= length`. If the panic returns, so does the function.
+fn codegen_slice_length(gf : &mut GenFunction, start : TypedNode, end : TypedNode, length : TypedNode)
+  -> Result<IntValue, Error>
+{
+  let i64_type = gf.gen.context.i64_type();
+  let signed = start.type_tag().signed_int();
+  let mut bounds = vec![];
+  for n in &[start, end] {
+    let v = gf.codegen_int(*n)?;
+    bounds.push(
+      if v.get_type().get_bit_width() == 64 { v }
+      else if signed { gf.builder.build_int_s_extend(v, i64_type, "slice_index") }
+      else { gf.builder.build_int_z_extend(v, i64_type, "slice_index") });
+  }
+  let (start, end) = (bounds[0], bounds[1]);
+  let length = gf.codegen_int(length)?;
+  if gf.gen.options.bounds_checks {
+    // negative indices wrap around to huge ones, so unsigned comparisons are enough
+    let ordered = gf.builder.build_int_compare(IntPredicate::ULE, start, end, "ordered");
+    let fits = gf.builder.build_int_compare(IntPredicate::ULE, end, length, "fits");
+    let in_bounds = gf.builder.build_and(ordered, fits, "in_bounds");
+    let f = gf.fn_val;
+    let fail_block = gf.gen.context.append_basic_block(&f, "slice_out_of_bounds");
+    let ok_block = gf.gen.context.append_basic_block(&f, "slice_in_bounds");
+    gf.builder.build_conditional_branch(in_bounds, &ok_block, &fail_block);
+    gf.builder.position_at_end(&fail_block);
+    let report = match gf.gen.module.get_function("slice_out_of_bounds") {
+      Some(f) => f,
+      None => {
+        let fn_type = gf.gen.context.void_type().fn_type(
+          &[i64_type.into(), i64_type.into(), i64_type.into()], false);
+        let f = gf.gen.module.add_function("slice_out_of_bounds", fn_type, None);
+        gf.gen.functions_to_link.push((f, SymbolLocation::CBind("slice_out_of_bounds".into())));
+        f
+      }
+    };
+    gf.builder.build_call(report, &[start.into(), end.into(), length.into()], "void");
+    codegen_return_zeroes(gf);
+    gf.builder.position_at_end(&ok_block);
+  }
+  Ok(gf.builder.build_int_sub(end, start, "slice_length"))
+}
+
+/// Calls `safepoint`, which is true if the loop has been cancelled
+fn codegen_safepoint(gf : &mut GenFunction) -> IntValue {
+  codegen_runtime_check(gf, "safepoint", "cancelled")
+}
+
+/// Calls a function in the runtime that takes no arguments and returns a bool
+fn codegen_runtime_check(gf : &mut GenFunction, name : &str, result_name : &str) -> IntValue {
+  let check = match gf.gen.module.get_function(name) {
+    Some(f) => f,
+    None => {
+      let fn_type = gf.gen.context.bool_type().fn_type(&[], false);
+      let f = gf.gen.module.add_function(name, fn_type, None);
+      gf.gen.functions_to_link.push((f, SymbolLocation::CBind(name.into())));
+      f
+    }
+  };
+  gf.builder.build_call(check, &[], result_name)
+    .try_as_basic_value().left().unwrap().into_int_value()
+}
+
+/// Counts a step when a sandboxed function is entered, and returns zeroes straight
+/// away if the step budget has run out (see metering.rs)
+fn codegen_entry_step(gf : &mut GenFunction) {
+  let exhausted = codegen_runtime_check(gf, "metered_step", "exhausted");
+  let f = gf.fn_val;
+  let exhausted_block = gf.gen.context.append_basic_block(&f, "steps_exhausted");
+  let body_block = gf.gen.context.append_basic_block(&f, "body");
+  gf.builder.build_conditional_branch(exhausted, &exhausted_block, &body_block);
+  gf.builder.position_at_end(&exhausted_block);
+  codegen_return_zeroes(gf);
+  gf.builder.position_at_end(&body_block);
+}
+
+/// Returns zeroes from the function, for code that can't carry on. The runtime
+/// functions that panic return inside a recovery boundary (see recovery.rs).
+fn codegen_return_zeroes(gf : &mut GenFunction) {
+  match gf.fn_val.get_type().get_return_type() {
+    Some(t) => gf.builder.build_return(Some(&const_zero(t))),
+    None => gf.builder.build_return(None),
+  };
+}
+
+/// Returns the pointer to the container's data, and the index
+fn get_index_data_ptr(gf : &mut GenFunction, container : TypedNode, index : TypedNode)
+  -> Result<(PointerValue, IntValue), Error>
+{
   if index.type_tag().int() {
     match &container.type_tag().content {
       TypeContent::Ptr => {
-        return gf.codegen_pointer(container);
+        let ptr = gf.codegen_pointer(container)?;
+        return Ok((ptr, gf.codegen_int(index)?));
       }
       TypeContent::Def(name, _)=> {
         if name.as_ref() == "array" {
-          // TODO: add bounds checks?
           let array = gf.codegen_struct(container)?;
+          let index_value = gf.codegen_int(index)?;
+          if gf.gen.options.bounds_checks {
+            let length = gf.builder.build_extract_value(array, 1, "array_length")
+              .unwrap().into_int_value();
+            codegen_bounds_check(gf, index_value, index.type_tag().signed_int(), length);
+          }
           let ptr = gf.builder.build_extract_value(array, 0, "array_pointer")
             .unwrap().into_pointer_value();
           let corrected_type = {
@@ -760,7 +1277,7 @@ fn get_index_data_ptr(gf : &mut GenFunction, container : TypedNode, index : Type
             gf.gen.pointer_to_type(element_type)
           };
           let ptr = gf.builder.build_pointer_cast(ptr, corrected_type, "field cast");
-          return Ok(ptr);
+          return Ok((ptr, index_value));
         }
       }
       _ => ()
@@ -773,8 +1290,7 @@ fn codegen_set_index(
   gf : &mut GenFunction, container : TypedNode, index : TypedNode, new_value : TypedNode)
     -> Result<MaybeVal, Error>
 {
-  let ptr = get_index_data_ptr(gf, container, index)?;
-  let index = gf.codegen_int(index)?;
+  let (ptr, index) = get_index_data_ptr(gf, container, index)?;
   let element_ptr = unsafe { gf.builder.build_gep(ptr, &[index], "element_ptr") };
   let new_value = gf.codegen_value(new_value)?;
   gf.builder.build_store(element_ptr, new_value);
@@ -785,8 +1301,7 @@ fn codegen_index(
   gf : &mut GenFunction, container : TypedNode, index : TypedNode)
     -> Result<GenVal, Error>
 {
-  let ptr = get_index_data_ptr(gf, container, index)?;
-  let index = gf.codegen_int(index)?;
+  let (ptr, index) = get_index_data_ptr(gf, container, index)?;
   let element_ptr = unsafe { gf.builder.build_gep(ptr, &[index], "element_ptr") };
   return Ok(reg(element_ptr.into()));
 }
@@ -806,13 +1321,6 @@ fn codegen_binary_intrinsic_call(gf : &mut GenFunction, node : TypedNode, name :
     else if ta.int() {
       return integer_binary_ops(gf, name, a, b);
     }
-    else if ta.boolean() {
-      match name {
-        "&&" => return gf.codegen_short_circuit_op(a, b, ShortCircuitOp::And),
-        "||" => return gf.codegen_short_circuit_op(a, b, ShortCircuitOp::Or),
-        _ => (),
-      }
-    }
   }
   panic!("COMPILER BUG: encountered unrecognised intrinsic, {}({}, {}).",
     name, a.type_tag(), b.type_tag())
@@ -882,14 +1390,93 @@ else {
 panic!("COMPILER BUG: encountered unrecognised intrinsic, {}({}).", name, t);
 }
 
+/// Intrinsics that take or return a `vec4f`, lowered to LLVM vector instructions.
+/// Returns None if it isn't one of these (e.g. taking the address of a vector).
+fn codegen_vector_intrinsic_call(gf : &mut GenFunction, node : TypedNode, name : &str, args : &[NodeId])
+  -> Result<Option<MaybeVal>, Error>
+{
+  let is_vector = |t : &Type| t.content == TypeContent::Prim(PType::Vec4F);
+  let args : Vec<TypedNode> = args.iter().map(|a| node.get(*a)).collect();
+  if !is_vector(node.type_tag()) && !args.iter().any(|a| is_vector(a.type_tag())) {
+    return Ok(None);
+  }
+  let i32_type = gf.gen.context.i32_type();
+  let vector_type = gf.gen.context.f32_type().vec_type(4);
+  let v : BasicValueEnum = match (name, args.as_slice()) {
+    ("vec4f", elements) | ("splat", elements) => {
+      let mut values = vec![];
+      for e in elements {
+        values.push(gf.codegen_float(*e)?);
+      }
+      let mut v = vector_type.get_undef();
+      for i in 0..4 {
+        let index = i32_type.const_int(i as u64, false);
+        v = gf.builder.build_insert_element(v, values[i % values.len()], index, "vector_element");
+      }
+      v.into()
+    }
+    ("-", [a]) => {
+      let a = gf.codegen_value(*a)?.into_vector_value();
+      gf.builder.build_float_neg(a, "op_result").into()
+    }
+    (op, [a, b]) if ["+", "-", "*", "/", "dot"].contains(&op) => {
+      let a = gf.codegen_value(*a)?.into_vector_value();
+      let b = gf.codegen_value(*b)?.into_vector_value();
+      match op {
+        "+" => gf.builder.build_float_add(a, b, "op_result").into(),
+        "-" => gf.builder.build_float_sub(a, b, "op_result").into(),
+        "*" => gf.builder.build_float_mul(a, b, "op_result").into(),
+        "/" => gf.builder.build_float_div(a, b, "op_result").into(),
+        "dot" => {
+          let products = gf.builder.build_float_mul(a, b, "products");
+          let mut sum = gf.gen.context.f32_type().const_zero();
+          for i in 0..4 {
+            let index = i32_type.const_int(i, false);
+            let p = gf.builder.build_extract_element(products, index, "product").into_float_value();
+            sum = gf.builder.build_float_add(sum, p, "dot");
+          }
+          sum.into()
+        }
+        _ => panic!("COMPILER BUG: encountered unrecognised vector intrinsic {}", op),
+      }
+    }
+    ("extract", [v, i]) => {
+      let v = gf.codegen_value(*v)?.into_vector_value();
+      let i = gf.codegen_int(*i)?;
+      gf.builder.build_extract_element(v, i, "vector_element")
+    }
+    ("shuffle", [_, _, _, _, _]) => {
+      let v = gf.codegen_value(args[0])?.into_vector_value();
+      let mut mask = vec![];
+      for i in &args[1..] {
+        match i.content() {
+          Content::Literal(PrimitiveVal::Int(x)) if *x >= 0 && *x < 4 =>
+            mask.push(i32_type.const_int(*x as u64, false)),
+          _ => return error(*i, "shuffle indices must be integer literals from 0 to 3"),
+        }
+      }
+      let mask = VectorType::const_vector(&mask);
+      gf.builder.build_shuffle_vector(v, vector_type.get_undef(), mask, "shuffle").into()
+    }
+    _ => return Ok(None),
+  };
+  Ok(Some(reg(v).into()))
+}
+
 fn codegen_intrinsic_call(gf : &mut GenFunction, node : TypedNode, name : &str, args : &[NodeId], sig : FunctionSignature)
   -> Result<MaybeVal, Error>
 {
+  if let Some(v) = codegen_vector_intrinsic_call(gf, node, name, args)? {
+    return Ok(v);
+  }
   if let [a, b, c] = args {
     let (a, b, c) = (node.get(*a), node.get(*b), node.get(*c));
     if name == "SetIndex" {
       return codegen_set_index(gf, a, b, c);
     }
+    if name == SLICE_LENGTH {
+      return Ok(reg(codegen_slice_length(gf, a, b, c)?.into()).into());
+    }
     panic!("COMPILER BUG: encountered unrecognised intrinsic, {}({}, {}, {}).",
       name, a.type_tag(), b.type_tag(), c.type_tag());
   }
@@ -903,14 +1490,35 @@ fn codegen_intrinsic_call(gf : &mut GenFunction, node : TypedNode, name : &str,
     let t = gf.gen.to_basic_type(node.info, sig.return_type).unwrap();
     return Ok(reg(const_zero(t)).into())
   }
+  else if args.len() == 0 && name == RETURN_ADDRESS {
+    return Ok(reg(codegen_return_address(gf).into()).into())
+  }
   panic!("COMPILER BUG: encountered unrecognised intrinsic {}", name)
 }
 
+/// Calls `llvm.returnaddress`, for the address the current function will return to
+fn codegen_return_address(gf : &mut GenFunction) -> PointerValue {
+  let f = match gf.gen.module.get_function("llvm.returnaddress") {
+    Some(f) => f,
+    None => {
+      let i8_ptr = gf.gen.context.i8_type().ptr_type(AddressSpace::Generic);
+      let fn_type = i8_ptr.fn_type(&[gf.gen.context.i32_type().into()], false);
+      gf.gen.module.add_function("llvm.returnaddress", fn_type, None)
+    }
+  };
+  let level = gf.gen.context.i32_type().const_int(0, false);
+  gf.builder.build_call(f, &[level.into()], "return_address")
+    .try_as_basic_value().left().unwrap().into_pointer_value()
+}
+
 impl <'l, 'a> GenFunction<'l, 'a> {
 
   pub fn new(gen: &'l mut Gen<'a>, builder : Builder, fn_val : FunctionValue) -> GenFunction<'l, 'a> {
     let variables = HashMap::new();
-    GenFunction{ gen, fn_val, builder, variables, blocks: vec![Block::new()], labels_in_scope: vec![] }
+    GenFunction{
+      gen, fn_val, builder, variables, blocks: vec![Block::new()], labels_in_scope: vec![],
+      tuple_return: None,
+    }
   }
 
   fn create_entry_block_alloca(&self, t : BasicTypeEnum, name : &str) -> PointerValue {
@@ -926,6 +1534,43 @@ impl <'l, 'a> GenFunction<'l, 'a> {
     pointer
   }
 
+  /// Allocates space for `length` values once, in the entry block, so that it's
+  /// reused if the code that needs it runs more than once
+  fn create_entry_block_array_alloca(&self, t : BasicTypeEnum, length : IntValue, name : &str) -> PointerValue {
+    let current_block = self.builder.get_insert_block().unwrap();
+    let function = self.fn_val;
+    let entry = function.get_first_basic_block().unwrap();
+    match entry.get_first_instruction() {
+      Some(fi) => self.builder.position_before(&fi),
+      None => self.builder.position_at_end(&entry),
+    }
+    let pointer = self.builder.build_array_alloca(t, length, name);
+    self.builder.position_at_end(&current_block);
+    pointer
+  }
+
+  /// Allocates an array with `tracked_malloc`, so that it counts against the unit
+  /// that owns the current function, and uses that unit's allocator (see allocations.rs)
+  fn build_tracked_array_malloc(&mut self, t : BasicTypeEnum, length : u64, name : &str) -> PointerValue {
+    let i64_type = self.gen.context.i64_type();
+    let i8_ptr = self.gen.context.i8_type().ptr_type(AddressSpace::Generic);
+    let malloc = match self.gen.module.get_function("tracked_malloc") {
+      Some(f) => f,
+      None => {
+        let fn_type = i8_ptr.fn_type(&[i64_type.into(), i8_ptr.into()], false);
+        let f = self.gen.module.add_function("tracked_malloc", fn_type, None);
+        self.gen.functions_to_link.push((f, SymbolLocation::CBind("tracked_malloc".into())));
+        f
+      }
+    };
+    let size = i64_type.const_int(self.gen.target_data.get_abi_size(&t) * length, false);
+    let caller = self.fn_val.as_global_value().as_pointer_value();
+    let caller = self.builder.build_pointer_cast(caller, i8_ptr, "caller");
+    let ptr = self.builder.build_call(malloc, &[size.into(), caller.into()], name)
+      .try_as_basic_value().left().unwrap().into_pointer_value();
+    self.builder.build_pointer_cast(ptr, self.gen.pointer_to_type(Some(t)), name)
+  }
+
   fn init_local_var(&mut self, var_id : ReferenceId, name: &str, value : BasicValueEnum) {
     let pointer = self.create_entry_block_alloca(value.get_type(), name);
     self.builder.build_store(pointer, value);
@@ -999,7 +1644,7 @@ impl <'l, 'a> GenFunction<'l, 'a> {
     match v.storage {
       Storage::Pointer => {
         let ptr = *v.value.as_pointer_value();
-        self.builder.build_load(ptr, "stack_value")
+        self.build_load(ptr, v.unaligned, "stack_value")
       }
       Storage::Register => {
         v.value
@@ -1114,35 +1759,96 @@ impl <'l, 'a> GenFunction<'l, 'a> {
     return error(convert_node, "type cast not supported");
   }
 
-  fn codegen_short_circuit_op(&mut self, a : TypedNode, b : TypedNode, op : ShortCircuitOp)
+  /// Compiles a switch to an LLVM `switch`, which can become a jump table
+  fn codegen_switch(&mut self, node : TypedNode, value : TypedNode, cases : &[(Vec<i64>, NodeId)], default : Option<NodeId>)
+    -> Result<MaybeVal, Error>
+  {
+    let f = self.fn_val;
+    let v = self.codegen_int(value)?;
+    let int_type = v.get_type();
+    let end_block = self.gen.context.append_basic_block(&f, "endswitch");
+    // create a block for each arm
+    let mut arms : Vec<(BasicBlock, NodeId)> =
+      cases.iter().map(|(_, body)| (self.gen.context.append_basic_block(&f, "case"), *body)).collect();
+    let default_arm = default.map(|d| (self.gen.context.append_basic_block(&f, "switch_default"), d));
+    {
+      let jumps : Vec<(IntValue, &BasicBlock)> =
+        cases.iter().zip(arms.iter())
+        .flat_map(|((cs, _), (block, _))| cs.iter().map(move |c| (int_type.const_int(*c as u64, false), block)))
+        .collect();
+      let else_block = default_arm.as_ref().map(|(b, _)| b).unwrap_or(&end_block);
+      self.builder.build_switch(v, else_block, &jumps);
+    }
+    arms.extend(default_arm);
+    // generate the arms
+    let mut results = vec![];
+    for (block, body) in arms.iter() {
+      self.builder.position_at_end(block);
+      let value = self.codegen_expression_to_register(node.get(*body))?;
+      results.push((value, self.builder.get_insert_block().unwrap()));
+    }
+    // As with `if`, arms that diverge can't reach the end if the others produce a value
+    let has_value = default.is_some() && node.type_tag().content != TypeContent::Prim(PType::Void);
+    let any_value = results.iter().any(|(v, _)| v.is_some());
+    for (value, end) in results.iter() {
+      self.builder.position_at_end(end);
+      if has_value && value.is_none() && any_value {
+        self.builder.build_unreachable();
+      }
+      else {
+        self.builder.build_unconditional_branch(&end_block);
+      }
+    }
+    self.builder.position_at_end(&end_block);
+    let incoming : Vec<_> = results.iter().filter_map(|(v, b)| v.as_ref().map(|v| (v, b))).collect();
+    match incoming.first() {
+      Some((v, _)) if has_value => {
+        let phi = self.builder.build_phi(v.get_type(), "switch_result");
+        for (v, b) in incoming.iter() {
+          phi.add_incoming(&[(*v, *b)]);
+        }
+        Ok(reg(phi.as_basic_value()).into())
+      }
+      _ => Ok(Void),
+    }
+  }
+
+  /// Returns `a` if it decides the result, and `b` otherwise. `a` decides `&&` if it
+  /// is false or none, and `||` if it is true or some.
+  fn codegen_short_circuit_op(&mut self, node : TypedNode, a : TypedNode, b : TypedNode, op : ShortCircuitOp)
     -> Result<GenVal, Error>
   {
     use ShortCircuitOp::*;
-    let short_circuit_outcome = match op {
-      And => self.gen.context.bool_type().const_int(0, false),
-      Or => self.gen.context.bool_type().const_int(1, false),
-    };
     // create basic blocks
     let f = self.fn_val;
     let b_start_block = self.gen.context.append_basic_block(&f, "b_block");
     let end_block = self.gen.context.append_basic_block(&f, "end");
-    // compute a
-    let a_value = self.codegen_int(a)?;
+    // compute a, and find out whether it's true or some
+    let a_value = self.codegen_value(a)?;
+    let a_set = match a_value {
+      BasicValueEnum::StructValue(sv) => {
+        let def = node.node_type_def().expect("short circuit operand is not an option");
+        let i = def.fields.iter().position(|(f, _)| f.name.as_ref() == "is_some").unwrap();
+        let index = self.gen.field_placements(node.info, def, node.type_tag())[i].index;
+        self.builder.build_extract_value(sv, index, "is_some").unwrap().into_int_value()
+      }
+      v => v.into_int_value(),
+    };
     let a_end_block = self.builder.get_insert_block().unwrap();
     match op {
-      And => self.builder.build_conditional_branch(a_value, &b_start_block, &end_block),
-      Or => self.builder.build_conditional_branch(a_value, &end_block, &b_start_block),
+      And => self.builder.build_conditional_branch(a_set, &b_start_block, &end_block),
+      Or => self.builder.build_conditional_branch(a_set, &end_block, &b_start_block),
     };
     // maybe compute b
     self.builder.position_at_end(&b_start_block);
-    let b_value = self.codegen_int(b)?;
+    let b_value = self.codegen_value(b)?;
     let b_end_block = self.builder.get_insert_block().unwrap();
     self.builder.build_unconditional_branch(&end_block);
     // end block
     self.builder.position_at_end(&end_block);
-    let phi = self.builder.build_phi(self.gen.context.bool_type(), "result");
+    let phi = self.builder.build_phi(a_value.get_type(), "result");
     phi.add_incoming(&[
-      (&short_circuit_outcome, &a_end_block),
+      (&a_value, &a_end_block),
       (&b_value, &b_end_block),
     ]);
     return Ok(reg(phi.as_basic_value()));
@@ -1186,7 +1892,159 @@ impl <'l, 'a> GenFunction<'l, 'a> {
     }
   }
 
+  /// Combines the values of a struct's bitfields into the fields that hold them
+  fn codegen_pack_bitfields(&mut self, info : &CompileInfo, def : &TypeDefinition, t : &Type, values : Vec<BasicValueEnum>)
+    -> Vec<BasicValueEnum>
+  {
+    if def.bitfields.is_empty() {
+      return values;
+    }
+    let mut fields : Vec<BasicValueEnum> = vec![];
+    let placements = self.gen.field_placements(info, def, t);
+    for (v, p) in values.into_iter().zip(placements.iter()) {
+      match p.bits {
+        None => fields.push(v),
+        Some((offset, width)) => {
+          let v = v.into_int_value();
+          let storage =
+            if p.index as usize == fields.len() {
+              match p.storage_bytes {
+                Some(bytes) => self.gen.context.custom_width_int_type(bytes * 8).const_int(0, false),
+                None => v.get_type().const_int(0, false),
+              }
+            }
+            else { fields.pop().unwrap().into_int_value() };
+          fields.push(self.codegen_insert_bits(storage, v, offset, width).into());
+        }
+      }
+    }
+    // System V runs are held in arrays of bytes
+    for p in placements.iter() {
+      if let Some(bytes) = p.storage_bytes {
+        let i = p.index as usize;
+        if fields[i].is_int_value() {
+          fields[i] = self.reinterpret(fields[i], self.gen.context.i8_type().array_type(bytes).into());
+        }
+      }
+    }
+    fields
+  }
+
+  /// The integer that holds a run of bitfields, from the field that holds it
+  fn bitfield_storage_int(&mut self, storage : BasicValueEnum) -> IntValue {
+    match storage {
+      BasicValueEnum::ArrayValue(a) => {
+        let bytes = a.get_type().len();
+        let t = self.gen.context.custom_width_int_type(bytes * 8);
+        self.reinterpret(storage, t.into()).into_int_value()
+      }
+      v => v.into_int_value(),
+    }
+  }
+
+  /// Changes the width of an integer, extending it with its sign if it's signed
+  fn resize_int(&mut self, v : IntValue, t : IntType, signed : bool) -> IntValue {
+    let (from, to) = (v.get_type().get_bit_width(), t.get_bit_width());
+    if from > to { self.builder.build_int_truncate(v, t, "resized") }
+    else if from == to { v }
+    else if signed { self.builder.build_int_s_extend(v, t, "resized") }
+    else { self.builder.build_int_z_extend(v, t, "resized") }
+  }
+
+  /// Reads a bitfield out of the integer that holds it, as an integer of type `t`
+  fn codegen_extract_bits(&mut self, storage : IntValue, offset : u32, width : u32, t : IntType, signed : bool) -> IntValue {
+    let st = storage.get_type();
+    let bits = st.get_bit_width();
+    // shift the bitfield to the top and back down, so that signed ones are sign extended
+    let up = st.const_int((bits - offset - width) as u64, false);
+    let down = st.const_int((bits - width) as u64, false);
+    let v = self.builder.build_left_shift(storage, up, "bitfield");
+    let v = self.builder.build_right_shift(v, down, signed, "bitfield");
+    self.resize_int(v, t, signed)
+  }
+
+  /// Writes a value into a bitfield of the integer that holds it
+  fn codegen_insert_bits(&mut self, storage : IntValue, value : IntValue, offset : u32, width : u32) -> IntValue {
+    let t = storage.get_type();
+    let bits = t.get_bit_width();
+    // the storage can be wider than 64 bits, so the masks are built at its width
+    let ones = self.builder.build_not(t.const_int(0, false), "ones");
+    let mask = self.builder.build_right_shift(ones, t.const_int((bits - width) as u64, false), false, "mask");
+    let placed_mask = self.builder.build_left_shift(mask, t.const_int(offset as u64, false), "mask");
+    let keep = self.builder.build_not(placed_mask, "keep");
+    let cleared = self.builder.build_and(storage, keep, "cleared");
+    let v = self.resize_int(value, t, false);
+    let v = self.builder.build_and(v, mask, "masked");
+    let v = self.builder.build_left_shift(v, t.const_int(offset as u64, false), "shifted");
+    self.builder.build_or(cleared, v, "bitfield")
+  }
+
+  /// Loads through a pointer. Unaligned loads, from the fields of packed structs, are
+  /// given an alignment of 1, as LLVM would otherwise assume the type's alignment.
+  fn build_load(&mut self, ptr : PointerValue, unaligned : bool, name : &str) -> BasicValueEnum {
+    let v = self.builder.build_load(ptr, name);
+    if unaligned {
+      v.as_instruction_value().unwrap().set_alignment(1).unwrap();
+    }
+    v
+  }
+
+  /// Stores through a pointer (see `build_load`)
+  fn build_store(&mut self, ptr : PointerValue, value : BasicValueEnum, unaligned : bool) {
+    let store = self.builder.build_store(ptr, value);
+    if unaligned {
+      store.set_alignment(1).unwrap();
+    }
+  }
+
+  /// Whether a field access reads from a packed struct
+  fn packed_container(&self, container : TypedNode) -> bool {
+    let mut ct = container.type_tag();
+    while let Some(inner) = ct.ptr() {
+      ct = inner;
+    }
+    match &ct.content {
+      TypeContent::Def(name, unit_id) =>
+        container.info.find_type_def(name, *unit_id).map(|def| def.layout.packed).unwrap_or(false),
+      _ => false,
+    }
+  }
+
+  /// The struct that a field is accessed from, dereferencing any pointers to it
+  fn codegen_field_container(&mut self, container : TypedNode) -> Result<GenVal, Error> {
+    let mut v = self.codegen_expression(container)?.unwrap();
+    let mut ct = container.type_tag();
+    while let Some(inner) = ct.ptr() {
+      ct = inner;
+      let ptr = self.genval_to_register(v);
+      v = pointer(*ptr.as_pointer_value());
+    }
+    Ok(v)
+  }
+
+  /// If a node accesses a bitfield, the struct it's accessed from and where the bitfield is
+  fn bitfield_access<'a>(&mut self, node : TypedNode<'a>) -> Option<(TypedNode<'a>, FieldPlacement)> {
+    let (container, field) = match node.content() {
+      Content::FieldAccess{ container, field } => (node.get(*container), field),
+      _ => return None,
+    };
+    let mut ct = container.type_tag();
+    while let Some(inner) = ct.ptr() {
+      ct = inner;
+    }
+    let def = match &ct.content {
+      TypeContent::Def(name, unit_id) => node.info.find_type_def(name, *unit_id)?,
+      _ => return None,
+    };
+    def.bitfield_width(&field.name)?;
+    let i = def.fields.iter().position(|(n, _)| n.name.as_ref() == field.name.as_ref())?;
+    Some((container, self.gen.field_placements(node.info, def, ct)[i]))
+  }
+
   fn codegen_address_of_expression(&mut self, value : TypedNode) -> Result<GenVal, Error> {
+    if self.bitfield_access(value).is_some() {
+      return error(value, "can't take the address of a bitfield");
+    }
     let v = self.codegen_expression(value)?.unwrap();
     Ok(reg(self.codegen_address_of_genval(v)?.into()))
   }
@@ -1208,9 +2066,14 @@ impl <'l, 'a> GenFunction<'l, 'a> {
         let gv = self.get_linked_global_reference(info, def);
         pointer(gv.as_pointer_value())
       }
+      SymbolInit::Lazy(_) => {
+        let initialiser = self.get_linked_lazy_initialiser(def);
+        self.build_function_value_call(initialiser, &[], "void");
+        let gv = self.get_linked_global_reference(info, def);
+        pointer(gv.as_pointer_value())
+      }
       SymbolInit::Function(_) => {
-        let fv = self.get_linked_function_reference(info, def);
-        reg(fv.as_global_value().as_pointer_value().into())
+        reg(self.get_linked_function_reference(info, def).into())
       }
       SymbolInit::CBind => {
         if let Some(sig) = def.type_tag.sig() {
@@ -1218,10 +2081,8 @@ impl <'l, 'a> GenFunction<'l, 'a> {
             local_f
           }
           else {
-            let f = self.gen.codegen_prototype(info, &def.name, sig.return_type, None, sig.args);
             let symloc = SymbolLocation::CBind(def.name.clone());
-            self.gen.functions_to_link.push((f, symloc));
-            f
+            self.gen.codegen_cbind_prototype(info, &def.name, sig, symloc)
           };
           reg(fv.as_global_value().as_pointer_value().into())
         }
@@ -1252,25 +2113,46 @@ impl <'l, 'a> GenFunction<'l, 'a> {
     }
   }
 
-  fn get_linked_function_reference(&mut self, info: &CompileInfo, def : &SymbolDefinition) -> FunctionValue {
+  fn get_linked_lazy_initialiser(&mut self, def : &SymbolDefinition) -> FunctionValue {
+    let name = lazy_initialiser_name(&def.name);
+    if let Some(f) = self.gen.module.get_function(&name) {
+      f
+    }
+    else {
+      let fn_type = self.gen.context.void_type().fn_type(&[], false);
+      let f = self.gen.module.add_function(&name, fn_type, None);
+      let symloc = SymbolLocation::LazyInitialiser(def.unit_id, def.id);
+      self.gen.functions_to_link.push((f, symloc));
+      f
+    }
+  }
+
+  /// Returns a pointer to the function. Functions in the same unit group are referenced
+  /// directly, and functions in other units are loaded from their call slots, so that
+  /// they can be patched when their unit is reloaded.
+  fn get_linked_function_reference(&mut self, info: &CompileInfo, def : &SymbolDefinition) -> PointerValue {
     if def.is_polymorphic() {
       panic!("{} {}", "Tried to get the address of a polymorphic function definition.",
         "This will always fail, and means there is a bug somewhere earlier in the pipeline.");
     }
     match &def.initialiser {
       SymbolInit::Function(init) => {
-        // Check if it was already added
+        // Every function in the unit group was declared up front
         if let Some(f) = self.gen.module.get_function(&init.name_for_codegen) {
-          f
+          return f.as_global_value().as_pointer_value();
         }
-        // Else find and include it
-        else {
-          let sig = def.type_tag.sig().unwrap();
-          let f = self.gen.codegen_prototype(info, &init.name_for_codegen, sig.return_type, Some(&init.args), sig.args);
-          let symloc = SymbolLocation::Function(def.unit_id, def.id);
-          self.gen.functions_to_link.push((f, symloc));
-          f
+        let slot_name = format!("{}.slot", init.name_for_codegen);
+        let slot = if let Some(gv) = self.gen.module.get_global(&slot_name) {
+          gv
         }
+        else {
+          let t = self.gen.to_basic_type(info, &def.type_tag).unwrap();
+          let gv = self.gen.module.add_global(t, Some(AddressSpace::Generic), &slot_name);
+          let symloc = SymbolLocation::FunctionSlot(def.unit_id, def.id);
+          self.gen.globals_to_link.push((gv, symloc));
+          gv
+        };
+        *self.builder.build_load(slot.as_pointer_value(), &def.name).as_pointer_value()
       }
       _ => panic!("expected function initialiser"),
     }
@@ -1287,8 +2169,12 @@ impl <'l, 'a> GenFunction<'l, 'a> {
     }
   }
 
-  fn build_function_pointer_call(&mut self, f : PointerValue, args : &[BasicValueEnum], name : &str) -> MaybeVal {
+  fn build_function_pointer_call(
+    &mut self, f : PointerValue, args : &[BasicValueEnum], convention : CallingConvention, name : &str)
+      -> MaybeVal
+  {
     let call = self.builder.build_call(f, args, name);
+    call.set_call_convention(c_abi::calling_convention_id(convention));
     let r = call.try_as_basic_value().left();
     return r.map(reg).map(IsVal).unwrap_or(Void);
   }
@@ -1305,6 +2191,9 @@ impl <'l, 'a> GenFunction<'l, 'a> {
     // Check if it's an intrinsic
     if function.is_intrinsic_function() {
       let name = &function.node_symbol_def().unwrap().name;
+      if node.method_receiver() != MethodReceiver::Value {
+        return error(node, format!("intrinsic '{}' can't take its receiver by reference or dereferenced", name));
+      }
       return codegen_intrinsic_call(self, node, name, args, function.type_tag().sig().unwrap());
     }
 
@@ -1316,13 +2205,54 @@ impl <'l, 'a> GenFunction<'l, 'a> {
     else {
       self.codegen_pointer(function)?
     };
+    let sig = function.type_tag().sig().unwrap();
     let mut arg_vals = vec!();
-    for &a in args.iter() {
+    for (i, &a) in args.iter().enumerate() {
       let a = node.get(a);
-      let v = self.codegen_value(a)?;
+      if a.is_thinned() {
+        arg_vals.push(self.codegen_thinned_closure(a, &sig.args[i])?);
+        continue;
+      }
+      let v = match node.method_receiver() {
+        MethodReceiver::Reference if i == 0 => {
+          let p = self.codegen_address_of_expression(a)?;
+          self.genval_to_register(p)
+        }
+        MethodReceiver::Dereference(levels) if i == 0 => {
+          let mut v = self.codegen_value(a)?;
+          for _ in 0..levels {
+            v = self.builder.build_load(*v.as_pointer_value(), "receiver");
+          }
+          v
+        }
+        _ => self.codegen_value(a)?,
+      };
       arg_vals.push(v);
     }
-    Ok(self.build_function_pointer_call(function_pointer, arg_vals.as_slice(), "return_val"))
+    let convention = sig.convention;
+    match self.gen.tuple_return(node.info, sig.return_type) {
+      Some((t, PassAs::Indirect{ .. })) => {
+        let sret = self.create_entry_block_alloca(t, "sret");
+        arg_vals.insert(0, sret.into());
+        self.build_function_pointer_call(function_pointer, arg_vals.as_slice(), convention, "void");
+        Ok(reg(self.builder.build_load(sret, "return_val")).into())
+      }
+      Some((t, PassAs::Coerce(_))) => {
+        match self.build_function_pointer_call(function_pointer, arg_vals.as_slice(), convention, "return_val") {
+          IsVal(v) => {
+            let v = self.genval_to_register(v);
+            Ok(reg(self.reinterpret(v, t)).into())
+          }
+          Void => Ok(Void),
+        }
+      }
+      _ => Ok(self.build_function_pointer_call(function_pointer, arg_vals.as_slice(), convention, "return_val")),
+    }
+  }
+
+  /// Reinterprets a value as a different type of the same size
+  fn reinterpret(&self, v : BasicValueEnum, t : BasicTypeEnum) -> BasicValueEnum {
+    self.gen.reinterpret(&self.builder, v, t, &|t| self.create_entry_block_alloca(t, "abi_cast"))
   }
 
   fn get_linked_drop_reference(&mut self, _info : &CompileInfo, _t : &Type) -> Option<FunctionValue> {
@@ -1403,14 +2333,78 @@ impl <'l, 'a> GenFunction<'l, 'a> {
       Content::FunctionCall{ function, args } => {
         return self.codegen_function_call(node, node.get(*function), args);
       }
-      Content::SizeOf{ .. } => {
+      Content::SizeOf{ query, .. } => {
         let sizeof_type = node.sizeof_type().expect("sizeof node has no type associated with it");
         let t = self.gen.to_basic_type(info, &sizeof_type);
-        reg(self.gen.size_of_type(t).into())
+        match query {
+          LayoutQuery::Size => reg(self.gen.size_of_type(t).into()),
+          LayoutQuery::Alignment => {
+            let alignment = t.map(|t| self.gen.target_data.get_abi_alignment(&t)).unwrap_or(1);
+            reg(self.gen.context.i64_type().const_int(alignment as u64, false).into())
+          }
+          LayoutQuery::Offset(field) => {
+            let def = match &sizeof_type.content {
+              TypeContent::Def(name, unit_id) => info.find_type_def(name, *unit_id).unwrap(),
+              _ => panic!("offsetof type is not a type definition"),
+            };
+            let offset = match def.kind {
+              TypeKind::Struct => {
+                let i = def.fields.iter().position(|(f, _)| f.name == field.name).unwrap();
+                let struct_type = t.unwrap().into_struct_type();
+                let index = self.gen.field_placements(info, def, &sizeof_type)[i].index;
+                self.gen.target_data.offset_of_element(&struct_type, index).unwrap()
+              }
+              // every field of a union starts at the beginning
+              TypeKind::Union => 0,
+              // the fields of a tagged union come after its tag
+              TypeKind::TaggedUnion => {
+                let i = def.fields.iter().position(|(f, _)| f.name == field.name).unwrap();
+                let field_type = self.gen.field_basic_types(info, def, &sizeof_type)[i];
+                let variant_type = self.gen.variant_type(field_type);
+                self.gen.target_data.offset_of_element(&variant_type, 1).unwrap()
+              }
+            };
+            reg(self.gen.context.i64_type().const_int(offset, false).into())
+          }
+          LayoutQuery::TypeInfo => {
+            let shape = match self.gen.type_shape(info, &sizeof_type) {
+              Some(shape) => shape,
+              None => return error(node, format!(
+                "typeinfo only describes numbers, bools and structs of them, not '{}'", sizeof_type)),
+            };
+            let size = t.map(|t| self.gen.target_data.get_abi_size(&t)).unwrap_or(0);
+            let id = reflection::register(TypeDescription{ size, shape });
+            reg(self.gen.context.i64_type().const_int(id, false).into())
+          }
+          LayoutQuery::BitOffset(field) => {
+            let def = match &sizeof_type.content {
+              TypeContent::Def(name, unit_id) => info.find_type_def(name, *unit_id).unwrap(),
+              _ => panic!("bitoffsetof type is not a type definition"),
+            };
+            let i = def.fields.iter().position(|(f, _)| f.name == field.name).unwrap();
+            let placement = self.gen.field_placements(info, def, &sizeof_type)[i];
+            let (bit_offset, _) = placement.bits.expect("bitoffsetof field is not a bitfield");
+            let struct_type = t.unwrap().into_struct_type();
+            let offset = self.gen.target_data.offset_of_element(&struct_type, placement.index).unwrap();
+            let offset = offset * 8 + bit_offset as u64;
+            reg(self.gen.context.i64_type().const_int(offset, false).into())
+          }
+        }
       }
       Content::Convert{ from_value, .. } => {
         self.codegen_convert(node, node.get(*from_value))?
       }
+      Content::ShortCircuit{ op, left, right, function } => {
+        let function = node.get(*function);
+        if function.node_symbol_def().is_some() {
+          // an overload of the operator, which is called like any other function
+          return self.codegen_function_call(node, function, &[*left, *right]);
+        }
+        self.codegen_short_circuit_op(node, node.get(*left), node.get(*right), *op)?
+      }
+      Content::Switch{ value, cases, default, .. } => {
+        return self.codegen_switch(node, node.get(*value), cases, *default);
+      }
       Content::While{ condition, body } => {
         let (cond_node, body_node) = (node.get(*condition), node.get(*body));
         let f = self.fn_val;
@@ -1427,8 +2421,14 @@ impl <'l, 'a> GenFunction<'l, 'a> {
         self.builder.position_at_end(&body_block);
         self.codegen_expression(body_node)?;
 
-        // loop back to start
-        self.builder.build_unconditional_branch(&cond_block);
+        // loop back to start, unless the loop is cancelled at its safepoint
+        if self.gen.options.safepoints || self.gen.options.sandbox.is_some() {
+          let cancelled = codegen_safepoint(self);
+          self.builder.build_conditional_branch(cancelled, &exit_block, &cond_block);
+        }
+        else {
+          self.builder.build_unconditional_branch(&cond_block);
+        }
         // exit
         self.builder.position_at_end(&exit_block);
         return Ok(Void);
@@ -1459,12 +2459,22 @@ impl <'l, 'a> GenFunction<'l, 'a> {
         let value = self.codegen_expression_to_register(body)?;
         let block = self.builder.get_insert_block().unwrap();
         let (_, label_state) = self.labels_in_scope.pop().unwrap();
-        self.builder.build_unconditional_branch(&label_state.exit_block);
+        let phi_values = label_state.phi_values;
+        if value.is_none() && phi_values.len() > 0 {
+          // The body diverges, so every value arrives through a break
+          self.builder.build_unreachable();
+        }
+        else {
+          self.builder.build_unconditional_branch(&label_state.exit_block);
+        }
         self.builder.position_at_end(&label_state.exit_block);
-        if let Some(v) = value {
-          let phi = self.builder.build_phi(v.get_type(), "label_return");
-          phi.add_incoming(&[(&v, &block)]);
-          for (v, b) in label_state.phi_values.iter() {
+        let t = value.map(|v| v.get_type()).or(phi_values.first().map(|(v, _)| v.get_type()));
+        if let Some(t) = t {
+          let phi = self.builder.build_phi(t, "label_return");
+          if let Some(v) = value {
+            phi.add_incoming(&[(&v, &block)]);
+          }
+          for (v, b) in phi_values.iter() {
             phi.add_incoming(&[(v, b)]);
           }
           reg(phi.as_basic_value())
@@ -1490,26 +2500,35 @@ impl <'l, 'a> GenFunction<'l, 'a> {
         self.builder.position_at_end(&then_start_block);
         let then_value = self.codegen_expression_to_register(then_branch)?;
         let then_end_block = self.builder.get_insert_block().unwrap();
-        self.builder.build_unconditional_branch(&end_block);
         // else block
         self.builder.position_at_end(&else_start_block);
         let else_value = self.codegen_expression_to_register(else_branch)?;
         let else_end_block = self.builder.get_insert_block().unwrap();
-        self.builder.build_unconditional_branch(&end_block);
+        // If the if expression has a value but only one branch produced one,
+        // the other branch diverged and its end can't be reached.
+        let has_value = node.type_tag().content != TypeContent::Prim(PType::Void);
+        for (value, end) in &[(then_value, then_end_block), (else_value, else_end_block)] {
+          self.builder.position_at_end(end);
+          if has_value && value.is_none() && (then_value.is_some() || else_value.is_some()) {
+            self.builder.build_unreachable();
+          }
+          else {
+            self.builder.build_unconditional_branch(&end_block);
+          }
+        }
         // end block
         self.builder.position_at_end(&end_block);
-        if then_value.is_some() && else_value.is_some() {
-          let v1 = then_value.unwrap();
-          let v2 = else_value.unwrap();
-          let phi = self.builder.build_phi(v1.get_type(), "if_result");
-          phi.add_incoming(&[
-            (&v1, &then_end_block),
-            (&v2, &else_end_block),
-          ]);
-          reg(phi.as_basic_value())
-        }
-        else {
-          return Ok(Void);
+        match (then_value, else_value) {
+          (Some(v1), Some(v2)) => {
+            let phi = self.builder.build_phi(v1.get_type(), "if_result");
+            phi.add_incoming(&[
+              (&v1, &then_end_block),
+              (&v2, &else_end_block),
+            ]);
+            reg(phi.as_basic_value())
+          }
+          (Some(v), None) | (None, Some(v)) if has_value => reg(v),
+          _ => return Ok(Void),
         }
       }
       Content::Block(nodes) => {
@@ -1562,21 +2581,29 @@ impl <'l, 'a> GenFunction<'l, 'a> {
         let t = self.gen.composite_type(info, def, node.type_tag());
         match def.kind {
           TypeKind::Struct => {
-            self.codegen_struct_initialise(t, a?.as_slice())
+            let a = self.codegen_pack_bitfields(info, def, node.type_tag(), a?);
+            self.codegen_struct_initialise(t, a.as_slice())
           }
           TypeKind::Union => {
             self.codegen_union_initialise(t.into(), a?[0])
           }
+          TypeKind::TaggedUnion => {
+            let variant = field_values[0].0.as_ref().unwrap();
+            let i = def.fields.iter().position(|(f, _)| f.name == variant.name).unwrap();
+            let field_type = self.gen.field_basic_types(info, def, node.type_tag())[i];
+            let variant_type = self.gen.variant_type(field_type);
+            let tag = self.gen.context.i8_type().const_int(i as u64, false);
+            let v = self.codegen_struct_initialise(variant_type, &[tag.into(), a?[0]]);
+            self.codegen_union_initialise(t.into(), v.value)
+          }
         }
       }
       Content::FieldAccess{ container, field } => {
         let container = node.get(*container);
-        let mut v = self.codegen_expression(container)?.unwrap();
+        let v = self.codegen_field_container(container)?;
         let mut ct = container.type_tag();
         while let Some(inner) = ct.ptr() {
           ct = inner;
-          let ptr = self.genval_to_register(v);
-          v = pointer(*ptr.as_pointer_value());
         }
         let def = match &ct.content {
           TypeContent::Def(name, unit_id) => {
@@ -1586,16 +2613,35 @@ impl <'l, 'a> GenFunction<'l, 'a> {
         };
         match def.kind {
           TypeKind::Struct => {
-            let (field_index, _) =
-              def.fields.iter().enumerate()
-              .find(|(_, (n, _))| n.name.as_ref() == field.name.as_ref()).unwrap();
+            let i = def.fields.iter().position(|(n, _)| n.name.as_ref() == field.name.as_ref()).unwrap();
+            let placement = self.gen.field_placements(info, def, ct)[i];
+            let field_index = placement.index;
+            if let Some((offset, width)) = placement.bits {
+              // bitfields are read into a register, because they have no address
+              let storage = match v.storage {
+                Storage::Register =>
+                  self.builder.build_extract_value(
+                    *v.value.as_struct_value(), field_index, &field.name).unwrap(),
+                Storage::Pointer => {
+                  let ptr = unsafe {
+                    self.builder.build_struct_gep(*v.value.as_pointer_value(), field_index, &field.name)
+                  };
+                  self.build_load(ptr, def.layout.packed || v.unaligned, &field.name)
+                }
+              };
+              let signed = node.type_tag().signed_int();
+              let storage = self.bitfield_storage_int(storage);
+              let t = self.gen.to_basic_type(info, node.type_tag()).unwrap().into_int_type();
+              let bits = self.codegen_extract_bits(storage, offset, width, t, signed);
+              return Ok(reg(bits.into()).into());
+            }
             let field_type = self.gen.to_basic_type(info, node.type_tag());
             match v.storage {
               Storage::Register => {
                 // if the struct is in a register, dereference the field into a register
                 let mut reg_val =
                   self.builder.build_extract_value(
-                    *v.value.as_struct_value(), field_index as u32, &field.name).unwrap();
+                    *v.value.as_struct_value(), field_index, &field.name).unwrap();
                 if node.type_tag().pointer() {
                   // this cast is necessary because all pointer fields are tagged as void pointers
                   // in the IR, due to an issue with generating cyclic references.
@@ -1611,7 +2657,7 @@ impl <'l, 'a> GenFunction<'l, 'a> {
                 // if this is a pointer to the struct, get a pointer to the field
                 let ptr = *v.value.as_pointer_value();
                 let field_ptr_untyped = unsafe {
-                  self.builder.build_struct_gep(ptr, field_index as u32, &field.name)
+                  self.builder.build_struct_gep(ptr, field_index, &field.name)
                 };
                 // this cast is necessary because all pointer fields are tagged as void pointers
                 // in the IR, due to an issue with generating cyclic references.
@@ -1621,7 +2667,8 @@ impl <'l, 'a> GenFunction<'l, 'a> {
                 let field_ptr =
                   self.builder.build_pointer_cast(
                     field_ptr_untyped, self.gen.pointer_to_type(field_type), "field_cast");
-                pointer(field_ptr)
+                if def.layout.packed || v.unaligned { unaligned_pointer(field_ptr) }
+                else { pointer(field_ptr) }
               }
             }
           }
@@ -1638,7 +2685,32 @@ impl <'l, 'a> GenFunction<'l, 'a> {
                 // if this is a pointer to the struct, get a pointer to the field
                 let ptr = *v.value.as_pointer_value();
                 let field_ptr = self.builder.build_pointer_cast(ptr, self.gen.pointer_to_type(t), "union_cast");
-                pointer(field_ptr)
+                if v.unaligned { unaligned_pointer(field_ptr) } else { pointer(field_ptr) }
+              }
+            }
+          }
+          TypeKind::TaggedUnion => {
+            // only `match` reads tagged unions, to test the tag or read the variant it found
+            let ptr = self.codegen_address_of_genval(v)?;
+            let access = VariantAccess::from_field(&field.name).expect("tagged union read outside of a match");
+            let i = def.fields.iter().position(|(f, _)| f.name.as_ref() == access.variant()).unwrap();
+            match access {
+              VariantAccess::Test(_) => {
+                let i8_type = self.gen.context.i8_type();
+                let tag_ptr = self.builder.build_pointer_cast(ptr, i8_type.ptr_type(AddressSpace::Generic), "tag_ptr");
+                let tag = self.builder.build_load(tag_ptr, "tag").into_int_value();
+                let expected = i8_type.const_int(i as u64, false);
+                reg(self.builder.build_int_compare(IntPredicate::EQ, tag, expected, "is_variant").into())
+              }
+              VariantAccess::Read(_) => {
+                let field_type = self.gen.field_basic_types(info, def, ct)[i];
+                let variant_type = self.gen.variant_type(field_type);
+                let variant_ptr =
+                  self.builder.build_pointer_cast(ptr, variant_type.ptr_type(AddressSpace::Generic), "variant_ptr");
+                let value_ptr = unsafe { self.builder.build_struct_gep(variant_ptr, 1, &field.name) };
+                // pointer fields are stored as void pointers, as in structs
+                let t = self.gen.to_basic_type(info, node.type_tag());
+                pointer(self.builder.build_pointer_cast(value_ptr, self.gen.pointer_to_type(t), "field_cast"))
               }
             }
           }
@@ -1657,7 +2729,13 @@ impl <'l, 'a> GenFunction<'l, 'a> {
         if let [inner_type] = node.type_tag().children() {
           let element_type = self.gen.to_basic_type(info, inner_type).unwrap();
           let length = self.gen.context.i32_type().const_int(elements.len() as u64, false).into();
-          let array_ptr = self.builder.build_array_malloc(element_type, length, "array_malloc");
+          let array_ptr =
+            if info.nodes.stack_arrays.contains(&node.node.id) {
+              self.create_entry_block_array_alloca(element_type, length, "array_alloca")
+            }
+            else {
+              self.build_tracked_array_malloc(element_type, elements.len() as u64, "array_malloc")
+            };
           for (i, e) in elements.iter().enumerate() {
             let v = self.codegen_value(node.get(*e))?;
             let index = self.gen.context.i32_type().const_int(i as u64, false).into();
@@ -1684,6 +2762,29 @@ impl <'l, 'a> GenFunction<'l, 'a> {
       }
       Content::Assignment{ assignee, value } => {
         let (assignee, value) = (node.get(*assignee), node.get(*value));
+        if let Some((container, placement)) = self.bitfield_access(assignee) {
+          // read the integer that holds the bitfield, change its bits, and write it back
+          let (offset, width) = placement.bits.unwrap();
+          let v = self.codegen_field_container(container)?;
+          let unaligned = v.unaligned || self.packed_container(container);
+          let struct_ptr = match v.storage {
+            Storage::Pointer => *v.value.as_pointer_value(),
+            Storage::Register => return error(assignee, "cannot assign to this construct"),
+          };
+          let val = self.codegen_value(value)?.into_int_value();
+          let storage_ptr = unsafe {
+            self.builder.build_struct_gep(struct_ptr, placement.index, "bitfield_storage")
+          };
+          let loaded = self.build_load(storage_ptr, unaligned, "bitfield_storage");
+          let storage = self.bitfield_storage_int(loaded);
+          let storage = self.codegen_insert_bits(storage, val, offset, width);
+          let storage = match loaded {
+            BasicValueEnum::ArrayValue(a) => self.reinterpret(storage.into(), a.get_type().into()),
+            _ => storage.into(),
+          };
+          self.build_store(storage_ptr, storage, unaligned);
+          return Ok(Void);
+        }
         let assign_location = self.codegen_expression(assignee)?.unwrap();
         let assign_ptr = match assign_location.storage {
           Storage::Pointer => {
@@ -1703,7 +2804,7 @@ impl <'l, 'a> GenFunction<'l, 'a> {
         // TODO: this is very inefficient when assigning large structs. Can optimise
         // by detecting pointers and using the memcopy intrinsic
         let reg_val = self.maybeval_to_register(val).unwrap();
-        self.builder.build_store(assign_ptr, reg_val);
+        self.build_store(assign_ptr, reg_val, assign_location.unaligned);
         return Ok(Void);
       }
       Content::VariableInitialise{ name, type_tag: _, value, var_scope } => {
@@ -1713,6 +2814,8 @@ impl <'l, 'a> GenFunction<'l, 'a> {
             let v = self.codegen_value(value)?;
             self.init_local_var(name.id, &name.name, v);
           }
+          // initialised by its lazy initialiser instead
+          VarScope::Global(GlobalType::Lazy) => (),
           VarScope::Global(_) => {
             let aaa = (); // THIS SHOULDN'T HAPPEN FOR CONST GLOBALS
             let v = self.codegen_value(value)?;
@@ -1793,7 +2896,18 @@ impl <'l, 'a> GenFunction<'l, 'a> {
     // TODO: Call the necessary Drop and Clone functions
     if let Some(value_node) = value_node {
       let v = self.codegen_expression_to_register(value_node)?;
-      self.builder.build_return(v.as_ref().map(|v| v as &dyn BasicValue));
+      match (self.tuple_return, v) {
+        (Some((_, PassAs::Indirect{ .. })), Some(v)) => {
+          let sret = self.fn_val.get_first_param().unwrap().into_pointer_value();
+          self.builder.build_store(sret, v);
+          self.builder.build_return(None);
+        }
+        (Some((_, PassAs::Coerce(t))), Some(v)) => {
+          let v = self.reinterpret(v, t);
+          self.builder.build_return(Some(&v));
+        }
+        _ => { self.builder.build_return(v.as_ref().map(|v| v as &dyn BasicValue)); }
+      }
     }
     else {
       self.builder.build_return(None);
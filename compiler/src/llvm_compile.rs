@@ -8,7 +8,8 @@ use error::Error;
 use c_interface::CSymbols;
 use types::{SymbolId, SymbolInit};
 use code_store::{CodeStore, CodegenId};
-use llvm_codegen::Gen;
+use llvm_codegen::{Gen, lazy_initialiser_name, CODE_END};
+use compiler::CompileOptions;
 
 use inkwell::context::{Context};
 use inkwell::passes::PassManager;
@@ -19,8 +20,11 @@ use inkwell::module::Module;
 
 pub enum SymbolLocation {
   CBind(RefStr),
-  Function(UnitId, SymbolId),
+  /// The call slot of a function in another unit (see call_slots.rs)
+  FunctionSlot(UnitId, SymbolId),
   Global(UnitId, SymbolId),
+  /// The function that initialises a lazy static
+  LazyInitialiser(UnitId, SymbolId),
 }
 
 pub struct LlvmUnit {
@@ -54,6 +58,7 @@ impl LlvmCompiler {
     codegen_id : CodegenId,
     unit_group : &[UnitId],
     code_store : &CodeStore,
+    options : CompileOptions,
   ) -> Result<LlvmUnit, Error>
   {
     let name = code_store.name(unit_group[0]);
@@ -64,7 +69,7 @@ impl LlvmCompiler {
       .expect("could not create execution engine");
 
     let pm = PassManager::create(&llvm_module);
-    if compiler::ENABLE_IR_OPTIMISATION {
+    if options.optimise {
       pm.add_instruction_combining_pass();
       pm.add_reassociate_pass();
       pm.add_gvn_pass();
@@ -81,19 +86,28 @@ impl LlvmCompiler {
     {
       let gen = Gen::new(
         &self.context, &mut llvm_module, &mut ee.get_target_data(),
-        &mut globals_to_link, &mut functions_to_link, &pm);
+        &mut globals_to_link, &mut functions_to_link, &pm, options);
       gen.codegen_module(unit_group, code_store)?
     };
 
-    if compiler::DEBUG_PRINTING_IR {
-      println!("{}", llvm_module.print_to_string());
-    }
-
     let lu = LlvmUnit { codegen_id, ee, llvm_module, globals_to_link, functions_to_link };
     Ok(lu)
   }
 }
 
+/// The address of a compiled function
+pub fn function_symbol_address(code_store : &CodeStore, unit_id : UnitId, symbol_id : SymbolId) -> usize {
+  let def = code_store.types(unit_id).symbols.get(&symbol_id).unwrap();
+  let init = match &def.initialiser {
+    SymbolInit::Function(init) => init, _ => panic!("expected function initialiser") 
+  };
+  let lu = code_store.llvm_unit(unit_id);
+  unsafe {
+    lu.ee.get_function_address(&init.name_for_codegen)
+      .expect("function pointer was null") as usize
+  }
+}
+
 fn find_symbol_address(code_store : &CodeStore, c_symbols : &CSymbols, loc : &SymbolLocation) -> usize {
   match loc {
     SymbolLocation::CBind(name) => {
@@ -104,23 +118,23 @@ fn find_symbol_address(code_store : &CodeStore, c_symbols : &CSymbols, loc : &Sy
         panic!("c symbol '{}' could not be found.", name)
       }
     }
-    SymbolLocation::Function(unit_id, symbol_id) => {
+    SymbolLocation::FunctionSlot(unit_id, symbol_id) => {
+      code_store.call_slots.slot_address(*symbol_id, || function_symbol_address(code_store, *unit_id, *symbol_id))
+    }
+    SymbolLocation::Global(unit_id, symbol_id) => {
       let def = code_store.types(*unit_id).symbols.get(&symbol_id).unwrap();
-      let init = match &def.initialiser {
-        SymbolInit::Function(init) => init, _ => panic!("expected function initialiser") 
-      };
       let lu = code_store.llvm_unit(*unit_id);
       unsafe {
-        lu.ee.get_function_address(&init.name_for_codegen)
-          .expect("function pointer was null") as usize
+        lu.ee.get_global_address(&def.name)
+          .expect("global pointer was null") as usize
       }
     }
-    SymbolLocation::Global(unit_id, symbol_id) => {
+    SymbolLocation::LazyInitialiser(unit_id, symbol_id) => {
       let def = code_store.types(*unit_id).symbols.get(&symbol_id).unwrap();
       let lu = code_store.llvm_unit(*unit_id);
       unsafe {
-        lu.ee.get_global_address(&def.name)
-          .expect("global pointer was null") as usize
+        lu.ee.get_function_address(&lazy_initialiser_name(&def.name))
+          .expect("function pointer was null") as usize
       }
     }
   }
@@ -146,3 +160,63 @@ pub fn link_unit(
   // Finalize unit
   lu.ee.run_static_constructors();
 }
+
+/// The address range of each of a unit's statics, once it has been linked
+pub fn static_ranges(code_store : &CodeStore, unit_id : UnitId) -> Vec<(usize, usize)> {
+  let lu = code_store.llvm_unit(unit_id);
+  let td = lu.ee.get_target_data();
+  code_store.types(unit_id).symbols.values()
+    .filter(|def| match def.initialiser {
+      SymbolInit::Expression(_) | SymbolInit::Lazy(_) => !def.is_polymorphic(),
+      _ => false,
+    })
+    .filter_map(|def| {
+      let gv = lu.llvm_module.get_global(&def.name)?;
+      let size = td.get_abi_size(&gv.as_pointer_value().get_type().get_element_type()) as usize;
+      let start = unsafe { lu.ee.get_global_address(&def.name)? } as usize;
+      Some((start, start + size))
+    })
+    .collect()
+}
+
+/// The names of a unit's compiled functions
+fn function_names(code_store : &CodeStore, unit_id : UnitId) -> Vec<String> {
+  let mut names = vec![];
+  for def in code_store.types(unit_id).symbols.values() {
+    match &def.initialiser {
+      SymbolInit::Function(init) if !def.is_polymorphic() => names.push(init.name_for_codegen.to_string()),
+      SymbolInit::Lazy(_) => {
+        names.push(lazy_initialiser_name(&def.name));
+        names.push(format!("{}.lazy_value", def.name));
+      }
+      _ => (),
+    }
+  }
+  names
+}
+
+/// The address range of each function compiled for a group of units, with the unit
+/// that it belongs to. A module's functions are laid out in order, so each one ends
+/// where the next one starts, and the last one ends at the module's `CODE_END`.
+pub fn function_ranges(code_store : &CodeStore, unit_group : &[UnitId]) -> Vec<(usize, usize, UnitId)> {
+  let lu = code_store.llvm_unit(unit_group[0]);
+  let address = |name : &str| unsafe { lu.ee.get_function_address(name) }.map(|a| a as usize);
+  let mut starts = vec![];
+  for &unit_id in unit_group {
+    for name in function_names(code_store, unit_id) {
+      if let Some(a) = address(&name) {
+        starts.push((a, unit_id));
+      }
+    }
+  }
+  starts.sort_unstable();
+  let end = address(CODE_END).expect("module has no end marker");
+  let mut ranges = vec![];
+  for (i, &(start, unit_id)) in starts.iter().enumerate() {
+    let next = starts.get(i + 1).map(|s| s.0).unwrap_or(end);
+    if next > start {
+      ranges.push((start, next, unit_id));
+    }
+  }
+  ranges
+}
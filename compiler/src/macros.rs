@@ -0,0 +1,138 @@
+// User-defined macros.
+//
+// A macro is written like a function, but with `macro` instead of `fun`:
+//
+//   macro twice(e : expr) => expr { #($e + $e) }
+//
+// Its arguments and result are all `ptr(expr)`, so the types can be left out. The
+// macro is compiled into an ordinary function, and the units that import it expand
+// calls to it while they are being structured, by running that function on the
+// unstructured arguments. A unit can't use a macro that it defines itself, because
+// it hasn't been compiled yet.
+//
+// Everything in an expansion that didn't come from the calling unit is given the
+// location of the call, so that errors in the expansion point at the call site.
+// Arguments that were spliced in keep their locations.
+//
+// Expansions are hygienic. A local that the macro's own code binds with `let` or
+// `var` is renamed, along with the macro's references to it, so it can't capture or
+// shadow any of the caller's names. A macro can still bind a name that the caller
+// can see, by splicing in `unhygienic("name")` rather than writing the name in the
+// quote. `gensym("name")` makes a symbol that can't clash with anything.
+
+use crate::common::*;
+use crate::error::{Error, error, TextLocation};
+use crate::expr::{Expr, ExprContent};
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Macros can expand to calls to other macros, but only this deep
+pub static MAX_EXPANSION_DEPTH : usize = 64;
+
+static NEXT_NAME : AtomicU64 = AtomicU64::new(0);
+
+/// A name that can't appear in source code, because the lexer doesn't allow `#` in names
+pub fn fresh_name(name : &str) -> String {
+  format!("{}#{}", name, NEXT_NAME.fetch_add(1, Ordering::SeqCst))
+}
+
+/// A macro from an imported unit
+#[derive(Clone, Debug)]
+pub struct MacroDef {
+  pub name : RefStr,
+  /// The unit that defined the macro
+  pub unit_id : UnitId,
+  pub args : usize,
+  /// The compiled macro function
+  pub address : usize,
+}
+
+type E = *const Expr;
+
+/// Runs a macro on the arguments of a call to it
+pub fn expand(m : &MacroDef, call : &Expr, args : &[Expr]) -> Result<Expr, Error> {
+  if args.len() != m.args {
+    return error(call, format!("macro '{}' takes {} arguments, but was given {}", m.name, m.args, args.len()));
+  }
+  let a : Vec<E> = args.iter().map(|e| e as E).collect();
+  let result = unsafe {
+    match a.as_slice() {
+      [] => std::mem::transmute::<_, extern "C" fn() -> E>(m.address)(),
+      [a] => std::mem::transmute::<_, extern "C" fn(E) -> E>(m.address)(*a),
+      [a, b] => std::mem::transmute::<_, extern "C" fn(E, E) -> E>(m.address)(*a, *b),
+      [a, b, c] => std::mem::transmute::<_, extern "C" fn(E, E, E) -> E>(m.address)(*a, *b, *c),
+      [a, b, c, d] => std::mem::transmute::<_, extern "C" fn(E, E, E, E) -> E>(m.address)(*a, *b, *c, *d),
+      _ => return error(call, format!("macro '{}' takes more than 4 arguments, which isn't supported", m.name)),
+    }
+  };
+  if result.is_null() {
+    return error(call, format!("macro '{}' returned a null expression", m.name));
+  }
+  // quoted expressions are never freed, so this doesn't own the result
+  let expansion = unsafe { &*result };
+  let expansion = rename_bindings(expansion, m.unit_id);
+  Ok(relocate(&expansion, call.loc))
+}
+
+/// Renames the locals that the macro's own code binds
+fn rename_bindings(e : &Expr, macro_unit : UnitId) -> Expr {
+  let mut bound = HashSet::new();
+  find_bindings(e, macro_unit, &mut bound);
+  let renamed = bound.into_iter().map(|name| { let new_name = fresh_name(&name); (name, new_name) }).collect();
+  rename(e, macro_unit, &renamed)
+}
+
+fn find_bindings(e : &Expr, macro_unit : UnitId, bound : &mut HashSet<String>) {
+  if let Some((name, es)) = e.try_construct() {
+    if name == "let" || name == "var" {
+      // `let shadow x = ...` has the definition second
+      if let Some(def) = es.last() {
+        let target = match def.try_construct() {
+          Some(("=", [target, _])) => target,
+          _ => def,
+        };
+        let target = match target.try_construct() {
+          Some((":", [target, _])) => target,
+          _ => target,
+        };
+        if let Some(s) = target.try_symbol() {
+          if target.loc.source == macro_unit {
+            bound.insert(s.to_string());
+          }
+        }
+      }
+    }
+    for e in es {
+      find_bindings(e, macro_unit, bound);
+    }
+  }
+}
+
+fn rename(e : &Expr, macro_unit : UnitId, renamed : &HashMap<String, String>) -> Expr {
+  let content = match e.try_construct() {
+    // field names aren't variables
+    Some((".", [a, field])) =>
+      ExprContent::list(".".into(), vec![rename(a, macro_unit, renamed), field.clone()]),
+    Some((name, es)) =>
+      ExprContent::list(name.into(), es.iter().map(|e| rename(e, macro_unit, renamed)).collect()),
+    None => match e.try_symbol().and_then(|s| renamed.get(s)) {
+      Some(new_name) if e.loc.source == macro_unit => ExprContent::symbol(new_name.clone()),
+      _ => e.content.clone(),
+    },
+  };
+  Expr { loc: e.loc, content }
+}
+
+/// Gives everything that didn't come from the call's own source the call's location
+fn relocate(e : &Expr, call_site : TextLocation) -> Expr {
+  let loc = if e.loc.source == call_site.source { e.loc } else { call_site };
+  let content = match e.try_construct() {
+    Some((name, es)) => {
+      let children = es.iter().map(|e| relocate(e, call_site)).collect();
+      ExprContent::list(name.into(), children)
+    }
+    None => e.content.clone(),
+  };
+  Expr { loc, content }
+}
@@ -15,24 +15,64 @@ mod types;
 mod intrinsics;
 mod code_store;
 mod llvm_codegen;
+mod c_abi;
 mod llvm_compile;
 mod compiler;
 mod interpret;
 mod repl;
 mod graph;
+mod features;
+mod events;
+mod exports;
+mod call_slots;
+mod macros;
+mod stages;
+mod docs;
+mod formatter;
+mod analysis;
+mod folding;
+mod escape;
+mod capture;
+mod libraries;
+mod handles;
+mod sdl_bindings;
+mod audio;
+mod images;
+mod debug_draw;
+mod probes;
+mod pointers;
+mod allocations;
+mod watchdog;
+mod safepoint;
+mod metering;
+mod recovery;
+mod reflection;
+mod golden;
+mod fuzz;
+mod shutdown;
+mod vm;
+mod project;
+mod text_edit;
+mod font_render;
+mod editor;
 pub mod c_interface;
 
 #[cfg(test)]
 mod test;
 
 use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{self, Read, BufRead, Write};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use std::path::{Path, PathBuf};
 use std::env;
 
-use crate::interpret::interpreter;
+use crate::interpret::{program_interpreter, Interpreter};
 use crate::compiler::Val;
+use crate::project::Project;
 use crate::error::Error;
+use crate::formatter::FormatOptions;
 
 pub fn print_result(r : Result<Val, Error>) -> String {
   match r {
@@ -41,39 +81,370 @@ pub fn print_result(r : Result<Val, Error>) -> String {
   }
 }
 
+static USAGE : &str = "\
+usage: cauldron <command> [arguments]
+
+commands:
+  run <file>                   run a program, or the entry of a project manifest
+  watch [file] [asset dirs...] run a program, and restart it when its code changes
+  project [manifest]           watch a project (project.toml by default)
+  edit [--font <file>] <file>  open a program (or a project's entry) in the built-in editor,
+                               and restart it whenever it's saved
+  repl                         start an interactive session
+  check <file>                 typecheck a program and print the type of every expression
+  test [files...]              run the test blocks in programs (or in the project's entry)
+  features <file>              list the language features that a program uses
+  doc [--html] <file>          print a markdown (or HTML) reference for a program's definitions
+  fmt [--check] <files...>     format programs in place, or list the ones that aren't formatted
+  constraints <file>           print a program's type constraints in DOT format
+  golden [--bless] [files...]  compare programs' output to their golden files
+  fuzz-input <file>            run a file through the fuzzing entry points
+  --help                       print this message
+
+With no command, the project in the working directory is watched.
+The exit code is 0 on success, 1 if something failed and 2 for bad arguments.";
+
+/// Exit codes, for scripts
+const EXIT_FAILURE : i32 = 1;
+const EXIT_USAGE : i32 = 2;
+
+fn usage_error(message : &str) -> ! {
+  eprintln!("{}\n\n{}", message, USAGE);
+  std::process::exit(EXIT_USAGE);
+}
+
 fn load(path : &str) -> String {
   let path = PathBuf::from(path);
-  let mut f = File::open(path).expect("file not found");
   let mut code = String::new();
-  f.read_to_string(&mut code).unwrap();
-  code
+  match File::open(&path).and_then(|mut f| f.read_to_string(&mut code)) {
+    Ok(_) => code,
+    Err(e) => {
+      println!("failed to read '{}': {}", path.display(), e);
+      std::process::exit(EXIT_FAILURE);
+    }
+  }
+}
+
+/// An interpreter for a program, along with the path of the program. If the path
+/// is a project manifest, the program is the project's entry file.
+fn program_and_interpreter(path : &str) -> (Interpreter, String) {
+  if project::is_manifest(path) {
+    match Project::load(path).and_then(|p| Ok((p.interpreter()?, p.entry))) {
+      Ok(v) => v,
+      Err(e) => {
+        println!("{}", e);
+        std::process::exit(EXIT_FAILURE);
+      }
+    }
+  }
+  else {
+    (program_interpreter(path), path.to_string())
+  }
 }
 
-fn load_and_run(path : &str) {
+/// Runs a program, or the entry file of a project if the path is a manifest.
+/// Returns false if the program failed to compile or run.
+fn load_and_run(path : &str) -> (Interpreter, bool) {
+  let (mut i, path) = program_and_interpreter(path);
+  let ok = run_program(&mut i, &path);
+  (i, ok)
+}
+
+fn run_program(i : &mut Interpreter, path : &str) -> bool {
   let code = load(path);
-  let mut i = interpreter();
   let result = i.run_module(&code, path);
+  if let Some(unit_id) = i.c.code_store.named_unit(path) {
+    for w in i.c.code_store.warnings(unit_id) {
+      println!("{}", w.display());
+    }
+  }
+  let ok = result.is_ok();
   println!("{}", print_result(result));
+  ok
+}
+
+/// Runs a program, and then keeps its units loaded so that session commands
+/// (like `:test`) can be run against them. The watcher runs programs this way.
+/// It stops on Ctrl-C, or when stdin is closed, and shuts down cleanly.
+fn load_and_serve(path : &str) {
+  shutdown::install_handler();
+  let (mut i, _) = load_and_run(path);
+  let (tx, rx) = mpsc::channel();
+  thread::spawn(move || {
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+      if line.map(|l| tx.send(l)).is_err() {
+        break;
+      }
+    }
+  });
+  while !shutdown::requested() {
+    match rx.recv_timeout(Duration::from_millis(50)) {
+      Ok(line) => {
+        if !repl::run_command(&mut i, &line) {
+          println!("expected a command, such as ':test name'");
+        }
+      }
+      Err(RecvTimeoutError::Timeout) => (),
+      Err(RecvTimeoutError::Disconnected) => break,
+    }
+  }
+  let metrics = &i.c.code_store.metrics;
+  let total : Duration = metrics.values().map(|m| m.total()).sum();
+  println!("compiled {} units in {:.2}ms this session", metrics.len(), total.as_micros() as f64 / 1000.0);
+  i.c.shutdown();
+  io::stdout().flush().unwrap();
+}
+
+fn watch_project(path : &str) -> bool {
+  match Project::load(path) {
+    Ok(p) => {
+      watcher::watch_project(path, &p);
+      true
+    }
+    Err(e) => {
+      println!("{}", e);
+      false
+    }
+  }
+}
+
+/// Opens a program in the editor, with the watcher running it in the background.
+/// If the path is a project manifest, the project's entry file is edited.
+fn edit(args : &[&str]) -> bool {
+  let (font, path) = match args {
+    ["--font", font, path] | [path, "--font", font] => (Some(font.to_string()), *path),
+    [path] => (None, *path),
+    _ => usage_error("expected `edit [--font <file>] <file>`"),
+  };
+  let font = match font.or_else(editor::find_font) {
+    Some(font) => font,
+    None => {
+      println!("no monospaced font was found; pass one with `--font <file>`");
+      return false;
+    }
+  };
+  let file = if project::is_manifest(path) {
+    match Project::load(path) {
+      Ok(p) => p.entry,
+      Err(e) => {
+        println!("{}", e);
+        return false;
+      }
+    }
+  }
+  else { path.to_string() };
+  let watch_path = path.to_string();
+  let watcher = thread::spawn(move || {
+    if project::is_manifest(&watch_path) { watch_project(&watch_path); }
+    else { watcher::watch(&watch_path, &[]); }
+  });
+  let result = editor::run(&file, &font);
+  shutdown::request();
+  watcher.join().unwrap();
+  match result {
+    Ok(()) => true,
+    Err(e) => {
+      println!("{}", e);
+      false
+    }
+  }
+}
+
+/// Runs the test blocks in each program. Returns false if any of them fail, or
+/// if a program doesn't load.
+fn run_tests(paths : &[&str]) -> bool {
+  let mut ok = true;
+  for &path in paths {
+    let (mut i, path) = program_and_interpreter(path);
+    let code = load(&path);
+    match i.run_module(&code, &path) {
+      Ok(_) => {
+        let unit_id = i.c.code_store.named_unit(&path).unwrap();
+        let report = i.c.run_tests(unit_id);
+        report.print();
+        ok &= report.failed() == 0;
+      }
+      Err(e) => {
+        println!("{}", e.display());
+        ok = false;
+      }
+    }
+  }
+  ok
+}
+
+fn report_features(path : &str) -> bool {
+  let code = load(path);
+  let mut i = program_interpreter(path);
+  match i.feature_report(&code, path) {
+    Ok(report) => { print!("{}", report); true }
+    Err(e) => { println!("{}", e.display()); false }
+  }
+}
+
+fn check_types(path : &str) -> bool {
+  let (mut i, path) = program_and_interpreter(path);
+  let code = load(&path);
+  match i.check_module(&code) {
+    Ok(report) => { print!("{}", report); true }
+    Err(es) => {
+      for e in es { println!("{}", e.display()) }
+      false
+    }
+  }
+}
+
+/// `doc [--html] <file>`. A project manifest documents the project's entry file.
+fn print_docs(args : &[&str]) -> bool {
+  let (html, path) = match args {
+    ["--html", path] | [path, "--html"] => (true, *path),
+    [path] => (false, *path),
+    _ => usage_error("expected `doc [--html] <file>`"),
+  };
+  let (mut i, path) = program_and_interpreter(path);
+  let code = load(&path);
+  match i.document_module(&code, &path) {
+    Ok(docs) => {
+      print!("{}", if html { docs.html() } else { docs.markdown() });
+      true
+    }
+    Err(es) => {
+      for e in es { println!("{}", e.display()) }
+      false
+    }
+  }
+}
+
+/// `fmt [--check] <files...>`. The format options come from the project in the
+/// working directory, if there is one.
+fn format_files(args : &[&str]) -> bool {
+  let check = args.contains(&"--check");
+  let paths : Vec<&str> = args.iter().cloned().filter(|&a| a != "--check").collect();
+  if paths.is_empty() {
+    usage_error("expected `fmt [--check] <files...>`");
+  }
+  let options =
+    if !Path::new(project::MANIFEST_FILE).exists() { FormatOptions::default() }
+    else {
+      match Project::load(project::MANIFEST_FILE) {
+        Ok(p) => p.format,
+        Err(e) => {
+          println!("{}", e);
+          return false;
+        }
+      }
+    };
+  let mut ok = true;
+  for path in paths {
+    let code = load(path);
+    match formatter::format(&code, options) {
+      Ok(formatted) if formatted == code => (),
+      Ok(_) if check => {
+        println!("'{}' isn't formatted", path);
+        ok = false;
+      }
+      Ok(formatted) => {
+        if let Err(e) = std::fs::write(path, formatted) {
+          println!("failed to write '{}': {}", path, e);
+          ok = false;
+        }
+      }
+      Err(e) => {
+        println!("failed to format '{}': {}", path, e.display());
+        ok = false;
+      }
+    }
+  }
+  ok
+}
+
+fn print_constraint_graph(path : &str) -> bool {
+  let code = load(path);
+  let mut i = program_interpreter(path);
+  match i.constraint_graph(&code) {
+    Ok(dot) => { print!("{}", dot); true }
+    Err(e) => { println!("{}", e.display()); false }
+  }
+}
+
+/// Runs a file through the fuzzing entry points, to reproduce a crash that a fuzzer found
+fn fuzz_input(path : &str) -> bool {
+  let data = match std::fs::read(path) {
+    Ok(data) => data,
+    Err(e) => {
+      println!("failed to read '{}': {}", path, e);
+      return false;
+    }
+  };
+  println!("lex: {:?}", fuzz::fuzz_lex(&data).map_err(|es| es.len()));
+  println!("parse: {:?}", fuzz::fuzz_parse(&data).map_err(|e| e.display().to_string()));
+  println!("infer: {:?}", fuzz::fuzz_infer(&data).map_err(|es| es.len()));
+  true
+}
+
+/// `golden [--bless] [programs...]`
+fn golden_tests(args : &[&str]) -> bool {
+  let bless = args.contains(&"--bless");
+  let mut paths : Vec<String> =
+    args.iter().filter(|&&a| a != "--bless").map(|a| a.to_string()).collect();
+  if paths.is_empty() {
+    match golden::read_program_list(golden::DEFAULT_PROGRAM_LIST) {
+      Ok(ps) => paths = ps,
+      Err(e) => {
+        println!("{}", e);
+        return false;
+      }
+    }
+  }
+  golden::run_golden_tests(&paths, bless)
 }
 
 fn main(){
   let args: Vec<String> = env::args().collect();
   let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
-  match &args[1..] {
-    ["watch", path] => {
-      watcher::watch(path.as_ref())
+  let ok = match &args[1..] {
+    ["--help"] | ["-h"] | ["help"] => {
+      println!("{}", USAGE);
+      true
+    }
+    ["watch"] => { watcher::watch("code/scratchpad.code", &[]); true }
+    // watch <path> [asset directories...]
+    a if a.len() >= 2 && a[0] == "watch" => {
+      watcher::watch(a[1], &a[2..]);
+      true
     }
-    ["watch"] => watcher::watch("code/scratchpad.code"),
-    ["repl"] => repl::run_repl(),
-    ["run", path] => {
-      load_and_run(path)
+    // project [manifest or directory]
+    ["project"] => watch_project(project::MANIFEST_FILE),
+    ["project", path] => watch_project(path),
+    ["repl"] => { repl::run_repl(); true }
+    ["run", path] => load_and_run(path).1,
+    ["serve", path] => { load_and_serve(path); true }
+    ["features", path] => report_features(path),
+    ["check", path] => check_types(path),
+    a if a.len() >= 1 && a[0] == "doc" => print_docs(&a[1..]),
+    a if a.len() >= 1 && a[0] == "fmt" => format_files(&a[1..]),
+    a if a.len() >= 1 && a[0] == "edit" => edit(&a[1..]),
+    ["test"] => {
+      if !Path::new(project::MANIFEST_FILE).exists() {
+        usage_error("expected a file to test, or a project in the working directory");
+      }
+      run_tests(&[project::MANIFEST_FILE])
     }
+    a if a.len() >= 2 && a[0] == "test" => run_tests(&a[1..]),
+    // prints DOT, e.g. `constraints foo.code | dot -Tsvg > foo.svg`
+    ["constraints", path] => print_constraint_graph(path),
+    ["fuzz-input", path] => fuzz_input(path),
+    a if a.len() >= 1 && a[0] == "golden" => golden_tests(&a[1..]),
     [] => {
-      //load_and_run("code/scratchpad.code")
-      watcher::watch("code/tetris/loader.code");
+      if !Path::new(project::MANIFEST_FILE).exists() {
+        usage_error("there's no project in the working directory");
+      }
+      watch_project(project::MANIFEST_FILE)
     },
-    args => {
-      println!("unrecognised arguments {:?}", args);
-    }
+    args => usage_error(&format!("unrecognised arguments {:?}", args)),
+  };
+  if !ok {
+    std::process::exit(EXIT_FAILURE);
   }
-}
\ No newline at end of file
+}
@@ -0,0 +1,49 @@
+// Step budgets for sandboxed code, so that a scripting console embedded in a game
+// can't stall the host with a snippet that loops or recurses forever.
+//
+// Units compiled with `CompileOptions::sandbox` count a step each time one of their
+// functions is entered, and each time one of their loops goes round (at its
+// safepoint, so loops in the other code they call count too). The vm backend counts
+// a step for every node that it evaluates. Each evaluation of a sandboxed unit's
+// top-level code gets the number of steps that its options give it.
+//
+// Like the watchdog, running out can't unwind out of compiled code. Instead, every
+// loop exits at its next safepoint, and every sandboxed function returns zeroes as
+// soon as it's entered, until the metered call returns and its caller reports it.
+// The vm stops with an error.
+
+use std::cell::Cell;
+
+thread_local! {
+  /// The steps left in the budget of the metered call on this thread, if there is one
+  static REMAINING : Cell<Option<u64>> = Cell::new(None);
+  static EXHAUSTED : Cell<bool> = Cell::new(false);
+}
+
+/// Runs `f` with a budget of steps. Also returns whether the budget ran out. With no
+/// budget `f` just runs, and a metered call inside another one runs under its budget.
+pub fn meter<T>(budget : Option<u64>, f : impl FnOnce() -> T) -> (T, bool) {
+  let metered = REMAINING.with(|r| r.get().is_some());
+  if budget.is_none() || metered {
+    return (f(), false);
+  }
+  REMAINING.with(|r| r.set(budget));
+  let v = f();
+  REMAINING.with(|r| r.set(None));
+  (v, EXHAUSTED.with(|e| e.replace(false)))
+}
+
+/// Counts a step against the budget. Returns true once it has run out.
+pub fn step() -> bool {
+  REMAINING.with(|r| match r.get() {
+    Some(0) => {
+      EXHAUSTED.with(|e| e.set(true));
+      true
+    }
+    Some(n) => {
+      r.set(Some(n - 1));
+      false
+    }
+    None => false,
+  })
+}
@@ -77,16 +77,28 @@ fn parse_config() -> ParseConfig {
   c.infix(&["as"]);
   c.infix(&["&&", "||"]);
   c.infix(&[">", "<", ">=", "<=", "==", "!="]);
+  c.infix(&[".."]);
   c.infix(&["%"]);
   c.infix_prefix(&["+", "-"], &["-"]);
   c.infix(&["*", "/", "%"]);
-  c.infix_prefix(&["=>"], &["!", "&", "*",]);
-  c.infix(&["(", "["]);
+  c.infix_prefix(&["=>"], &["!", "&", "*", "?"]);
+  c.infix(&["(", "[", "?"]);
   c.infix(&["."]);
   c.prefix(&["#", "$"]);
   c
 }
 
+/// How tightly each operator binds, for printing expressions as code. Higher binds tighter.
+pub struct Precedence {
+  pub infix : HashMap<RefStr, i32>,
+  pub prefix : HashMap<RefStr, i32>,
+}
+
+pub fn precedence() -> Precedence {
+  let c = parse_config();
+  Precedence { infix: c.infix_precedence, prefix: c.prefix_precedence }
+}
+
 // TODO: this might be better implemented with a ring buffer (or just a backwards vec)
 struct ParseState<'l> {
   source : SourceId,
@@ -94,8 +106,13 @@ struct ParseState<'l> {
   pos : usize,
   config : &'l ParseConfig,
   cache : &'l StringCache,
+  /// How many expressions deep the parser currently is
+  depth : usize,
 }
 
+/// Deeper nesting than this is an error, so that malformed input can't overflow the stack
+const MAX_NESTING_DEPTH : usize = 200;
+
 use TokenType::*;
 
 fn match_symbol(t : &Token, s : &str) -> bool {
@@ -119,7 +136,7 @@ impl <'l> ParseState<'l> {
   fn new(source : SourceId, tokens : Vec<Token>, config : &'l ParseConfig, cache : &'l StringCache)
     -> ParseState<'l>
   {
-    ParseState { source, tokens, pos: 0, config, cache }
+    ParseState { source, tokens, pos: 0, config, cache, depth: 0 }
   }
 
   fn has_tokens(&self) -> bool {
@@ -253,6 +270,16 @@ impl <'l> ParseState<'l> {
 
 /// This expression parser is vaguely based on some blogs about pratt parsing.
 fn pratt_parse(ps : &mut ParseState, precedence : i32) -> Result<Expr, Error> {
+  if ps.depth >= MAX_NESTING_DEPTH {
+    return error(ps.peek()?.loc, "expression is nested too deeply");
+  }
+  ps.depth += 1;
+  let result = pratt_parse_nested(ps, precedence);
+  ps.depth -= 1;
+  result
+}
+
+fn pratt_parse_nested(ps : &mut ParseState, precedence : i32) -> Result<Expr, Error> {
   let mut expr = parse_prefix(ps)?;
   while ps.has_tokens() {
     let t = ps.peek()?;
@@ -276,7 +303,7 @@ fn pratt_parse(ps : &mut ParseState, precedence : i32) -> Result<Expr, Error> {
         let (tag, separator) = match separator {
           ";" => ("block", ";"),
           "," => ("tuple", ","),
-          _ => panic!(),
+          s => return error(t.loc, format!("unexpected separator '{}'", s)),
         };
         ps.pop_type(TokenType::Symbol)?;
         expr = parse_list(ps, vec![expr], separator, tag.into())?;
@@ -330,6 +357,22 @@ fn parse_paren_infix(ps : &mut ParseState, left_expr : Expr, first_arg : Option<
 fn parse_prefix(ps : &mut ParseState) -> Result<Expr, Error> {
   let start = ps.peek_marker();
   let t = ps.peek()?;
+  // `#[stage(compile)] fun ...`. Any other `#[...]` is a quoted array.
+  if match_symbol(t, "#")
+    && ps.peek_ahead(1).map(|t| match_symbol(t, "[")) == Some(true)
+    && ps.peek_ahead(2).map(|t| match_symbol(t, "stage")) == Some(true)
+  {
+    let &kp = ps.config.prefix_precedence.get("#keyword").unwrap();
+    ps.skip();
+    ps.expect("[")?;
+    ps.expect("stage")?;
+    ps.expect("(")?;
+    let phase = parse_simple_string(ps)?;
+    ps.expect(")")?;
+    ps.expect("]")?;
+    let def = pratt_parse(ps, kp)?;
+    return Ok(ps.add_list("stage", vec![phase, def], start));
+  }
   // if the next token is a prefix operator
   if let Some(new_precedence) = get(&ps.config.prefix_precedence, t.symbol()) {
     let t = ps.peek()?;
@@ -354,7 +397,12 @@ fn parse_prefix(ps : &mut ParseState) -> Result<Expr, Error> {
 fn parse_infix(ps : &mut ParseState, left_expr : Expr, precedence : i32) -> Result<Expr, Error> {
   let infix_start = left_expr.loc.start;
   let t = ps.peek()?;
-  if match_symbol(t, ".") {
+  if match_symbol(t, "?") {
+    // postfix operator for propagating failures
+    ps.expect_type(TokenType::Symbol)?;
+    Ok(ps.add_list("?", vec![left_expr], infix_start))
+  }
+  else if match_symbol(t, ".") {
     // special handling for method call syntax
     ps.expect_type(TokenType::Symbol)?;
     let right_expr = pratt_parse(ps, precedence)?;
@@ -485,6 +533,11 @@ fn try_parse_keyword_term(ps : &mut ParseState) -> Result<Option<Expr>, Error> {
       let body = parse_block_in_braces(ps)?;
       ps.add_list("while", vec![cond, body], start)
     }
+    "unsafe" => {
+      ps.pop_type(TokenType::Symbol)?;
+      let body = parse_block_in_braces(ps)?;
+      ps.add_list("unsafe", vec![body], start)
+    }
     "for" => {
       ps.pop_type(TokenType::Symbol)?;
       let var_range = pratt_parse(ps, kp)?;
@@ -494,8 +547,13 @@ fn try_parse_keyword_term(ps : &mut ParseState) -> Result<Option<Expr>, Error> {
     "struct" => {
       ps.pop_type(TokenType::Symbol)?;
       let name = pratt_parse(ps, kp)?;
-      let fields = parse_block_in_braces(ps)?;
-      ps.add_list("struct", vec![name, fields], start)
+      // layout attributes, like `struct vertex with packed { ... }`
+      let mut es = vec![name];
+      if ps.accept("with") {
+        es.push(parse_list(ps, vec![], ",", "attributes".into())?);
+      }
+      es.push(parse_block_in_braces(ps)?);
+      ps.add_list("struct", es, start)
     }
     "union" => {
       ps.pop_type(TokenType::Symbol)?;
@@ -503,10 +561,73 @@ fn try_parse_keyword_term(ps : &mut ParseState) -> Result<Option<Expr>, Error> {
       let fields = parse_block_in_braces(ps)?;
       ps.add_list("union", vec![name, fields], start)
     }
+    // `tagged union name { ... }`. Only a keyword when followed by `union`.
+    "tagged" if ps.peek_ahead(1).and_then(|t| t.symbol()).map(|s| s.as_ref() == "union") == Some(true) => {
+      ps.pop_type(TokenType::Symbol)?;
+      let definition = pratt_parse(ps, kp)?;
+      ps.add_list("tagged", vec![definition], start)
+    }
+    // `match value { circle(r) => ..., rect => ..., _ => ... }`
+    "match" => {
+      ps.pop_type(TokenType::Symbol)?;
+      let value = pratt_parse(ps, kp)?;
+      let &arrow = ps.config.infix_precedence.get("=>").unwrap();
+      let arms_start = ps.peek_marker();
+      ps.expect("{")?;
+      let mut arms = vec![];
+      while !ps.accept("}") {
+        let arm_start = ps.peek_marker();
+        let pattern = pratt_parse(ps, arrow)?;
+        ps.expect("=>")?;
+        let body = parse_new_scope(ps, kp)?;
+        arms.push(ps.add_list("arm", vec![pattern, body], arm_start));
+        if !ps.accept(",") {
+          ps.accept(";");
+        }
+      }
+      let arms = ps.add_list("block", arms, arms_start);
+      ps.add_list("match", vec![value, arms], start)
+    }
+    // `switch value { 0 => ..., 1, 2 => ..., else => ... }`
+    "switch" => {
+      ps.pop_type(TokenType::Symbol)?;
+      let value = pratt_parse(ps, kp)?;
+      let &arrow = ps.config.infix_precedence.get("=>").unwrap();
+      let arms_start = ps.peek_marker();
+      ps.expect("{")?;
+      let mut arms = vec![];
+      while !ps.accept("}") {
+        let arm_start = ps.peek_marker();
+        let mut arm = vec![];
+        if ps.accept("else") {
+          arm.push(ps.add_symbol("else", arm_start));
+        }
+        else {
+          loop {
+            arm.push(pratt_parse(ps, arrow)?);
+            if !ps.accept(",") { break }
+          }
+        }
+        ps.expect("=>")?;
+        arm.push(parse_new_scope(ps, kp)?);
+        arms.push(ps.add_list("arm", arm, arm_start));
+        if !ps.accept(",") {
+          ps.accept(";");
+        }
+      }
+      let arms = ps.add_list("block", arms, arms_start);
+      ps.add_list("switch", vec![value, arms], start)
+    }
     "cbind" => {
       ps.pop_type(TokenType::Symbol)?;
       let typed_symbol = pratt_parse(ps, kp)?;
-      ps.add_list("cbind", vec![typed_symbol], start)
+      if ps.accept("with") {
+        let convention = pratt_parse(ps, kp)?;
+        ps.add_list("cbind", vec![typed_symbol, convention], start)
+      }
+      else {
+        ps.add_list("cbind", vec![typed_symbol], start)
+      }
     }
     "fun" => {
       ps.pop_type(TokenType::Symbol)?;
@@ -533,15 +654,75 @@ fn try_parse_keyword_term(ps : &mut ParseState) -> Result<Option<Expr>, Error> {
       }
       ps.add_list("fun", es, start)
     }
+    // `macro name(args) { ... }`. Only a keyword when followed by a name and arguments.
+    "macro" if ps.peek_ahead(2).map(|t| match_symbol(t, "(")) == Some(true) => {
+      ps.pop_type(TokenType::Symbol)?;
+      let mut es = vec![parse_prefix(ps)?];
+      ps.expect("(")?;
+      es.push(parse_list(ps, vec![], ",", "args".into())?);
+      ps.expect(")")?;
+      if ps.accept("=>") {
+        es.push(pratt_parse(ps, kp)?);
+      }
+      es.push(parse_block_in_braces(ps)?);
+      ps.add_list("macro", es, start)
+    }
+    // `test "name" { ... }`. Only a keyword when followed by a string, so that
+    // `test` can still be used as an ordinary name.
+    "test" if ps.peek_ahead(1).map(|t| t.token_type) == Some(StringLiteral) => {
+      ps.pop_type(TokenType::Symbol)?;
+      let name = parse_prefix(ps)?;
+      let body = parse_block_in_braces(ps)?;
+      ps.add_list("test", vec![name, body], start)
+    }
+    "pragma" => {
+      ps.pop_type(TokenType::Symbol)?;
+      let directive = pratt_parse(ps, kp)?;
+      ps.add_list("pragma", vec![directive], start)
+    }
     "static" => {
       ps.pop_type(TokenType::Symbol)?;
       let definition = pratt_parse(ps, kp)?;
       ps.add_list("static", vec![definition], start)
     }
-    "let" => {
+    // `lazy static x = ...`. Only a keyword when followed by `static`.
+    "lazy" if ps.peek_ahead(1).and_then(|t| t.symbol()).map(|s| s.as_ref() == "static") == Some(true) => {
       ps.pop_type(TokenType::Symbol)?;
       let definition = pratt_parse(ps, kp)?;
-      ps.add_list("let", vec![definition], start)
+      ps.add_list("lazy", vec![definition], start)
+    }
+    // `inline fun ...`. Only a keyword when followed by `fun`.
+    "inline" if ps.peek_ahead(1).and_then(|t| t.symbol()).map(|s| s.as_ref() == "fun") == Some(true) => {
+      ps.pop_type(TokenType::Symbol)?;
+      let definition = pratt_parse(ps, kp)?;
+      ps.add_list("inline", vec![definition], start)
+    }
+    // `init { ... }`. Only a keyword when followed by a block.
+    "init" if ps.peek_ahead(1).and_then(|t| t.symbol()).map(|s| s.as_ref() == "{") == Some(true) => {
+      ps.pop_type(TokenType::Symbol)?;
+      let body = parse_block_in_braces(ps)?;
+      ps.add_list("init", vec![body], start)
+    }
+    "let" | "var" => {
+      let keyword = if symbol == "let" { "let" } else { "var" };
+      ps.pop_type(TokenType::Symbol)?;
+      // `let shadow x = ...` rebinds a variable that is already defined in the same scope
+      let is_shadow = {
+        let shadow_keyword = ps.peek().ok().and_then(|t| t.symbol()).map(|s| s.as_ref() == "shadow");
+        let next = ps.peek_ahead(1).and_then(|t| t.symbol()).map(|s| s.as_ref() != "=" && s.as_ref() != ":");
+        shadow_keyword == Some(true) && next == Some(true)
+      };
+      if is_shadow {
+        let shadow_start = ps.peek_marker();
+        ps.skip();
+        let shadow = ps.add_symbol("shadow", shadow_start);
+        let definition = pratt_parse(ps, kp)?;
+        ps.add_list(keyword, vec![shadow, definition], start)
+      }
+      else {
+        let definition = pratt_parse(ps, kp)?;
+        ps.add_list(keyword, vec![definition], start)
+      }
     }
     "type" => {
       ps.pop_type(TokenType::Symbol)?;
@@ -559,6 +740,22 @@ fn try_parse_keyword_term(ps : &mut ParseState) -> Result<Option<Expr>, Error> {
         ps.add_list("return", vec![return_expr], start)
       }
     }
+    // `loop { ... }`. Only a keyword when followed by a block.
+    "loop" if ps.peek_ahead(1).and_then(|t| t.symbol()).map(|s| s.as_ref() == "{") == Some(true) => {
+      ps.pop_type(TokenType::Symbol)?;
+      let body = parse_block_in_braces(ps)?;
+      ps.add_list("loop", vec![body], start)
+    }
+    // `break`, `break label`, `break value` or `break label value`
+    "break" | "continue" => {
+      let keyword = if symbol == "break" { "break" } else { "continue" };
+      ps.pop_type(TokenType::Symbol)?;
+      let mut args = vec![];
+      while args.len() < 2 && !peek_statement_terminated(ps) {
+        args.push(pratt_parse(ps, kp)?);
+      }
+      ps.add_list(keyword, args, start)
+    }
     _ => return Ok(None),
   };
   Ok(Some(expr))
@@ -594,7 +791,7 @@ fn parse_expression_term(ps : &mut ParseState) -> Result<Expr, Error> {
               Ok(e)
             }
           }
-          _ => panic!(),
+          _ => error(ps.loc(start), format!("unexpected token '{}'", paren)),
         }
       }
       else {
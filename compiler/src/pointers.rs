@@ -0,0 +1,64 @@
+// Checked pointers, for finding pointers that outlive the unit they point into.
+//
+// A unit's statics are freed when it's unloaded, so a pointer to one of them that
+// was stored somewhere longer lived dangles after a reload. `checked(&x)` makes a
+// `checked_ptr(T)`, which carries the id of the unit whose statics it points into
+// and the location where it was made:
+//
+//   static scores = [0, 0, 0]
+//   let p = checked(&scores)
+//   *get(p) = [1, 2, 3]
+//
+// `get(p)` reports "pointer into unloaded module" if that unit has been unloaded.
+// The owner is found from the address ranges of the statics of every loaded unit.
+// Pointers to anything else, like the heap, have no owner and are never reported.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+#[derive(Default)]
+struct Units {
+  /// The unit id and address range of each static in a loaded unit
+  statics : Vec<(u64, usize, usize)>,
+  unloaded : HashSet<u64>,
+}
+
+thread_local! {
+  static UNITS : RefCell<Units> = RefCell::new(Units::default());
+}
+
+/// Records the address ranges of a unit's statics, once it has been linked
+pub fn add_unit(unit : u64, ranges : Vec<(usize, usize)>) {
+  UNITS.with(|us| {
+    let us = &mut *us.borrow_mut();
+    us.statics.extend(ranges.into_iter().map(|(start, end)| (unit, start, end)));
+  });
+}
+
+pub fn remove_unit(unit : u64) {
+  UNITS.with(|us| {
+    let us = &mut *us.borrow_mut();
+    if us.statics.iter().any(|s| s.0 == unit) {
+      us.statics.retain(|s| s.0 != unit);
+      us.unloaded.insert(unit);
+    }
+  });
+}
+
+/// The unit whose statics an address is in, or zero if it isn't in any
+pub fn owner(address : usize) -> u64 {
+  UNITS.with(|us| {
+    us.borrow().statics.iter()
+      .find(|&&(_, start, end)| address >= start && address < end)
+      .map(|s| s.0).unwrap_or(0)
+  })
+}
+
+/// Fails if a pointer owned by `unit`, and made at `site`, points into a unit that
+/// has been unloaded
+pub fn check(unit : u64, site : &str) -> Result<(), String> {
+  if UNITS.with(|us| us.borrow().unloaded.contains(&unit)) {
+    return Err(format!("pointer into unloaded module, allocated at {}", site));
+  }
+  Ok(())
+}
@@ -0,0 +1,65 @@
+// Live value probes. `?e` evaluates to `e`, and records its value each time it
+// runs, under the unit and source span of `e`:
+//
+//   let speed = ?(distance / time)
+//
+// It's a prefix operator rather than a function, so that it can't clash with
+// anything that a program defines. It binds like `!`, so `?f(x)` probes the call.
+//
+// Only the latest value of each probe is kept, along with how many times it has
+// run. The watcher's `:probes` command prints them, and `Interpreter::probes`
+// returns them with their unit names. A unit's probes are forgotten when it's unloaded.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Probe {
+  /// The id of the unit that the probe is in
+  pub unit : u64,
+  /// `line:col-line:col`
+  pub span : String,
+  pub value : String,
+  pub hits : u64,
+}
+
+#[derive(Default)]
+struct Probes {
+  probes : Vec<Probe>,
+  index : HashMap<(u64, String), usize>,
+}
+
+thread_local! {
+  static PROBES : RefCell<Probes> = RefCell::new(Probes::default());
+}
+
+pub fn record(unit : u64, span : &str, value : String) {
+  PROBES.with(|ps| {
+    let ps = &mut *ps.borrow_mut();
+    match ps.index.get(&(unit, span.to_string())) {
+      Some(&i) => {
+        let p = &mut ps.probes[i];
+        p.value = value;
+        p.hits += 1;
+      }
+      None => {
+        ps.index.insert((unit, span.to_string()), ps.probes.len());
+        ps.probes.push(Probe { unit, span: span.to_string(), value, hits: 1 });
+      }
+    }
+  });
+}
+
+/// Every probe that has run, in the order they first ran
+pub fn latest() -> Vec<Probe> {
+  PROBES.with(|ps| ps.borrow().probes.clone())
+}
+
+/// Forgets the probes in a unit
+pub fn forget_unit(unit : u64) {
+  PROBES.with(|ps| {
+    let ps = &mut *ps.borrow_mut();
+    ps.probes.retain(|p| p.unit != unit);
+    ps.index = ps.probes.iter().enumerate().map(|(i, p)| ((p.unit, p.span.clone()), i)).collect();
+  });
+}
@@ -0,0 +1,309 @@
+// Project manifests, which describe a program well enough to run and watch it.
+//
+// A manifest is called `project.toml`, and is written in a small subset of TOML:
+// `key = value` lines, where a value is a string, a bool, an integer or a single-line
+// array of strings, an `[options]` section for the compile options and a `[format]`
+// section for the formatter. Comments start with `#`. Paths are relative to the
+// manifest's directory.
+//
+//   entry = "loader.code"          # the program to run (required)
+//   sources = ["lib"]              # code to watch for changes
+//   assets = ["assets/**/*.png"]   # assets to watch (see `asset_changed`)
+//   libraries = ["SDL2"]           # native libraries to load at startup
+//   prelude = ["core", "ext.code"] # instead of a prelude file (see `Prelude`)
+//
+//   [options]
+//   optimise = false
+//   bounds_checks = true
+//   backend = "llvm"               # or "vm", to interpret the entry file
+//   safepoints = true              # let the watchdog interrupt loops
+//   frame_budget_ms = 100          # interrupt reloaded code and frames that run longer
+//   sandbox_steps = 1000000        # cut each evaluation short after this many steps
+//
+//   [format]
+//   on_save = true                 # format code files when they are saved while watching
+//   indent = 2
+//   width = 100
+//
+// Sources and assets are paths, which can contain wildcards. `*` and `?` match
+// within one path component, and `**` matches any number of components. A path
+// without wildcards matches everything under it.
+
+use crate::compiler::{CompileOptions, Backend};
+use crate::interpret::{Prelude, Interpreter, interpreter_with_prelude};
+use crate::formatter::FormatOptions;
+
+use std::path::Path;
+use std::time::Duration;
+
+pub static MANIFEST_FILE : &str = "project.toml";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Project {
+  /// The directory that the manifest is in
+  pub dir : String,
+  pub entry : String,
+  pub sources : Vec<String>,
+  pub assets : Vec<String>,
+  pub libraries : Vec<String>,
+  pub prelude : Prelude,
+  pub options : CompileOptions,
+  /// See `Compiler::frame_budget`
+  pub frame_budget : Option<Duration>,
+  pub format : FormatOptions,
+  pub format_on_save : bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+  Str(String),
+  Bool(bool),
+  Int(i64),
+  List(Vec<String>),
+}
+
+/// True if the path looks like a manifest, rather than a program
+pub fn is_manifest(path : &str) -> bool {
+  path.ends_with(".toml")
+}
+
+fn join(dir : &str, path : &str) -> String {
+  Path::new(dir).join(path).to_string_lossy().replace("\\", "/")
+}
+
+/// Splits a path pattern into the directory before its first wildcard, which is
+/// watched for changes, and the rest of the pattern
+pub fn split_glob(pattern : &str) -> (String, String) {
+  let components : Vec<&str> = pattern.split('/').collect();
+  let n =
+    components.iter().position(|c| c.contains(|ch| ch == '*' || ch == '?'))
+    .unwrap_or(components.len());
+  let root = match components[..n].join("/") {
+    r if !r.is_empty() => r,
+    _ if n > 0 => "/".into(),
+    _ => ".".into(),
+  };
+  (root, components[n..].join("/"))
+}
+
+/// Whether a relative path matches a pattern that has no root (see `split_glob`)
+pub fn glob_match(pattern : &str, path : &str) -> bool {
+  fn components(p : &[Vec<char>], s : &[Vec<char>]) -> bool {
+    match p.split_first() {
+      None => s.is_empty(),
+      Some((c, rest)) if c[..] == ['*', '*'] => (0..=s.len()).any(|i| components(rest, &s[i..])),
+      Some((c, rest)) => !s.is_empty() && component(c, &s[0]) && components(rest, &s[1..]),
+    }
+  }
+  fn component(p : &[char], s : &[char]) -> bool {
+    match p.split_first() {
+      None => s.is_empty(),
+      Some(('*', rest)) => (0..=s.len()).any(|i| component(rest, &s[i..])),
+      Some(('?', rest)) => !s.is_empty() && component(rest, &s[1..]),
+      Some((c, rest)) => s.first() == Some(c) && component(rest, &s[1..]),
+    }
+  }
+  let split = |s : &str| -> Vec<Vec<char>> {
+    s.split('/').filter(|c| !c.is_empty() && *c != ".").map(|c| c.chars().collect()).collect()
+  };
+  components(&split(pattern), &split(path))
+}
+
+/// Whether a changed file is matched by one of the path patterns. The file
+/// watcher reports absolute paths, so they are compared with the canonical roots.
+pub fn matches_any(patterns : &[String], changed : &Path) -> bool {
+  patterns.iter().any(|pattern| {
+    let (root, rest) = split_glob(pattern);
+    let canonical = std::fs::canonicalize(&root).unwrap_or_else(|_| root.clone().into());
+    match changed.strip_prefix(&canonical).or_else(|_| changed.strip_prefix(&root)) {
+      Ok(relative) => rest.is_empty() || glob_match(&rest, &relative.to_string_lossy().replace("\\", "/")),
+      Err(_) => false,
+    }
+  })
+}
+
+/// Removes a comment from the end of a line, unless the `#` is in a string
+fn strip_comment(line : &str) -> &str {
+  let mut in_string = false;
+  let mut escaped = false;
+  for (i, c) in line.char_indices() {
+    match c {
+      '\\' if in_string && !escaped => { escaped = true; continue }
+      '"' if !escaped => in_string = !in_string,
+      '#' if !in_string => return &line[..i],
+      _ => (),
+    }
+    escaped = false;
+  }
+  line
+}
+
+fn parse_string(s : &str) -> Result<String, String> {
+  if s.len() < 2 || !s.starts_with('"') || !s.ends_with('"') {
+    return Err(format!("expected a string, found '{}'", s));
+  }
+  let mut out = String::new();
+  let mut chars = s[1..s.len()-1].chars();
+  while let Some(c) = chars.next() {
+    match c {
+      '\\' => match chars.next() {
+        Some('n') => out.push('\n'),
+        Some('t') => out.push('\t'),
+        Some(c @ '"') | Some(c @ '\\') => out.push(c),
+        _ => return Err(format!("invalid escape in string {}", s)),
+      }
+      '"' => return Err(format!("unexpected quote in string {}", s)),
+      c => out.push(c),
+    }
+  }
+  Ok(out)
+}
+
+fn parse_value(s : &str) -> Result<Value, String> {
+  match s {
+    "true" => return Ok(Value::Bool(true)),
+    "false" => return Ok(Value::Bool(false)),
+    _ => (),
+  }
+  if let Ok(i) = s.parse::<i64>() {
+    return Ok(Value::Int(i));
+  }
+  if s.starts_with('[') {
+    if !s.ends_with(']') {
+      return Err("arrays have to be on a single line".into());
+    }
+    let inner = s[1..s.len()-1].trim();
+    let mut items = vec![];
+    // strings can't contain commas, which keeps this simple
+    for item in inner.split(',').map(|i| i.trim()) {
+      if item.is_empty() {
+        continue;
+      }
+      items.push(parse_string(item)?);
+    }
+    return Ok(Value::List(items));
+  }
+  parse_string(s).map(Value::Str)
+}
+
+/// Parses the manifest into `(section, key, value, line number)` entries
+fn parse_entries(text : &str) -> Result<Vec<(String, String, Value, usize)>, String> {
+  let mut entries = vec![];
+  let mut section = String::new();
+  for (n, line) in text.lines().enumerate() {
+    let line_number = n + 1;
+    let line = strip_comment(line).trim();
+    if line.is_empty() {
+      continue;
+    }
+    if line.starts_with('[') {
+      if !line.ends_with(']') {
+        return Err(format!("line {}: expected ']' at the end of a section header", line_number));
+      }
+      section = line[1..line.len()-1].trim().to_string();
+      continue;
+    }
+    let eq = line.find('=').ok_or_else(|| format!("line {}: expected 'key = value'", line_number))?;
+    let key = line[..eq].trim().to_string();
+    let value = parse_value(line[eq+1..].trim()).map_err(|e| format!("line {}: {}", line_number, e))?;
+    entries.push((section.clone(), key, value, line_number));
+  }
+  Ok(entries)
+}
+
+impl Project {
+  /// Loads a manifest. The path can also be the directory that the manifest is in.
+  pub fn load(path : &str) -> Result<Project, String> {
+    let path =
+      if Path::new(path).is_dir() { join(path, MANIFEST_FILE) }
+      else { path.to_string() };
+    let text = std::fs::read_to_string(&path)
+      .map_err(|e| format!("failed to read project manifest '{}': {}", path, e))?;
+    let dir = Path::new(&path).parent().map(|p| p.to_string_lossy().replace("\\", "/")).unwrap_or_default();
+    Project::parse(&text, &dir).map_err(|e| format!("error in '{}', {}", path, e))
+  }
+
+  /// Parses the text of a manifest that is in `dir`
+  pub fn parse(text : &str, dir : &str) -> Result<Project, String> {
+    let mut entry = None;
+    let mut project = Project {
+      dir: dir.into(), entry: String::new(), sources: vec![], assets: vec![],
+      libraries: vec![], prelude: Prelude::empty(), options: CompileOptions::default(),
+      frame_budget: None, format: FormatOptions::default(), format_on_save: false,
+    };
+    let mut prelude = None;
+    for (section, key, value, line_number) in parse_entries(text)? {
+      match (section.as_str(), key.as_str(), value) {
+        ("", "entry", Value::Str(s)) => entry = Some(join(dir, &s)),
+        ("", "sources", Value::List(l)) => project.sources = l.iter().map(|s| join(dir, s)).collect(),
+        ("", "assets", Value::List(l)) => project.assets = l.iter().map(|s| join(dir, s)).collect(),
+        // library names are looked up in the search paths, as well as the project directory
+        ("", "libraries", Value::List(l)) => project.libraries = l,
+        ("", "prelude", Value::List(l)) => {
+          let mut p = Prelude::empty();
+          for m in l.iter() {
+            if m == "core" {
+              p.modules.extend(Prelude::default_core().modules);
+            }
+            else {
+              p.modules.push(join(dir, m));
+            }
+          }
+          prelude = Some(p);
+        }
+        ("options", "optimise", Value::Bool(b)) => project.options.optimise = b,
+        ("options", "bounds_checks", Value::Bool(b)) => project.options.bounds_checks = b,
+        ("options", "safepoints", Value::Bool(b)) => project.options.safepoints = b,
+        ("options", "sandbox_steps", Value::Int(n)) if n > 0 => project.options.sandbox = Some(n as u64),
+        ("options", "frame_budget_ms", Value::Int(ms)) if ms > 0 =>
+          project.frame_budget = Some(Duration::from_millis(ms as u64)),
+        ("options", "backend", Value::Str(b)) => {
+          project.options.backend = match b.as_str() {
+            "llvm" => Backend::Llvm,
+            "vm" => Backend::Vm,
+            _ => return Err(format!("line {}: unknown backend '{}'", line_number, b)),
+          }
+        }
+        ("format", "on_save", Value::Bool(b)) => project.format_on_save = b,
+        ("format", "indent", Value::Int(i)) if i >= 0 => project.format.indent = i as usize,
+        ("format", "width", Value::Int(i)) if i > 0 => project.format.width = i as usize,
+        (_, _, v) => {
+          let key = if section.is_empty() { key.clone() } else { format!("{}.{}", section, key) };
+          let found = match v {
+            Value::Str(_) => "a string", Value::Bool(_) => "a bool",
+            Value::Int(i) if i < 0 => "a negative number", Value::Int(0) => "zero", Value::Int(_) => "a number",
+            Value::List(_) => "an array",
+          };
+          return Err(format!("line {}: '{}' is not a manifest key that takes {}", line_number, key, found));
+        }
+      }
+    }
+    project.entry = entry.ok_or_else(|| "the manifest doesn't have an entry".to_string())?;
+    project.prelude = match prelude {
+      Some(p) => p,
+      None => Prelude::for_program(&project.entry)?,
+    };
+    Ok(project)
+  }
+
+  /// Every path pattern that should restart the program when it changes
+  pub fn code_paths(&self) -> Vec<String> {
+    let mut paths = vec![self.entry.clone()];
+    paths.extend(self.sources.iter().cloned());
+    paths.extend(self.prelude.modules.iter().cloned());
+    paths
+  }
+
+  /// An interpreter with the project's prelude, libraries and options, ready to
+  /// run the entry file
+  pub fn interpreter(&self) -> Result<Interpreter, String> {
+    let mut i = interpreter_with_prelude(self.prelude.clone());
+    i.c.default_options = self.options;
+    i.c.frame_budget = self.frame_budget;
+    i.c.libraries.search_paths.push(self.dir.clone());
+    for l in self.libraries.iter() {
+      i.c.libraries.find_and_load(l)?;
+    }
+    Ok(i)
+  }
+}
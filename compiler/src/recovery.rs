@@ -0,0 +1,52 @@
+// Recovery from panics in the language, so that a bug in code that is being edited
+// doesn't take the session down with it.
+//
+// Calls from the host into the language (a unit's top-level code, and each
+// `run_frame` callback) run inside a recovery boundary. A Rust panic can't unwind
+// through JIT-compiled frames, so a panic inside a boundary is only recorded.
+// Like a watchdog interrupt, every loop then exits at its next safepoint, and the
+// function that failed a bounds check returns zeroes instead of reading out of
+// bounds. The rest of the code carries on with those zeroes until the call returns,
+// and the boundary reports the panic. Outside a boundary, a panic is still a panic.
+//
+// The frame that panicked can be run again once its code has been fixed, because
+// `run_frame` calls through the call slot of its function (see call_slots.rs), and
+// reloading a module re-points the slot at the new version.
+
+use std::cell::{Cell, RefCell};
+
+thread_local! {
+  static GUARDED : Cell<bool> = Cell::new(false);
+  /// The first panic inside the innermost boundary on this thread
+  static PANIC : RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Runs `f`, and also returns the message of the first panic inside it. Boundaries
+/// can be nested, like a `run_frame` call in top-level code, and a panic is
+/// reported by the innermost one.
+pub fn recover<T>(f : impl FnOnce() -> T) -> (T, Option<String>) {
+  let outer_panic = PANIC.with(|p| p.borrow_mut().take());
+  let guarded = GUARDED.with(|g| g.replace(true));
+  let v = f();
+  GUARDED.with(|g| g.set(guarded));
+  (v, PANIC.with(|p| p.replace(outer_panic)))
+}
+
+/// True if the code running in the boundary on this thread has panicked
+pub fn unwinding() -> bool {
+  PANIC.with(|p| p.borrow().is_some())
+}
+
+/// Called when the language panics. Inside a boundary the panic is recorded, and
+/// the caller has to return.
+pub fn panic(message : String) {
+  if !GUARDED.with(|g| g.get()) {
+    panic!("{}", message);
+  }
+  PANIC.with(|p| {
+    let mut p = p.borrow_mut();
+    if p.is_none() {
+      *p = Some(message);
+    }
+  });
+}
@@ -0,0 +1,174 @@
+// Descriptions of types, for runtime code that works on values of any type. The
+// property checks in core/check.code use them to make random values, to shrink
+// failing ones, and to print them.
+//
+// `typeinfo(T)` is compiled to the id of a description of `T`, which is registered
+// when the code is generated. Only numbers, bools, and structs of those (including
+// tuples) can be described, so `typeinfo` is an error for any other type.
+
+use std::cell::RefCell;
+
+use rand::Rng;
+use rand::rngs::SmallRng;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Shape {
+  Int{ bytes : u32, signed : bool },
+  Float{ bytes : u32 },
+  Bool,
+  /// The fields are in order, with their offsets in bytes
+  Struct{ name : String, fields : Vec<(String, u64, Shape)> },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TypeDescription {
+  pub size : u64,
+  pub shape : Shape,
+}
+
+thread_local! {
+  static DESCRIPTIONS : RefCell<Vec<TypeDescription>> = RefCell::new(vec![]);
+}
+
+/// Returns the id of a description, which is the same each time it's registered
+pub fn register(d : TypeDescription) -> u64 {
+  DESCRIPTIONS.with(|ds| {
+    let mut ds = ds.borrow_mut();
+    match ds.iter().position(|x| *x == d) {
+      Some(i) => i as u64,
+      None => {
+        ds.push(d);
+        (ds.len() - 1) as u64
+      }
+    }
+  })
+}
+
+pub fn description(id : u64) -> Option<TypeDescription> {
+  DESCRIPTIONS.with(|ds| ds.borrow().get(id as usize).cloned())
+}
+
+/// A value of a field that isn't a struct
+#[derive(Clone, Copy, PartialEq)]
+enum Leaf {
+  Int(i128),
+  Float(f64),
+  Bool(bool),
+}
+
+/// The fields that aren't structs, in order, with their offsets
+fn leaves(shape : &Shape, offset : u64, out : &mut Vec<(u64, Shape)>) {
+  match shape {
+    Shape::Struct{ fields, .. } => {
+      for (_, field_offset, s) in fields {
+        leaves(s, offset + field_offset, out);
+      }
+    }
+    s => out.push((offset, s.clone())),
+  }
+}
+
+unsafe fn read_leaf(shape : &Shape, p : *const u8) -> Leaf {
+  use std::ptr::read_unaligned as read;
+  match *shape {
+    Shape::Int{ bytes: 8, signed: true } => Leaf::Int(read(p as *const i64) as i128),
+    Shape::Int{ bytes: 4, signed: true } => Leaf::Int(read(p as *const i32) as i128),
+    Shape::Int{ bytes: 8, .. } => Leaf::Int(read(p as *const u64) as i128),
+    Shape::Int{ bytes: 4, .. } => Leaf::Int(read(p as *const u32) as i128),
+    Shape::Int{ bytes: 2, .. } => Leaf::Int(read(p as *const u16) as i128),
+    Shape::Int{ .. } => Leaf::Int(read(p) as i128),
+    Shape::Float{ bytes: 4 } => Leaf::Float(read(p as *const f32) as f64),
+    Shape::Float{ .. } => Leaf::Float(read(p as *const f64)),
+    Shape::Bool => Leaf::Bool(read(p) != 0),
+    Shape::Struct{ .. } => panic!("a struct is not a leaf"),
+  }
+}
+
+unsafe fn write_leaf(shape : &Shape, p : *mut u8, v : Leaf) {
+  use std::ptr::write_unaligned as write;
+  match (shape, v) {
+    (Shape::Int{ bytes: 8, .. }, Leaf::Int(i)) => write(p as *mut u64, i as u64),
+    (Shape::Int{ bytes: 4, .. }, Leaf::Int(i)) => write(p as *mut u32, i as u32),
+    (Shape::Int{ bytes: 2, .. }, Leaf::Int(i)) => write(p as *mut u16, i as u16),
+    (Shape::Int{ .. }, Leaf::Int(i)) => write(p, i as u8),
+    (Shape::Float{ bytes: 4 }, Leaf::Float(f)) => write(p as *mut f32, f as f32),
+    (Shape::Float{ .. }, Leaf::Float(f)) => write(p as *mut f64, f),
+    (Shape::Bool, Leaf::Bool(b)) => write(p, b as u8),
+    _ => panic!("value doesn't match its shape"),
+  }
+}
+
+/// Small numbers are more likely to hit edge cases than huge ones
+fn arbitrary_leaf(shape : &Shape, rng : &mut SmallRng) -> Leaf {
+  match *shape {
+    Shape::Int{ bytes, signed: true } => {
+      let max = std::cmp::min(1000, (1i128 << (bytes * 8 - 1)) - 1) as i64;
+      Leaf::Int(rng.gen_range(-max, max + 1) as i128)
+    }
+    Shape::Int{ bytes, signed: false } => {
+      let max = std::cmp::min(1000, (1i128 << (bytes * 8)) - 1) as i64;
+      Leaf::Int(rng.gen_range(0, max + 1) as i128)
+    }
+    Shape::Float{ .. } => Leaf::Float(rng.gen::<f64>() * 2000.0 - 1000.0),
+    Shape::Bool => Leaf::Bool(rng.gen()),
+    Shape::Struct{ .. } => panic!("a struct is not a leaf"),
+  }
+}
+
+/// Smaller values to try in place of `v`, closest to zero first
+fn shrink_leaf(v : Leaf) -> Vec<Leaf> {
+  let mut vs = match v {
+    Leaf::Int(0) | Leaf::Bool(false) => vec![],
+    Leaf::Float(f) if f == 0.0 => vec![],
+    Leaf::Int(i) => vec![Leaf::Int(0), Leaf::Int(i / 2), Leaf::Int(i - i.signum())],
+    Leaf::Float(f) if f.floor() != f => vec![Leaf::Float(0.0), Leaf::Float(f.floor()), Leaf::Float(f / 2.0)],
+    Leaf::Float(f) => vec![Leaf::Float(0.0), Leaf::Float(f / 2.0)],
+    Leaf::Bool(true) => vec![Leaf::Bool(false)],
+  };
+  vs.dedup();
+  vs
+}
+
+/// Writes a random value of the type to `out`
+pub unsafe fn arbitrary(d : &TypeDescription, rng : &mut SmallRng, out : *mut u8) {
+  let mut ls = vec![];
+  leaves(&d.shape, 0, &mut ls);
+  for (offset, shape) in ls {
+    write_leaf(&shape, out.add(offset as usize), arbitrary_leaf(&shape, rng));
+  }
+}
+
+/// Writes the `n`th value that's a bit smaller than `v` to `out`. Each one differs
+/// from `v` in one field. Returns false if there are fewer than `n + 1` of them.
+pub unsafe fn shrink(d : &TypeDescription, v : *const u8, mut n : u64, out : *mut u8) -> bool {
+  let mut ls = vec![];
+  leaves(&d.shape, 0, &mut ls);
+  for (offset, shape) in ls {
+    let smaller = shrink_leaf(read_leaf(&shape, v.add(offset as usize)));
+    if n < smaller.len() as u64 {
+      std::ptr::copy(v, out, d.size as usize);
+      write_leaf(&shape, out.add(offset as usize), smaller[n as usize]);
+      return true;
+    }
+    n -= smaller.len() as u64;
+  }
+  false
+}
+
+/// Formats a value like `point { x: 1, y: -3 }`
+pub unsafe fn describe(shape : &Shape, v : *const u8) -> String {
+  match shape {
+    Shape::Struct{ name, fields } => {
+      let fields : Vec<String> =
+        fields.iter()
+        .map(|(f, offset, s)| format!("{}: {}", f, describe(s, v.add(*offset as usize))))
+        .collect();
+      format!("{} {{ {} }}", name, fields.join(", "))
+    }
+    s => match read_leaf(s, v) {
+      Leaf::Int(i) => i.to_string(),
+      Leaf::Float(f) => f.to_string(),
+      Leaf::Bool(b) => b.to_string(),
+    }
+  }
+}
@@ -3,8 +3,12 @@ use crate::interpret::{interpreter, Interpreter};
 use crate::error::{Error, ErrorContent};
 use crate::compiler::Val;
 use crate::parser::EXPECTED_TOKEN_ERROR;
+use crate::images;
 
-use rustyline::Editor;
+use rustyline::{Editor, Config};
+use rustyline::error::ReadlineError;
+
+use std::fs;
 
 pub enum ReplResult {
   Complete(Val),
@@ -17,6 +21,7 @@ use ReplResult::*;
 fn repl_eval(i : &mut Interpreter, code : &str) -> ReplResult {
   match i.eval(code) {
     Ok(e) => {
+      i.session.push(code.into());
       return Complete(e);
     }
     Err(e) => {
@@ -30,28 +35,316 @@ fn repl_eval(i : &mut Interpreter, code : &str) -> ReplResult {
   }
 }
 
+const DEFAULT_BENCH_RUNS : usize = 100;
+
+/// Separates the forms in a saved session. It's a comment, so a saved session
+/// is also an ordinary source file.
+const SESSION_SEPARATOR : &str = "// ----";
+
+fn save_session(i : &Interpreter, path : &str) -> Result<(), String> {
+  let mut text = String::new();
+  for (n, form) in i.session.iter().enumerate() {
+    if n > 0 {
+      text.push_str(SESSION_SEPARATOR);
+      text.push('\n');
+    }
+    text.push_str(form);
+    text.push('\n');
+  }
+  fs::write(path, text).map_err(|e| format!("failed to save session to '{}': {}", path, e))
+}
+
+/// Evaluates each form in the file in order, as though it had been typed into
+/// the REPL, and stops at the first one that fails. Returns the number of forms.
+fn load_session(i : &mut Interpreter, path : &str) -> Result<usize, String> {
+  let text = fs::read_to_string(path)
+    .map_err(|e| format!("failed to load session '{}': {}", path, e))?;
+  let mut forms = vec![String::new()];
+  for line in text.lines() {
+    if line.trim() == SESSION_SEPARATOR {
+      forms.push(String::new());
+    }
+    else {
+      let form = forms.last_mut().unwrap();
+      if !form.is_empty() {
+        form.push('\n');
+      }
+      form.push_str(line);
+    }
+  }
+  forms.retain(|f| !f.trim().is_empty());
+  for (n, form) in forms.iter().enumerate() {
+    match i.eval(form) {
+      Ok(_) => i.session.push(form.clone()),
+      Err(e) => return Err(format!("form {} of '{}' failed: {}", n + 1, path, e.display())),
+    }
+  }
+  Ok(forms.len())
+}
+
+/// Handles a session command (a line starting with ':'). These are shared by the
+/// REPL and by programs run from the watcher. Returns false if the line isn't a command.
+pub fn run_command(i : &mut Interpreter, line : &str) -> bool {
+  let line = line.trim();
+  if !line.starts_with(':') {
+    return false;
+  }
+  // sent by the watcher. The path can contain spaces, so it's the rest of the line.
+  if line.starts_with(":asset-changed ") {
+    let path = line[":asset-changed ".len()..].trim();
+    images::asset_changed(path);
+    if let Err(e) = i.c.events.publish_asset_changed(path) {
+      println!("{}", e);
+    }
+    return true;
+  }
+  // sent by the watcher after a restart, to rerun the tests affected by a changed file
+  if line.starts_with(":tests-affected-by ") {
+    let path = line[":tests-affected-by ".len()..].trim();
+    for unit_id in i.c.units_with_tests_affected_by(path) {
+      i.c.run_tests(unit_id).print();
+    }
+    return true;
+  }
+  // `:bench [runs] expr`
+  if line.starts_with(":bench ") {
+    let rest = line[":bench ".len()..].trim();
+    let (runs, expr) = match rest.find(' ').map(|n| (rest[..n].parse::<usize>(), &rest[n..])) {
+      Some((Ok(runs), expr)) => (runs, expr),
+      _ => (DEFAULT_BENCH_RUNS, rest),
+    };
+    match i.bench(expr, runs) {
+      Ok(r) => println!(
+        "{} runs: min {:?}, median {:?}, {} bytes allocated per run",
+        r.runs, r.min, r.median, r.bytes_per_run),
+      Err(e) => println!("Error occured: {}", e.display()),
+    }
+    return true;
+  }
+  // `:save path` and `:load path`, where the path can contain spaces
+  if line.starts_with(":save ") {
+    let path = line[":save ".len()..].trim();
+    match save_session(i, path) {
+      Ok(()) => println!("saved {} forms to '{}'", i.session.len(), path),
+      Err(e) => println!("{}", e),
+    }
+    return true;
+  }
+  if line.starts_with(":load ") {
+    let path = line[":load ".len()..].trim();
+    match load_session(i, path) {
+      Ok(n) => println!("loaded {} forms from '{}'", n, path),
+      Err(e) => println!("{}", e),
+    }
+    return true;
+  }
+  // `:costs path`, the cost of each function in a file, for the editor to show
+  if line.starts_with(":costs ") {
+    let path = line[":costs ".len()..].trim();
+    for (loc, calls) in i.c.function_costs(path) {
+      println!("{}: {} calls", loc, calls);
+    }
+    return true;
+  }
+  // `:layout T`, where the type can contain spaces
+  if line.starts_with(":layout ") {
+    let t = line[":layout ".len()..].trim();
+    match i.layout(t) {
+      Ok(l) => {
+        println!("{}: size {}, alignment {}", t, l.size, l.alignment);
+        for (field, offset) in l.fields {
+          println!("  {} at offset {}", field, offset);
+        }
+        for (field, offset, width) in l.bitfields {
+          println!("  {} at bit {}, {} bits wide", field, offset, width);
+        }
+      }
+      Err(e) => println!("Error occured: {}", e.display()),
+    }
+    return true;
+  }
+  let args : Vec<&str> = line[1..].split_whitespace().collect();
+  match args.as_slice() {
+    ["test", name] => {
+      match i.c.run_test(name) {
+        Ok(true) => println!("test {} ... ok", name),
+        Ok(false) => println!("test {} ... FAILED", name),
+        Err(e) => println!("Error occured: {}", e.display()),
+      }
+    }
+    // runs the test blocks in every loaded unit, or in one named unit
+    ["tests"] => {
+      for unit_id in i.c.units_with_tests() {
+        i.c.run_tests(unit_id).print();
+      }
+    }
+    ["tests", name] => {
+      match i.c.code_store.named_unit(name) {
+        Some(unit_id) => i.c.run_tests(unit_id).print(),
+        None => println!("no unit called '{}' is loaded", name),
+      }
+    }
+    // how long each loaded unit took to compile, to keep an eye on reload times
+    ["metrics"] => {
+      for (&unit_id, m) in i.c.code_store.metrics.iter() {
+        println!("{}: {}", i.c.code_store.name(unit_id), m);
+      }
+    }
+    ["metrics", name] => {
+      match i.c.code_store.named_unit(name).and_then(|u| i.c.code_store.metrics.get(&u)) {
+        Some(m) => println!("{}: {}", name, m),
+        None => println!("no unit called '{}' is loaded", name),
+      }
+    }
+    // dump what a unit was compiled into
+    [dump @ "ir", name] | [dump @ "expr", name] | [dump @ "nodes", name] => {
+      match i.c.code_store.named_unit(name) {
+        Some(unit_id) => {
+          let text = match *dump {
+            "ir" => i.c.dump_ir(unit_id),
+            "expr" => i.c.dump_expr(unit_id),
+            _ => i.c.dump_nodes(unit_id),
+          };
+          match text {
+            Some(text) => println!("{}", text),
+            None => println!("unit '{}' has no {} to show", name, dump),
+          }
+        }
+        None => println!("no unit called '{}' is loaded", name),
+      }
+    }
+    // the latest value of each `?e` probe that has run
+    ["probes"] => {
+      for (unit, p) in i.probes() {
+        println!("{} {} = {} ({} hits)", unit, p.span, p.value, p.hits);
+      }
+    }
+    ["reload-prelude"] => {
+      i.reload_core_modules();
+      if i.core_error.is_none() {
+        println!("core modules reloaded");
+      }
+    }
+    ["screenshot", path] => {
+      i.c.capture.request_screenshot(path);
+      println!("the next frame will be saved to '{}'", path);
+    }
+    ["record", directory] => {
+      match i.c.capture.start_recording(directory) {
+        Ok(()) => println!("recording frames to '{}'", directory),
+        Err(e) => println!("{}", e),
+      }
+    }
+    ["stop-recording"] => {
+      match i.c.capture.stop_recording() {
+        Some(frames) => println!("recorded {} frames", frames),
+        None => println!("nothing is being recorded"),
+      }
+    }
+    _ => println!("unrecognised command '{}'", line),
+  }
+  true
+}
+
+/// Kept in the directory that the REPL is started from (normally the project root)
+const HISTORY_FILE : &str = ".repl_history";
+
+/// History is saved one entry per line, so newlines are escaped. This means that
+/// multi-line entries (like function definitions) are recalled as a single entry.
+pub fn escape_history_entry(entry : &str) -> String {
+  entry.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+pub fn unescape_history_entry(line : &str) -> String {
+  let mut s = String::new();
+  let mut chars = line.chars();
+  while let Some(c) = chars.next() {
+    if c == '\\' {
+      match chars.next() {
+        Some('n') => s.push('\n'),
+        Some(c) => s.push(c),
+        None => s.push('\\'),
+      }
+    }
+    else {
+      s.push(c);
+    }
+  }
+  s
+}
+
+fn load_history(rl : &mut Editor<()>) {
+  if let Ok(text) = fs::read_to_string(HISTORY_FILE) {
+    for line in text.lines() {
+      rl.add_history_entry(unescape_history_entry(line));
+    }
+  }
+}
+
+fn save_history(rl : &Editor<()>) {
+  let mut text = String::new();
+  for entry in rl.history().iter() {
+    text.push_str(&escape_history_entry(entry));
+    text.push('\n');
+  }
+  if let Err(e) = fs::write(HISTORY_FILE, text) {
+    println!("failed to save history to '{}': {}", HISTORY_FILE, e);
+  }
+}
+
+fn add_history_entry(rl : &mut Editor<()>, entry : String) {
+  rl.add_history_entry(entry);
+  // saved straight away, because the REPL is often killed rather than exited
+  save_history(rl);
+}
+
 pub fn run_repl() {
-  let mut rl = Editor::<()>::new();
+  let config = Config::builder()
+    .max_history_size(1000)
+    .history_ignore_dups(true)
+    .build();
+  let mut rl = Editor::<()>::with_config(config);
+  load_history(&mut rl);
   let mut i = interpreter();
+  if i.core_error.is_some() {
+    println!("Only a minimal core is loaded. Fix the error and then use ':reload-prelude'.");
+  }
+  println!("(ctrl-r searches the history, ctrl-d exits)");
 
   loop {
-    let mut input_line = rl.readline("repl> ").unwrap();
+    let mut input_line = match rl.readline("repl> ") {
+      Ok(line) => line,
+      Err(ReadlineError::Interrupted) => continue,
+      Err(_) => break,
+    };
+    if run_command(&mut i, &input_line) {
+      add_history_entry(&mut rl, input_line);
+      continue;
+    }
 
     loop {
       match repl_eval(&mut i, input_line.as_str()) {
         Complete(val) => {
-          rl.add_history_entry(input_line);
+          add_history_entry(&mut rl, input_line);
           println!("{:?}", val);
           break;
         }
         Incomplete => {
           // get more tokens
-          let next_line = rl.readline(". ").unwrap();
-          input_line.push_str("\n");
-          input_line.push_str(next_line.as_str());
+          match rl.readline(". ") {
+            Ok(next_line) => {
+              input_line.push_str("\n");
+              input_line.push_str(next_line.as_str());
+            }
+            // abandon the entry, but keep it so that it can be recalled and fixed
+            Err(_) => {
+              add_history_entry(&mut rl, input_line);
+              break;
+            }
+          }
         }
         Failed(e) => {
-          rl.add_history_entry(input_line);
+          add_history_entry(&mut rl, input_line);
           println!("Error occured: {}", e.display());
           break;
         }
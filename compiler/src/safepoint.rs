@@ -0,0 +1,50 @@
+// Safepoints are checks that code compiled with `CompileOptions::safepoints`
+// makes at each loop back-edge (in `while` loops, and the `for` loops built on
+// them). They let the host interrupt long-running loops in the language: the
+// watchdog uses them to cancel code that runs over its budget, a panic cancels the
+// loops of the code that panicked (see recovery.rs), and hooks can use
+// them for anything that has to happen while a loop runs (sampling for a profiler,
+// say, or a garbage collector later on).
+//
+// Cancellation is cooperative. A cancelled loop exits as if its condition was
+// false, and the code after it carries on. Sandboxed code has safepoints even
+// without that option, because they count its steps (see metering.rs).
+
+use crate::{watchdog, metering, recovery};
+
+use std::cell::{Cell, RefCell};
+
+/// Returned by `add_hook`, to remove the hook again
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HookId(u64);
+
+/// Called at every safepoint. Returns true to cancel the loop.
+pub type Hook = Box<dyn FnMut() -> bool>;
+
+thread_local! {
+  static HOOKS : RefCell<Vec<(HookId, Hook)>> = RefCell::new(vec![]);
+  static NEXT_HOOK : Cell<u64> = Cell::new(0);
+}
+
+/// Adds a hook for the safepoints on this thread
+pub fn add_hook(hook : Hook) -> HookId {
+  let id = HookId(NEXT_HOOK.with(|n| n.replace(n.get() + 1)));
+  HOOKS.with(|hs| hs.borrow_mut().push((id, hook)));
+  id
+}
+
+pub fn remove_hook(id : HookId) {
+  HOOKS.with(|hs| hs.borrow_mut().retain(|(i, _)| *i != id));
+}
+
+/// Called at loop back-edges. Returns true if the loop should exit. Every hook
+/// runs, even if an earlier one cancels the loop. Safepoints reached by a hook's
+/// own code don't run the hooks again. Each one is also a step against the step
+/// budget, if there is one (see metering.rs).
+pub fn safepoint() -> bool {
+  let cancelled = metering::step() | watchdog::interrupted() | recovery::unwinding();
+  HOOKS.with(|hs| match hs.try_borrow_mut() {
+    Ok(mut hs) => hs.iter_mut().fold(cancelled, |cancel, (_, hook)| hook() || cancel),
+    Err(_) => cancelled,
+  })
+}
@@ -0,0 +1,55 @@
+// SDL2 is linked into the compiler (through the sdl2 crate), so the language can
+// bind to it directly instead of loading the library at runtime. The names on
+// the left are the ones that code/sdl2.code declares with `cbind`.
+//
+// To expose another SDL function, add it to the table below and declare it in
+// sdl2.code with the matching signature.
+
+use crate::common::*;
+
+use std::collections::HashMap;
+
+use sdl2::sys;
+
+macro_rules! sdl_symbols {
+  ($($name:expr => $f:ident,)*) => {
+    pub fn register_sdl_symbols(sym : &mut HashMap<RefStr, usize>) {
+      $(sym.insert($name.into(), (sys::$f as *const()) as usize);)*
+    }
+  }
+}
+
+sdl_symbols! {
+  "sdl_init" => SDL_Init,
+
+  // windows
+  "sdl_create_window" => SDL_CreateWindow,
+  "sdl_destroy_window" => SDL_DestroyWindow,
+  "sdl_set_window_position" => SDL_SetWindowPosition,
+
+  // renderer
+  "sdl_create_renderer" => SDL_CreateRenderer,
+  "sdl_destroy_renderer" => SDL_DestroyRenderer,
+  "sdl_clear" => SDL_RenderClear,
+  "sdl_set_draw_color" => SDL_SetRenderDrawColor,
+  "sdl_present" => SDL_RenderPresent,
+  "sdl_fill_rect" => SDL_RenderFillRect,
+  "sdl_draw_rect" => SDL_RenderDrawRect,
+  "sdl_get_renderer_output_size" => SDL_GetRendererOutputSize,
+  "sdl_render_read_pixels" => SDL_RenderReadPixels,
+
+  // textures
+  "sdl_create_texture" => SDL_CreateTexture,
+  "sdl_destroy_texture" => SDL_DestroyTexture,
+  "sdl_update_texture" => SDL_UpdateTexture,
+  "sdl_draw_texture" => SDL_RenderCopy,
+
+  // events
+  "sdl_poll_event" => SDL_PollEvent,
+
+  // surfaces and files (used for frame capture)
+  "sdl_create_rgb_surface_with_format_from" => SDL_CreateRGBSurfaceWithFormatFrom,
+  "sdl_free_surface" => SDL_FreeSurface,
+  "sdl_rw_from_file" => SDL_RWFromFile,
+  "sdl_save_bmp_rw" => SDL_SaveBMP_RW,
+}
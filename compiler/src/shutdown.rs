@@ -0,0 +1,30 @@
+// Ctrl-C handling, so that watch mode can shut down cleanly.
+//
+// The first Ctrl-C only sets a flag. The watcher and the served program poll it,
+// and a program that runs its own loop can check it with `shutdown_requested`.
+// The default handler is put back straight away, so a second Ctrl-C kills the
+// process if the first one wasn't noticed (a program stuck in a loop, say).
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static SHUTDOWN_REQUESTED : AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_signal : libc::c_int) {
+  SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+  unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL); }
+}
+
+pub fn install_handler() {
+  unsafe {
+    libc::signal(libc::SIGINT, on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t);
+  }
+}
+
+pub fn requested() -> bool {
+  SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Asks for a shutdown as if Ctrl-C had been pressed
+pub fn request() {
+  SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
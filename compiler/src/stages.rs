@@ -0,0 +1,114 @@
+// Staged compilation.
+//
+// A top-level function marked with `#[stage(compile)]` runs while its module is
+// being loaded, and the expression that it returns is spliced into the module in
+// place of the function:
+//
+//   #[stage(compile)]
+//   fun squares() => expr {
+//     var fields = #{}
+//     ...
+//     #(struct squares { $fields })
+//   }
+//
+// The stage functions are compiled first, on their own, into a temporary unit with
+// the same imports as the module. So they can use anything that the module imports,
+// but nothing that the module itself defines. They take no arguments and return a
+// `ptr(expr)`. The temporary unit is unloaded once they have all run, before the
+// module is structured.
+//
+// Everything in a stage's result that didn't come from the module's own source is
+// given the location of the stage function, so that errors point somewhere useful.
+
+use crate::error::{Error, error, TextLocation};
+use crate::expr::{Expr, ExprContent};
+
+/// A function that runs at compile time
+pub struct Stage<'l> {
+  pub name : &'l str,
+  /// The function definition, without its `#[stage(...)]` marker
+  pub def : &'l Expr,
+  /// The marked definition
+  pub expr : &'l Expr,
+}
+
+fn top_level(module : &Expr) -> &[Expr] {
+  match module.try_construct() {
+    Some(("block", es)) => es,
+    _ => std::slice::from_ref(module),
+  }
+}
+
+/// Finds the stage functions at the top level of a module
+pub fn find_stages(module : &Expr) -> Result<Vec<Stage>, Error> {
+  let mut stages = vec![];
+  for e in top_level(module) {
+    if let Some(("stage", [phase, def])) = e.try_construct() {
+      if phase.try_symbol() != Some("compile") {
+        return error(phase, format!("unknown stage '{}', expected 'compile'", phase));
+      }
+      let (name, args) = match def.try_construct() {
+        Some(("fun", [name, args, _, ..])) if name.try_symbol().is_some() =>
+          (name.try_symbol().unwrap(), args),
+        _ => return error(def, "expected a named function after #[stage(compile)]"),
+      };
+      if !args.children().is_empty() {
+        return error(args, format!("stage function '{}' can't take arguments", name));
+      }
+      stages.push(Stage { name, def, expr: e });
+    }
+  }
+  Ok(stages)
+}
+
+/// The definitions of the stage functions, as a module of their own
+pub fn stage_module(module : &Expr, stages : &[Stage]) -> Expr {
+  let defs = stages.iter().map(|s| s.def.clone()).collect();
+  Expr::new(ExprContent::list("block".into(), defs), module.loc)
+}
+
+type E = *const Expr;
+
+/// Runs a compiled stage function, and copies the expression it returns
+pub fn run(stage : &Stage, address : usize) -> Result<Expr, Error> {
+  let result = unsafe { std::mem::transmute::<_, extern "C" fn() -> E>(address)() };
+  if result.is_null() {
+    return error(stage.expr, format!("stage function '{}' returned a null expression", stage.name));
+  }
+  // the result may point into the stage unit, which is about to be unloaded
+  let result = unsafe { &*result };
+  Ok(copy(result, stage.expr.loc))
+}
+
+/// Copies an expression and all of its strings, giving everything that didn't come
+/// from the stage's own source the stage's location
+fn copy(e : &Expr, stage_loc : TextLocation) -> Expr {
+  let loc = if e.loc.source == stage_loc.source { e.loc } else { stage_loc };
+  let content = match &e.content {
+    ExprContent::List(s, es) => {
+      let children = es.as_slice().iter().map(|e| copy(e, stage_loc)).collect();
+      ExprContent::list(s.as_str().into(), children)
+    }
+    ExprContent::Symbol(s) => ExprContent::symbol(s.as_str().into()),
+    ExprContent::LiteralString(s) => ExprContent::literal_string(s.as_str().into()),
+    c => c.clone(),
+  };
+  Expr { loc, content }
+}
+
+/// Replaces each stage function in a module with the expression that it returned
+pub fn splice(module : &Expr, stages : &[Stage], results : Vec<Expr>) -> Expr {
+  let mut results = results.into_iter();
+  let es : Vec<Expr> = top_level(module).iter().map(|e| {
+    if stages.iter().any(|s| std::ptr::eq(s.expr, e)) {
+      results.next().unwrap()
+    }
+    else {
+      e.clone()
+    }
+  }).collect();
+  match module.try_construct() {
+    Some(("block", _)) => Expr::new(ExprContent::list("block".into(), es), module.loc),
+    _ => es.into_iter().next().unwrap(),
+  }
+}
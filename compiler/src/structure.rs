@@ -1,10 +1,14 @@
 
 use crate::common::*;
-use crate::error::{Error, error, TextLocation};
-use crate::expr::{Expr, ExprContent};
-use crate::intrinsics::UNSAFE_ZERO_INIT;
+use crate::error::{Error, error, warning_raw, TextLocation};
+use crate::expr::{Expr, ExprContent, is_method_call};
+use crate::intrinsics::{UNSAFE_ZERO_INIT, MAX_TUPLE_LENGTH, tuple_type_name, tuple_field_name};
+use crate::analysis::{Warning, SHADOWED_GLOBALS};
+use crate::macros::{self, MacroDef, MAX_EXPANSION_DEPTH};
+use crate::lexer::DocComment;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
 pub static TOP_LEVEL_FUNCTION_NAME : &'static str = "__top_level";
 
@@ -29,7 +33,48 @@ impl From<Uid> for ReferenceId {
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum TypeKind {
-  Struct, Union
+  Struct, Union,
+  /// A union that stores which of its fields it holds, from `tagged union name { ... }`.
+  /// Its fields can only be read with `match`.
+  TaggedUnion,
+}
+
+/// How a struct is laid out in memory, from `struct name with packed { ... }` or
+/// `struct name with align(16) { ... }`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StructLayout {
+  /// No padding between the fields, and an alignment of one
+  pub packed : bool,
+  /// The least alignment of the struct. Its size is padded to a multiple of it.
+  pub align : Option<u32>,
+}
+
+/// How a function is called. Only functions bound with `cbind` can use anything
+/// other than `C`, e.g. `cbind foo : fun() => i32 with convention(stdcall)`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CallingConvention {
+  C, Stdcall, Fastcall
+}
+
+impl CallingConvention {
+  pub fn from_string(s : &str) -> Option<Self> {
+    match s {
+      "c" => Some(CallingConvention::C),
+      "stdcall" => Some(CallingConvention::Stdcall),
+      "fastcall" => Some(CallingConvention::Fastcall),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for CallingConvention {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CallingConvention::C => write!(f, "c"),
+      CallingConvention::Stdcall => write!(f, "stdcall"),
+      CallingConvention::Fastcall => write!(f, "fastcall"),
+    }
+  }
 }
 
 #[derive(Debug, Clone)]
@@ -40,12 +85,28 @@ pub struct Reference {
 }
 
 /// TODO: This is a messy way of supporting REPL functionality.
+///
+/// A `Lazy` global's value is a function body, which is run the first time the
+/// global is used, rather than where it's defined.
 #[derive(Debug, Clone, Copy, PartialEq)]
-pub enum GlobalType { Normal, CBind }
+pub enum GlobalType { Normal, CBind, Lazy }
 
 #[derive(Debug, Clone, Copy)]
 pub enum VarScope { Local, Global(GlobalType) }
 
+/// `&&` or `||`, which only evaluate their right operand if the left doesn't decide the result
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShortCircuitOp { And, Or }
+
+impl ShortCircuitOp {
+  pub fn symbol(self) -> &'static str {
+    match self {
+      ShortCircuitOp::And => "&&",
+      ShortCircuitOp::Or => "||",
+    }
+  }
+}
+
 #[derive(Debug)]
 pub enum Content {
   Literal(PrimitiveVal),
@@ -59,23 +120,48 @@ pub enum Content {
 
   IfThen{ condition: NodeId, then_branch: NodeId },
   IfThenElse{ condition: NodeId, then_branch: NodeId, else_branch: NodeId },
+  /// Works on bools, or on options, where it returns the first `none` for `&&` and the
+  /// first `some` for `||`. For any other operands, it calls the overload `function`
+  /// of the operator, which isn't a child, as it's only evaluated for that.
+  ShortCircuit{ op: ShortCircuitOp, left: NodeId, right: NodeId, function: NodeId },
+  /// Jumps to the body with an integer case that matches the value, or to `default`.
+  /// `case_locs` has where each case was written.
+  Switch{ value: NodeId, cases: Vec<(Vec<i64>, NodeId)>, default: Option<NodeId>, case_locs: HashMap<i64, TextLocation> },
   Block(Vec<NodeId>),
   Quote(Box<Expr>),
   Reference { name: RefStr, refers_to: Option<ReferenceId> },
   FunctionDefinition{ name: RefStr, args: Vec<(Reference, Option<Box<Expr>>)>, return_tag: Option<Box<Expr>>, type_vars : Vec<RefStr>, body: NodeId },
-  CBind { name: RefStr, type_tag : Box<Expr> },
-  TypeDefinition{ name: RefStr, kind : TypeKind, fields: Vec<(Reference, Option<Box<Expr>>)>, type_vars : Vec<RefStr> },
+  CBind { name: RefStr, type_tag : Box<Expr>, convention : CallingConvention },
+  TypeDefinition{
+    name: RefStr, kind : TypeKind, fields: Vec<(Reference, Option<Box<Expr>>)>, type_vars : Vec<RefStr>,
+    layout : StructLayout,
+    /// The width in bits of each field that is a bitfield
+    bitfields : Vec<(RefStr, u32)>,
+  },
   TypeConstructor{ name: Reference, field_values: Vec<(Option<Reference>, NodeId)> },
   FieldAccess{ container: NodeId, field: Reference },
   ArrayLiteral(Vec<NodeId>),
   FunctionCall{ function: NodeId, args: Vec<NodeId> },
   While{ condition: NodeId, body: NodeId },
   Convert{ from_value: NodeId, into_type: Box<Expr> },
-  SizeOf{ type_tag: Box<Expr> },
+  /// `sizeof(T)`, `alignof(T)`, `offsetof(T, field)`, `bitoffsetof(T, bitfield)` or `typeinfo(T)`
+  SizeOf{ type_tag: Box<Expr>, query: LayoutQuery },
   Label{ label: LabelId, body: NodeId },
   BreakToLabel{ label: LabelId, return_value: Option<NodeId> },
 }
 
+/// Which part of a type's memory layout a `SizeOf` node asks for
+#[derive(Clone, Debug)]
+pub enum LayoutQuery {
+  Size,
+  Alignment,
+  Offset(Reference),
+  /// The offset of a bitfield in bits
+  BitOffset(Reference),
+  /// The id of a description of the type, for runtime code (see `reflection.rs`)
+  TypeInfo,
+}
+
 impl Content {
   pub fn node_value_type(&self) -> NodeValueType {
     match self {
@@ -83,11 +169,46 @@ impl Content {
       Literal(_) | Quote(_)
         => NodeValueType::Reference,
       Block(_) | FunctionCall{..} |
-      IfThenElse{..} | TypeConstructor{..}
+      IfThenElse{..} | ShortCircuit{..} | Switch{..} | TypeConstructor{..}
         => NodeValueType::Owned,
       _ => NodeValueType::Nil,
     }
   }
+
+  /// The nodes directly inside this one, in the order they are evaluated
+  pub fn children(&self) -> Vec<NodeId> {
+    match self {
+      VariableInitialise{ value, .. } => vec![*value],
+      Assignment{ assignee, value } => vec![*value, *assignee],
+      IfThen{ condition, then_branch } => vec![*condition, *then_branch],
+      IfThenElse{ condition, then_branch, else_branch } =>
+        vec![*condition, *then_branch, *else_branch],
+      ShortCircuit{ left, right, .. } => vec![*left, *right],
+      Switch{ value, cases, default, .. } => {
+        let mut v = vec![*value];
+        v.extend(cases.iter().map(|(_, n)| *n));
+        v.extend(default.iter().cloned());
+        v
+      }
+      Block(nodes) => nodes.clone(),
+      FunctionDefinition{ body, .. } => vec![*body],
+      TypeConstructor{ field_values, .. } => field_values.iter().map(|(_, n)| *n).collect(),
+      FieldAccess{ container, .. } => vec![*container],
+      ArrayLiteral(elements) => elements.clone(),
+      FunctionCall{ function, args } => {
+        let mut v = vec![*function];
+        v.extend_from_slice(args);
+        v
+      }
+      While{ condition, body } => vec![*condition, *body],
+      Convert{ from_value, .. } => vec![*from_value],
+      Label{ body, .. } => vec![*body],
+      BreakToLabel{ return_value, .. } => return_value.iter().cloned().collect(),
+      Literal(_) | TypeAlias{..} | Quote(_) | Content::Reference{..} |
+      CBind{..} | TypeDefinition{..} | SizeOf{..}
+        => vec![],
+    }
+  }
 }
 
 use Content::*;
@@ -103,7 +224,7 @@ pub enum NodeValueType {
   Nil,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Ord, PartialOrd)]
 pub struct NodeId(Uid);
 
 impl From<Uid> for NodeId { fn from(v : Uid) -> Self { NodeId(v) } }
@@ -122,18 +243,229 @@ pub struct NodeConverter<'l> {
 
   symbols : HashMap<ReferenceId, Reference>,
 
+  pragmas : Vec<Pragma>,
+  unsafe_blocks : HashSet<NodeId>,
+  endless_loops : HashSet<NodeId>,
+  destructured_tuples : HashMap<NodeId, usize>,
+  method_calls : HashSet<NodeId>,
+  mutable_locals : HashSet<ReferenceId>,
+
+  /// Names of the statics defined at the top level of the unit
+  globals : HashSet<RefStr>,
+  /// Names of everything defined at the top level of the unit
+  definitions : HashSet<RefStr>,
+  warnings : Vec<Warning>,
+  tests : Vec<TestDefinition>,
+  init_functions : Vec<RefStr>,
+  macros : Vec<RefStr>,
+  /// The macros that the unit can use, from the units it imports
+  macro_defs : &'l HashMap<RefStr, MacroDef>,
+  inline_functions : Vec<InlineDef>,
+  /// The inline functions that the unit can use, from the units it imports
+  inline_defs : &'l HashMap<RefStr, InlineDef>,
+  expansion_depth : usize,
+  doc_comments : &'l [DocComment],
+  docs : HashMap<NodeId, RefStr>,
+  matches : Vec<TaggedMatch>,
+
   cache: &'l StringCache,
 }
 
 pub struct FunctionConverter<'l, 'lt> {
   t : &'l mut NodeConverter<'lt>,
   labels_in_scope : Vec<LabelId>,
+  /// The loops around the code being converted, innermost last
+  loops : Vec<LoopLabels>,
   block_scope : Vec<Vec<Reference>>,
 }
 
+/// The labels that `break` and `continue` jump to, to leave a loop
+struct LoopLabels {
+  name : Option<RefStr>,
+  break_label : LabelId,
+  continue_label : LabelId,
+  /// Only `loop` can break with a value, because other loops can also stop without one
+  has_value : bool,
+  /// Whether the body uses `continue`, and so needs a label
+  continued : bool,
+}
+
+/// A directive for the compiler, like `pragma allow(unused_variables)`.
+/// Pragmas apply to the whole unit that they appear in.
+#[derive(Debug, Clone)]
+pub struct Pragma {
+  pub name : RefStr,
+  pub args : Vec<RefStr>,
+  pub loc : TextLocation,
+}
+
+/// A `test "name" { ... }` block. The body is compiled as a function with no
+/// arguments, which is run by `Compiler::run_tests`.
+#[derive(Debug, Clone)]
+pub struct TestDefinition {
+  pub name : RefStr,
+  pub function_name : RefStr,
+  pub loc : TextLocation,
+}
+
+/// A `match` on a tagged union. It's desugared into a chain of `if`s, so this is
+/// kept for the typechecker to check that the arms cover every variant.
+#[derive(Debug, Clone)]
+pub struct TaggedMatch {
+  /// The value being matched on
+  pub value : NodeId,
+  /// The variant of each arm, other than `_`
+  pub variants : Vec<Reference>,
+  pub has_default : bool,
+  pub loc : TextLocation,
+}
+
+/// The hidden fields that `match` reads from a tagged union. Their names start with
+/// `@`, so they can't be written in code.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VariantAccess<'l> {
+  /// `@is_circle` is true if the value is the `circle` variant
+  Test(&'l str),
+  /// `@circle` is the value of the `circle` variant
+  Read(&'l str),
+}
+
+impl <'l> VariantAccess<'l> {
+  pub fn from_field(field : &'l str) -> Option<Self> {
+    if field.starts_with("@is_") {
+      Some(VariantAccess::Test(&field[4..]))
+    }
+    else if field.starts_with('@') {
+      Some(VariantAccess::Read(&field[1..]))
+    }
+    else {
+      None
+    }
+  }
+
+  pub fn field_name(self) -> String {
+    match self {
+      VariantAccess::Test(v) => format!("@is_{}", v),
+      VariantAccess::Read(v) => format!("@{}", v),
+    }
+  }
+
+  pub fn variant(self) -> &'l str {
+    match self { VariantAccess::Test(v) | VariantAccess::Read(v) => v }
+  }
+}
+
+/// `test "draws a line"` becomes `test_draws_a_line`, so it can also be run with `:test`
+pub fn test_function_name(name : &str) -> String {
+  let name : String = name.chars().map(|c| if c.is_alphanumeric() { c } else { '_' }).collect();
+  format!("test_{}", name)
+}
+
+/// A function defined with `inline fun`. Calls to it from the units that import it
+/// are replaced with its body. Type tags that mention the function's type variables
+/// are dropped, so those types are inferred at each call site.
+#[derive(Debug, Clone)]
+pub struct InlineDef {
+  pub name : RefStr,
+  /// Each argument's name, with its type tag if it has one
+  pub args : Vec<Expr>,
+  pub return_tag : Option<Expr>,
+  pub body : Expr,
+}
+
+impl InlineDef {
+  fn from_function(cache : &StringCache, expr : &Expr, exprs : &[Expr]) -> Result<InlineDef, Error> {
+    let (name, args, return_tag, polytypes, body) = match exprs {
+      [name, args, body] => (name, args, None, None, body),
+      [name, args, return_tag, polytypes, body] => (name, args, Some(return_tag), Some(polytypes), body),
+      [name, args, unknown, body] => match unknown.try_construct() {
+        Some(("polytypes", _)) => (name, args, None, Some(unknown), body),
+        _ => (name, args, Some(unknown), None, body),
+      },
+      _ => return error(expr, "malformed function definition"),
+    };
+    let name = cache.get(name.unwrap_symbol()?);
+    let type_vars : Vec<&str> =
+      polytypes.map(|p| p.children().iter().filter_map(|e| e.try_symbol()).collect())
+      .unwrap_or_default();
+    let generic = |e : &Expr| any_symbol(e, &|s| type_vars.contains(&s));
+    if generic(body) {
+      return error(body, format!("inline function '{}' can't refer to its type variables in its body", name));
+    }
+    let args = args.children().iter().map(|a| match a.try_construct() {
+      Some((":", [arg_name, t])) if generic(t) => arg_name.clone(),
+      _ => a.clone(),
+    }).collect();
+    let return_tag = return_tag.filter(|t| !generic(t)).cloned();
+    Ok(InlineDef { name, args, return_tag, body: body.clone() })
+  }
+
+  /// True if the other definition has the same name, arguments and body
+  pub fn same_definition(&self, other : &InlineDef) -> bool {
+    let same_return_tag = match (&self.return_tag, &other.return_tag) {
+      (Some(a), Some(b)) => a.structurally_equal(b),
+      (a, b) => a.is_none() && b.is_none(),
+    };
+    self.name == other.name && same_return_tag
+      && self.args.len() == other.args.len()
+      && self.args.iter().zip(other.args.iter()).all(|(a, b)| a.structurally_equal(b))
+      && self.body.structurally_equal(&other.body)
+  }
+
+  /// True if the function refers to any of the names
+  fn refers_to_any(&self, names : &HashSet<RefStr>) -> bool {
+    let f = |s : &str| names.contains(s);
+    self.args.iter().chain(self.return_tag.iter()).chain(Some(&self.body))
+      .any(|e| any_symbol(e, &f))
+  }
+}
+
+fn any_symbol(e : &Expr, f : &dyn Fn(&str) -> bool) -> bool {
+  match &e.content {
+    ExprContent::Symbol(s) => f(s.as_str()),
+    ExprContent::List(_, _) => e.children().iter().any(|e| any_symbol(e, f)),
+    _ => false,
+  }
+}
+
+/// The definitions that can have doc comments
+static DOCUMENTED : &'static [&'static str] = &["fun", "macro", "struct", "union", "tagged", "cbind", "static", "lazy"];
+
+static PRAGMAS : &'static [&'static str] = &["allow", "default_int", "default_float", "require_unsafe"];
+
 pub struct Nodes {
   pub nodes : HashMap<NodeId, Node>,
   pub symbols : HashMap<ReferenceId, Reference>,
+  pub pragmas : Vec<Pragma>,
+  /// Blocks that were written as `unsafe { ... }`
+  pub unsafe_blocks : HashSet<NodeId>,
+  /// The `while` nodes of `loop`s, which can only be left with `break` or `return`
+  pub endless_loops : HashSet<NodeId>,
+  /// The hidden locals that `let (a, b) = ...` reads its values from, with the
+  /// length of tuple that they hold
+  pub destructured_tuples : HashMap<NodeId, usize>,
+  /// Calls written as `x.f(...)`, whose receiver `x` can be passed by reference
+  /// or dereferenced to fit the first parameter of `f`
+  pub method_calls : HashSet<NodeId>,
+  /// Locals that can be assigned to. These are the ones declared with `var`,
+  /// and function arguments. Locals declared with `let` are immutable.
+  pub mutable_locals : HashSet<ReferenceId>,
+  /// Warnings found while structuring, like locals shadowing globals
+  pub warnings : Vec<Warning>,
+  pub tests : Vec<TestDefinition>,
+  /// The functions that `init { ... }` blocks were compiled into, in the order
+  /// that they appear. `Compiler::initialise` runs them after the top-level code.
+  pub init_functions : Vec<RefStr>,
+  /// The functions that were defined with `macro`, which later units can expand
+  pub macros : Vec<RefStr>,
+  /// The doc comments of the definitions that have them
+  pub docs : HashMap<NodeId, RefStr>,
+  /// The functions that were defined with `inline fun`, which later units inline
+  pub inline_functions : Vec<InlineDef>,
+  /// Array literals that don't outlive their function, so they go on the stack.
+  /// Filled in after typechecking, by `escape::mark_stack_arrays`.
+  pub stack_arrays : HashSet<NodeId>,
+  pub matches : Vec<TaggedMatch>,
   pub root : NodeId,
 }
 
@@ -178,18 +510,126 @@ impl <'l> NodeRef<'l> {
 pub fn to_nodes(
   uid_generator : &mut UIDGenerator,
   cache : &StringCache,
-  expr : &Expr)
+  expr : &Expr,
+  macro_defs : &HashMap<RefStr, MacroDef>,
+  inline_defs : &HashMap<RefStr, InlineDef>,
+  doc_comments : &[DocComment])
     -> Result<Nodes, Error>
 {
   let mut nc = NodeConverter {
     uid_generator,
     nodes: HashMap::new(),
     symbols: HashMap::new(),
+    pragmas: vec![],
+    unsafe_blocks: HashSet::new(),
+    endless_loops: HashSet::new(),
+    destructured_tuples: HashMap::new(),
+    method_calls: HashSet::new(),
+    mutable_locals: HashSet::new(),
+    globals: static_names(expr),
+    definitions: definition_names(expr),
+    warnings: vec![],
+    tests: vec![],
+    init_functions: vec![],
+    macros: vec![],
+    macro_defs, inline_functions: vec![], inline_defs, expansion_depth: 0,
+    doc_comments, docs: HashMap::new(), matches: vec![],
     cache,
   };
   let mut fc = FunctionConverter::new(&mut nc, vec![]);
   let top_level = fc.top_level_expression(expr)?;
-  Ok(Nodes{ root: top_level, nodes: nc.nodes, symbols: nc.symbols })
+  Ok(Nodes{
+    root: top_level, nodes: nc.nodes, symbols: nc.symbols,
+    pragmas: nc.pragmas, unsafe_blocks: nc.unsafe_blocks, endless_loops: nc.endless_loops,
+    destructured_tuples: nc.destructured_tuples, method_calls: nc.method_calls,
+    mutable_locals: nc.mutable_locals, warnings: nc.warnings,
+    tests: nc.tests, init_functions: nc.init_functions,
+    macros: nc.macros, docs: nc.docs, inline_functions: nc.inline_functions,
+    stack_arrays: HashSet::new(), matches: nc.matches,
+  })
+}
+
+fn is_loop(e : &Expr) -> bool {
+  match e.try_construct() {
+    Some(("while", _)) | Some(("for", _)) | Some(("loop", _)) => true,
+    _ => false,
+  }
+}
+
+/// Reads the attributes after `with` in a struct definition
+fn struct_layout(attributes : &Expr) -> Result<StructLayout, Error> {
+  let mut layout = StructLayout::default();
+  for a in attributes.children() {
+    match (a.try_symbol(), a.try_construct()) {
+      (Some("packed"), _) => layout.packed = true,
+      (_, Some(("call", [f, n]))) if f.try_symbol() == Some("align") => {
+        match &n.content {
+          ExprContent::LiteralInt(n) if *n > 0 && (*n as u64).is_power_of_two() && *n <= 1 << 16 =>
+            layout.align = Some(*n as u32),
+          _ => return error(n, "alignment must be a power of two, up to 65536"),
+        }
+      }
+      _ => return error(a, "expected a struct attribute, 'packed' or 'align(n)'"),
+    }
+  }
+  if layout.packed && layout.align.is_some() {
+    return error(attributes, "a struct can't be both packed and aligned");
+  }
+  Ok(layout)
+}
+
+/// Finds the names of the statics defined at the top level, so that locals
+/// which shadow them can be warned about.
+fn static_names(expr : &Expr) -> HashSet<RefStr> {
+  let mut names = HashSet::new();
+  for e in expr.children() {
+    let e = match e.try_construct() {
+      Some(("lazy", [e])) => e,
+      _ => e,
+    };
+    if let Some(("static", [def])) = e.try_construct() {
+      if let Some(("=", [name_expr, _])) = def.try_construct() {
+        let name_expr = match name_expr.try_construct() {
+          Some((":", [name_expr, _])) => name_expr,
+          _ => name_expr,
+        };
+        if let Some(name) = name_expr.try_symbol() {
+          names.insert(name.into());
+        }
+      }
+    }
+  }
+  names
+}
+
+/// Finds the names of everything defined at the top level. A call isn't inlined if
+/// the inline function refers to one of these names, since it would find the unit's
+/// definition instead of the one that it meant.
+fn definition_names(expr : &Expr) -> HashSet<RefStr> {
+  let mut names = static_names(expr);
+  for e in expr.children() {
+    let e = match e.try_construct() {
+      Some(("inline", [e])) | Some(("stage", [_, e])) | Some(("tagged", [e])) => e,
+      _ => e,
+    };
+    let name = match e.try_construct() {
+      Some(("fun", [name, ..])) | Some(("macro", [name, ..])) |
+      Some(("struct", [name, _])) | Some(("union", [name, _])) => name,
+      Some(("cbind", [typed, ..])) => match typed.try_construct() {
+        Some((":", [name, _])) => name,
+        _ => typed,
+      },
+      _ => continue,
+    };
+    let name = match name.try_construct() {
+      Some(("call", [name, ..])) => name,
+      _ => name,
+    };
+    if let Some(name) = name.try_symbol() {
+      names.insert(name.into());
+    }
+  }
+  names
 }
 
 impl <'l> NodeConverter<'l> {
@@ -214,7 +654,7 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
   pub fn new(t : &'l mut NodeConverter<'lt>, args : Vec<Reference>)
    -> FunctionConverter<'l, 'lt>
   {
-    FunctionConverter { t, labels_in_scope : vec![], block_scope: vec![args] }
+    FunctionConverter { t, labels_in_scope : vec![], loops: vec![], block_scope: vec![args] }
   }
 
   fn add_var_to_scope(&mut self, var : Reference) {
@@ -222,6 +662,30 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
     scope.push(var);
   }
 
+  /// Locals may shadow the unit's statics, but it's usually a mistake
+  fn warn_if_shadowing_global(&mut self, var : &Reference) {
+    if self.t.globals.contains(&var.name) {
+      let w = warning_raw(var.loc, format!("'{}' shadows a static with the same name", var.name));
+      self.t.warnings.push((SHADOWED_GLOBALS, w));
+    }
+  }
+
+  /// Defines a new local. Redefining a local in the same scope is an
+  /// error, unless it's explicitly rebound with `let shadow`.
+  fn define_local(&mut self, var : Reference, rebind : bool) -> Result<(), Error> {
+    if !rebind {
+      let scope = self.block_scope.last().unwrap();
+      if let Some(prev) = scope.iter().find(|v| v.name == var.name) {
+        return error(var.loc, format!(
+          "'{}' is already defined in this scope, at {}; use `let shadow {} = ...` to rebind it",
+          var.name, prev.loc, var.name));
+      }
+    }
+    self.warn_if_shadowing_global(&var);
+    self.add_var_to_scope(var);
+    Ok(())
+  }
+
   fn find_var(&self, name : &str) -> Option<&Reference> {
     for var in self.block_scope.iter().flat_map(|i| i.iter()).rev() {
       if name == var.name.as_ref() { return Some(var) }
@@ -264,6 +728,26 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
     Ok(())
   }
 
+  /// Gives a definition the doc comment on the lines directly above it
+  fn attach_doc(&mut self, expr : &Expr, id : NodeId) {
+    match expr.try_construct() {
+      Some((name, _)) if DOCUMENTED.contains(&name) => (),
+      _ => return,
+    }
+    let comments = self.t.doc_comments;
+    let mut lines = vec![];
+    let mut line = expr.loc.start.line;
+    while let Some(c) = comments.iter().find(|c| c.source == expr.loc.source && c.line + 1 == line) {
+      lines.push(c.text.as_str());
+      line = c.line;
+    }
+    if !lines.is_empty() {
+      lines.reverse();
+      let doc = self.cached(&lines.join("\n"));
+      self.t.docs.insert(id, doc);
+    }
+  }
+
   fn node(&mut self, expr : &Expr, content : Content) -> NodeId {
     self.t.node(expr, content)
   }
@@ -313,6 +797,71 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
     Ok(self.node(expr, c))
   }
 
+  /// `(a, b)` makes a tuple, which is a struct with a field for each value
+  fn tuple_to_node(&mut self, expr : &Expr, exprs : &[Expr]) -> Result<NodeId, Error> {
+    if exprs.len() < 2 || exprs.len() > MAX_TUPLE_LENGTH {
+      return error(expr, format!("tuples hold between 2 and {} values", MAX_TUPLE_LENGTH));
+    }
+    let values = exprs.iter().map(|e| self.to_node(e)).collect::<Result<Vec<NodeId>, Error>>()?;
+    let name = self.t.symbol(&tuple_type_name(exprs.len()), expr);
+    Ok(self.type_constructor(expr, name, values))
+  }
+
+  /// `let (a, b) = v` keeps the tuple `v` in a hidden local, and reads each of
+  /// its fields into a new local
+  fn destructure(&mut self, expr : &Expr, names : &[Expr], value_expr : &Expr, mutable : bool, rebind : bool)
+    -> Result<NodeId, Error>
+  {
+    if names.len() > MAX_TUPLE_LENGTH {
+      return error(expr, format!("tuples hold between 2 and {} values", MAX_TUPLE_LENGTH));
+    }
+    let value = self.to_node(value_expr)?;
+    let tuple = self.t.symbol("@tuple", expr);
+    let let_tuple = self.let_var(expr, tuple.clone(), value);
+    self.t.destructured_tuples.insert(let_tuple, names.len());
+    let mut nodes = vec![let_tuple];
+    for (i, name_expr) in names.iter().enumerate() {
+      // `_` skips a value
+      if name_expr.try_symbol() == Some("_") {
+        continue;
+      }
+      let (name, type_tag) = self.typed_symbol(name_expr)?;
+      let container = self.node(name_expr, Content::Reference{ name: tuple.name.clone(), refers_to: Some(tuple.id) });
+      let field = self.t.symbol(&tuple_field_name(i), name_expr);
+      let value = self.node(name_expr, FieldAccess{ container, field });
+      self.define_local(name.clone(), rebind)?;
+      if mutable {
+        self.t.mutable_locals.insert(name.id);
+      }
+      nodes.push(self.node(name_expr, VariableInitialise{ name, type_tag, value, var_scope: VarScope::Local }));
+    }
+    Ok(self.node(expr, Block(nodes)))
+  }
+
+  fn cbind_to_node(&mut self, expr : &Expr, typed_symbol : &Expr, convention_expr : Option<&Expr>)
+    -> Result<NodeId, Error>
+  {
+    let convention = match convention_expr {
+      None => CallingConvention::C,
+      Some(e) => match e.try_construct() {
+        Some(("call", [f, c])) if f.try_symbol() == Some("convention") => {
+          let c = c.unwrap_symbol()?;
+          match CallingConvention::from_string(c) {
+            Some(c) => c,
+            None => return error(e, format!("unknown calling convention '{}'", c)),
+          }
+        }
+        _ => return error(e, "expected `convention(...)` after `with` in cbind"),
+      }
+    };
+    if let (":", [name_expr, type_expr]) = typed_symbol.unwrap_construct()? {
+      let name = self.cached(name_expr.unwrap_symbol()?);
+      let type_tag = type_expr.clone().into();
+      return Ok(self.node(expr, CBind{ name, type_tag, convention }));
+    }
+    error(expr, "invalid cbind expression")
+  }
+
   fn function_def_to_node(
     &mut self,
     expr : &Expr,
@@ -347,10 +896,61 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
       else { vec![] }
     };
     let mut function_checker = FunctionConverter::new(self.t, arg_symbols);
+    for (arg, _) in args.iter() {
+      function_checker.warn_if_shadowing_global(arg);
+      function_checker.t.mutable_locals.insert(arg.id);
+    }
     let body = function_checker.to_function_body(body)?;
     return Ok(self.node(expr, FunctionDefinition{name, args, type_vars, return_tag, body}));
   }
 
+  /// Replaces a call to an inline function with the function's body, which runs in
+  /// a scope that can only see its arguments, so the call site's locals can't capture
+  /// its names. `return` in the body leaves the inlined body. Returns `None` if the call
+  /// has to stay a call.
+  fn inline_call(&mut self, expr : &Expr, def : &InlineDef, args : &[Expr])
+    -> Result<Option<NodeId>, Error>
+  {
+    let named_args = args.iter().any(|a| a.try_construct().map(|(s, _)| s == ":") == Some(true));
+    if args.len() != def.args.len() || named_args || self.t.expansion_depth >= MAX_EXPANSION_DEPTH
+      || self.t.definitions.contains(&def.name) || def.refers_to_any(&self.t.definitions)
+    {
+      return Ok(None);
+    }
+    let mut nodes = vec![];
+    let mut params = vec![];
+    for (arg, param) in args.iter().zip(def.args.iter()) {
+      let value = self.to_node(arg)?;
+      let (name, type_tag) = self.typed_symbol(param)?;
+      self.t.mutable_locals.insert(name.id);
+      params.push(name.clone());
+      nodes.push(self.node(arg, VariableInitialise{ name, type_tag, value, var_scope: VarScope::Local }));
+    }
+    let block_scope = std::mem::replace(&mut self.block_scope, vec![params]);
+    let labels_in_scope = std::mem::replace(&mut self.labels_in_scope, vec![]);
+    let loops = std::mem::replace(&mut self.loops, vec![]);
+    self.t.expansion_depth += 1;
+    let body = self.labelled_node(expr, |fc| fc.to_node(&def.body));
+    self.t.expansion_depth -= 1;
+    self.block_scope = block_scope;
+    self.labels_in_scope = labels_in_scope;
+    self.loops = loops;
+    let body = body?;
+    // the function was checked for unsafe operations where it was defined
+    self.t.unsafe_blocks.insert(body);
+    if let Some(t) = &def.return_tag {
+      let result = self.t.symbol("@inline_result", expr);
+      let type_tag = Some(Box::new(t.clone()));
+      nodes.push(self.node(expr, VariableInitialise{
+        name: result.clone(), type_tag, value: body, var_scope: VarScope::Local }));
+      nodes.push(self.node(expr, Content::Reference{ name: result.name, refers_to: Some(result.id) }));
+    }
+    else {
+      nodes.push(body);
+    }
+    Ok(Some(self.node(expr, Block(nodes))))
+  }
+
   fn construct_to_node(&mut self, expr : &Expr) -> Result<NodeId, Error> {
     let (instr, children) = expr.unwrap_construct()?;
     match (instr, children) {
@@ -361,7 +961,97 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
           Some("sizeof") => {
             if exprs.len() == 2 {
               let type_tag = exprs[1].clone().into();
-              return Ok(self.node(expr, SizeOf{ type_tag }));
+              return Ok(self.node(expr, SizeOf{ type_tag, query: LayoutQuery::Size }));
+            }
+          }
+          Some("alignof") => {
+            if exprs.len() == 2 {
+              let type_tag = exprs[1].clone().into();
+              return Ok(self.node(expr, SizeOf{ type_tag, query: LayoutQuery::Alignment }));
+            }
+          }
+          Some("typeinfo") => {
+            if exprs.len() == 2 {
+              let type_tag = exprs[1].clone().into();
+              return Ok(self.node(expr, SizeOf{ type_tag, query: LayoutQuery::TypeInfo }));
+            }
+          }
+          Some("offsetof") => {
+            if exprs.len() == 3 {
+              let type_tag = exprs[1].clone().into();
+              let field = self.expr_to_symbol(&exprs[2])?;
+              return Ok(self.node(expr, SizeOf{ type_tag, query: LayoutQuery::Offset(field) }));
+            }
+          }
+          Some("bitoffsetof") => {
+            if exprs.len() == 3 {
+              let type_tag = exprs[1].clone().into();
+              let field = self.expr_to_symbol(&exprs[2])?;
+              return Ok(self.node(expr, SizeOf{ type_tag, query: LayoutQuery::BitOffset(field) }));
+            }
+          }
+          // expectations are passed their source location, so that failures can report it,
+          // unless the unit has its own function by that name
+          Some(s) if (s == "expect" || s == "expect_eq")
+            && self.find_var(s).is_none() && !self.t.definitions.contains(s) =>
+          {
+            let mut args =
+              exprs[1..].iter().map(|e| self.to_node(e))
+              .collect::<Result<Vec<NodeId>, Error>>()?;
+            let loc = format!("{}", expr.loc.start);
+            args.push(self.node(expr, Literal(PrimitiveVal::String(loc))));
+            let name = self.cached(&format!("{}_at", s));
+            let function = self.node(function_expr, Content::Reference{ name, refers_to: None });
+            return Ok(self.node(expr, FunctionCall{ function, args }));
+          }
+          // `?e` records the value of `e` each time it runs, under the span of `e`
+          Some("?") if exprs.len() == 2 => {
+            let value = &exprs[1];
+            let loc = value.loc;
+            let span = format!("{}:{}-{}:{}", loc.start.line, loc.start.col, loc.end.line, loc.end.col);
+            let args = vec![
+              self.to_node(value)?,
+              self.node(expr, Literal(PrimitiveVal::Int(loc.source.inner().inner() as i64))),
+              self.node(expr, Literal(PrimitiveVal::String(span))),
+            ];
+            let name = self.cached("probe_at");
+            let function = self.node(function_expr, Content::Reference{ name, refers_to: None });
+            return Ok(self.node(expr, FunctionCall{ function, args }));
+          }
+          Some(s) if (s == "&&" || s == "||") && exprs.len() == 3 => {
+            let op = if s == "&&" { ShortCircuitOp::And } else { ShortCircuitOp::Or };
+            let left = self.to_node(&exprs[1])?;
+            let right = self.to_node(&exprs[2])?;
+            let name = self.cached(s);
+            let function = self.node(function_expr, Content::Reference{ name, refers_to: None });
+            return Ok(self.node(expr, ShortCircuit{ op, left, right, function }));
+          }
+          // `checked(p)` records where the checked pointer was made, to report if it dangles
+          Some("checked") if exprs.len() == 2 && self.find_var("checked").is_none() => {
+            let site = format!("{}", expr.loc.start);
+            let args = vec![
+              self.to_node(&exprs[1])?,
+              self.node(expr, Literal(PrimitiveVal::String(site))),
+            ];
+            let name = self.cached("checked_at");
+            let function = self.node(function_expr, Content::Reference{ name, refers_to: None });
+            return Ok(self.node(expr, FunctionCall{ function, args }));
+          }
+          Some(s) if self.t.macro_defs.contains_key(s) && self.find_var(s).is_none() => {
+            let m = self.t.macro_defs.get(s).unwrap();
+            if self.t.expansion_depth >= MAX_EXPANSION_DEPTH {
+              return error(expr, format!("macro expansion went more than {} deep, in '{}'", MAX_EXPANSION_DEPTH, s));
+            }
+            let expansion = macros::expand(m, expr, &exprs[1..])?;
+            self.t.expansion_depth += 1;
+            let node = self.to_node(&expansion);
+            self.t.expansion_depth -= 1;
+            return node;
+          }
+          Some(s) if self.t.inline_defs.contains_key(s) && self.find_var(s).is_none() => {
+            let inline_defs = self.t.inline_defs;
+            if let Some(node) = self.inline_call(expr, &inline_defs[s], &exprs[1..])? {
+              return Ok(node);
             }
           }
           _ => (),
@@ -371,7 +1061,11 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
           .collect::<Result<Vec<NodeId>, Error>>()?;
         let function = self.to_node(function_expr)?;
         let content = FunctionCall{ function, args };
-        return Ok(self.node(expr, content));
+        let call = self.node(expr, content);
+        if is_method_call(function_expr, &exprs[1..]) {
+          self.t.method_calls.insert(call);
+        }
+        return Ok(call);
       }
       ("as", [from_value, into_type]) => {
         let from_value = self.to_node(from_value)?;
@@ -388,11 +1082,57 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
         }
         error(expr, "malformed let expression")
       }
-      ("let", [e]) => {
+      ("lazy", [e]) => {
+        if let Some(("static", [def])) = e.try_construct() {
+          if let Some(("=", [name_expr, value_expr])) = def.try_construct() {
+            let (name, type_tag) = self.typed_symbol(name_expr)?;
+            // the initialiser runs on its own, so it can't see the top-level locals
+            let value = FunctionConverter::new(self.t, vec![]).to_function_body(value_expr)?;
+            let var_scope = VarScope::Global(GlobalType::Lazy);
+            let c = VariableInitialise { name, type_tag, value, var_scope };
+            return Ok(self.node(expr, c));
+          }
+        }
+        error(expr, "malformed lazy static expression")
+      }
+      ("inline", [def]) => {
+        match def.try_construct() {
+          Some(("fun", exprs)) => {
+            let inline_def = InlineDef::from_function(self.t.cache, def, exprs)?;
+            self.t.inline_functions.push(inline_def);
+            self.to_node(def)
+          }
+          _ => error(expr, "expected a function definition after 'inline'"),
+        }
+      }
+      ("stage", [_, def]) => {
+        error(def, "stage functions must be defined at the top level of a module")
+      }
+      ("init", [body]) => {
+        let function_name = format!("__init_{}", self.t.init_functions.len());
+        let function_name = self.cached(&function_name);
+        self.t.init_functions.push(function_name.clone());
+        let body = FunctionConverter::new(self.t, vec![]).to_function_body(body)?;
+        Ok(self.node(expr, FunctionDefinition{
+          name: function_name, args: vec![], type_vars: vec![], return_tag: None, body }))
+      }
+      ("let", exprs) | ("var", exprs) => {
+        let mutable = instr == "var";
+        let (rebind, e) = match exprs {
+          [e] => (false, e),
+          [shadow, e] if shadow.try_symbol() == Some("shadow") => (true, e),
+          _ => return error(expr, "malformed let expression"),
+        };
         if let Some(("=", [name_expr, value_expr])) = e.try_construct() {
+          if let Some(("tuple", names)) = name_expr.try_construct() {
+            return self.destructure(expr, names, value_expr, mutable, rebind);
+          }
           let (name, type_tag) = self.typed_symbol(name_expr)?;
           let value = self.to_node(value_expr)?;
-          self.add_var_to_scope(name.clone());
+          self.define_local(name.clone(), rebind)?;
+          if mutable {
+            self.t.mutable_locals.insert(name.id);
+          }
           let c = VariableInitialise{ name, type_tag, value, var_scope: VarScope::Local };
           return Ok(self.node(expr, c));
         }
@@ -408,6 +1148,35 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
         }
         error(expr, "malformed let expression")
       }
+      ("pragma", [e]) => {
+        let (name, args) = match e.try_construct() {
+          Some(("call", exprs)) => (&exprs[0], &exprs[1..]),
+          _ => (e, &[] as &[Expr]),
+        };
+        let name = self.cached(name.unwrap_symbol()?);
+        if !PRAGMAS.contains(&name.as_ref()) {
+          return error(expr, format!("unknown pragma '{}'", name));
+        }
+        let args =
+          args.iter().map(|e| { let s = self.cached(e.unwrap_symbol()?) ; Ok(s) })
+          .collect::<Result<Vec<_>, Error>>()?;
+        self.t.pragmas.push(Pragma{ name, args, loc: expr.loc });
+        Ok(self.node(expr, Literal(PrimitiveVal::Void)))
+      }
+      ("test", [name, body]) => {
+        let name = match &name.content {
+          ExprContent::LiteralString(s) => self.cached(s.as_str()),
+          _ => return error(name, "expected a test name"),
+        };
+        let function_name = self.cached(&test_function_name(&name));
+        if self.t.tests.iter().any(|t| t.function_name == function_name) {
+          return error(expr, format!("test '{}' is defined more than once", name));
+        }
+        self.t.tests.push(TestDefinition{ name, function_name: function_name.clone(), loc: expr.loc });
+        let body = FunctionConverter::new(self.t, vec![]).to_function_body(body)?;
+        Ok(self.node(expr, FunctionDefinition{
+          name: function_name, args: vec![], type_vars: vec![], return_tag: None, body }))
+      }
       ("#", [quoted_expr]) => {
         self.quote_to_node(expr, quoted_expr)
       }
@@ -431,20 +1200,13 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
         let c = BreakToLabel{ label, return_value };
         Ok(self.node(expr, c))
       }
-      ("while", [condition_expr, body_expr]) => {
-        // Add label to scope in case the loop breaks
-        self.labelled_node(expr, |fc| {
-          let condition = fc.to_node(condition_expr)?;
-          let body = fc.to_node(body_expr)?;
-          let while_node = fc.node(expr, While{ condition, body });
-          Ok(while_node)
-        })
-      }
-      ("for", [range_expr, body_expr]) => {
-        self.for_loop(expr, range_expr, body_expr)
-      }
+      ("while", _) | ("for", _) | ("loop", _) => self.loop_to_node(expr, None, expr),
+      // a labelled loop, like `outer : while x { ... }`
+      (":", [label, loop_expr]) if is_loop(loop_expr) => self.loop_to_node(expr, Some(label), loop_expr),
+      ("break", args) => self.break_to_node(expr, args),
+      ("continue", args) => self.continue_to_node(expr, args),
       ("if", exprs) => {
-        if exprs.len() > 3 {
+        if exprs.len() < 2 || exprs.len() > 3 {
           return error(expr, "malformed if expression");
         }
         let condition = self.to_node(&exprs[0])?;
@@ -464,14 +1226,13 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
         })?;
         Ok(self.node(expr, Block(nodes)))
       }
-      ("cbind", [e]) => {
-        if let (":", [name_expr, type_expr]) = e.unwrap_construct()? {
-          let name = self.cached(name_expr.unwrap_symbol()?);
-          let type_tag = type_expr.clone().into();
-          return Ok(self.node(expr, CBind{ name, type_tag }));
-        }
-        error(expr, "invalid cbind expression")
+      ("unsafe", [block_expr]) => {
+        let block = self.to_node(block_expr)?;
+        self.t.unsafe_blocks.insert(block);
+        Ok(block)
       }
+      ("cbind", [e]) => self.cbind_to_node(expr, e, None),
+      ("cbind", [e, convention_expr]) => self.cbind_to_node(expr, e, Some(convention_expr)),
       ("fun", exprs) => {
         // slightly ugly hack to work out which subexpression is which.
         // the return type tag is easily mixed up with the polytypes expression.
@@ -496,34 +1257,49 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
           }
         }
       }
-      ("union", [name, fields_expr]) => {
-        let name = self.cached(name.unwrap_symbol()?);
-        let fields =
-          fields_expr.children().iter()
-          .map(|e| self.typed_symbol(e))
-          .collect::<Result<Vec<_>, Error>>()?;
-        let td = TypeDefinition{name, kind: TypeKind::Union, fields, type_vars: vec![] };
-        Ok(self.node(expr, td))
-      }
-      ("struct", [name, fields_expr]) => {
-        let (name, type_vars) = {
-          if let Some(("call", exprs)) = name.try_construct() {
-            let name = self.cached(exprs[0].unwrap_symbol()?);
-            let type_vars : Result<Vec<_>, _> =
-              exprs[1..].iter().map(|e| { let s = self.cached(e.unwrap_symbol()?) ; Ok(s) }).collect();
-            (name, type_vars?)
-          }
-          else {
-            let name = self.cached(name.unwrap_symbol()?);
-            (name, vec![])
-          }
+      ("macro", exprs) => {
+        let (name, args, body) = match exprs {
+          [name, args, body] | [name, args, _, body] => (name, args, body),
+          _ => return error(expr, "malformed macro definition"),
         };
-        let fields =
-          fields_expr.children().iter()
-          .map(|e| self.typed_symbol(e))
-          .collect::<Result<Vec<_>, Error>>()?;
-        Ok(self.node(expr, TypeDefinition{name, kind: TypeKind::Struct, fields, type_vars }))
+        // the arguments and the result are always expressions
+        let expr_type = |e : &Expr| {
+          let ptr = Expr::new(ExprContent::symbol("ptr".into()), e.loc);
+          let t = Expr::new(ExprContent::symbol("expr".into()), e.loc);
+          Expr::new(ExprContent::list("call".into(), vec![ptr, t]), e.loc)
+        };
+        let mut typed_args = vec![];
+        for a in args.children() {
+          let arg_name = match a.try_construct() {
+            Some((":", [arg_name, _])) => arg_name,
+            _ => a,
+          };
+          let typed = vec![arg_name.clone(), expr_type(a)];
+          typed_args.push(Expr::new(ExprContent::list(":".into(), typed), a.loc));
+        }
+        let typed_args = Expr::new(ExprContent::list("args".into(), typed_args), args.loc);
+        let name_symbol = self.cached(name.unwrap_symbol()?);
+        self.t.macros.push(name_symbol);
+        self.function_def_to_node(expr, name, &typed_args, Some(&expr_type(expr)), None, body)
+      }
+      (kind @ "union", [name, fields_expr]) | (kind @ "struct", [name, fields_expr]) |
+      (kind @ "struct", [name, _, fields_expr]) => {
+        let kind = if kind == "union" { TypeKind::Union } else { TypeKind::Struct };
+        let layout = match expr.children() {
+          [_, attributes, _] => struct_layout(attributes)?,
+          _ => StructLayout::default(),
+        };
+        self.type_definition(expr, kind, name, fields_expr, layout)
+      }
+      ("tagged", [def]) => {
+        match def.try_construct() {
+          Some(("union", [name, fields_expr])) =>
+            self.type_definition(expr, TypeKind::TaggedUnion, name, fields_expr, StructLayout::default()),
+          _ => error(expr, "expected a union definition after 'tagged'"),
+        }
       }
+      ("match", [value, arms]) => self.match_to_node(expr, value, arms.children()),
+      ("switch", [value, arms]) => self.switch_to_node(expr, value, arms.children()),
       (".", [container_expr, field_expr]) => {
         let container = self.to_node(container_expr)?;
         let field = self.expr_to_symbol(field_expr)?;
@@ -537,9 +1313,17 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
         }
         Ok(self.node(expr, ArrayLiteral(elements)))
       }
+      ("tuple", exprs) => self.tuple_to_node(expr, exprs),
       ("index", exprs) => {
         let array_expr = &exprs[0];
         if let [index_expr] = &exprs[1..] {
+          // `a[start..end]` is a slice of `a`
+          if let Some(("call", [op, start, end])) = index_expr.try_construct() {
+            if op.try_symbol() == Some("..") {
+              let args = vec![self.to_node(array_expr)?, self.to_node(start)?, self.to_node(end)?];
+              return Ok(self.function_call(expr, "slice", args));
+            }
+          }
           let container = self.to_node(array_expr)?;
           let index = self.to_node(index_expr)?;
           let element_pointer = self.function_call(expr, "Index", vec![container, index]);
@@ -547,6 +1331,9 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
         }
         error(expr, "malformed index expression")
       }
+      ("?", [e]) => {
+        self.propagate_failure(expr, e)
+      }
       (construct, _) => {
         error(expr, format!("invalid '{}' expression", construct))
       }
@@ -556,15 +1343,15 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
   pub fn to_node(&mut self, expr : &Expr) -> Result<NodeId, Error> {
     match &expr.content {
       ExprContent::List(_, _) => {
-        return self.construct_to_node(expr);
+        let id = self.construct_to_node(expr)?;
+        self.attach_doc(expr, id);
+        return Ok(id);
       }
       ExprContent::Symbol(s) => {
         // this is just a normal symbol
         let s = s.as_str();
         if s == "break" {
-          let label = *self.labels_in_scope.last().unwrap();
-          let c = BreakToLabel{ label , return_value: None };
-          return Ok(self.node(expr, c));
+          return self.break_to_node(expr, &[]);
         }
         let name = self.cached(s);
         if let Some(var) = self.find_var(&s) {
@@ -630,6 +1417,134 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
     Ok(self.t.node(loc, Label{ label, body: body? }))
   }
 
+  fn loop_to_node(&mut self, e : &Expr, label : Option<&Expr>, loop_expr : &Expr) -> Result<NodeId, Error> {
+    let name = match label {
+      Some(l) => match l.try_symbol() {
+        Some(s) => Some(self.cached(s)),
+        None => return error(l, "expected a label name"),
+      }
+      None => None,
+    };
+    match loop_expr.try_construct() {
+      Some(("while", [condition_expr, body_expr])) => {
+        self.labelled_loop(e, name, false, |fc| {
+          let condition = fc.to_node(condition_expr)?;
+          let body = fc.loop_body(e, body_expr)?;
+          Ok(fc.node(e, While{ condition, body }))
+        })
+      }
+      Some(("loop", [body_expr])) => {
+        self.labelled_loop(e, name, true, |fc| {
+          let condition = fc.node(e, Literal(PrimitiveVal::Bool(true)));
+          let body = fc.loop_body(e, body_expr)?;
+          let while_node = fc.node(e, While{ condition, body });
+          fc.t.endless_loops.insert(while_node);
+          Ok(while_node)
+        })
+      }
+      Some(("for", [range_expr, body_expr])) => self.for_loop(e, name, range_expr, body_expr),
+      _ => error(loop_expr, "malformed loop"),
+    }
+  }
+
+  /// Converts a loop, with the labels that `break` and `continue` jump to in scope
+  fn labelled_loop<F>(&mut self, e : &Expr, name : Option<RefStr>, has_value : bool, f : F)
+    -> Result<NodeId, Error>
+    where F : Fn(&mut FunctionConverter) -> Result<NodeId, Error>
+  {
+    let break_label = LabelId(self.t.uid_generator.next());
+    let continue_label = LabelId(self.t.uid_generator.next());
+    self.loops.push(LoopLabels { name, break_label, continue_label, has_value, continued: false });
+    let body = f(self);
+    self.loops.pop();
+    Ok(self.node(e, Label{ label: break_label, body: body? }))
+  }
+
+  /// Converts the body of the innermost loop. If it uses `continue`, it's wrapped in
+  /// the label that `continue` jumps to, and its value is dropped to match.
+  fn loop_body(&mut self, e : &Expr, body_expr : &Expr) -> Result<NodeId, Error> {
+    let body = self.to_node(body_expr)?;
+    let l = self.loops.last().unwrap();
+    if !l.continued {
+      return Ok(body);
+    }
+    let label = l.continue_label;
+    let void = self.node(e, Literal(PrimitiveVal::Void));
+    let block = self.node(e, Block(vec![body, void]));
+    Ok(self.node(e, Label{ label, body: block }))
+  }
+
+  /// Finds the loop that a `break` or `continue` leaves, which is the innermost one
+  /// unless it names a label
+  fn find_loop(&self, e : &Expr, label : Option<&str>) -> Result<usize, Error> {
+    let keyword = e.try_construct().map(|(k, _)| k).unwrap_or("break");
+    match label {
+      Some(name) => {
+        match self.loops.iter().rposition(|l| l.name.as_ref().map(|n| n.as_ref()) == Some(name)) {
+          Some(i) => Ok(i),
+          None => error(e, format!("there is no loop labelled '{}' around this '{}'", name, keyword)),
+        }
+      }
+      None => {
+        if self.loops.is_empty() {
+          return error(e, format!("'{}' outside of a loop", keyword));
+        }
+        Ok(self.loops.len() - 1)
+      }
+    }
+  }
+
+  /// True if the expression names the label of a loop in scope
+  fn is_loop_label(&self, e : &Expr) -> bool {
+    match e.try_symbol() {
+      Some(s) => self.loops.iter().any(|l| l.name.as_ref().map(|n| n.as_ref()) == Some(s)),
+      None => false,
+    }
+  }
+
+  /// Converts `break`, `break label`, `break value` or `break label value`. A symbol
+  /// on its own is a label if a loop around the `break` has that label.
+  fn break_to_node(&mut self, e : &Expr, args : &[Expr]) -> Result<NodeId, Error> {
+    let (label, value) = match args {
+      [] => (None, None),
+      [a] if self.is_loop_label(a) => (a.try_symbol(), None),
+      [v] => (None, Some(v)),
+      [a, v] => match a.try_symbol() {
+        Some(s) => (Some(s), Some(v)),
+        None => return error(a, "expected a label name"),
+      }
+      _ => return error(e, "malformed break"),
+    };
+    let i = self.find_loop(e, label)?;
+    let return_value = match value {
+      Some(v) => {
+        if !self.loops[i].has_value {
+          return error(e, "only 'loop' can break with a value, because other loops can also stop without one");
+        }
+        Some(self.to_node(v)?)
+      }
+      None => None,
+    };
+    let label = self.loops[i].break_label;
+    Ok(self.node(e, BreakToLabel{ label, return_value }))
+  }
+
+  /// Converts `continue` or `continue label`
+  fn continue_to_node(&mut self, e : &Expr, args : &[Expr]) -> Result<NodeId, Error> {
+    let label = match args {
+      [] => None,
+      [a] => match a.try_symbol() {
+        Some(s) => Some(s),
+        None => return error(a, "expected a label name"),
+      }
+      _ => return error(e, "malformed continue"),
+    };
+    let i = self.find_loop(e, label)?;
+    self.loops[i].continued = true;
+    let label = self.loops[i].continue_label;
+    Ok(self.node(e, BreakToLabel{ label, return_value: None }))
+  }
+
   fn new_block_scope<T, F>(&mut self, f : F)
     -> Result<T, Error>
     where
@@ -683,12 +1598,207 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
     self.node(expr, TypeConstructor{ name, field_values })
   }
 
+  fn type_definition(
+    &mut self, expr : &Expr, kind : TypeKind, name : &Expr, fields_expr : &Expr, layout : StructLayout)
+    -> Result<NodeId, Error>
+  {
+    let (name, type_vars) = {
+      if let Some(("call", exprs)) = name.try_construct() {
+        let name = self.cached(exprs[0].unwrap_symbol()?);
+        let type_vars : Result<Vec<_>, _> =
+          exprs[1..].iter().map(|e| { let s = self.cached(e.unwrap_symbol()?) ; Ok(s) }).collect();
+        (name, type_vars?)
+      }
+      else {
+        let name = self.cached(name.unwrap_symbol()?);
+        (name, vec![])
+      }
+    };
+    let mut fields = vec![];
+    let mut bitfields = vec![];
+    for e in fields_expr.children() {
+      // `flags : u8 : 3` is a bitfield, three bits wide
+      if let Some((":", [typed, width])) = e.try_construct() {
+        if let Some((":", _)) = typed.try_construct() {
+          let field = self.typed_symbol(typed)?;
+          match (kind, &width.content) {
+            (TypeKind::Union, _) | (TypeKind::TaggedUnion, _) => return error(e, "unions can't have bitfields"),
+            (_, ExprContent::LiteralInt(w)) if *w > 0 && *w <= 64 =>
+              bitfields.push((field.0.name.clone(), *w as u32)),
+            _ => return error(width, "bitfield width must be between 1 and 64"),
+          }
+          fields.push(field);
+          continue;
+        }
+      }
+      fields.push(self.typed_symbol(e)?);
+    }
+    // the tag is a u8
+    if kind == TypeKind::TaggedUnion && fields.len() > 256 {
+      return error(expr, "a tagged union can't have more than 256 variants");
+    }
+    Ok(self.node(expr, TypeDefinition{name, kind, fields, type_vars, layout, bitfields }))
+  }
+
+  /// Desugars a match on a tagged union into something like:
+  ///
+  ///   let v = value
+  ///   if v.@is_circle { let r = v.@circle; ... }
+  ///   else if v.@is_rect { let r = v.@rect; ... }
+  ///   else { ... }
+  ///
+  /// The typechecker only lets these hidden fields be read from tagged unions. If
+  /// there's no `_` arm, the last arm is the `else`, so the typechecker also checks
+  /// that the arms cover every variant.
+  fn match_to_node(&mut self, e : &Expr, value : &Expr, arms : &[Expr]) -> Result<NodeId, Error> {
+    let mut cases : Vec<(Reference, Option<&Expr>, &Expr)> = vec![];
+    let mut default = None;
+    for arm in arms {
+      let (pattern, body) = match arm.try_construct() {
+        Some(("arm", [pattern, body])) => (pattern, body),
+        _ => return error(arm, "malformed match arm"),
+      };
+      if default.is_some() {
+        return error(arm, "the '_' arm must come last");
+      }
+      let (variant, binding) = match (pattern.try_symbol(), pattern.try_construct()) {
+        (Some("_"), _) => {
+          default = Some(body);
+          continue;
+        }
+        (Some(_), _) => (pattern, None),
+        (_, Some(("call", [variant, binding]))) => (variant, Some(binding)),
+        _ => return error(pattern, "expected a variant, like 'circle' or 'circle(r)', or '_'"),
+      };
+      let variant = self.expr_to_symbol(variant)?;
+      if cases.iter().any(|c| c.0.name == variant.name) {
+        return error(pattern, format!("variant '{}' is matched more than once", variant.name));
+      }
+      cases.push((variant, binding, body));
+    }
+    if cases.is_empty() && default.is_none() {
+      return error(e, "a match needs at least one arm");
+    }
+    self.new_block_scope(|fc| {
+      let v = fc.t.symbol("@match_value", e);
+      let value_node = fc.to_node(value)?;
+      let let_node = fc.let_var(e, v.clone(), value_node);
+      let mut else_branch = match default {
+        Some(body) => Some(fc.new_block_scope(|fc| fc.to_node(body))?),
+        None => None,
+      };
+      let variants = cases.iter().map(|(variant, _, _)| variant.clone()).collect();
+      for (variant, binding, body) in cases.iter().rev() {
+        let arm = fc.new_block_scope(|fc| {
+          let mut nodes = vec![];
+          if let Some(binding) = binding {
+            let container = fc.node(e, Content::Reference{ name: v.name.clone(), refers_to: Some(v.id) });
+            let field = fc.t.symbol(&VariantAccess::Read(&variant.name).field_name(), variant.loc);
+            let read = fc.node(e, FieldAccess{ container, field });
+            let binding = fc.expr_to_symbol(binding)?;
+            fc.warn_if_shadowing_global(&binding);
+            nodes.push(fc.let_var(e, binding.clone(), read));
+            fc.add_var_to_scope(binding);
+          }
+          nodes.push(fc.to_node(body)?);
+          Ok(fc.node(e, Block(nodes)))
+        })?;
+        else_branch = Some(match else_branch {
+          None => arm,
+          Some(else_branch) => {
+            let container = fc.node(e, Content::Reference{ name: v.name.clone(), refers_to: Some(v.id) });
+            let field = fc.t.symbol(&VariantAccess::Test(&variant.name).field_name(), variant.loc);
+            let condition = fc.node(e, FieldAccess{ container, field });
+            fc.node(e, IfThenElse{ condition, then_branch: arm, else_branch })
+          }
+        });
+      }
+      let has_default = default.is_some();
+      fc.t.matches.push(TaggedMatch{ value: value_node, variants, has_default, loc: e.loc });
+      Ok(fc.node(e, Block(vec![let_node, else_branch.unwrap()])))
+    })
+  }
+
+  /// Converts `switch value { 0 => ..., 1, 2 => ..., else => ... }`. The cases have
+  /// to be integer literals, so that it can be compiled to a jump table.
+  fn switch_to_node(&mut self, e : &Expr, value : &Expr, arms : &[Expr]) -> Result<NodeId, Error> {
+    let value = self.to_node(value)?;
+    let mut cases : Vec<(Vec<i64>, NodeId)> = vec![];
+    let mut case_locs = HashMap::new();
+    let mut default = None;
+    for arm in arms {
+      let (patterns, body) = match arm.try_construct() {
+        Some(("arm", [patterns @ .., body])) if !patterns.is_empty() => (patterns, body),
+        _ => return error(arm, "malformed switch arm"),
+      };
+      if default.is_some() {
+        return error(arm, "the 'else' arm must come last");
+      }
+      let body = self.new_block_scope(|fc| fc.to_node(body))?;
+      if patterns[0].try_symbol() == Some("else") {
+        default = Some(body);
+        continue;
+      }
+      let mut values = vec![];
+      for pattern in patterns {
+        let case = match (&pattern.content, pattern.try_construct()) {
+          (ExprContent::LiteralInt(i), _) => *i,
+          (_, Some(("call", [minus, v]))) if minus.try_symbol() == Some("-") => {
+            match &v.content {
+              ExprContent::LiteralInt(i) => -i,
+              _ => return error(pattern, "switch cases must be integer literals"),
+            }
+          }
+          _ => return error(pattern, "switch cases must be integer literals"),
+        };
+        if values.contains(&case) || cases.iter().any(|(cs, _)| cs.contains(&case)) {
+          return error(pattern, format!("case {} appears more than once", case));
+        }
+        values.push(case);
+        case_locs.insert(case, pattern.loc);
+      }
+      cases.push((values, body));
+    }
+    Ok(self.node(e, Switch{ value, cases, default, case_locs }))
+  }
+
+  /// Desugars `e?` into something like:
+  /// 
+  ///   let v = e
+  ///   if is_failure(v) { return propagate_failure(v) }
+  ///   success_value(v)
+  /// 
+  /// These functions are overloaded for both `option` and `result` in the prelude.
+  fn propagate_failure(&mut self, e : &Expr, value : &Expr) -> Result<NodeId, Error> {
+    self.new_block_scope(|fc| {
+      let v = fc.t.symbol("@try_var", e);
+      let value = fc.to_node(value)?;
+      let let_node = fc.let_var(e, v.clone(), value);
+      let return_node = {
+        let var_ref = fc.node(e, Content::Reference{ name: v.name.clone(), refers_to: Some(v.id) });
+        let failure = fc.function_call(e, "propagate_failure", vec![var_ref]);
+        let label = *fc.labels_in_scope.first().unwrap();
+        fc.node(e, BreakToLabel{ label, return_value: Some(failure) })
+      };
+      let if_node = {
+        let var_ref = fc.node(e, Content::Reference{ name: v.name.clone(), refers_to: Some(v.id) });
+        let condition = fc.function_call(e, "is_failure", vec![var_ref]);
+        fc.node(e, IfThen{ condition, then_branch: return_node })
+      };
+      let success = {
+        let var_ref = fc.node(e, Content::Reference{ name: v.name.clone(), refers_to: Some(v.id) });
+        fc.function_call(e, "success_value", vec![var_ref])
+      };
+      Ok(fc.node(e, Block(vec![let_node, if_node, success])))
+    })
+  }
+
   /// TODO: this is implemented entirely in terms of other constructs. It might be nice
   /// to move it into an earlier part of the pipeline (such as an expression macro) to
   /// limit logic duplication and make the code more maintainable.
-  fn for_loop(&mut self, e : &Expr, range : &Expr, body : &Expr) -> Result<NodeId, Error> {
+  fn for_loop(&mut self, e : &Expr, name : Option<RefStr>, range : &Expr, body : &Expr) -> Result<NodeId, Error> {
     if let Some(("in", [var, range])) = range.try_construct() {
-      let n = self.labelled_node(e.loc, |fc| {
+      let n = self.labelled_loop(e, name.clone(), false, |fc| {
         fc.new_block_scope(|fc| {
           let it_var = fc.t.symbol("@range_var", e);
           let loop_var = fc.expr_to_symbol(var)?;
@@ -712,7 +1822,7 @@ impl <'l, 'lt> FunctionConverter<'l, 'lt> {
               };
               fc.function_call(e, "next", vec![it, var_ref])
             };
-            let body = fc.to_node(body)?;
+            let body = fc.loop_body(e, body)?;
             fc.node(e, While { condition, body })
           };
           let nodes = vec![let_it_node, let_loop_node, while_node];
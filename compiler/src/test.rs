@@ -1,9 +1,24 @@
 
-use crate::error::Error;
-use crate::interpret::{Interpreter, interpreter};
+use crate::error::{Error, ErrorContent, Severity, TextLocation};
+use crate::types::internal_error;
+use crate::interpret::{Interpreter, Prelude, interpreter, interpreter_with_core_path, interpreter_with_prelude};
 use crate::structure::TOP_LEVEL_FUNCTION_NAME;
-use crate::compiler::Val;
+use crate::compiler::{Val, CompileOptions, OnAbiChange};
+use crate::code_store::KEPT_SYMBOL_VERSIONS;
 use crate::c_interface::SStr;
+use crate::repl::{run_command, escape_history_entry, unescape_history_entry};
+use crate::libraries::{SharedLibraries, library_file_name};
+use crate::project::{Project, split_glob, glob_match, matches_any};
+use crate::formatter::{self, FormatOptions};
+use crate::debug_draw;
+use crate::safepoint;
+use crate::pointers;
+use crate::allocations;
+use std::time::Duration;
+use std::path::Path;
+use std::rc::Rc;
+use std::cell::Cell;
+use crate::text_edit::{TextEditorState, EditHistory, CaretMove, CaretMoveType};
 
 fn result_string(r : Result<Val, Error>) -> String {
   match r {
@@ -87,6 +102,92 @@ rusty_fork_test! {
     assert_result(code, Val::U64(15));
   }
 
+  #[test]
+  fn test_inference_from_global_references() {
+    // the literal's type comes from a reference, whichever is seen first
+    let code = "
+      fun get() => u8 { x }
+      static x = 5
+      get()
+    ";
+    assert_result(code, Val::U8(5));
+    let code = "
+      static y = 5
+      let z : u32 = y
+      z
+    ";
+    assert_result(code, Val::U32(5));
+  }
+
+  #[test]
+  fn test_static_initialisation_order() {
+    let code = "
+      fun double_b() => i64 { b * 2 }
+      static a = 1
+      static b = a + 1
+      static c = double_b()
+      c
+    ";
+    assert_result(code, Val::I64(4));
+    // `b` is initialised first, because `a` depends on it
+    let code = "
+      fun double_b() => i64 { b * 2 }
+      static a = double_b()
+      let x = 3
+      static b = 2
+      a + x
+    ";
+    assert_result(code, Val::I64(7));
+    let code = "
+      fun get_b() => i64 { b }
+      static a = get_b()
+      let x = 3
+      static b = x
+      a
+    ";
+    assert_error(code, "static 'b' has to be initialised before 'a', but its initialiser uses 'x', which is defined in between");
+    let code = "
+      fun get_b() => i64 { b }
+      static a : i64 = get_b()
+      static b : i64 = a
+      b
+    ";
+    assert_error(code, "cycle in the initialisers of statics 'a', 'b'");
+  }
+
+  #[test]
+  fn test_lazy_statics() {
+    // `a` can use `b`, because it isn't initialised until it's used
+    let code = "
+      static calls = 0
+      fun expensive() => i64 {
+        calls = calls + 1
+        21
+      }
+      lazy static a = expensive() + b
+      static b = 21
+      let before = calls
+      let v = a + a
+      before * 1000 + calls * 100 + v
+    ";
+    assert_result(code, Val::I64(184));
+  }
+
+  #[test]
+  fn test_init_blocks() {
+    let mut i = interpreter();
+    let code = "
+      static x = 1
+      init { x = x * 10 }
+      init { x = x + 5 }
+      x = x + 1
+      x
+    ";
+    // the init blocks run after the top-level code
+    assert_eq!(i.eval(code).unwrap(), Val::I64(2));
+    assert_eq!(i.eval("x").unwrap(), Val::I64(25));
+  }
+
 
   #[test]
   fn test_conversions() {
@@ -111,17 +212,40 @@ rusty_fork_test! {
     assert_result("true || false", Val::Bool(true));
     // Make sure they terminate early
     let and = "
-      let a = 0
+      var a = 0
       false && (a = 1; true)
       a
     ";
     let or = "
-      let a = 0
+      var a = 0
       true || (a = 1; true)
       a
     ";
     assert_result(and, Val::I64(0));
     assert_result(or, Val::I64(0));
+    // on options, `||` gives the first some and `&&` gives the first none
+    assert_result("(none() || some(3) || some(4)).unwrap()", Val::I64(3));
+    assert_result("(some(2) && some(5)).unwrap()", Val::I64(5));
+    assert_result("let a = some(2)\n(a && none()).is_some", Val::Bool(false));
+    let coalesce = "
+      var a = 0
+      let o = some(7) || (a = 1; none())
+      o.unwrap() + a
+    ";
+    assert_result(coalesce, Val::I64(7));
+    assert_error("1 && 2", "'&&' needs bool or option operands");
+    // any other operands call the operator's overload, which takes both of them
+    let overloaded = "
+      struct flag { set : bool }
+      fun &&(a : flag, b : flag) => flag { flag.new(a.set && b.set) }
+      var made = 0
+      fun f(v : bool) => flag { made = made + 1; flag.new(v) }
+      let r = f(false) && f(true)
+      if r.set { -1 } else { made }
+    ";
+    assert_result(overloaded, Val::I64(2));
+    assert_result("fun ||(a : i64, b : i64) => i64 { if a != 0 { a } else { b } }\n0 || 5", Val::I64(5));
+    assert_error("struct flag { set : bool }\nflag.new(true) || flag.new(false)", "'||' needs bool or option operands, or an overload");
   }
 
   #[test]
@@ -143,7 +267,7 @@ rusty_fork_test! {
   #[test]
   fn test_assignment(){
     let a = "
-      let a = 4
+      var a = 4
       a = a + 5
       a
     ";
@@ -152,7 +276,7 @@ rusty_fork_test! {
         x : i64
         y : i64
       }
-      let a = point.new(x: 5, y: 50)
+      var a = point.new(x: 5, y: 50)
       a.x = a.x + 10
       a.y = 500
       a.x + a.y
@@ -196,6 +320,118 @@ rusty_fork_test! {
     assert_result(code, Val::I64(21));
   }
 
+  #[test]
+  fn test_layout_queries() {
+    let code = "
+      struct s { a : u8; b : i64; c : i32 }
+      sizeof(s) + alignof(s) * 100 + offsetof(s, b) * 10000 + offsetof(s, c) * 1000000
+    ";
+    assert_result(code, Val::U64(24 + 800 + 80000 + 16000000));
+    assert_error("struct s { a : u8 }\n offsetof(s, d)", "type 's' has no field 'd'");
+  }
+
+  #[test]
+  fn test_struct_layout_attributes() {
+    let code = "
+      struct p with packed { a : u8; b : i64; c : i32 }
+      struct q with align(32) { a : u8; b : i64 }
+      sizeof(p) + alignof(p) * 100 + offsetof(p, c) * 10000 + sizeof(q) * 1000000 + alignof(q) * 100000000
+    ";
+    assert_result(code, Val::U64(13 + 100 + 90000 + 32000000 + 3200000000));
+    assert_error("struct s with packed, align(8) { a : u8 }", "a struct can't be both packed and aligned");
+    assert_error("struct s with align(3) { a : u8 }", "alignment must be a power of two");
+    assert_error("struct s with tight { a : u8 }", "expected a struct attribute");
+    let mut i = interpreter();
+    i.run_module("struct vertex with packed { a : u8; b : f32 }", "vertex").unwrap();
+    let l = i.layout("vertex").unwrap();
+    assert_eq!((l.size, l.alignment), (5, 1));
+    let offsets : Vec<(&str, u64)> = l.fields.iter().map(|(f, o)| (f.as_ref(), *o)).collect();
+    assert_eq!(offsets, vec![("a", 0), ("b", 1)]);
+    assert_eq!(i.layout("array(u16)").unwrap().fields.len(), 2);
+    // a unit that isn't imported doesn't change what the name refers to
+    i.c.load_module("struct vertex { x : f32; y : f32; z : f32 }", None, &[]).unwrap();
+    assert_eq!(i.layout("vertex").unwrap().fields.len(), 2);
+    // the fields of packed structs can be read and written wherever they are
+    let unaligned = "
+      struct header with packed { tag : u8; length : u64; crc : u32 }
+      var h = header.new(1, 2, 3)
+      h.length = h.length + 40
+      h.crc = h.crc * 2
+      let p = &h
+      p.length + (p.crc as u64)
+    ";
+    assert_result(unaligned, Val::U64(48));
+  }
+
+  #[test]
+  fn test_bitfields() {
+    let flags = "
+      struct flags { a : u8 : 3; b : u8 : 5; c : u16; d : i32 : 4; e : i32 : 12 }
+      struct split { x : u8 : 6; y : u8 : 6 }
+      var f = flags.new(5 as u8, 9 as u8, 700 as u16, (-3) as i32, 1000 as i32)
+      f.b = 17 as u8
+      f.a = 13 as u8
+      f.d = f.d - (1 as i32)
+    ";
+    assert_result(&format!("{} sizeof(flags) * 10 + sizeof(split)", flags), Val::U64(82));
+    assert_result(&format!("{} (f.a as i64) + (f.b as i64) * 10 + (f.c as i64) * 1000", flags), Val::I64(700175));
+    assert_result(&format!("{} (f.d as i64) * 10000 + (f.e as i64)", flags), Val::I64(-39000));
+    assert_error("struct s { a : u8 : 0 }", "bitfield width must be between 1 and 64");
+    assert_error("struct s { a : u8 : 9 }", "bitfield 'a' needs an integer type of at least 9 bits");
+    assert_error("struct s { a : f64 : 3 }", "needs an integer type");
+    assert_error("union s { a : u8 : 3 }", "unions can't have bitfields");
+    assert_error("struct s { a : u8 : 3 }\noffsetof(s, a)", "bitfield 'a' has no offset");
+    assert_error(&format!("{} &f.b", flags), "can't take the address of a bitfield");
+    assert_error("struct s(T) { a : T : 3 }\ns(u8).new(1 as u8)", "needs an integer type");
+    // bitfields are laid out like the host's C compilers lay them out
+    let mixed = "
+      struct mixed { a : u8 : 4; b : u32 : 4; c : i64 : 40 }
+      var m = mixed.new(9 as u8, 3 as u32, (-5) as i64)
+      m.c = m.c * (1000000000 as i64)
+    ";
+    let (size, offsets) = if cfg!(windows) { (16, 32 * 1000 + 64) } else { (8, 4 * 1000 + 8) };
+    assert_result(&format!("{} sizeof(mixed)", mixed), Val::U64(size));
+    assert_result(&format!("{} bitoffsetof(mixed, b) * 1000 + bitoffsetof(mixed, c)", mixed), Val::U64(offsets));
+    assert_result(&format!("{} (m.a as i64) + (m.b as i64) * 10 + m.c", mixed), Val::I64(-4999999961));
+    assert_error("struct s { a : u8 : 3; b : u8 }\nbitoffsetof(s, b)", "'b' is not a bitfield");
+    let mut i = interpreter();
+    i.run_module("struct mixed { a : u8 : 4; b : u32 : 4; c : i64 : 40 }", "mixed").unwrap();
+    let layout = i.layout("mixed").unwrap();
+    let widths : Vec<u32> = layout.bitfields.iter().map(|(_, _, w)| *w).collect();
+    assert_eq!(widths, vec![4, 4, 40]);
+  }
+
+  #[test]
+  fn test_simd_vectors() {
+    let code = "
+      let a = vec4f(1.0, 2.0, 3.0, 4.0)
+      let b = a * splat(2.0) + shuffle(a, 3, 2, 1, 0)
+      dot(b, splat(1.0)) + extract(-a, 0)
+    ";
+    assert_result(code, Val::F32(29.0));
+    let b = "
+      let i = 1
+      shuffle(splat(1.0), i, 0, 0, 0)
+    ";
+    assert_error(b, "shuffle indices must be integer literals");
+  }
+
+  #[test]
+  fn test_math_module() {
+    let code = "
+      let m = mat4().translate(vec3(1.0, 2.0, 3.0)).scale(vec3(2.0, 2.0, 2.0))
+      let p = m * vec3(1.0, 1.0, 1.0)
+      let q = m.inverse().unwrap() * p
+      let c = lerp(vec2(1.0, 2.0), vec2(3.0, 6.0), 0.5)
+      dot(p, q) + c.x * c.y + length(vec2(3.0, 4.0))
+    ";
+    assert_result(code, Val::F64(31.0));
+    let singular = "mat4().scale(vec3(0.0, 1.0, 1.0)).inverse().is_some";
+    assert_result(singular, Val::Bool(false));
+    let nan = "let n = 0.0 / 0.0\nmat4().scale(vec3(n, 1.0, 1.0)).inverse().is_some";
+    assert_result(nan, Val::Bool(false));
+  }
+
   #[test]
   fn test_union() {
     let a = "
@@ -207,7 +443,7 @@ rusty_fork_test! {
         u : u64
         i : bar
       }
-      let v = foo.new(u : 16 as u64)
+      var v = foo.new(u : 16 as u64)
       v.i = bar.new(((v.u as i64) + 16) as i32, 0 as i32)
       v.u
     ";
@@ -225,6 +461,111 @@ rusty_fork_test! {
     assert_result(b, Val::I64(5));
   }
 
+  #[test]
+  fn test_tagged_union() {
+    let shapes = "
+      struct size { w : f64; h : f64 }
+      tagged union shape {
+        circle : f64
+        rect : size
+        point : bool
+      }
+    ";
+    let a = "
+      fun area(s : shape) {
+        match s {
+          circle(r) => r * r * 3.0
+          rect(sz) => sz.w * sz.h
+          point => 0.0
+        }
+      }
+      let shapes = [shape.new(circle: 2.0), shape.new(rect: size.new(3.0, 4.0)), shape.new(point: true)]
+      var total = 0.0
+      for s in shapes {
+        total = total + area(s) + match s { circle => 100.0, _ => 0.0 }
+      }
+      total + (sizeof(shape) * 1000 + offsetof(shape, circle) * 10000) as f64
+    ";
+    assert_result(&format!("{}{}", shapes, a), Val::F64(124.0 + 24000.0 + 80000.0));
+    let s = "let s = shape.new(circle: 1.0)\n";
+    assert_error(&format!("{}{} s.circle", shapes, s), "can only be read with 'match'");
+    assert_error(&format!("{}{} match s {{ circle(r) => r, rect => 0.0 }}", shapes, s), "doesn't cover 'point'");
+    assert_error(&format!("{}{} match s {{ circel(r) => r, _ => 0.0 }}", shapes, s), "has no variant 'circel'");
+    assert_error(&format!("{}{} match s {{ circle => 1, circle => 2, _ => 3 }}", shapes, s), "matched more than once");
+    assert_error(&format!("{} match size.new(1.0, 2.0) {{ circle => 1, _ => 2 }}", shapes), "only tagged unions can be matched on");
+  }
+
+  #[test]
+  fn test_switch() {
+    let code = "
+      fun tile_cost(tile : u8) => i64 {
+        switch tile {
+          0 => 1
+          1, 2 => 10
+          else => 100
+        }
+      }
+      fun sign(v : i64) {
+        switch v { -1 => 0.5, 0 => 0, else => 2.5 }
+      }
+      var hits = 0
+      for i in range(0, 5) {
+        switch i * 2 {
+          2 => { hits = hits + 1 }
+          4 => { hits = hits + 10 }
+        }
+      }
+      tile_cost(0) + tile_cost(2) + tile_cost(7) + hits + (sign(-1) + sign(0) + sign(3)) as i64
+    ";
+    assert_result(code, Val::I64(1 + 10 + 100 + 11 + 3));
+    assert_error("switch 3 { 1 => 1, 1 => 2, else => 3 }", "case 1 appears more than once");
+    assert_error("switch 3 { else => 1, 2 => 2 }", "the 'else' arm must come last");
+    assert_error("let x = 2\nswitch 3 { x => 1, else => 2 }", "switch cases must be integer literals");
+    assert_error("switch 2.5 { 1 => 1, else => 2 }", "conflicting types inferred");
+    assert_error("let t : u8 = 3\nswitch t { 300 => 1, else => 2 }", "case 300 doesn't fit in 'u8'");
+    let wide_case = "
+      let t : u8 = 3
+      switch t {
+        1, 300 =>
+          1
+        else => 2
+      }
+    ";
+    assert_error(wide_case, "(line: 4, column: 11 to 14), message: case 300 doesn't fit in 'u8'");
+    assert_error("switch 3 { 1 => true, else => 2.5 }",
+      "the branches of this 'switch' have incompatible types, 'bool' and 'Float'");
+    assert_result("let v = switch 2 { 2 => 7, else => 1 }
+v", Val::I64(7));
+  }
+
+  #[test]
+  fn test_raw_union_access() {
+    let code = "
+      union number { i : i64; f : f64 }
+      var n = number.new(i: 5)
+      let a = n.i
+      n.f = 1.5
+      let b = unsafe { n.i }
+      a + b
+    ";
+    let mut i = interpreter();
+    // the core modules only read raw unions where they know which field is set
+    let core_warnings = i.c.code_store.nodes.keys()
+      .flat_map(|&u| i.c.code_store.warnings(u).iter())
+      .filter(|w| format!("{}", w.display()).contains("untagged union"))
+      .count();
+    assert_eq!(core_warnings, 0);
+    i.run_module(code, "raw").unwrap();
+    let unit_id = i.c.code_store.named_unit("raw").unwrap();
+    let warnings : Vec<String> =
+      i.c.code_store.warnings(unit_id).iter().map(|w| format!("{}", w.display())).collect();
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    assert!(warnings[0].contains("read of field 'i' of untagged union 'number'"));
+    i.run_module(&format!("pragma allow(raw_union_access)\n{}", code), "allowed").unwrap();
+    let unit_id = i.c.code_store.named_unit("allowed").unwrap();
+    assert_eq!(i.c.code_store.warnings(unit_id).len(), 0);
+  }
+
   #[test]
   fn test_return(){
     let code = "
@@ -250,6 +591,89 @@ rusty_fork_test! {
     assert_result(code.as_str(), Val::I64(2));
   }
 
+  #[test]
+  fn test_load_errors_are_shown() {
+    let path = std::env::temp_dir().join("cauldron_load_error.code");
+    let path_str = path.to_str().unwrap().replace('\\', "/");
+    let load = format!(r#"load_module("{}").is_some"#, path_str);
+    let mut i = interpreter();
+    std::fs::write(&path, "fun f() => i64 { 1 +").unwrap();
+    assert_result_with_interpreter(&mut i, &load, Val::Bool(false));
+    let shown = debug_draw::diagnostics();
+    assert_eq!(shown.len(), 1);
+    assert_eq!(shown[0].0, path_str);
+    // the errors stay until the module loads
+    std::fs::write(&path, "fun f() => i64 { 1 }").unwrap();
+    assert_result_with_interpreter(&mut i, &load, Val::Bool(true));
+    assert!(debug_draw::diagnostics().is_empty());
+  }
+
+  #[test]
+  fn test_frame_budget() {
+    let path = std::env::temp_dir().join("cauldron_spin.code");
+    std::fs::write(&path, "var n = 0\nwhile true { n = n + 1 }").unwrap();
+    let mut i = interpreter();
+    i.c.frame_budget = Some(Duration::from_millis(50));
+    // a reloaded module that never finishes its top level is interrupted
+    let code = format!(r#"load_module("{}").is_some"#, path.to_str().unwrap().replace('\\', "/"));
+    assert_result_with_interpreter(&mut i, &code, Val::Bool(false));
+    let code = "
+      fun spin() { while true {} }
+      fun quick() { var n = 0; while n < 10 { n = n + 1 } }
+      run_frame(spin) == false && run_frame(quick)
+    ";
+    assert_result_with_interpreter(&mut i, code, Val::Bool(true));
+  }
+
+  #[test]
+  fn test_panic_recovery() {
+    assert_error(
+      "fun third(a : array(i64)) => i64 { a[2] }\nthird([1, 2])",
+      "the top-level code panicked: index 2 is out of bounds for an array of length 2");
+    // a frame that panicked runs the fixed version once it's reloaded
+    let mut i = interpreter();
+    i.run_module("fun frame() { let a = [1, 2]; var n = 0; while true { n = n + a[n] } }", "lib").unwrap();
+    i.run_module("fun run() => bool { run_frame(frame) }", "user").unwrap();
+    assert_result_with_interpreter(&mut i, "run()", Val::Bool(false));
+    i.reload_module("lib", "fun frame() { let a = [1, 2]; var n = 0; n = a[1] }", OnAbiChange::Reject).unwrap();
+    assert_result_with_interpreter(&mut i, "run()", Val::Bool(true));
+  }
+
+  #[test]
+  fn test_safepoint_hooks() {
+    let mut i = interpreter();
+    let hits = Rc::new(Cell::new(0));
+    let h = hits.clone();
+    // cancels loops after 100 safepoints
+    let hook = safepoint::add_hook(Box::new(move || { h.set(h.get() + 1); h.get() >= 100 }));
+    assert_result_with_interpreter(&mut i, "var n = 0\nwhile true { n = n + 1 }\nn", Val::I64(100));
+    hits.set(0);
+    assert_result_with_interpreter(&mut i, "var m = 0\nfor x in range(0, 10) { m = m + x }\nm", Val::I64(45));
+    assert!(hits.get() >= 10);
+    safepoint::remove_hook(hook);
+    hits.set(0);
+    assert_result_with_interpreter(&mut i, "var k = 0\nwhile k < 200 { k = k + 1 }\nk", Val::I64(200));
+    assert_eq!(hits.get(), 0);
+  }
+
+  #[test]
+  fn test_sandbox_steps() {
+    let mut i = interpreter();
+    i.c.default_options = CompileOptions { sandbox: Some(10000), ..CompileOptions::debug() };
+    assert_result_with_interpreter(&mut i, "var n = 0\nwhile n < 100 { n = n + 1 }\nn", Val::I64(100));
+    // loops and recursion are both cut short once the budget runs out
+    let e = i.eval("while true {}").err().unwrap();
+    assert!(format!("{}", e.display()).contains("took more than 10000 steps"));
+    let e = i.eval("fun forever(n : i64) => i64 { forever(n + 1) }\nforever(0)").err().unwrap();
+    assert!(format!("{}", e.display()).contains("took more than 10000 steps"));
+    // each evaluation gets a new budget
+    assert_result_with_interpreter(&mut i, "var m = 0\nwhile m < 100 { m = m + 1 }\nm", Val::I64(100));
+    // the vm counts every node that it evaluates
+    let options = CompileOptions { sandbox: Some(100), ..CompileOptions::vm() };
+    let e = i.c.load_module_with_options("var k = 0\nwhile true { k = k + 1 }", None, &[], options).err().unwrap();
+    assert!(format!("{}", e.display()).contains("ran out of steps"));
+  }
+
   #[test]
   fn test_quote_interpolation(){
     let a = format!(r#"
@@ -282,7 +706,7 @@ rusty_fork_test! {
   #[test]
   fn test_while() {
     let a = "
-      let x = 10
+      var x = 10
       while true {
         x = x - 1
         if x <= 5 {
@@ -293,7 +717,7 @@ rusty_fork_test! {
     ";
     assert_result(a, Val::I64(5));
     let b = "
-      let x = 1
+      var x = 1
       while x < 10 {
         x = x + 6
       }
@@ -305,13 +729,13 @@ rusty_fork_test! {
   #[test]
   fn test_for() {
     let a = "
-      let x = 0
+      var x = 0
       for i in range(0, 10) { x = x + i }
       x
     ";
     assert_result(a, Val::I64(45));
     let b = "
-      let total = 0
+      var total = 0
       for x in range(0, 10000) {
         for y in range(10, 20) {
           total = total + x * y
@@ -325,6 +749,45 @@ rusty_fork_test! {
     assert_result(b, Val::I64(2175));
   }
 
+  #[test]
+  fn test_labelled_loops() {
+    let a = "
+      var total = 0
+      outer : for x in range(0, 5) {
+        for y in range(0, 5) {
+          if y > x { continue outer }
+          if x == 4 { break outer }
+          total = total + 1
+        }
+      }
+      var i = 0
+      var odd = 0
+      while i < 10 {
+        i = i + 1
+        if i % 2 == 0 { continue }
+        odd = odd + i
+      }
+      total + odd
+    ";
+    assert_result(a, Val::I64(10 + 25));
+    let b = "
+      fun first_square_over(n : i64) => i64 {
+        var i = 0
+        loop {
+          i = i + 1
+          if i * i > n { break i }
+        }
+      }
+      let x = loop { break 5 }
+      first_square_over(50) + x
+    ";
+    assert_result(b, Val::I64(8 + 5));
+    assert_error("var i = 0\nwhile i < 3 { break 4 }", "only 'loop' can break with a value");
+    assert_error("fun f() { break }", "'break' outside of a loop");
+    assert_error("while true { continue outer }", "there is no loop labelled 'outer' around this 'continue'");
+    assert_error("let x : i64 = loop { break 1.5 }", "conflicting types inferred");
+  }
+
 
   #[test]
   fn test_jit_module_variable_linking() {
@@ -407,20 +870,108 @@ rusty_fork_test! {
     assert_eq!(blah[1], Blah::B(67));
   }
 
-  /// TODO: test that structs are passed into C functions correctly
-  // #[test]
-  // fn test_struct_abi() {
-  //   The naive approach doesn't work because windows does this:
-    
-  //       define void @print({ i8*, i64 }* noalias nocapture dereferenceable(16) %s) unnamed_addr #3
-    
-  //   Incidentally, to trust Godbolt for ABI comparisons on Windows, I have to pass
-  //   an argument to rustc to stop it from assuming linux:
-    
-  //       --target x86_64-pc-windows-msvc
-    
-  //   panic!("test not implemented");
-  // }
+  /// Tagged unions are laid out like Rust's enums with a `u8` representation
+  #[test]
+  fn test_tagged_union_alignment() {
+    let mut i = interpreter();
+    #[repr(u8)]
+    #[derive(PartialEq, Debug)]
+    enum Blah { A(u8), B(i64) }
+    let code = r#"
+      tagged union blah {
+        a : u8
+        b : i64
+      }
+      fun main(v : ptr(blah)) {
+        v[0] = blah.new(a: 17 as u8)
+        v[1] = blah.new(b: 67)
+      }
+    "#;
+    let blah : [Blah ; 2] = i.run_with_pointer_return(code, "main").unwrap();
+    assert_eq!(blah[0], Blah::A(17));
+    assert_eq!(blah[1], Blah::B(67));
+  }
+
+  /// Structs are lowered to the platform's C ABI when they are passed to or
+  /// returned from cbind functions. Windows passes a struct of this size like this,
+  /// for example:
+  ///
+  ///     define void @print({ i8*, i64 }* noalias nocapture dereferenceable(16) %s) unnamed_addr #3
+  ///
+  /// Incidentally, to trust Godbolt for ABI comparisons on Windows, I have to pass
+  /// an argument to rustc to stop it from assuming linux:
+  ///
+  ///     --target x86_64-pc-windows-msvc
+  #[test]
+  fn test_struct_abi() {
+    let code = "
+      struct pair { a : i64; b : f64 }
+      struct triple { a : i64; b : i64; c : i64 }
+      cbind test_pair_sum : fun(p : pair) => f64
+      cbind test_make_triple : fun(a : i64) => triple
+      cbind test_triple_sum : fun(t : triple) => i64
+      let t = test_make_triple(2)
+      test_pair_sum(pair.new(test_triple_sum(t), 0.5)) + t.c as f64
+    ";
+    assert_result(code, Val::F64(18.5));
+  }
+
+  #[test]
+  fn test_tuples() {
+    let code = "
+      fun divmod(a : i64, b : i64) => (i64, i64) { (a / b, a % b) }
+      fun spread(x : i64) => (i64, i64, i64, bool) { (x, x * 2, x * 3, x > 0) }
+      let (q, r) = divmod(17, 5)
+      var (a, b, c, positive) = spread(4)
+      a = a + 1
+      let (_, half) = (1.5, 0.5)
+      q * 1000 + r * 100 + a + b + c + (if positive { 10 } else { 0 }) + ((half * 2.0) as i64) * 10000
+    ";
+    assert_result(code, Val::I64(3000 + 200 + 5 + 8 + 12 + 10 + 10000));
+    assert_error("let (a, b) = (1, 2, 3)", "conflicting types inferred");
+    assert_error("let (a, b) = 5", "conflicting types inferred");
+    assert_error("fun f() => (i64, bool) { (1, 2) }", "conflicting types inferred");
+    // tuples are the prelude's tup structs, so they work with the code written for those
+    let prelude_tuples = "
+      let t : tup2(i64, bool) = (1, true)
+      let (a, b) = tup(3, 4)
+      print((a, t.v1))
+      t.v0 + a + b
+    ";
+    assert_result(prelude_tuples, Val::I64(8));
+  }
+
+  /// Tuples are returned like C structs, so Rust can call functions that return them
+  #[test]
+  fn test_tuple_abi() {
+    #[repr(C)]
+    #[derive(Debug, PartialEq)]
+    struct Triple { a : i64, b : i64, c : i64 }
+    #[repr(C)]
+    #[derive(Debug, PartialEq)]
+    struct Pair { a : i32, b : i32 }
+    let mut i = interpreter();
+    let code = "fun triple(x : i64) => (i64, i64, i64) { (x, x + 1, x + 2) }";
+    let t : Triple = i.run_named_function_with_arg(code, "triple", 5i64).unwrap();
+    assert_eq!(t, Triple { a: 5, b: 6, c: 7 });
+    let code = "fun pair(x : i64) => (i32, i32) { (x as i32, (x * 2) as i32) }";
+    let p : Pair = i.run_named_function_with_arg(code, "pair", 3i64).unwrap();
+    assert_eq!(p, Pair { a: 3, b: 6 });
+  }
+
+  /// stdcall is the same as C on x86-64, so this only checks that the convention
+  /// is carried through direct calls and calls through function pointers
+  #[test]
+  fn test_calling_conventions() {
+    let code = "
+      cbind test_add : fun(a : i64, b : i64) => i64 with convention(stdcall)
+      let f = test_add
+      test_add(1, 2) + f(3, 4)
+    ";
+    assert_result(code, Val::I64(10));
+    let code = "cbind test_add : fun(a : i64, b : i64) => i64 with convention(pascal)";
+    assert_error(code, "unknown calling convention 'pascal'");
+  }
 
   // TODO: this test isn't very good
   #[test]
@@ -436,6 +987,18 @@ rusty_fork_test! {
     assert_eq!(s.as_str(), expected);
   }
 
+  #[test]
+  fn test_string_functions() {
+    let code = r#"
+      let s = "héllo wörld"
+      let parts = s.split(" ")
+      let w = s.slice_chars(6, 8)
+      let total = s.len_chars() + parts.len() + s.find("wö").unwrap()
+      total + w.concat("!").len_chars()
+    "#;
+    assert_result(code, Val::U64(23));
+  }
+
   #[test]
   fn test_c_function_bind() {
     let code = "
@@ -454,6 +1017,42 @@ rusty_fork_test! {
     assert_result(code, Val::I64(47));
   }
 
+  #[test]
+  fn test_shared_library_registry() {
+    let code = "
+      let missing = load_library(\"not_a_real_library\")
+      let unloaded = unload_library(missing)
+      missing.handle == 0 && unloaded == false && loaded_libraries().len() == 0
+    ";
+    assert_result(code, Val::Bool(true));
+  }
+
+  #[test]
+  fn test_stale_handles() {
+    let code = "
+      let r = seeded_rng(1)
+      let live = list_live_handles().len()
+      drop_seeded_rng(r)
+      let r2 = seeded_rng(1)
+      // the slot is reused, but the old handle doesn't match it
+      rand_u64(r) == 0 && rand_u64(r2) != 0 && r.id != r2.id && live == list_live_handles().len()
+    ";
+    assert_result(code, Val::Bool(true));
+  }
+
+  #[test]
+  fn test_library_search() {
+    let expected = if cfg!(windows) { "dir/foo.dll" } else if cfg!(target_os = "macos") { "dir/libfoo.dylib" } else { "dir/libfoo.so" };
+    assert_eq!(library_file_name("dir/foo"), expected);
+    assert_eq!(library_file_name("foo.dll"), "foo.dll");
+    let mut libs = SharedLibraries::new();
+    libs.search_paths = vec!["a".into(), "b/".into()];
+    let e = libs.find_and_load("missing").unwrap_err();
+    let file_name = library_file_name("missing");
+    assert!(e.contains(&format!("a/{}", file_name)));
+    assert!(e.contains(&format!("b/{}", file_name)));
+  }
+
   #[test]
   fn test_overloading() {
     let code = "
@@ -471,6 +1070,73 @@ rusty_fork_test! {
     assert_result(code, Val::I64(30));
   }
 
+  #[test]
+  fn test_generic_struct() {
+    let code = "
+      struct pair(T) { a : T; b : T }
+      fun swap(p : pair(T)) => pair(T) with T {
+        pair.new(p.b, p.a)
+      }
+      let p = swap(pair.new(1, 20))
+      let q : pair(f32) = pair.new(1.5, 2.5)
+      p.a * 10 + p.b + (q.a + q.b) as i64
+    ";
+    assert_result(code, Val::I64(205));
+    assert_error("struct pair(T) { a : T; b : T }\n pair.new(1, 2.5)", "conflicting types inferred");
+  }
+
+  #[test]
+  fn test_recursive_type_error() {
+    let code = "
+      struct node { value : i64; next : ptr(node) }
+      let n = node.new(5, null())
+      n.value
+    ";
+    assert_result(code, Val::I64(5));
+    let a = "struct node { value : i64; next : node }";
+    assert_error(a, "recursive type 'node' needs indirection");
+    assert_error(a, "`next : ptr(node)`");
+    assert_error("struct a { b : b }\n struct b { a : a }", "contains itself through 'b.a'");
+    assert_error("struct pair(T) { a : T; b : T }\n struct tree { p : pair(tree) }", "through 'p.a'");
+    // The elements of arrays are behind their data pointer
+    assert_result("struct tree { children : array(tree) }\n 3", Val::I64(3));
+    let elements = "
+      struct pair(T) { a : T; b : T }
+      struct both { small : pair(i64); big : pair(tree) }
+      struct tree { b : both }
+    ";
+    assert_error(elements, "through 'b.big.a'");
+  }
+
+  #[test]
+  fn test_internal_type_error() {
+    // Impossible solver states become ordinary errors, so the session survives them
+    let e = internal_error(TextLocation::zero(), "unresolved types found, but no errors generated", &[]);
+    assert!(e.severity == Severity::Error);
+    let message = format!("{}", e.display());
+    assert!(message.contains("internal compiler error: unresolved types found"), "{}", message);
+    assert!(message.contains("Constraints involved:"), "{}", message);
+  }
+
+  #[test]
+  fn test_overload_ranking() {
+    let code = "
+      fun pick(v : i64) => i64 { 1 }
+      fun pick(v : T) => i64 with T { 2 }
+      fun small(v : i16) => i64 { 3 }
+      fun small(v : T) => i64 with T { 4 }
+      let x : i32 = 5
+      pick(1) * 100 + small(1) * 10 + pick(x)
+    ";
+    assert_result(code, Val::I64(132));
+    let ambiguous = "
+      fun size(v : i32) => i64 { 32 }
+      fun size(v : u8) => i64 { 8 }
+      size(1)
+    ";
+    assert_error(ambiguous, "not resolved");
+  }
+
   #[test]
   fn test_first_class_function() {
     let code = "
@@ -478,7 +1144,7 @@ rusty_fork_test! {
         a + b
       }
       fun fold(a : array(i64), len : i64, v : i64, f : fun(i64, i64) => i64) {
-        let i = 0
+        var i = 0
         while i < len {
           v = f(v, a[i])
           i = i + 1
@@ -491,6 +1157,89 @@ rusty_fork_test! {
     assert_result(code, Val::I64(10));
   }
 
+  #[test]
+  fn test_closures() {
+    let code = "
+      fun double(x : i64) => i64 { x * 2 }
+      fun add_env(offset : ptr(i64), x : i64) => i64 { *offset + x }
+      fun sum(a : i64, b : i64) => i64 { a + b }
+      struct button { on_click : closure(fun(i64) => i64) }
+      let fs = list()
+      fs.add(closure(double))
+      fs.add(closure(add_env, alloc(100)))
+      var total = 0
+      for f in fs {
+        total = total + f.call(5)
+      }
+      let b = button.new(closure(add_env, alloc(1000)))
+      let apply = thin(closure(sum))
+      total + b.on_click.call(1) + apply(20, 2)
+    ";
+    assert_result(code, Val::I64(10 + 105 + 1001 + 22));
+    // a null context is still passed
+    let code = "
+      fun no_env(env : ptr(i64), x : i64) => i64 { if (env as u64) == 0 { x } else { -1 } }
+      closure(no_env, null()).call(7)
+    ";
+    assert_result(code, Val::I64(7));
+    // closures are thinned when they're passed to C functions
+    let code = "
+      static frames = 0
+      fun frame() { frames = frames + 1 }
+      let c = closure(frame)
+      compiler.run_frame(c) && compiler.run_frame(closure(frame)) && frames == 2
+    ";
+    assert_result(code, Val::Bool(true));
+    assert_error(
+      "fun frame(env : ptr(i64)) {}\ncompiler.run_frame(closure(frame, alloc(1)))",
+      "a closure with a context can't be passed to a C function");
+  }
+
+  #[test]
+  fn test_method_receivers() {
+    let code = "
+      struct counter { n : i64 }
+      fun bump(c : ptr(counter), by : i64) { c.n = c.n + by }
+      fun count(c : counter) => i64 { c.n }
+      var c = counter.new(1)
+      c.bump(10)
+      let p = &c
+      p.bump(100)
+      p.count() * 1000 + c.count()
+    ";
+    assert_result(code, Val::I64(111111));
+    let code = "
+      struct counter { n : i64 }
+      fun bump(c : ptr(counter), by : i64) { c.n = c.n + by }
+      let x = 5
+      x.bump(1)
+    ";
+    assert_error(code, "conflicting types inferred");
+  }
+
+  #[test]
+  fn test_pointer_chains() {
+    let code = "
+      struct inner { n : i64 }
+      struct outer { i : inner ; p : ptr(inner) }
+      fun bump(c : ptr(inner), by : i64) { c.n = c.n + by }
+      fun count(c : inner) => i64 { c.n }
+      var x = inner.new(1)
+      var o = outer.new(inner.new(10), &x)
+      let po = &o
+      let ppo = &po
+      ppo.i.n = ppo.i.n + 5
+      ppo.p.n = 2
+      ppo.i.bump(100)
+      ppo.p.bump(1000)
+      let px = &x
+      let ppx = &px
+      ppx.bump(10000)
+      ppx.count() * 1000 + ppo.i.count()
+    ";
+    assert_result(code, Val::I64(11002 * 1000 + 115));
+  }
+
   #[test]
   fn test_polymorphism() {
     let code = r#"
@@ -500,7 +1249,7 @@ rusty_fork_test! {
         nums.add(x)
       }
 
-      let total = 0
+      var total = 0
       for x in range(0, 10) {
         total = total + nums[x]
       }
@@ -510,33 +1259,675 @@ rusty_fork_test! {
   }
 
   #[test]
-  fn test_index_set() {
-    let a = r#"
-      let l = list()
-      l.add(5)
-      l[0] = 4
-      l[0]
-    "#;
-    let b = r#"
-      struct blah { x : i64; y : i64 }
-      let l = list()
-      l.add(blah.new(40, 100))
-      l[0].y = 4
-      l[0].x + l[0].y
+  fn test_polymorphic_instance_errors() {
+    let code = r#"
+      fun double(a : T) => T with T { a + a }
+      struct point { x : i64 }
+      double(point.new(1))
     "#;
-    assert_result(a, Val::I64(4));
-    assert_result(b, Val::I64(44));
+    assert_error(code, "in 'double' instanced as fun(point) => point");
   }
 
   #[test]
-  fn test_nonexistent_types(){
-    let code = "
-      struct foo {
-        data : sijfsiofssdfio
+  fn test_list_growth() {
+    let code = r#"
+      let l = list([1, 2, 3])
+      for x in range(0, 100) {
+        l.add(x)
       }
-      10
-    ";
-    assert_error(code, "sijfsiofssdfio");
+      let last = l.pop()
+      (l.len() as i64) + last + l[2]
+    "#;
+    assert_result(code, Val::I64(102 + 99 + 3));
+  }
+
+  #[test]
+  fn test_map() {
+    let a = r#"
+      let m = map()
+      for i in range(0, 100) {
+        m.insert(i, i * 2)
+      }
+      m.insert(5, 1000)
+      m.remove(6)
+      var total = 0
+      for kv in m {
+        total = total + kv.v1
+      }
+      total + (m.len() as i64) + m.get(5).unwrap()
+    "#;
+    assert_result(a, Val::I64(9900 - 10 - 12 + 1000 + 99 + 1000));
+    let b = r#"
+      let m = map()
+      m.insert("one", 1)
+      m.insert("two", 2)
+      m.contains("two") && !m.contains("three") && m.get("one").unwrap() == 1
+    "#;
+    assert_result(b, Val::Bool(true));
+  }
+
+  #[test]
+  fn test_propagation_operator() {
+    let a = r#"
+      fun parse_digit(c : u8) => result(i64, string) {
+        if c < 48 || c > 57 {
+          return err("not a digit")
+        }
+        ok((c - 48) as i64)
+      }
+      fun parse_pair(s : string) => result(i64, string) {
+        let a = parse_digit(s.data[0])?
+        let b = parse_digit(s.data[1])?
+        ok(a * 10 + b)
+      }
+      let x = parse_pair("42").unwrap()
+      let y = parse_pair("4x").unwrap_err()
+      x + (y.len() as i64)
+    "#;
+    assert_result(a, Val::I64(42 + 11));
+    let b = r#"
+      fun sum(m : map(i64, i64), a : i64, b : i64) => option(i64) {
+        let x = m.get(a)?
+        let y = m.get(b)?
+        some(x + y)
+      }
+      let m = map()
+      m.insert(1, 10)
+      m.insert(2, 20)
+      !sum(m, 1, 3).is_some && sum(m, 1, 2).unwrap() == 30
+    "#;
+    assert_result(b, Val::Bool(true));
+  }
+
+  #[test]
+  fn test_run_language_test() {
+    let mut i = interpreter();
+    i.run_module("fun test_add() => bool { 2 + 2 == 5 }", "tests").unwrap();
+    assert_eq!(i.c.run_test("add"), Ok(false));
+    // hot-patch the test
+    i.unload_module("tests");
+    i.run_module("fun test_add() => bool { 2 + 2 == 4 }", "tests").unwrap();
+    assert_eq!(i.c.run_test("test_add"), Ok(true));
+    i.eval("fun test_nothing() { }").unwrap();
+    assert_eq!(i.c.run_test("nothing"), Ok(true));
+    assert!(i.c.run_test("missing").is_err());
+  }
+
+  #[test]
+  fn test_dataflow_warnings() {
+    let mut i = interpreter();
+    let code = r#"
+      fun uninit() => i64 {
+        let a : i64 = UnsafeZeroInit()
+        a + 1
+      }
+      fun unchecked() {
+        let p = malloc(8) as ptr(i64)
+        *p = 5
+        free(p as ptr(u8))
+        free(p as ptr(u8))
+      }
+      fun fine() => i64 {
+        let a : i64 = UnsafeZeroInit()
+        let b = &a
+        *b = 3
+        let p = malloc(8) as ptr(i64)
+        if p as u64 != 0 {
+          *p = a
+          free(p as ptr(u8))
+        }
+        a
+      }
+      fun set_to_one(p : ptr(i64)) { *p = 1 }
+      fun auto_ref() => i64 {
+        let a : i64 = UnsafeZeroInit()
+        a.set_to_one()
+        a
+      }
+    "#;
+    i.run_module(code, "warnings").unwrap();
+    let unit_id = i.c.code_store.named_unit("warnings").unwrap();
+    let warnings : Vec<String> =
+      i.c.code_store.warnings(unit_id).iter().map(|w| format!("{}", w.display())).collect();
+    assert_eq!(warnings.len(), 3, "{:?}", warnings);
+    assert!(warnings[0].contains("'a' may be used before it is initialised"));
+    assert!(warnings[1].contains("'p' may be null"));
+    assert!(warnings[2].contains("'p' may already have been freed"));
+  }
+
+  #[test]
+  fn test_unused_and_unreachable_warnings() {
+    let mut i = interpreter();
+    let code = r#"
+      fun foo(a : i64, _b : i64) => i64 {
+        let c = 5
+        return 3
+        a
+      }
+    "#;
+    i.run_module(code, "warnings").unwrap();
+    let unit_id = i.c.code_store.named_unit("warnings").unwrap();
+    let warnings : Vec<String> =
+      i.c.code_store.warnings(unit_id).iter().map(|w| format!("{}", w.display())).collect();
+    assert_eq!(warnings.len(), 2, "{:?}", warnings);
+    assert!(warnings[0].contains("unused variable 'c'"));
+    assert!(warnings[1].contains("unreachable code"));
+    let suppressed = format!("pragma allow(unused_variables, unreachable_code)\n{}", code);
+    i.run_module(&suppressed, "suppressed").unwrap();
+    let unit_id = i.c.code_store.named_unit("suppressed").unwrap();
+    assert_eq!(i.c.code_store.warnings(unit_id).len(), 0);
+    assert_error("pragma allow(not_a_lint)", "unknown lint");
+    assert_error("pragma not_a_pragma", "unknown pragma");
+  }
+
+  #[test]
+  fn test_require_unsafe() {
+    let a = "
+      pragma require_unsafe
+      let x = 5
+      let p = &x
+      unsafe {
+        *p = 6
+      }
+      let a = [1, 2, 3]
+      a[1] + x
+    ";
+    assert_result(a, Val::I64(8));
+    let b = "
+      pragma require_unsafe
+      let x = 5
+      let p = &x
+      *p
+    ";
+    assert_error(b, "dereference of a raw pointer");
+    assert_error("pragma require_unsafe\n let x = 5\n (&x) as u64", "cast from");
+    let c = "
+      pragma require_unsafe(warn)
+      let x = 5
+      let p = &x
+      *p
+    ";
+    let mut i = interpreter();
+    i.run_module(c, "warn").unwrap();
+    let unit_id = i.c.code_store.named_unit("warn").unwrap();
+    assert_eq!(i.c.code_store.warnings(unit_id).len(), 1);
+  }
+
+  #[test]
+  fn test_mutability() {
+    let a = "
+      struct point { x : i64; y : i64 }
+      var p = point.new(1, 2)
+      p.x = 10
+      let q = &p
+      q.y = 20
+      var n = 0
+      for i in range(0, 4) { n = n + i }
+      p.x + p.y + n
+    ";
+    assert_result(a, Val::I64(36));
+    assert_error("let x = 5\n x = 6", "cannot assign to immutable variable 'x'");
+    assert_error("let x = 5\n x = 6", "'x' is defined at (line: 1");
+    let b = "
+      struct point { x : i64; y : i64 }
+      let p = point.new(1, 2)
+      p.x = 10
+    ";
+    assert_error(b, "cannot assign to field 'x' of immutable variable 'p'");
+    assert_result("fun f(a : i64) { a = a + 1; a }\n f(4)", Val::I64(5));
+  }
+
+  #[test]
+  fn test_shadowing() {
+    let a = "
+      let x = 5
+      let shadow x = x + 1
+      let y = {
+        let x = x * 10
+        x
+      }
+      x + y
+    ";
+    assert_result(a, Val::I64(66));
+    assert_error("let x = 5\n let x = 6\n x", "already defined in this scope");
+    let b = "
+      static count = 3
+      fun f(count : i64) { count }
+      let count = 4
+      f(count)
+    ";
+    let mut i = interpreter();
+    assert_eq!(i.run_module(b, "shadowing").unwrap(), Val::I64(4));
+    let unit_id = i.c.code_store.named_unit("shadowing").unwrap();
+    assert_eq!(i.c.code_store.warnings(unit_id).len(), 2);
+    i.run_module("pragma allow(shadowed_globals)\n static n = 1\n let n = 2\n n", "allowed").unwrap();
+    let unit_id = i.c.code_store.named_unit("allowed").unwrap();
+    assert_eq!(i.c.code_store.warnings(unit_id).len(), 0);
+  }
+
+  #[test]
+  fn test_export_slots() {
+    let mut i = interpreter();
+    let slot = i.export_function("callback", "callbacks", "get_value");
+    let read_slot = || unsafe { *slot };
+    let call_slot = || {
+      let f : extern "C" fn() -> i64 = unsafe { std::mem::transmute(*slot) };
+      f()
+    };
+    assert_eq!(read_slot(), 0);
+    i.run_module("fun get_value() => i64 { 10 }", "callbacks").unwrap();
+    assert_eq!(call_slot(), 10);
+    i.unload_module("callbacks");
+    assert_eq!(read_slot(), 0);
+    i.run_module("fun get_value() => i64 { 20 }", "callbacks").unwrap();
+    assert_eq!(call_slot(), 20);
+    assert_eq!(i.export_function("callback", "callbacks", "get_value"), slot);
+  }
+
+  #[test]
+  fn test_string_cache_collection() {
+    let mut i = interpreter();
+    let before = i.c.cache.string_count();
+    i.run_module("fun a_rather_unusual_name(an_unusual_arg : i64) { an_unusual_arg }", "strings").unwrap();
+    let loaded = i.c.cache.string_count();
+    assert!(loaded > before);
+    i.unload_module("strings");
+    assert!(i.c.cache.string_count() < loaded);
+  }
+
+  #[test]
+  fn test_event_bus() {
+    let a = r#"
+      struct moved { x : i64; y : i64 }
+      let s : subscription(moved) = subscribe("moved")
+      publish("moved", moved.new(3, 4))
+      publish("moved", moved.new(10, 20))
+      let m = moved.new(0, 0)
+      var total = 0
+      while s.poll(&m) {
+        total = total + m.x * m.y
+      }
+      s.unsubscribe()
+      publish("moved", moved.new(1, 1))
+      total
+    "#;
+    assert_result(a, Val::I64(212));
+  }
+
+  #[test]
+  fn test_check_module() {
+    let mut i = interpreter();
+    let report = i.check_module("static checked = 5\n let b = checked as f64\n b").unwrap();
+    assert!(report.types.values().any(|t| t == "I64"));
+    assert!(report.types.values().any(|t| t == "F64"));
+    let errors = i.check_module("let a : i64 = 5.5").map(|_| ()).unwrap_err();
+    assert!(!errors.is_empty());
+    // nothing from the checked code is kept
+    assert!(i.eval("checked").is_err());
+  }
+
+  #[test]
+  fn test_doc_comments() {
+    let mut i = interpreter();
+    let code = "
+      ## A point on the screen
+      struct point { x : i64; y : i64 }
+
+      ## Adds two points.
+      ##
+      ## The result is a new point.
+      fun add(a : point, b : point) => point {
+        point.new(a.x + b.x, a.y + b.y)
+      }
+
+      // not a doc comment
+      fun undocumented() => i64 { 1 }
+    ";
+    let docs = i.document_module(code, "points").unwrap();
+    let names : Vec<_> = docs.entries.iter().map(|e| e.name.to_string()).collect();
+    assert_eq!(names, vec!["point", "add", "undocumented"]);
+    assert_eq!(docs.entries[0].doc.as_ref().map(|d| d.as_ref()), Some("A point on the screen"));
+    assert_eq!(docs.entries[1].doc.as_ref().map(|d| d.as_ref()), Some("Adds two points.\n\nThe result is a new point."));
+    assert!(docs.entries[2].doc.is_none());
+    assert_eq!(docs.entries[1].signature, "fun add(a : point, b : point) => point");
+    assert!(docs.markdown().contains("## add"));
+    assert!(docs.html().contains("<p>The result is a new point.</p>"));
+    // after code, `##` is a quote of a quote
+    assert_result("let q = ##(1 + 1)\n5", Val::I64(5));
+  }
+
+  #[test]
+  fn test_format() {
+    let code = "// header\nfun add(a : i64,\n        b : i64) => i64 {\n  a+b   // sum\n}\n\n\nlet x = add(1,   2)*3\nif x > 5 then x else { 0 }\n";
+    let expected = "// header\nfun add(a : i64, b : i64) => i64 {\n  a + b // sum\n}\n\nlet x = add(1, 2) * 3\nif x > 5 { x } else { 0 }\n";
+    let options = FormatOptions::default();
+    assert_eq!(formatter::format(code, options).unwrap(), expected);
+    assert_eq!(formatter::format(expected, options).unwrap(), expected);
+    // parentheses are only kept where they're needed
+    let code = "x = ((a + b)) * -c\na - (b - c)\n(p.x).y(1)";
+    assert_eq!(formatter::format(code, options).unwrap(), "x = (a + b) * -c\na - (b - c)\np.x.y(1)\n");
+    let narrow = FormatOptions { indent: 4, width: 10 };
+    assert_eq!(formatter::format("foo(aaaa, bbbb, cccc)", narrow).unwrap(), "foo(\n    aaaa,\n    bbbb,\n    cccc\n)\n");
+    assert!(formatter::format("let = (", options).is_err());
+  }
+
+  #[test]
+  fn test_text_editing() {
+    let original = "fun f() {\n  1\n}";
+    let mut editor = TextEditorState::new(original);
+    let mut history = EditHistory::new();
+    let step = |editor : &mut TextEditorState, move_type| editor.move_caret(CaretMove { highlighting: false, move_type });
+    step(&mut editor, CaretMoveType::Down);
+    step(&mut editor, CaretMoveType::End);
+    assert_eq!(editor.caret_line_col(), (1, 3));
+    // a new line keeps the indentation
+    let text = format!("\n{}2", editor.current_indentation());
+    let edit = editor.insert(text);
+    history.apply_text_edit(&mut editor, edit);
+    assert_eq!(editor.buffer.to_string(), "fun f() {\n  1\n  2\n}");
+    let edit = editor.backspace().unwrap();
+    history.apply_text_edit(&mut editor, edit);
+    assert_eq!(editor.buffer.to_string(), "fun f() {\n  1\n  \n}");
+    assert!(history.undo(&mut editor) && history.undo(&mut editor));
+    assert!(!history.undo(&mut editor));
+    assert_eq!(editor.buffer.to_string(), original);
+    assert!(history.redo(&mut editor));
+    assert_eq!(editor.caret_line_col(), (2, 3));
+    // Home goes to the first character that isn't indentation
+    step(&mut editor, CaretMoveType::Home);
+    assert_eq!(editor.caret_line_col(), (2, 2));
+  }
+
+  #[test]
+  fn test_constraint_graph() {
+    let mut i = interpreter();
+    let dot = i.constraint_graph("let a : i64 = 5\n a + 1.5").unwrap();
+    assert!(dot.starts_with("digraph constraints {"));
+    assert!(dot.contains("SymbolRef +"));
+    assert!(dot.contains("label=\"I64"));
+    // the type error is listed at the end
+    assert!(dot.trim_end().lines().last().unwrap().starts_with("// "));
+    assert!(i.eval("a").is_err());
+  }
+
+  #[test]
+  fn test_repl_history_entries() {
+    let entry = "fun f(s : string) {\n  s + \"\\\\n\"\n}";
+    let line = escape_history_entry(entry);
+    assert!(!line.contains('\n'));
+    assert_eq!(unescape_history_entry(&line), entry);
+  }
+
+  #[test]
+  fn test_probes() {
+    let mut i = interpreter();
+    let code = "fun f(x : i64) => i64 {\n  ?(x * 2) + 1\n}\nfun probe(x : i64) => i64 { x }\nf(1)\nprobe(f(5))\n?\"hi\"";
+    i.run_module(code, "probed").unwrap();
+    let probes : Vec<_> = i.probes().into_iter().filter(|(u, _)| u.as_ref() == "probed").map(|(_, p)| p).collect();
+    assert_eq!(probes.len(), 2);
+    assert!(probes[0].span.starts_with("2:"));
+    assert_eq!((probes[0].value.as_str(), probes[0].hits), ("10", 2));
+    assert!(probes[1].span.starts_with("7:"));
+    assert_eq!(probes[1].value, "\"hi\"");
+    i.unload_module("probed");
+    assert!(i.probes().iter().all(|(u, _)| u.as_ref() != "probed"));
+  }
+
+  #[test]
+  fn test_checked_pointers() {
+    let mut i = interpreter();
+    let code = "static score = 5\nfun score_ptr() => checked_ptr(i64) {\n  checked(&score)\n}";
+    i.run_module(code, "owner").unwrap();
+    assert_result_with_interpreter(&mut i, "*get(score_ptr()) = 7; score", Val::I64(7));
+    // pointers to anything but statics have no owner
+    assert_result_with_interpreter(&mut i, "let x = 3; checked(&x).unit", Val::U64(0));
+    let unit = match i.eval("score_ptr().unit").unwrap() { Val::U64(u) => u, v => panic!("{:?}", v) };
+    assert!(unit > 0);
+    assert_eq!(pointers::check(unit, "line: 3, column: 3"), Ok(()));
+    i.unload_module("owner");
+    let e = pointers::check(unit, "line: 3, column: 3").unwrap_err();
+    assert_eq!(e, "pointer into unloaded module, allocated at line: 3, column: 3");
+  }
+
+  #[test]
+  fn test_memory_report() {
+    let mut i = interpreter();
+    let code = "static buffer = malloc(48)\nstatic list = alloc(5)\nfree(malloc(100))";
+    i.run_module(code, "leaky").unwrap();
+    let leaky = |report : Vec<allocations::ModuleMemory>| report.into_iter().find(|m| m.module == "leaky");
+    let m = leaky(allocations::report()).unwrap();
+    assert_eq!((m.allocations, m.bytes, m.unloaded), (2, 56, false));
+    i.unload_module("leaky");
+    let m = leaky(allocations::report()).unwrap();
+    assert_eq!((m.allocations, m.bytes, m.unloaded), (2, 56, true));
+    // units that didn't leak are forgotten once they're unloaded
+    let before = allocations::unloaded_units();
+    for _ in 0..5 {
+      i.run_module("free(malloc(16))", "tidy").unwrap();
+      i.unload_module("tidy");
+    }
+    assert!(allocations::unloaded_units() <= before);
+    assert!(leaky(allocations::report()).is_some());
+  }
+
+  #[test]
+  fn test_custom_allocator() {
+    let code = "
+      static arena = malloc(1024)
+      static used : u64 = 0
+      static frees = 0
+      fun bump(size : u64) => ptr(u8) {
+        let p = &arena[used as i64]
+        used = used + size
+        p
+      }
+      fun release(p : ptr(u8)) { frees = frees + 1 }
+      set_allocator(bump, release)
+      let a = alloc(5)
+      let b = realloc(a, 2)
+      *b = 3
+      dealloc(b)
+      clear_allocator()
+      dealloc(alloc(4))
+      let in_arena = (b as u64) - (arena as u64) < 1024
+      if in_arena { (used as i64) * 10 + frees } else { -1 }
+    ";
+    assert_result(code, Val::I64(242));
+  }
+
+  #[test]
+  fn test_session_save_and_load() {
+    let dir = std::env::temp_dir();
+    let loaded = dir.join("cauldron session in.code");
+    let saved = dir.join("cauldron session out.code");
+    std::fs::write(&loaded, "static a = 5\n// ----\nfun f() {\n  a * 2\n}\n").unwrap();
+    let mut i = interpreter();
+    assert!(run_command(&mut i, &format!(":load {}", loaded.display())));
+    assert_result_with_interpreter(&mut i, "f()", Val::I64(10));
+    assert!(run_command(&mut i, &format!(":save {}", saved.display())));
+    let text = std::fs::read_to_string(&saved).unwrap();
+    assert_eq!(text, "static a = 5\n// ----\nfun f() {\n  a * 2\n}\n");
+  }
+
+  #[test]
+  fn test_bench() {
+    let mut i = interpreter();
+    let r = i.bench("malloc(100)", 10).unwrap();
+    assert_eq!(r.runs, 10);
+    assert_eq!(r.bytes_per_run, 100);
+    assert!(r.min <= r.median);
+    assert!(i.bench("not_defined(1)", 10).is_err());
+  }
+
+  #[test]
+  fn test_test_blocks() {
+    let mut i = interpreter();
+    let code = r#"
+      fun double(x : i64) { x * 2 }
+      test "double works" { double(2) == 4 }
+      test "double is broken" { double(2) == 5 }
+      test "nothing to check" { double(1) ; () }
+      let test = 3
+      test
+    "#;
+    assert_eq!(i.run_module(code, "tested").unwrap(), Val::I64(3));
+    let unit_id = i.c.code_store.named_unit("tested").unwrap();
+    assert_eq!(i.c.units_with_tests(), vec![unit_id]);
+    let report = i.c.run_tests(unit_id);
+    let names : Vec<&str> = report.results.iter().map(|r| r.name.as_ref()).collect();
+    assert_eq!(names, vec!["double works", "double is broken", "nothing to check"]);
+    assert_eq!((report.passed(), report.failed()), (2, 1));
+    assert_eq!(i.c.run_test("double_works").unwrap(), true);
+    assert_error("test \"a\" { true }\n test \"a\" { false }", "defined more than once");
+    // the watcher reruns only the tests of the file that changed
+    let path = std::env::temp_dir().join("cauldron_tested.code");
+    let path_str = path.to_str().unwrap().to_string();
+    let code = "test \"loaded from a file\" { true }";
+    std::fs::write(&path, code).unwrap();
+    i.run_module(code, &path_str).unwrap();
+    let file_unit = i.c.code_store.named_unit(&path_str).unwrap();
+    assert_eq!(i.c.units_with_tests(), vec![unit_id, file_unit]);
+    assert_eq!(i.c.units_with_tests_affected_by(&path_str), vec![file_unit]);
+  }
+
+  #[test]
+  fn test_assertions() {
+    let mut i = interpreter();
+    let code = r#"
+      test "passes" { expect(2 > 1) ; expect_eq("a", "a") }
+      test "fails" { expect_eq(1 + 1, 3) ; true }
+    "#;
+    i.run_module(code, "asserts").unwrap();
+    let unit_id = i.c.code_store.named_unit("asserts").unwrap();
+    let report = i.c.run_tests(unit_id);
+    let outcomes : Vec<bool> = report.results.iter().map(|r| r.outcome == Ok(true)).collect();
+    assert_eq!(outcomes, vec![true, false]);
+    // outside a test, the code carries on, and the failure is reported once it returns
+    assert_error("var x = 1\nexpect(x == 2)\nx = 3", "1 expectations failed in the top-level code");
+    // a unit's own `expect` is called as it is
+    assert_result("fun expect(v : i64) => i64 { v * 2 }\nexpect(21)", Val::I64(42));
+  }
+
+  #[test]
+  fn test_property_checks() {
+    let code = r#"
+      struct point { x : i64 ; y : i64 }
+      struct sized { size : u8 ; visible : bool ; scale : f32 }
+
+      fun below_fifty(x : i64) => bool { x < 50 }
+      fun commutes(p : point) => bool { p.x + p.y == p.y + p.x }
+      fun in_box(p : point) => bool { p.x < 10 || p.y < 10 }
+      fun small(s : sized) => bool { s.size < 200 || !s.visible }
+      let found = check(7, 100, commutes) && !check(7, 100, below_fifty) && !check(7, 1000, small)
+      let smallest = shrink_failure(point.new(900, 300), in_box)
+      let shrunk = shrink_failure(900, below_fifty) == 50 && smallest.x == 10 && smallest.y == 10
+      let s = shrink_failure(sized.new(250, true, 3.5), small)
+      found && shrunk && s.size == 200 && s.visible && s.scale == 0.0
+    "#;
+    assert_result(code, Val::Bool(true));
+    assert_error("fun valid(p : ptr(i64)) => bool { true }\ncheck(valid)",
+      "typeinfo only describes numbers, bools and structs of them, not 'ptr(i64)'");
+  }
+
+  #[test]
+  fn test_fuzz_entry_points() {
+    use crate::fuzz::{fuzz_lex, fuzz_parse, fuzz_infer};
+    let nested = "(".repeat(10000);
+    let bad_inputs : Vec<&[u8]> = vec![
+      &b"\"abc\\"[..], &b"\xff\xfe"[..], nested.as_bytes(), &b"if"[..], &b"let a = "[..],
+    ];
+    for input in bad_inputs {
+      assert!(fuzz_parse(input).is_err());
+      assert!(fuzz_infer(input).is_err());
+    }
+    assert_eq!(fuzz_lex(b"/* never closed").unwrap(), 0);
+    assert_eq!(fuzz_lex(b"let a = 5").unwrap(), 4);
+    assert!(fuzz_infer(b"let a = 5\n a + 1").is_ok());
+  }
+
+  #[test]
+  fn test_asset_changed_events() {
+    let mut i = interpreter();
+    i.eval("static assets = subscribe_asset_changes()").unwrap();
+    assert!(run_command(&mut i, ":asset-changed images\\big tile.png"));
+    let code = r#"
+      let e = asset_changed.new("")
+      var matched = 0
+      while assets.poll(&e) {
+        if e.path == "images/big tile.png" { matched = matched + 1 }
+      }
+      matched
+    "#;
+    assert_result_with_interpreter(&mut i, code, Val::I64(1));
+  }
+
+  #[test]
+  fn test_frame_capture_requests() {
+    let mut i = interpreter();
+    i.eval(r#"screenshot("shot.bmp")"#).unwrap();
+    let dir = std::env::temp_dir().join("cauldron_capture_test");
+    i.c.capture.start_recording(dir.to_str().unwrap()).unwrap();
+    // the program passes the pixels over, and never sees where they are saved
+    let code = r#"
+      let pixels = [1 as u8, 2, 3, 4, 5, 6, 7, 8]
+      if compiler.frame_presented() {
+        compiler.save_frame(pixels.data, 2, 1, 8)
+      }
+      else { false }
+    "#;
+    assert_result_with_interpreter(&mut i, code, Val::Bool(true));
+    assert_result_with_interpreter(&mut i, code, Val::Bool(true));
+    assert_eq!(i.c.capture.stop_recording(), Some(2));
+    assert_result_with_interpreter(&mut i, code, Val::Bool(false));
+    let frame = std::fs::read(dir.join("frame_000001.bmp")).unwrap();
+    assert_eq!(&frame[..2], b"BM");
+    assert_eq!(&frame[frame.len() - 8..], &[1, 2, 3, 4, 5, 6, 7, 8]);
+    assert_eq!(std::fs::read("shot.bmp").unwrap(), std::fs::read(dir.join("frame_000000.bmp")).unwrap());
+    std::fs::remove_file("shot.bmp").unwrap();
+  }
+
+  #[test]
+  fn test_index_set() {
+    let a = r#"
+      let l = list()
+      l.add(5)
+      l[0] = 4
+      l[0]
+    "#;
+    let b = r#"
+      struct blah { x : i64; y : i64 }
+      let l = list()
+      l.add(blah.new(40, 100))
+      l[0].y = 4
+      l[0].x + l[0].y
+    "#;
+    assert_result(a, Val::I64(4));
+    assert_result(b, Val::I64(44));
+  }
+
+  #[test]
+  fn test_feature_report() {
+    let mut i = interpreter();
+    let code = "
+      union foo { a : i64; b : f64 }
+      let p = alloc(5)
+      let a = [1, 2, 3]
+      *p + a[1]
+    ";
+    let report = i.feature_report(code, "features").unwrap();
+    assert!(report.features.contains_key("union"));
+    assert_eq!(report.features.get("raw pointer dereference"), Some(&1));
+  }
+
+  #[test]
+  fn test_nonexistent_types(){
+    let code = "
+      struct foo {
+        data : sijfsiofssdfio
+      }
+      10
+    ";
+    assert_error(code, "sijfsiofssdfio");
   }
 
   #[test]
@@ -591,6 +1982,478 @@ rusty_fork_test! {
     assert_result(code, Val::Void);
   }
 
+  #[test]
+  fn test_deterministic_codegen() {
+    let code = "
+      struct point {
+        x : i64
+        y : i64
+      }
+      fun f(a : i64) => i64 { a }
+      fun f(a : f64) => i64 { a as i64 }
+      fun g(a : T) => T with T { a }
+      static p = point.new(x: 1, y: 2)
+      fun h() => i64 {
+        g(f(p.x)) + g(3) + f(g(4.0))
+      }
+    ";
+    let compile = || {
+      let mut i = interpreter();
+      i.run_module(code, "determinism").unwrap();
+      let unit_id = i.c.code_store.named_unit("determinism").unwrap();
+      i.c.dump_ir(unit_id).unwrap()
+    };
+    let a = compile();
+    for _ in 0..3 {
+      assert_eq!(a, compile());
+    }
+  }
+
+  #[test]
+  fn test_compile_options() {
+    let code = "
+      fun third(a : array(i64)) => i64 { a[2] }
+      third([1, 2, 3])
+    ";
+    let mut i = interpreter();
+    assert_eq!(i.run_module(code, "checked").unwrap(), Val::I64(3));
+    i.c.default_options = CompileOptions::release();
+    assert_eq!(i.run_module(code, "unchecked").unwrap(), Val::I64(3));
+    let ir = |name| i.c.dump_ir(i.c.code_store.named_unit(name).unwrap()).unwrap();
+    assert!(ir("checked").contains("index_out_of_bounds"));
+    assert!(!ir("unchecked").contains("index_out_of_bounds"));
+  }
+
+  #[test]
+  fn test_array_iteration() {
+    let mut i = interpreter();
+    let code = "
+      struct coin { value : i64 }
+      fun total(a : array(coin)) => i64 {
+        var t = 0
+        for c in a { t = t + c.value }
+        t
+      }
+      total([coin.new(1), coin.new(2), coin.new(3)]) + total([])
+    ";
+    assert_eq!(i.run_module(code, "iterated").unwrap(), Val::I64(6));
+    // iterating doesn't need bounds checks, even with them turned on. `next` is
+    // compiled in the unit of its instance for arrays of coins, which this made.
+    let next_units : Vec<_> =
+      i.c.code_store.names.iter()
+      .filter(|(_, name)| name.starts_with("@poly[next]") && name.contains("coin"))
+      .map(|(&u, _)| u).collect();
+    assert_eq!(next_units.len(), 1);
+    let ir = i.c.dump_ir(next_units[0]).unwrap();
+    assert!(ir.contains("getelementptr"));
+    assert!(!ir.contains("index_out_of_bounds"));
+  }
+
+  #[test]
+  fn test_slices() {
+    let code = "
+      let a = [1, 2, 3, 4, 5]
+      var total = 0
+      for x in a[1..4] { total = total + x }
+      let l = list(a)
+      let middle = l[1..3]
+      let word = \"hello world\"[6..11]
+      var n = 0
+      for i in 0..4 { n = n + i }
+      let start : u32 = 2
+      let floats = [1.5, 2.5, 3.5][start..3]
+      total * 1000 + middle[0] * 100 + (middle.length as i64) * 10 + n
+        + (if word == \"world\" { 10000 } else { 0 }) + ((floats[0] * 2.0) as i64) * 100000
+    ";
+    assert_result(code, Val::I64(9000 + 200 + 20 + 6 + 10000 + 700000));
+    let options = FormatOptions::default();
+    assert_eq!(formatter::format("let r = 0 .. n\na[i ..n + 1]", options).unwrap(), "let r = 0..n\na[i..n + 1]\n");
+  }
+
+  #[test]
+  fn test_constant_folding() {
+    let mut i = interpreter();
+    let code = "
+      fun folded() => i64 {
+        if 2 * 3 > 5 && !false { (10 - 4) / 2 } else { 100 % 7 }
+      }
+      fun floats() => f64 { if 7 / 2 == 3 { 1.5 * 2.0 } else { 0.0 } }
+      fun sized() => u64 { sizeof(i32) + alignof(f64) * 10 }
+      fun wrapped() => u8 { 250 + 10 }
+      folded() * 1000 + (floats() as i64) * 100 + (sized() as i64) + (wrapped() as i64) * 10000
+    ";
+    assert_eq!(i.run_module(code, "folded").unwrap(), Val::I64(43384));
+    // the branches that can't be taken aren't compiled
+    let ir = i.c.dump_ir(i.c.code_store.named_unit("folded").unwrap()).unwrap();
+    assert!(!ir.contains("srem"));
+    assert!(!ir.contains("br i1"));
+  }
+
+  #[test]
+  fn test_stack_arrays() {
+    let mut i = interpreter();
+    let code = "
+      fun local_sum() => i64 {
+        var total = 0
+        let a = [1, 2, 3, 4]
+        let b = a
+        for x in b { total = total + x }
+        a[0] = 10
+        total + a[0] * (len(a) as i64)
+      }
+      fun escaping() => array(i64) {
+        let e = [5, 6]
+        e
+      }
+      local_sum() * 10 + escaping()[1]
+    ";
+    assert_eq!(i.run_module(code, "stack_arrays").unwrap(), Val::I64(506));
+    let ir = i.c.dump_ir(i.c.code_store.named_unit("stack_arrays").unwrap()).unwrap();
+    assert!(ir.contains("array_alloca"));
+    assert!(ir.contains("array_malloc"));
+  }
+
+  #[test]
+  fn test_dump_api() {
+    let mut i = interpreter();
+    i.run_module("fun dumped(a : i64) => i64 { a + 1 }", "dumped").unwrap();
+    let unit_id = i.c.code_store.named_unit("dumped").unwrap();
+    assert!(i.c.dump_ir(unit_id).unwrap().contains("dumped"));
+    assert!(i.c.dump_expr(unit_id).unwrap().contains("dumped"));
+    let nodes = i.c.dump_nodes(unit_id).unwrap();
+    assert!(nodes.contains("FunctionDefinition"));
+    assert!(nodes.contains(": i64"));
+    let code = r#"
+      let m = get_module("dumped").unwrap()
+      let ir = dump_ir(m).unwrap()
+      ir.length > 0
+    "#;
+    assert_eq!(i.eval(code).unwrap(), Val::Bool(true));
+  }
+
+  #[test]
+  fn test_shutdown() {
+    let mut i = interpreter();
+    let exits = i.c.events.subscribe::<i64>("exited").unwrap();
+    let code = "
+      fun on_exit() { publish(\"exited\", 1) }
+      fun on_last_exit() { publish(\"exited\", 2) }
+      at_exit(on_exit)
+      at_exit(on_last_exit)
+    ";
+    i.run_module(code, "exiting").unwrap();
+    i.run_module("at_exit(on_exit)", "unloaded").unwrap();
+    i.unload_module("unloaded");
+    // callbacks are also dropped with the unit that contains them
+    i.run_module("fun on_lib_exit() { publish(\"exited\", 3) }", "lib").unwrap();
+    i.run_module("fun register() { at_exit(on_lib_exit) }", "registrar").unwrap();
+    let register : extern "C" fn() = unsafe {
+      std::mem::transmute(i.c.function_address(i.c.code_store.named_unit("registrar").unwrap(), "register").unwrap())
+    };
+    register();
+    assert_eq!(i.c.exit_callbacks.last().unwrap().owner, i.c.code_store.named_unit("lib"));
+    i.unload_module("registrar");
+    i.unload_module("lib");
+    assert_eq!(i.c.exit_callbacks.len(), 2);
+    let order = i.c.code_store.unload_order();
+    let position = |name| order.iter().position(|&u| u == i.c.code_store.named_unit(name).unwrap());
+    assert!(position("exiting") < position("../code/core/prelude.code"));
+    i.c.shutdown();
+    assert!(i.c.code_store.named_unit("exiting").is_none());
+    // most recently registered first
+    assert_eq!(i.c.events.poll::<i64>(exits).unwrap(), Some(2));
+    assert_eq!(i.c.events.poll::<i64>(exits).unwrap(), Some(1));
+    assert_eq!(i.c.events.poll::<i64>(exits).unwrap(), None);
+  }
+
+  #[test]
+  fn test_vm_backend() {
+    let code = "
+      static limit = 10
+      fun fib(n : i64) => i64 {
+        if n < 2 { return n }
+        fib(n - 1) + fib(n - 2)
+      }
+      var total = 0
+      var i = 0
+      while i < limit {
+        total = total + fib(i)
+        i = i + 1
+      }
+      (total as f64) / 2.0
+    ";
+    let mut i = interpreter();
+    assert_eq!(i.eval_in_vm(code).unwrap(), Val::F64(44.0));
+    let unit_id = *i.c.code_store.vm_call_counts.keys().next().unwrap();
+    let hot = i.c.hot_functions(unit_id, 100);
+    assert_eq!(hot.len(), 1);
+    assert_eq!((hot[0].0.as_ref(), hot[0].1), ("fib", 276));
+    assert_eq!(i.eval_in_vm("let a : u8 = 250; a + 10").unwrap(), Val::U8(4));
+    let e = i.eval_in_vm("\"hello\"").unwrap_err();
+    assert!(format!("{}", e.display()).contains("the vm backend doesn't support strings yet"));
+    let e = i.eval_in_vm("fun f(n : i64) => i64 { f(n + 1) }\nf(0)").unwrap_err();
+    assert!(format!("{}", e.display()).contains("calls nested more than"));
+    // vm units aren't imported by later code
+    assert!(i.eval("fib(3)").is_err());
+  }
+
+  #[test]
+  fn test_function_costs() {
+    let path = std::env::temp_dir().join("cauldron_costs.code");
+    let code = "fun twice(n : i64) => i64 { n * 2 }\nfun unused() {}\ntwice(twice(1))";
+    std::fs::write(&path, code).unwrap();
+    let path = path.to_str().unwrap();
+    let mut i = interpreter();
+    let (unit_id, _) = i.c.load_module_with_options(code, Some(path), &[], CompileOptions::vm()).unwrap();
+    let twice =
+      i.c.code_store.types(unit_id).symbols.values()
+      .find(|def| def.name.as_ref() == "twice").unwrap().loc;
+    assert_eq!(i.c.function_costs(path), vec![(twice, 2)]);
+    // JIT-compiled functions aren't counted
+    let mut i = interpreter();
+    i.run_module(code, path).unwrap();
+    assert!(i.c.function_costs(path).is_empty());
+  }
+
+  #[test]
+  fn test_vm_parity() {
+    let programs = [
+      "let a : i32 = 7; let b : u16 = 3; ((a / 2) as f32) * 1.5 + (b * 2) as f32",
+      "let x = 10.0 / 4.0; if x > 2.0 { 1 } else { 2 }",
+      "
+        var total = 0
+        var x = 0
+        outer : while x < 5 {
+          x = x + 1
+          var y = 0
+          while y < 5 {
+            y = y + 1
+            if y > x { continue outer }
+            if x == 4 { break outer }
+            total = total + 1
+          }
+        }
+        var i = 0
+        let found = loop { i = i + 1; if i * i > 50 { break i } }
+        total * 100 + found
+      ",
+      "
+        fun cost(tile : u8) => i64 {
+          switch tile { 0 => 1, 1, 2 => 10, else => 100 }
+        }
+        cost(0) + cost(2) + cost(9)
+      ",
+      "
+        static calls = 0
+        fun seen(v : bool) => bool { calls = calls + 1; v }
+        let a = seen(false) && seen(true)
+        let b = seen(true) || seen(false)
+        if !a && b { calls } else { -1 }
+      ",
+      "
+        fun ||(a : i64, b : i64) => i64 { if a != 0 { a } else { b } }
+        (0 || 5) * 10 + (3 || 4)
+      ",
+      "
+        fun gcd(a : u64, b : u64) => u64 { if b == 0 { a } else { gcd(b, a % b) } }
+        gcd(1071, 462) as i64 - 1
+      ",
+    ];
+    for code in programs.iter() {
+      let mut i = interpreter();
+      let expected = i.eval(code).unwrap();
+      assert_eq!(i.eval_in_vm(code).unwrap(), expected, "{}", code);
+    }
+    // init blocks run after the top-level code, and tests see the statics they left
+    let code = "
+      static x = 1
+      init { x = x * 10 }
+      init { x = x + 5 }
+      test \"after init\" { x == 25 }
+      test \"wrong\" { x == 1 }
+      x = x + 1
+      x
+    ";
+    let mut i = interpreter();
+    assert_eq!(i.eval_in_vm(code).unwrap(), Val::I64(2));
+    let unit_id = *i.c.code_store.vm_call_counts.keys().next().unwrap();
+    let report = i.c.run_tests(unit_id);
+    let outcomes : Vec<bool> = report.results.iter().map(|r| r.outcome == Ok(true)).collect();
+    assert_eq!(outcomes, vec![true, false]);
+  }
+
+  #[test]
+  fn test_reload_patches_dependents() {
+    let mut i = interpreter();
+    i.run_module("fun value() => i64 { 1 }", "lib").unwrap();
+    i.run_module("fun call_value() => i64 { value() * 10 }", "user").unwrap();
+    assert_result_with_interpreter(&mut i, "call_value()", Val::I64(10));
+    let reload = i.reload_module("lib", "fun value() => i64 { 2 }", OnAbiChange::Reject).unwrap();
+    assert!(reload.patched);
+    assert_result_with_interpreter(&mut i, "call_value()", Val::I64(20));
+    assert!(i.c.retired.is_empty());
+    assert_eq!(i.c.code_store.symbol_versions("lib", "value").len(), 2);
+    // the type changed, so the dependents can't be patched
+    let e = i.reload_module("lib", "fun value() => f64 { 3.0 }", OnAbiChange::Reject).err().unwrap();
+    let message = format!("{}", e.display());
+    assert!(message.contains("the ABI of 'lib' changed"));
+    assert!(message.contains("'value' changed from"));
+    assert_result_with_interpreter(&mut i, "call_value()", Val::I64(20));
+    let reload = i.reload_module("lib", "fun value() => f64 { 3.0 }", OnAbiChange::UnloadDependents).unwrap();
+    assert!(!reload.patched);
+    assert_eq!(reload.abi_changes.len(), 1);
+    assert!(i.c.code_store.named_unit("user").is_none());
+    assert_result_with_interpreter(&mut i, "value()", Val::F64(3.0));
+    // only the latest versions are kept
+    for _ in 0..KEPT_SYMBOL_VERSIONS {
+      i.reload_module("lib", "fun value() => f64 { 3.0 }", OnAbiChange::Reject).unwrap();
+    }
+    assert_eq!(i.c.code_store.symbol_versions("lib", "value").len() as u32, KEPT_SYMBOL_VERSIONS);
+    // a failed reload leaves the old version in place
+    assert!(i.reload_module("lib", "fun value( {", OnAbiChange::Reject).is_err());
+    assert_result_with_interpreter(&mut i, "value()", Val::F64(3.0));
+  }
+
+  #[test]
+  fn test_reload_changed_inline_function() {
+    let mut i = interpreter();
+    i.run_module("inline fun scaled(x : i64) => i64 { x * 2 }", "lib").unwrap();
+    i.run_module("fun call_scaled() => i64 { scaled(5) }", "user").unwrap();
+    let reload = i.reload_module("lib", "inline fun scaled(x : i64) => i64 { x * 2 }", OnAbiChange::Reject).unwrap();
+    assert!(reload.patched);
+    // the old body was compiled into 'user', so it can't be patched
+    let e = i.reload_module("lib", "inline fun scaled(x : i64) => i64 { x * 3 }", OnAbiChange::Reject).err().unwrap();
+    assert!(format!("{}", e.display()).contains("the inline function 'scaled' changed"));
+    assert_result_with_interpreter(&mut i, "call_scaled()", Val::I64(10));
+    let reload = i.reload_module("lib", "inline fun scaled(x : i64) => i64 { x * 3 }", OnAbiChange::UnloadDependents).unwrap();
+    assert!(!reload.patched);
+    assert!(i.c.code_store.named_unit("user").is_none());
+    i.run_module("fun call_scaled() => i64 { scaled(5) }", "user").unwrap();
+    assert_result_with_interpreter(&mut i, "call_scaled()", Val::I64(15));
+  }
+
+  #[test]
+  fn test_macros() {
+    let mut i = interpreter();
+    let code = "
+      macro twice(e : expr) => expr { #($e + $e) }
+      macro minus(a, b) { #($b - $a) }
+      macro forever(e) { #(forever($e)) }
+    ";
+    i.run_module(code, "macros").unwrap();
+    assert_result_with_interpreter(&mut i, "twice(21)", Val::I64(42));
+    assert_result_with_interpreter(&mut i, "twice(minus(1, 10))", Val::I64(18));
+    // errors in an expansion are reported at the call
+    let e = i.eval("\n\ntwice(true)").err().unwrap();
+    let errors = match &e.message { ErrorContent::InnerErrors(_, es) => es.clone(), _ => vec![e.clone()] };
+    assert!(errors.iter().all(|e| e.location.start.line == 3));
+    let e = i.eval("minus(1)").err().unwrap();
+    assert!(format!("{}", e.display()).contains("macro 'minus' takes 2 arguments"));
+    let e = i.eval("forever(1)").err().unwrap();
+    assert!(format!("{}", e.display()).contains("macro expansion went more than 64 deep"));
+  }
+
+  #[test]
+  fn test_macro_hygiene() {
+    let mut i = interpreter();
+    let code = "
+      macro double(e) { #({ let x = $e; x + x }) }
+      macro bind_x(e) { let x = unhygienic(\"x\"); #(let $x = $e) }
+      macro fresh(e) { let t = gensym(\"t\"); #({ let $t = $e; $t * 3 }) }
+    ";
+    i.run_module(code, "hygiene").unwrap();
+    // the macro's `x` doesn't capture the caller's `x`
+    assert_result_with_interpreter(&mut i, "let x = 5; double(x + 1)", Val::I64(12));
+    // but a macro can bind a name on purpose
+    assert_result_with_interpreter(&mut i, "bind_x(7); x", Val::I64(7));
+    assert_result_with_interpreter(&mut i, "let t = 2; fresh(t)", Val::I64(6));
+  }
+
+  #[test]
+  fn test_inline_functions() {
+    let mut i = interpreter();
+    let code = "
+      static factor = 3
+      inline fun scaled(x : i64) => i64 { x * factor }
+      inline fun first_positive(a : i64, b : i64) => i64 {
+        if a > 0 { return a }
+        b
+      }
+    ";
+    i.run_module(code, "inlined").unwrap();
+    let code = "
+      let factor = 10
+      let a = -1
+      scaled(2) + first_positive(a, 4) * 10 + first_positive(2, a) * 100
+    ";
+    // the caller's locals don't capture the names in the inlined bodies
+    assert_eq!(i.run_module(code, "inliner").unwrap(), Val::I64(246));
+    let ir = i.c.dump_ir(i.c.code_store.named_unit("inliner").unwrap()).unwrap();
+    assert!(!ir.contains("scaled") && !ir.contains("first_positive"));
+    // the prelude's inline functions
+    assert_result_with_interpreter(&mut i, "var t = 0; for x in range(0, 4) { t = t + x }; t + len([1, 2])", Val::I64(8));
+    assert_result_with_interpreter(&mut i, "max(2, 5) * 10 + min(2, 5)", Val::I64(52));
+    // a unit's own definition of the name is used instead
+    assert_result_with_interpreter(&mut i, "fun scaled(x : f64) => f64 { x }\n scaled(2.0) == 2.0 && scaled(2) == 6", Val::Bool(true));
+  }
+
+  #[test]
+  fn test_expr_library() {
+    let mut i = interpreter();
+    let cases = vec![
+      ("child_count(#(f(1, 2)))", Val::U64(3)),
+      ("symbol_name(child(#(f(1, 2)), 0)).unwrap() == \"f\"", Val::Bool(true)),
+      ("construct_name(#(f(1, 2))).unwrap() == \"call\"", Val::Bool(true)),
+      ("symbol_name(#5).is_some", Val::Bool(false)),
+      ("equal(mk_call(\"f\", [#1, #2]), #(f(1, 2)))", Val::Bool(true)),
+      ("equal(mk_let(\"x\", #3), #(let x = 3))", Val::Bool(true)),
+      ("equal(mk_construct(\"call\", [#g]), #(g(1)))", Val::Bool(false)),
+      ("to_pretty_string(#(f(1)), 80) == \"(call f 1)\"", Val::Bool(true)),
+      ("to_pretty_string(#(f(1)), 4) == \"(call\\n  f\\n  1)\"", Val::Bool(true)),
+    ];
+    for (code, expected_result) in cases {
+      assert_result_with_interpreter(&mut i, code, expected_result);
+    }
+  }
+
+  #[test]
+  fn test_stages() {
+    let mut i = interpreter();
+    let code = "
+      #[stage(compile)]
+      fun make_table() => expr {
+        var total = 0
+        for i in range(0, 10) { total = total + i * i }
+        #(fun sum_of_squares() => i64 { $total })
+      }
+      sum_of_squares()
+    ";
+    assert_result_with_interpreter(&mut i, code, Val::I64(285));
+    // stage functions can't see the rest of the module
+    let code = "
+      fun helper() => i64 { 3 }
+      #[stage(compile)]
+      fun broken() => expr { let x = helper(); #(x) }
+    ";
+    assert!(i.eval(code).is_err());
+    let e = i.eval("#[stage(compile)] fun f(a : i64) => expr { #(a) }").err().unwrap();
+    assert!(format!("{}", e.display()).contains("can't take arguments"));
+    assert!(i.eval("#[stage(run)] fun f() => expr { #(1) }").is_err());
+  }
+
+  #[test]
+  fn test_compile_metrics() {
+    let mut i = interpreter();
+    i.run_module("fun f(a : i64) => i64 { a * 2 }\nf(3)", "timed").unwrap();
+    let unit_id = i.c.code_store.named_unit("timed").unwrap();
+    let m = i.c.code_store.metrics.get(&unit_id).unwrap();
+    assert!(m.inference.constraints > 0);
+    assert!(m.inference.passes > 0);
+    assert!(m.total() >= m.codegen);
+    i.c.unload_module(unit_id);
+    assert!(i.c.code_store.metrics.get(&unit_id).is_none());
+  }
+
   #[test]
   fn test_literal_hardening_bug() {
     let code = "
@@ -617,11 +2480,9 @@ rusty_fork_test! {
     }
   }
 
-  /// Infers conflicting types because the last line is `return i` instead of `i`,
-  /// and the expression `return i` evaluates to void, which defines the type of the
-  /// block expression. The type checker doesn't understand that this the return
-  /// value of this block is never used, because the code always terminates with a
-  /// return command.
+  /// The last line is `return i` instead of `i`. This used to infer conflicting
+  /// types, because `return i` evaluated to void, which defined the type of the
+  /// block expression. Returns now have the never type, which unifies with anything.
   #[test]
   fn test_return_bug() {
     let code = "
@@ -632,10 +2493,29 @@ rusty_fork_test! {
       return i
     }
     ";
-    // TODO: fix this issue. At the moment it's being left alone, because
-    // the problem is easy to work around.
-    // assert_result(code, Val::Void);
-    let aaa = ();
+    assert_result(code, Val::Void);
+    let b = "
+    fun foo(i : i64) {
+      if i > 50 {
+        return 50
+      }
+      return i
+    }
+    foo(70) + foo(3)
+    ";
+    assert_result(b, Val::I64(53));
+  }
+
+  #[test]
+  fn test_diverging_branch() {
+    let code = "
+      fun double_positive(i : i64) => i64 {
+        let x = if i > 0 { i } else { return 0 }
+        x * 2
+      }
+      double_positive(3) + double_positive(-1)
+    ";
+    assert_result(code, Val::I64(6));
   }
 
   #[test]
@@ -663,15 +2543,173 @@ rusty_fork_test! {
     assert_error(b, "");
   }
 
+  #[test]
+  fn test_symbol_suggestions() {
+    let a = "
+      fun square(a : i64) => i64 { a * a }
+      sqare(3)
+    ";
+    assert_error(a, "Did you mean:\n      square : fun(i64) => i64 defined at");
+    let b = "
+      fun square(a : i64) => i64 { a * a }
+      square(true)
+    ";
+    assert_error(b, "square : fun(i64) => i64");
+  }
+
+  #[test]
+  fn test_conflicting_type_sources() {
+    let code = "
+      let a = true
+      let b = a
+      let c : i64 = b
+    ";
+    assert_error(code, "conflicting types inferred");
+    assert_error(code, "was inferred at (line: 2");
+    assert_error(code, "was inferred at (line: 4");
+  }
+
+  #[test]
+  fn test_if_number_literals() {
+    let code = "
+      fun pick(a : bool) { if a { 1 } else { 2.5 } }
+      let b = if pick(true) > 1.5 { 10 } else if false { 20 } else { 0.5 }
+      pick(true) + pick(false) + b
+    ";
+    assert_result(code, Val::F64(4.0));
+    assert_result("let x : f32 = if true { 3 } else { 0.5 }\nx", Val::F32(3.0));
+    let mismatch = "
+      let x = 3.0
+      if x > 1.0 { true } else { 2.0 }
+    ";
+    assert_error(mismatch, "the branches of this 'if' have incompatible types, 'bool' and 'Float'");
+    assert_error(mismatch, "Float comes from (line: 3");
+  }
+
+  #[test]
+  fn test_literal_default_pragmas() {
+    let overloads = "
+      fun which(v : f32) => i64 { 32 }
+      fun which(v : f64) => i64 { 64 }
+      fun which(v : i32) => i64 { 32 }
+      fun which(v : i64) => i64 { 64 }
+    ";
+    let a = format!("{} which(1.5) + which(2)", overloads);
+    assert_result(&a, Val::I64(128));
+    let b = format!("pragma default_float(f32) {} which(1.5) + which(2)", overloads);
+    assert_result(&b, Val::I64(96));
+    let c = format!("pragma default_int(i32) {} which(1.5) + which(2)", overloads);
+    assert_result(&c, Val::I64(96));
+    assert_error("pragma default_int(f32)", "expects a single int type");
+  }
+
+  #[test]
+  fn test_minimal_core_fallback() {
+    let mut i = interpreter_with_core_path("not/a/real/path/");
+    assert!(i.core_error.is_some());
+    assert_result_with_interpreter(&mut i, "println(3 + 4); 7", Val::I64(7));
+    // the full prelude isn't there
+    assert!(i.eval("some(5)").is_err());
+  }
+
+  #[test]
+  fn test_prelude_layers() {
+    let dir = std::env::temp_dir().join("cauldron_prelude_test");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("extra.code"), "fun doubled(a : i64) => i64 { a * 2 }").unwrap();
+    std::fs::write(dir.join("more.code"), "fun quadrupled(a : i64) => i64 { doubled(doubled(a)) }").unwrap();
+    std::fs::write(dir.join("prelude.list"), "// no core modules\nextra.code\n\nmore.code\n").unwrap();
+    let prelude = Prelude::for_program(&dir.join("main.code").to_string_lossy()).unwrap();
+    assert_eq!(prelude.modules.len(), 2);
+    let mut i = interpreter_with_prelude(prelude);
+    assert!(i.core_error.is_none());
+    assert_result_with_interpreter(&mut i, "quadrupled(3)", Val::I64(12));
+    assert!(i.eval("some(5)").is_err());
+    // layered on top of the core modules
+    let prelude = Prelude::core("../code/").with_module(&dir.join("extra.code").to_string_lossy());
+    let mut i = interpreter_with_prelude(prelude);
+    assert_result_with_interpreter(&mut i, "doubled(some(4).unwrap())", Val::I64(8));
+    let mut i = interpreter_with_prelude(Prelude::empty());
+    assert_result_with_interpreter(&mut i, "3 + 4", Val::I64(7));
+  }
+
+  #[test]
+  fn test_project_manifest() {
+    let manifest = r#"
+      # a comment
+      entry = "main.code"   # another comment
+      sources = ["lib", "more/"]
+      libraries = ["foo"]
+      prelude = ["core", "extra.code"]
+
+      [options]
+      optimise = true
+      frame_budget_ms = 50
+
+      [format]
+      width = 80
+    "#;
+    let p = Project::parse(manifest, "proj").unwrap();
+    assert_eq!(p.entry, "proj/main.code");
+    assert_eq!(p.sources, vec!["proj/lib".to_string(), "proj/more/".to_string()]);
+    assert_eq!(p.libraries, vec!["foo".to_string()]);
+    assert_eq!(p.prelude.modules.last().unwrap(), "proj/extra.code");
+    assert_eq!(p.options, CompileOptions { optimise: true, ..CompileOptions::debug() });
+    assert_eq!(p.frame_budget, Some(Duration::from_millis(50)));
+    assert_eq!(p.format, FormatOptions { width: 80, ..FormatOptions::default() });
+    assert!(!p.format_on_save);
+    let e = Project::parse("sources = [\"a\"]", "").unwrap_err();
+    assert!(e.contains("doesn't have an entry"));
+    let e = Project::parse("entry = \"a.code\"\n[options]\noptimise = \"yes\"", "").unwrap_err();
+    assert!(e.contains("line 3: 'options.optimise' is not a manifest key that takes a string"));
+    let tetris = Project::load("../code/tetris").unwrap();
+    assert_eq!(tetris.entry, "../code/tetris/loader.code");
+  }
+
+  #[test]
+  fn test_project_globs() {
+    let p = Project::parse("entry = \"main.code\"\nassets = [\"assets/**/*.png\"]", "proj").unwrap();
+    assert_eq!(p.assets, vec!["proj/assets/**/*.png".to_string()]);
+    assert_eq!(split_glob(&p.assets[0]), ("proj/assets".to_string(), "**/*.png".to_string()));
+    assert_eq!(split_glob("lib"), ("lib".to_string(), "".to_string()));
+    assert_eq!(split_glob("*.code"), (".".to_string(), "*.code".to_string()));
+    assert!(glob_match("**/*.png", "a.png"));
+    assert!(glob_match("**/*.png", "sprites/big/a.png"));
+    assert!(!glob_match("**/*.png", "sprites/a.wav"));
+    assert!(glob_match("level_?.txt", "level_1.txt"));
+    assert!(!glob_match("level_?.txt", "level_10.txt"));
+    assert!(!glob_match("*.code", "lib/a.code"));
+    let patterns = vec!["proj/assets/*.png".to_string(), "proj/lib".to_string()];
+    assert!(matches_any(&patterns, Path::new("proj/assets/a.png")));
+    assert!(!matches_any(&patterns, Path::new("proj/assets/a.wav")));
+    assert!(!matches_any(&patterns, Path::new("proj/assets/sub/a.png")));
+    assert!(matches_any(&patterns, Path::new("proj/lib/sub/a.code")));
+    assert!(!matches_any(&patterns, Path::new("other/a.png")));
+  }
+
   #[test]
   fn test_duplicate_symbol_error() {
     let code = "
       static BLAH_BLAH : i64 = 5
       static BLAH_BLAH = 10.0
     ";
-    assert_error(code, "");
-    // TODO: the error message here is terrible, and the problem isn't spotted until codegen
-    let aaa = ();
+    assert_error(code, "'BLAH_BLAH' is already defined at (line: 2");
+    let functions = "
+      fun foo(a : i64) => i64 { a }
+      fun foo(b : i64) => i64 { b + 1 }
+    ";
+    assert_error(functions, "'foo' is already defined at (line: 2");
+    // overloads with different types are fine
+    let overloads = "
+      fun foo(a : i64) => i64 { a }
+      fun foo(a : f64) => i64 { 2 }
+      foo(1) + foo(1.0)
+    ";
+    assert_result(overloads, Val::I64(3));
+    let mut i = interpreter();
+    i.eval("fun bar(a : i64) => i64 { a }").unwrap();
+    let e = i.eval("fun bar(b : i64) => i64 { b }").unwrap_err();
+    assert!(format!("{}", e.display()).contains("already defined by an imported module"));
   }
 
   // #[test]
@@ -0,0 +1,113 @@
+// Renders a unit's type constraints as a graphviz DOT graph, for debugging inference.
+//
+// Type slots are the nodes, labelled with the type they resolved to (or '?' if they
+// didn't resolve) and their location. Constraints between two slots are drawn as
+// edges. The rest involve a symbol or more than two slots, so they are drawn as
+// boxes with an edge to each slot they use.
+
+use crate::types::{constraints, slots};
+
+use constraints::{Constraint, ConstraintContent, Constraints, TypeSlot};
+use slots::Slots;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+fn escape(s : &str) -> String {
+  s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+struct DotWriter<'l> {
+  c : &'l Constraints,
+  slots : &'l Slots<'l>,
+  slot_ids : HashMap<TypeSlot, usize>,
+  boxes : usize,
+  out : String,
+}
+
+impl <'l> DotWriter<'l> {
+  /// Slots are numbered in the order they are first used, so the output is stable
+  fn slot(&mut self, slot : TypeSlot) -> String {
+    if let Some(id) = self.slot_ids.get(&slot) {
+      return format!("s{}", id);
+    }
+    let id = self.slot_ids.len();
+    self.slot_ids.insert(slot, id);
+    let loc = self.c.loc(slot);
+    let (t, colour) = match self.slots.get(slot) {
+      Some(t) if t.is_concrete() => (format!("{}", t), "black"),
+      Some(t) => (format!("{}", t), "orange"),
+      None => ("?".into(), "red"),
+    };
+    writeln!(self.out, "  s{} [label=\"{}\\n{}:{}\", color={}];",
+      id, escape(&t), loc.start.line, loc.start.col, colour).unwrap();
+    format!("s{}", id)
+  }
+
+  fn edge(&mut self, a : TypeSlot, b : TypeSlot, label : &str) {
+    let (a, b) = (self.slot(a), self.slot(b));
+    writeln!(self.out, "  {} -> {} [label=\"{}\"];", a, b, escape(label)).unwrap();
+  }
+
+  fn constraint_box(&mut self, c : &Constraint, edges : &[(TypeSlot, String)]) {
+    let name = format!("c{}", self.boxes);
+    self.boxes += 1;
+    writeln!(self.out, "  {} [shape=box, label=\"{}\"];", name, escape(&format!("{}", c))).unwrap();
+    for (slot, label) in edges {
+      let s = self.slot(*slot);
+      writeln!(self.out, "  {} -> {} [label=\"{}\"];", name, s, escape(label)).unwrap();
+    }
+  }
+
+  fn constraint(&mut self, c : &Constraint) {
+    use ConstraintContent::*;
+    match &c.content {
+      Equalivalent(a, b) => self.edge(*a, *b, "="),
+      TypeParameter{ parent, parameter } => self.edge(*parent, *parameter, "type parameter"),
+      Convert{ val, into_type_slot } => self.edge(*val, *into_type_slot, "as"),
+      Branch{ output, cases, .. } => {
+        let mut edges = vec![(*output, "output".to_string())];
+        edges.extend(cases.iter().enumerate().map(|(i, s)| (*s, format!("case {}", i))));
+        self.constraint_box(c, &edges);
+      }
+      SizeOf{ slot, .. } => self.constraint_box(c, &[(*slot, "type".into())]),
+      FieldAccess{ container, result, .. } =>
+        self.constraint_box(c, &[(*container, "container".into()), (*result, "result".into())]),
+      Constructor{ def_slot, fields } => {
+        let mut edges = vec![(*def_slot, "type".to_string())];
+        edges.extend(fields.iter().enumerate().map(|(i, (name, s))| {
+          let label = name.as_ref().map(|n| n.name.to_string()).unwrap_or_else(|| format!("field {}", i));
+          (*s, label)
+        }));
+        self.constraint_box(c, &edges);
+      }
+      Function{ function, args, return_type, receiver, closure_args } => {
+        let mut edges = vec![(*function, "function".to_string())];
+        edges.extend(args.iter().enumerate().map(|(i, s)| (*s, format!("arg {}", i))));
+        edges.push((*return_type, "return".into()));
+        edges.extend(receiver.iter().map(|(_, s)| (*s, "receiver".to_string())));
+        edges.extend(closure_args.iter().map(|a| (a.value, format!("value {}", a.index))));
+        self.constraint_box(c, &edges);
+      }
+      SymbolDef{ slot, .. } => self.constraint_box(c, &[(*slot, "definition".into())]),
+      SymbolReference{ result, .. } => self.constraint_box(c, &[(*result, "reference".into())]),
+      ShortCircuit{ function, left, right, result, .. } => {
+        let edges = [
+          (*function, "overload".to_string()), (*left, "left".to_string()),
+          (*right, "right".to_string()), (*result, "result".to_string()),
+        ];
+        self.constraint_box(c, &edges);
+      }
+    }
+  }
+}
+
+pub fn constraints_to_dot(c : &Constraints, slots : &Slots) -> String {
+  let mut w = DotWriter { c, slots, slot_ids: HashMap::new(), boxes: 0, out: String::new() };
+  w.out.push_str("digraph constraints {\n");
+  for constraint in c.constraints.iter() {
+    w.constraint(constraint);
+  }
+  w.out.push_str("}\n");
+  w.out
+}
@@ -7,7 +7,7 @@ use error::{Error, error, error_raw, TextLocation};
 use expr::{Expr, ExprContent};
 use structure::{
   Node, NodeId, ReferenceId, Content, PrimitiveVal, LabelId,
-  VarScope, GlobalType, Reference, Nodes,
+  VarScope, GlobalType, Reference, Nodes, CallingConvention,
 };
 use crate::types::types::{
   Type, PType, TypeDefinition, FunctionInit, SymbolDefinition,
@@ -16,9 +16,10 @@ use crate::types::types::{
   ResolvedSymbol, TypeInfo,
 };
 use crate::types::type_errors::TypeErrors;
+use crate::intrinsics::{MAX_TUPLE_LENGTH, tuple_type_name};
 use compiler::DEBUG_PRINTING_TYPE_INFERENCE as DEBUG;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, BTreeMap};
 
 // A position in the program which requires a type
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
@@ -39,7 +40,8 @@ pub struct Constraint {
 
 pub enum ConstraintContent {
   Equalivalent(TypeSlot, TypeSlot),
-  Branch { output : TypeSlot, cases : Vec<TypeSlot> },
+  /// `keyword` names the construct that branches, for errors
+  Branch { output : TypeSlot, cases : Vec<TypeSlot>, keyword : &'static str },
   TypeParameter{ parent : TypeSlot, parameter : TypeSlot },
   Convert{ val : TypeSlot, into_type_slot : TypeSlot },
   SizeOf{ node : NodeId, slot : TypeSlot },
@@ -56,6 +58,12 @@ pub enum ConstraintContent {
     function : TypeSlot,
     args : Vec<TypeSlot>,
     return_type : TypeSlot,
+    /// For `x.f(...)`, the call and the slot of `x`. The first argument slot is
+    /// then separate from `x`, so that `x` can be referenced or dereferenced to fit it.
+    receiver : Option<(NodeId, TypeSlot)>,
+    /// For calls that might be to a C function, the arguments whose slots are
+    /// separate from their values, so that closures can be thinned to fit them
+    closure_args : Vec<ClosureArg>,
   },
   SymbolDef {
     symbol_id: SymbolId,
@@ -66,6 +74,16 @@ pub enum ConstraintContent {
     name : RefStr,
     result : TypeSlot,
   },
+  /// `&&` or `||`, which is built in for bools and options, and otherwise calls the
+  /// overload of the operator that takes the operands
+  ShortCircuit {
+    node : NodeId,
+    name : RefStr,
+    function : TypeSlot,
+    left : TypeSlot,
+    right : TypeSlot,
+    result : TypeSlot,
+  },
 }
 
 impl  fmt::Display for Constraint {
@@ -83,14 +101,29 @@ impl  fmt::Display for Constraint {
       SymbolDef { .. } => write!(f, "SymbolDef"),
       SymbolReference { name, .. } => write!(f, "SymbolRef {}", name),
       SizeOf{ .. } => write!(f, "SizeOf"),
+      ShortCircuit { name, .. } => write!(f, "ShortCircuit {}", name),
     }
   }
 }
 
+/// An argument of a call to a function with the same name as a `cbind`. If the
+/// call turns out to be to the `cbind`, a closure passed as the argument is passed
+/// as its bare function.
+pub struct ClosureArg {
+  /// The node of the function being called
+  pub callee : NodeId,
+  pub arg : NodeId,
+  pub index : usize,
+  /// The slot of the argument's value
+  pub value : TypeSlot,
+}
+
 pub struct Constraints {
   pub slots : HashMap<TypeSlot, TextLocation>,
-  pub node_slots : HashMap<NodeId, TypeSlot>,
+  pub node_slots : BTreeMap<NodeId, TypeSlot>,
   pub literals : Vec<NodeId>,
+  /// Integer literals that an `if` or `switch` returns from one of its branches
+  pub branch_literals : HashSet<NodeId>,
   pub variable_slots : HashMap<ReferenceId, TypeSlot>,
   pub constraints : Vec<Constraint>,
   pub assertions : Vec<Assertion>,
@@ -100,8 +133,9 @@ impl Constraints {
   pub fn new() -> Self {
     Constraints {
       slots: HashMap::new(),
-      node_slots: HashMap::new(),
+      node_slots: BTreeMap::new(),
       literals: vec![],
+      branch_literals: HashSet::new(),
       variable_slots: HashMap::new(),
       constraints: vec![],
       assertions: vec![],
@@ -181,6 +215,18 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
     }
   }
 
+  /// True if the function is called by a name that a visible `cbind` has
+  fn may_call_cbind(&self, n : &Nodes, function : NodeId) -> bool {
+    match &n.node(function).content {
+      Content::Reference{ name, refers_to: None } =>
+        self.t.visible_symbols().any(|def| match def.initialiser {
+          SymbolInit::CBind => def.name == *name && def.type_tag.sig().is_some(),
+          _ => false,
+        }),
+      _ => false,
+    }
+  }
+
   fn log_error<V>(&mut self, r : Result<V, Error>) -> Option<V> {
     match r {
       Ok(v) => Some(v),
@@ -239,6 +285,22 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
     }
   }
 
+  /// Lets an integer literal that a branch of an `if` or `switch` returns become a
+  /// float, if another branch returns a float
+  fn mark_branch_literal(&mut self, n : &Nodes, branch : NodeId) {
+    match &n.node(branch).content {
+      Content::Block(ns) => {
+        if let Some(last) = ns.last() {
+          self.mark_branch_literal(n, *last);
+        }
+      }
+      Content::Literal(PrimitiveVal::Int(_)) => {
+        self.c.branch_literals.insert(branch);
+      }
+      _ => (),
+    }
+  }
+
   fn create_symbol_id(&mut self, node_id : NodeId) -> SymbolId {
     let symbol_id = self.t.new_unit_id.new_symbol_id(self.gen);
     self.mapping.symbol_def_nodes.insert(symbol_id, node_id);
@@ -259,7 +321,7 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
     let node = n.node(id);
     // Assert type of the symbol
     let symbol_slot = self.new_slot(node.loc);
-    self.assert_type(symbol_slot, function_type);
+    self.assert_type(symbol_slot, function_type.clone());
     // Process the body
     let is_polymorphic_def = type_vars.len() > 0;
     if !is_polymorphic_def {
@@ -277,11 +339,13 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
         function: symbol_slot,
         args,
         return_type: body_slot,
+        receiver: None,
+        closure_args: vec![],
       });
     }
     // Register the symbol definition
     let symbol_id = self.create_symbol_id(id);
-    self.t.create_symbol({
+    let r = self.t.create_symbol({
       let name_for_codegen =
       self.cache.get(format!("{}.{}", name, self.gen.next()).as_str());
       let f = FunctionInit {
@@ -296,8 +360,13 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
         type_tag: Type::any(),
         initialiser: SymbolInit::Function(f),
         type_vars: type_vars.iter().cloned().collect(),
+        loc: node.loc,
+        doc: n.docs.get(&id).cloned(),
       }
-    });
+    }, Some(function_type));
+    if let Err(e) = r {
+      self.errors.push(e);
+    }
     // Bind the symbol definition to its type symbol
     self.constraint(SymbolDef {
       symbol_id,
@@ -345,6 +414,9 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
           Float(_) => {
             AbstractType::Float.into()
           }
+          Int(_) if self.c.branch_literals.contains(&id) => {
+            AbstractType::Number.into()
+          }
           Int(_) => {
             AbstractType::Integer.into()
           }
@@ -363,25 +435,38 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
           VarScope::Local => self.variable_to_slot(name),
           VarScope::Global(_) => self.new_slot(name.loc),
         };
-        if let Some(t) = type_tag {
-          self.tag_slot(var_slot, t);
+        let declared_type = type_tag.as_ref().and_then(|t| self.expr_to_type(t));
+        if let Some(t) = &declared_type {
+          self.assert_type(var_slot, t.clone());
         }
         let vid = self.process_node(n, *value);
         self.equalivalent(var_slot, vid);
+        // `let (a, b) = v` needs `v` to be a tuple of the right length
+        if let Some(&length) = n.destructured_tuples.get(&id) {
+          let mut t = Type::unresolved_def(self.cache.get(tuple_type_name(length)));
+          t.children = (0..length).map(|_| Type::any()).collect();
+          self.assert_type(vid, t);
+        }
         if let VarScope::Global(global_type) = *var_scope {
           let initialiser = match global_type {
             GlobalType::CBind => SymbolInit::CBind,
             GlobalType::Normal => SymbolInit::Expression(*value),
+            GlobalType::Lazy => SymbolInit::Lazy(*value),
           };
           let symbol_id = self.create_symbol_id(id);
-          self.t.create_symbol(SymbolDefinition {
+          let r = self.t.create_symbol(SymbolDefinition {
             id: symbol_id,
             unit_id: self.t.new_unit_id,
             name: name.name.clone(),
             type_tag: Type::any(),
             initialiser,
             type_vars: vec![],
-          });
+            loc: name.loc,
+            doc: n.docs.get(&id).cloned(),
+          }, declared_type);
+          if let Err(e) = r {
+            self.errors.push(e);
+          }
           self.constraint(SymbolDef{
             symbol_id,
             slot: var_slot,
@@ -401,11 +486,44 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
         self.process_node(n, *then_branch);
       }
       Content::IfThenElse{ condition, then_branch, else_branch } => {
+        self.mark_branch_literal(n, *then_branch);
+        self.mark_branch_literal(n, *else_branch);
         let cond = self.process_node(n, *condition);
         let then_br = self.process_node(n, *then_branch);
         let else_br = self.process_node(n, *else_branch);
         self.assert(cond, PType::Bool);
-        self.constraint(Branch { output: slot, cases: vec![then_br, else_br]});
+        self.constraint(Branch { output: slot, cases: vec![then_br, else_br], keyword: "if" });
+      }
+      Content::Switch{ value, cases, default, .. } => {
+        let v = self.process_node(n, *value);
+        self.assert_type(v, AbstractType::Integer.into());
+        if let Some(d) = default {
+          for (_, body) in cases.iter() {
+            self.mark_branch_literal(n, *body);
+          }
+          self.mark_branch_literal(n, *d);
+        }
+        let mut branches : Vec<TypeSlot> = cases.iter().map(|(_, body)| self.process_node(n, *body)).collect();
+        match default {
+          Some(d) => {
+            branches.push(self.process_node(n, *d));
+            self.constraint(Branch { output: slot, cases: branches, keyword: "switch" });
+          }
+          // without an `else`, it might not produce a value
+          None => self.assert(slot, PType::Void),
+        }
+      }
+      Content::ShortCircuit{ op, left, right, function } => {
+        let left = self.process_node(n, *left);
+        let right = self.process_node(n, *right);
+        let function_slot = self.node_to_slot(n.node(*function));
+        self.constraint(ShortCircuit {
+          node: id,
+          name: self.cache.get(op.symbol()),
+          function: function_slot,
+          left, right,
+          result: slot,
+        });
       }
       Content::Block(ns) => {
         let len = ns.len();
@@ -480,31 +598,47 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
             n, id, sig, polytypes.as_slice(), arg_names, *body, name);
         });
       }
-      Content::CBind { name, type_tag } => {
+      Content::CBind { name, type_tag, convention } => {
         self.assert(slot, PType::Void);
         let cbind_slot = self.new_slot(node.loc);
-        if let Some(t) = self.expr_to_type(type_tag) {
-          self.assert_type(cbind_slot, t);
+        let mut declared_type = self.expr_to_type(type_tag);
+        if *convention != CallingConvention::C {
+          match &mut declared_type {
+            Some(Type { content: TypeContent::Fun(c), .. }) => *c = *convention,
+            Some(_) => {
+              let e = error_raw(node.loc, "calling conventions can only be given to functions");
+              self.errors.push(e);
+            }
+            None => (),
+          }
+        }
+        if let Some(t) = &declared_type {
+          self.assert_type(cbind_slot, t.clone());
         }
         let symbol_id = self.create_symbol_id(id);
         self.constraint(SymbolDef {
           symbol_id,
           slot: cbind_slot,
         });
-        self.t.create_symbol(SymbolDefinition {
+        let r = self.t.create_symbol(SymbolDefinition {
           id: symbol_id,
           unit_id: self.t.new_unit_id,
           name: name.clone(),
           initialiser: SymbolInit::CBind,
           type_tag: Type::any(),
           type_vars: vec![],
-        });
+          loc: node.loc,
+          doc: n.docs.get(&id).cloned(),
+        }, declared_type);
+        if let Err(e) = r {
+          self.errors.push(e);
+        }
       }
       Content::TypeAlias { alias, type_aliased } => {
         // TODO: not yet implemented
         self.assert(slot, PType::Void);
       }
-      Content::TypeDefinition{ name, kind, fields, type_vars } => {
+      Content::TypeDefinition{ name, kind, fields, type_vars, layout, bitfields } => {
         self.assert(slot, PType::Void);
         if self.t.find_type_def(name.as_ref()).is_some() {
           let e = error_raw(node.loc, "type with this name already defined");
@@ -529,6 +663,10 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
               fields: fields.iter().map(|(f, _)| (f.clone(), Type::any())).collect(),
               kind: *kind,
               type_vars,
+              layout: *layout,
+              bitfields: bitfields.clone(),
+              loc: node.loc,
+              doc: n.docs.get(&id).cloned(),
             };
             gc.mapping.type_def_nodes.insert(name.clone(), id);
             gc.t.create_type_def(def);
@@ -567,21 +705,43 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
         self.constraint(TypeParameter{ parent: slot, parameter: element_slot });
       }
       Content::FunctionCall{ function, args } => {
-        let function = self.process_node(n, *function);
+        let function_slot = self.process_node(n, *function);
+        let mut arg_slots : Vec<TypeSlot> = args.iter().map(|id| self.process_node(n, *id)).collect();
+        let receiver = if n.method_calls.contains(&id) {
+          let receiver = arg_slots[0];
+          arg_slots[0] = self.new_slot(n.node(args[0]).loc);
+          Some((id, receiver))
+        }
+        else {
+          None
+        };
+        let mut closure_args = vec![];
+        if self.may_call_cbind(n, *function) {
+          let first = if receiver.is_some() { 1 } else { 0 };
+          for i in first..args.len() {
+            let value = arg_slots[i];
+            arg_slots[i] = self.new_slot(n.node(args[i]).loc);
+            closure_args.push(ClosureArg { callee: *function, arg: args[i], index: i, value });
+          }
+        }
         let fc = Function {
-          function,
-          args: args.iter().map(|id| self.process_node(n, *id)).collect(),
+          function: function_slot,
+          args: arg_slots,
           return_type: slot,
+          receiver,
+          closure_args,
         };
         let mut sig = SignatureBuilder::new(Type::any());
         for _ in args {
           sig.append_arg(Type::any());
         }
-        self.assert_type(function, sig.into());
+        self.assert_type(function_slot, sig.into());
         self.constraint(fc);
       }
       Content::While{ condition, body } => {
-        self.assert(slot, PType::Void);
+        // a `loop` only ends through its breaks, which give the type of its label
+        let t = if n.endless_loops.contains(&id) { PType::Never } else { PType::Void };
+        self.assert(slot, t);
         let cond = self.process_node(n, *condition);
         self.process_node(n, *body);
         self.assert(cond, PType::Bool);
@@ -592,7 +752,7 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
         let c = Convert { val: v, into_type_slot: slot };
         self.constraint(c);
       }
-      Content::SizeOf{ type_tag } => {
+      Content::SizeOf{ type_tag, .. } => {
         let size_slot = self.new_slot(type_tag.loc);
         self.tag_slot(size_slot, type_tag);
         self.constraint(SizeOf{
@@ -607,7 +767,7 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
         self.equalivalent(slot, body);
       }
       Content::BreakToLabel{ label, return_value } => {
-        self.assert(slot, PType::Void);
+        self.assert(slot, PType::Never);
         let label_slot = *self.labels.get(label).unwrap();
         if let Some(v) = return_value {
           let v = self.process_node(n, *v);
@@ -687,6 +847,13 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
             return Ok(sig.into());
           }
         }
+        Some(("tuple", exprs)) if (2..=MAX_TUPLE_LENGTH).contains(&exprs.len()) => {
+          let mut t = Type::unresolved_def(gc.cache.get(tuple_type_name(exprs.len())));
+          for e in exprs {
+            t.children.push(expr_to_type_internal(gc, e)?);
+          }
+          return Ok(t);
+        }
         Some(("call", exprs)) => {
           let name = &exprs[0];
           match name.unwrap_symbol()? {
@@ -719,20 +886,24 @@ impl <'l, 't> ConstraintGenerator<'l, 't> {
 pub struct TypeDirectory<'a> {
   pub imports : Vec<UnitId>,
   pub new_unit_id : UnitId,
-  pub types : &'a mut HashMap<UnitId, TypeInfo>,
+  pub types : &'a mut BTreeMap<UnitId, TypeInfo>,
   polytype_bindings : HashMap<RefStr, Type>,
   symbol_results : Vec<ResolvedSymbol>,
+  /// The types written in the definitions of the new unit's symbols, which are
+  /// known before inference runs
+  declared_types : HashMap<SymbolId, Type>,
 }
 
 // TODO: A lot of these functions are slow because they iterate through everything.
 // This could probably be improved with some caching, although any caching needs to
 // be wary of new symbols being added.
 impl <'a> TypeDirectory<'a> {
-  pub fn new(imports : Vec<UnitId>, new_unit_id : UnitId, types : &'a mut HashMap<UnitId, TypeInfo>) -> Self {
+  pub fn new(imports : Vec<UnitId>, new_unit_id : UnitId, types : &'a mut BTreeMap<UnitId, TypeInfo>) -> Self {
     TypeDirectory {
       imports, new_unit_id, types,
       polytype_bindings: HashMap::new(),
       symbol_results: vec![],
+      declared_types: HashMap::new(),
     }
   }
 
@@ -760,9 +931,57 @@ impl <'a> TypeDirectory<'a> {
       .type_defs.insert(def.name.clone(), def);
   }
 
-  pub fn create_symbol(&mut self, def : SymbolDefinition) {
+  /// Adds a symbol to the new unit. `declared_type` is the type written in the
+  /// definition, if there is one. Returns an error if the symbol can't be told
+  /// apart from one that is already visible, though the symbol is still added.
+  pub fn create_symbol(&mut self, def : SymbolDefinition, declared_type : Option<Type>)
+    -> Result<(), Error>
+  {
+    let duplicate = self.find_duplicate(&def, declared_type.as_ref()).map(|d| (d.loc, d.unit_id));
+    let name = def.name.clone();
+    let loc = def.loc;
+    if let Some(t) = declared_type {
+      self.declared_types.insert(def.id, t);
+    }
     self.types.get_mut(&self.new_unit_id).unwrap()
       .symbols.insert(def.id, def);
+    if let Some((existing_loc, unit_id)) = duplicate {
+      let s = if unit_id == self.new_unit_id {
+        format!("'{}' is already defined at {}", name, existing_loc)
+      }
+      else {
+        format!("'{}' is already defined by an imported module, at {}", name, existing_loc)
+      };
+      return error(loc, s);
+    }
+    Ok(())
+  }
+
+  /// Functions can share a name if their types are different. Other globals
+  /// can't share a name within a unit, but can shadow globals of a different
+  /// type from other units.
+  fn find_duplicate(&self, def : &SymbolDefinition, declared_type : Option<&Type>)
+    -> Option<&SymbolDefinition>
+  {
+    fn overloadable(def : &SymbolDefinition) -> bool {
+      match def.initialiser {
+        SymbolInit::Function(_) | SymbolInit::Intrinsic => true,
+        SymbolInit::Expression(_) | SymbolInit::Lazy(_) | SymbolInit::CBind => false,
+      }
+    }
+    self.visible_symbols().find(|existing| {
+      if existing.name != def.name || overloadable(existing) != overloadable(def) {
+        return false;
+      }
+      let same_unit = existing.unit_id == self.new_unit_id;
+      let existing_type =
+        if same_unit { self.declared_types.get(&existing.id) }
+        else { Some(&existing.type_tag) };
+      match (declared_type, existing_type) {
+        (Some(a), Some(b)) if a == b => true,
+        _ => same_unit && !overloadable(def),
+      }
+    })
   }
 
   /// Returns a slice of all matching definitions
@@ -784,6 +1003,13 @@ impl <'a> TypeDirectory<'a> {
     self.symbol_results.as_slice()
   }
 
+  /// Every symbol visible from the new unit
+  pub fn visible_symbols(&self) -> impl Iterator<Item=&SymbolDefinition> {
+    let types : &BTreeMap<UnitId, TypeInfo> = &*self.types;
+    std::iter::once(&self.new_unit_id).chain(self.imports.iter())
+      .flat_map(move |uid| types.get(uid).unwrap().symbols.values())
+  }
+
   pub fn find_type_def(&self, name : &str) -> Option<&TypeDefinition> {
     self.types.get(&self.new_unit_id).unwrap()
       .find_type_def(name).or_else(||
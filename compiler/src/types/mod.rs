@@ -6,9 +6,12 @@ mod slots;
 mod type_graph;
 mod type_errors;
 mod references;
+mod constraint_dot;
 
 pub use types::*;
 pub use solver::{
   typecheck_module,
-  typecheck_polymorphic_function_instance
-};
\ No newline at end of file
+  typecheck_polymorphic_function_instance,
+  constraint_graph,
+};
+pub(crate) use solver::internal_error;
\ No newline at end of file
@@ -43,13 +43,15 @@ fn reference_type(n : Node) -> ReferenceType {
     Block(_node) => {
       panic!()
     }
+    ShortCircuit{ .. } => Val,
+    Switch{ .. } => Val,
     Quote(_expr) => Val,
     Reference { name:_, refers_to:_ } => Ref,
     FunctionDefinition{ name:_, args:_, return_tag:_, type_vars:_, body:_ } => {
       panic!()
     }
-    CBind { name:_, type_tag:_ } => Val,
-    TypeDefinition{ name:_, kind:_, fields:_, type_vars:_ } => Val,
+    CBind { .. } => Val,
+    TypeDefinition{ .. } => Val,
     TypeConstructor{ name:_, field_values:_ } => Val,
     FieldAccess{ container:_, field:_ } => Ref,
     ArrayLiteral(_elements) => Val,
@@ -60,7 +62,7 @@ fn reference_type(n : Node) -> ReferenceType {
     }
     While{ condition:_, body:_ } => Val,
     Convert{ from_value:_, into_type:_ } => Val,
-    SizeOf{ type_tag:_ } => Val,
+    SizeOf{ type_tag:_, query:_ } => Val,
     Label{ label:_, body:_ } => {
       panic!()
     }
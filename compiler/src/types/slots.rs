@@ -3,7 +3,7 @@ use crate::{common, error};
 use crate::types::{types, constraints, type_graph, type_errors};
 
 use common::*;
-use error::error_raw;
+use error::{error_raw, TextLocation};
 
 use types::{Type, incremental_unify, UnifyResult};
 use constraints::{Constraint, Constraints, TypeSlot};
@@ -16,6 +16,9 @@ pub struct Slots<'a> {
   c : &'a Constraints,
   types : HashMap<TypeSlot, Type>,
   any : Type,
+  /// If a slot's type was last changed through an equivalence, this is the slot it
+  /// came from. Used to explain conflicting types.
+  sources : HashMap<TypeSlot, TypeSlot>,
 }
   
 impl <'a> Slots<'a> {
@@ -25,6 +28,7 @@ impl <'a> Slots<'a> {
       c,
       types : Default::default(),
       any: Type::any(),
+      sources : Default::default(),
     }
   }
 
@@ -44,6 +48,20 @@ impl <'a> Slots<'a> {
     t : &Type
   )
     -> UnifyResult
+  {
+    self.update_type_from(g, errors, slot, t, None)
+  }
+
+  /// Like `update_type`, but records that `t` was copied from another slot
+  pub fn update_type_from(
+    &mut self,
+    g : &mut TypeGraph,
+    errors : &mut TypeErrors,
+    slot : TypeSlot,
+    t : &Type,
+    from : Option<TypeSlot>,
+  )
+    -> UnifyResult
   {
     let slot_type = if let Some(t) = self.types.get_mut(&slot) {
       t
@@ -53,15 +71,61 @@ impl <'a> Slots<'a> {
     };
     let r = incremental_unify(t, slot_type);
     if !r.unify_success {
-      let s = format!("conflicting types inferred; {} and {}.", t, slot_type);
+      let slot_type = slot_type.clone();
+      let s = self.conflict_message(slot, &slot_type, t, from);
       errors.push(error_raw(self.c.loc(slot), s));
     }
     if r.mutable_type_changed {
+      if let Some(from) = from {
+        self.sources.insert(slot, from);
+      }
+      else {
+        self.sources.remove(&slot);
+      }
       g.type_updated(slot);
     }
     r
   }
 
+  /// Follows the equivalences that a slot's type came through, back to the slot
+  /// where it was first inferred. The result starts with `slot` itself.
+  fn source_chain(&self, slot : TypeSlot) -> Vec<TypeSlot> {
+    let mut chain = vec![slot];
+    let mut current = slot;
+    while let Some(&next) = self.sources.get(&current) {
+      if chain.contains(&next) { break }
+      chain.push(next);
+      current = next;
+    }
+    chain
+  }
+
+  fn conflict_message(&self, slot : TypeSlot, slot_type : &Type, t : &Type, from : Option<TypeSlot>) -> String {
+    let existing = self.source_chain(slot);
+    let incoming = match from {
+      Some(from) => self.source_chain(from),
+      None => vec![slot],
+    };
+    // the path from where `t` was inferred to where `slot_type` was inferred
+    let mut path : Vec<TextLocation> = vec![];
+    for s in incoming.iter().rev().chain(existing.iter()) {
+      let loc = self.c.loc(*s);
+      if path.last() != Some(&loc) {
+        path.push(loc);
+      }
+    }
+    let mut s = format!("conflicting types inferred; {} and {}.", t, slot_type);
+    s += &format!("\n   {} was inferred at {}", t, self.c.loc(*incoming.last().unwrap()));
+    s += &format!("\n   {} was inferred at {}", slot_type, self.c.loc(*existing.last().unwrap()));
+    if path.len() > 2 {
+      s += "\n   These are connected through:";
+      for loc in &path[1..path.len()-1] {
+        s += &format!("\n      {}", loc);
+      }
+    }
+    s
+  }
+
   /// returns true if the input type `t` was mutated
   pub fn update_type_mut(
     &mut self,
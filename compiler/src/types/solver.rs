@@ -8,22 +8,23 @@
 use itertools::Itertools;
 
 use crate::{common, error, structure, code_store, compiler};
-use crate::types::{types, constraints, slots, type_graph, type_errors};
+use crate::types::{types, constraints, slots, type_graph, type_errors, constraint_dot};
 
 use common::*;
 use error::{Error, error, error_raw, TextLocation, ErrorContent};
 use structure::{
-  NodeId, TypeKind, Nodes,
+  NodeId, TypeKind, Nodes, Content, LayoutQuery, VariantAccess,
 };
 
 use types::{
-  Type, PType, TypeContent, TypeInfo, SymbolId,
-  TypeMapping, AbstractType, SymbolInit,
+  Type, PType, TypeContent, TypeInfo, SymbolId, incremental_unify, unify_types,
+  TypeMapping, AbstractType, SymbolInit, LiteralDefaults, InferenceStats,
+  MethodReceiver, SignatureBuilder,
 };
 use constraints::{
   Constraint, ConstraintContent,
   Constraints, TypeSlot, Assertion,
-  TypeDirectory,
+  TypeDirectory, ClosureArg,
 };
 use slots::Slots;
 use type_graph::TypeGraph;
@@ -31,10 +32,16 @@ use type_errors::TypeErrors;
 use code_store::CodeStore;
 use compiler::DEBUG_PRINTING_TYPE_INFERENCE as DEBUG;
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use TypeContent::*;
 
+/// The prelude's type of function values that can carry a context
+const CLOSURE_TYPE_NAME : &str = "closure";
+
+/// The prelude's optional type, which `&&` and `||` work on
+const OPTION_TYPE_NAME : &str = "option";
+
 pub fn typecheck_module(
   unit_id : UnitId,
   code_store : &mut CodeStore,
@@ -47,15 +54,16 @@ pub fn typecheck_module(
   code_store.types.insert(unit_id, TypeInfo::new(unit_id));
   let mut mapping = TypeMapping::new();
   let mut errors = TypeErrors::new();
+  let nodes = code_store.nodes.get(&unit_id).unwrap();
+  let literal_defaults = LiteralDefaults::from_pragmas(&nodes.pragmas)?;
   let mut type_directory =
     TypeDirectory::new(imports, unit_id, &mut code_store.types);
-  let nodes = code_store.nodes.get(&unit_id).unwrap();
   let c =
     constraints::get_module_constraints(
       &nodes, &mut type_directory, &mut mapping, cache, gen, &mut errors);
   let i = Inference::new(
     &nodes, &mut type_directory,
-    &mut mapping, &c);
+    &mut mapping, &c, literal_defaults);
   i.infer(&mut errors);
   if !errors.is_empty() {    
     let c = ErrorContent::InnerErrors("type errors".into(), errors.concrete_errors);
@@ -65,6 +73,42 @@ pub fn typecheck_module(
   Ok(())
 }
 
+/// Typechecks a unit like `typecheck_module`, but returns its constraint graph in
+/// DOT format instead of keeping the results. The graph is returned even if the
+/// unit has type errors, because that's when it's needed. The errors are listed
+/// in comments at the end.
+pub fn constraint_graph(
+  unit_id : UnitId,
+  code_store : &mut CodeStore,
+  cache : &StringCache,
+  gen : &mut UIDGenerator,
+  imports : Vec<UnitId>,
+)
+  -> Result<String, Error>
+{
+  code_store.types.insert(unit_id, TypeInfo::new(unit_id));
+  let mut mapping = TypeMapping::new();
+  let mut errors = TypeErrors::new();
+  let nodes = code_store.nodes.get(&unit_id).unwrap();
+  let literal_defaults = LiteralDefaults::from_pragmas(&nodes.pragmas)?;
+  let mut type_directory =
+    TypeDirectory::new(imports, unit_id, &mut code_store.types);
+  let c =
+    constraints::get_module_constraints(
+      &nodes, &mut type_directory, &mut mapping, cache, gen, &mut errors);
+  let i = Inference::new(
+    &nodes, &mut type_directory,
+    &mut mapping, &c, literal_defaults);
+  let slots = i.infer(&mut errors);
+  let mut dot = constraint_dot::constraints_to_dot(&c, &slots);
+  for e in errors.concrete_errors.iter() {
+    for line in format!("{}", e.display()).lines() {
+      dot.push_str(&format!("// {}\n", line));
+    }
+  }
+  Ok(dot)
+}
+
 pub fn typecheck_polymorphic_function_instance(
   instance_unit : UnitId,
   poly_function_id : SymbolId,
@@ -84,6 +128,8 @@ pub fn typecheck_polymorphic_function_instance(
   let mut type_directory =
     TypeDirectory::new(imports, instance_unit, &mut code_store.types);
   let nodes = code_store.nodes.get(&poly_function_id.uid).unwrap();
+  // instances use the literal defaults of the unit that defined the function
+  let literal_defaults = LiteralDefaults::from_pragmas(&nodes.pragmas)?;
   let source_node =
     *code_store.type_mappings.get(&poly_function_id.uid).unwrap()
     .symbol_def_nodes.get(&poly_function_id).unwrap();
@@ -93,7 +139,7 @@ pub fn typecheck_polymorphic_function_instance(
       &mut type_directory, &mut mapping, cache, gen, &mut errors);
   let i = Inference::new(
     &nodes, &mut type_directory,
-    &mut mapping, &c);
+    &mut mapping, &c, literal_defaults);
   i.infer(&mut errors);
   if !errors.is_empty() {
     let c = ErrorContent::InnerErrors("type errors".into(), errors.concrete_errors);
@@ -108,6 +154,11 @@ struct Inference<'a> {
   t : &'a mut TypeDirectory<'a>,
   mapping : &'a mut TypeMapping,
   c : &'a Constraints,
+  literal_defaults : LiteralDefaults,
+  /// Whether each argument in `ClosureArg`s is thinned, once it's decided
+  closure_arg_fits : HashMap<NodeId, bool>,
+  /// Whether each `&&` and `||` calls an overload, once it's decided
+  short_circuit_fits : HashMap<NodeId, bool>,
 }
 
 impl <'a> Inference<'a> {
@@ -116,10 +167,11 @@ impl <'a> Inference<'a> {
     nodes : &'a Nodes,
     t : &'a mut TypeDirectory<'a>,
     mapping : &'a mut TypeMapping,
-    c : &'a Constraints)
+    c : &'a Constraints,
+    literal_defaults : LiteralDefaults)
       -> Self
   {
-    Inference { nodes, t, mapping, c }
+    Inference { nodes, t, mapping, c, literal_defaults, closure_arg_fits: HashMap::new(), short_circuit_fits: HashMap::new() }
   }
 
   fn unresolved_constraint_error(&mut self, errors : &mut TypeErrors, slots : &mut Slots, c : &Constraint) {
@@ -130,15 +182,17 @@ impl <'a> Inference<'a> {
     use ConstraintContent::*;
     let e = match &c.content  {
       Equalivalent(_a, _b) => return,
-      Branch{ output:_, cases:_ } => return,
+      Branch{ .. } => return,
       // this error should always be accompanied by other unresolved constraints
-      Function{ function:_, args:_, return_type:_ } => return,
+      Function{ .. } => return,
       Constructor { def_slot:_ , fields:_ } => return,
       Convert { val:_, into_type_slot:_ } => return,
       SymbolDef { symbol_id, slot:_ } => {
-        let def = self.t.get_symbol_mut(*symbol_id);
-        let node_id = *self.mapping.symbol_def_nodes.get(symbol_id).unwrap();
-        let loc = self.nodes.node(node_id).loc;
+        let def = self.t.get_symbol(*symbol_id);
+        let loc =
+          self.mapping.symbol_def_nodes.get(symbol_id)
+          .map(|node_id| self.nodes.node(*node_id).loc)
+          .unwrap_or(def.loc);
         error_raw(loc,
           format!("Symbol definition '{}' not resolved. Inferred type {}.", def.name, def.type_tag))
       }
@@ -149,8 +203,12 @@ impl <'a> Inference<'a> {
           let def = self.t.get_symbol(rs.id);
           format!("      {} : {}", def.name, rs.resolved_type)
         }).join("\n");
-        error_raw(self.c.loc(*result),
-          format!("Reference '{}' of type '{}' not resolved\n   Symbols available:\n{}", name, t, s))
+        let mut message =
+          format!("Reference '{}' of type '{}' not resolved\n   Symbols available:\n{}", name, t, s);
+        if symbols.is_empty() {
+          message.push_str(&self.suggestions(&name));
+        }
+        error_raw(self.c.loc(*result), message)
       }
       FieldAccess{ container:_, field, result:_ } => {
         error_raw(field.loc,
@@ -164,14 +222,69 @@ impl <'a> Inference<'a> {
       SizeOf { node:_, slot } => {
         error_raw(self.c.loc(*slot), "sizeof type not resolved")
       }
+      ShortCircuit { name, function, left, right, .. } => {
+        let (a, b) = (slots.get_or_any(*left), slots.get_or_any(*right));
+        error_raw(self.c.loc(*function),
+          format!("overload of '{}' for '{}' and '{}' not resolved", name, a, b))
+      }
     };
     errors.push(e);
   }
 
+  /// Lists visible symbols with similar names (or the same name, but the wrong type)
+  fn suggestions(&self, name : &str) -> String {
+    const MAX_SUGGESTIONS : usize = 5;
+    let is_identifier = name.chars().all(|c| c.is_alphanumeric() || c == '_');
+    let max_distance = std::cmp::max(1, name.chars().count() / 3);
+    let mut candidates : Vec<_> =
+      self.t.visible_symbols()
+      .filter_map(|def| {
+        let distance = {
+          if def.name.as_ref() == name { 0 }
+          else if is_identifier { edit_distance(name, &def.name) }
+          else { return None }
+        };
+        if distance <= max_distance { Some((distance, def)) } else { None }
+      })
+      .collect();
+    if candidates.is_empty() {
+      return "".into();
+    }
+    candidates.sort_by_key(|(distance, def)| (*distance, def.name.clone(), def.loc));
+    candidates.truncate(MAX_SUGGESTIONS);
+    let lines = candidates.iter().map(|(_, def)| {
+      if def.loc == TextLocation::zero() {
+        format!("      {} : {} (intrinsic)", def.name, def.type_tag)
+      }
+      else {
+        format!("      {} : {} defined at {}", def.name, def.type_tag, def.loc)
+      }
+    }).join("\n");
+    format!("\n   Did you mean:\n{}", lines)
+  }
+
   fn register_def(&mut self, node : NodeId, symbol_id : SymbolId) {
     self.mapping.symbol_references.insert(node, symbol_id);
   }
 
+  /// Feeds what a reference learned about a symbol back into the symbol's type, so
+  /// that its definition (and every other reference to it) is re-run. Otherwise the
+  /// result would depend on whether the definition or the reference was seen first.
+  /// Only monomorphic symbols from the unit being checked can be refined.
+  fn refine_symbol(&mut self, g : &mut TypeGraph, symbol_id : SymbolId, t : &Type) {
+    let new_unit_id = self.t.new_unit_id;
+    let def = self.t.get_symbol_mut(symbol_id);
+    if def.unit_id != new_unit_id || def.is_polymorphic() {
+      return;
+    }
+    let mut type_tag = def.type_tag.clone();
+    let r = incremental_unify(t, &mut type_tag);
+    if r.unify_success && r.mutable_type_changed {
+      def.type_tag = type_tag;
+      g.symbol_updated(def);
+    }
+  }
+
   /// Recursively copies, turning all `Abstract(Def)` types into resolved `Def` types,
   /// or throwing an error if no `Def` is found.
   fn resolve_abstract_defs<'l>(&self, loc : TextLocation, t : &'l Type)
@@ -246,6 +359,18 @@ impl <'a> Inference<'a> {
         for (i, t) in fs.into_iter().enumerate() {
           def.fields[i].1 = t;
         }
+        for (f, t) in def.fields.iter() {
+          if let Some(width) = def.bitfield_width(&f.name) {
+            match t.int_bits() {
+              Some(bits) if width <= bits => (),
+              // includes fields whose type isn't known, since the layout needs their width
+              _ => {
+                let s = format!("bitfield '{}' needs an integer type of at least {} bits", f.name, width);
+                errors.push(error_raw(f.loc, s));
+              }
+            }
+          }
+        }
       }
     }
   }
@@ -261,7 +386,7 @@ impl <'a> Inference<'a> {
     match &c.content  {
       Equalivalent(a, b) =>
         force_equivalence(slots, g, errors, *a, *b),
-      Branch{ output, cases } => {
+      Branch{ output, cases, keyword } => {
         if let Some(t) = slots.get(*output) {
           if t.content == TypeContent::Prim(PType::Void) {
             return;
@@ -273,7 +398,8 @@ impl <'a> Inference<'a> {
             return;
           }
         }
-        // Check if the branch types are all known, and none are void
+        // Check if the branch types are all known, and none are void. Number literals
+        // count as known, so that they can agree on a type before they are hardened.
         for slot in cases {
           if let Some(t) = slots.get(*slot) {
             if t.content == TypeContent::Prim(PType::Void) {
@@ -282,19 +408,36 @@ impl <'a> Inference<'a> {
               slots.update_type(g, errors, *output, &t);
               return;
             }
-            if t.is_concrete() {
+            if t.is_concrete() || is_number_literal(t) {
               continue;
             }
           }
           // This type isn't known/concrete yet, so cannot assert the output type
           return;
         }
-        // The branch types are all known. Unify each one with the output.
+        // The branch types are all known. If two can't agree, say which branches they
+        // came from, rather than where they met.
+        let known : Vec<_> =
+          cases.iter().map(|s| (*s, slots.get(*s).unwrap()))
+          .filter(|(_, t)| t.content != Prim(PType::Never))
+          .collect();
+        for (i, (a, ta)) in known.iter().enumerate() {
+          for (b, tb) in known[i+1..].iter() {
+            if unify_types(ta, tb).is_none() {
+              let s = format!(
+                "the branches of this '{}' have incompatible types, '{}' and '{}'\n   {} comes from {}\n   {} comes from {}",
+                keyword, ta, tb, ta, self.c.loc(*a), tb, self.c.loc(*b));
+              errors.push(error_raw(self.c.loc(*output), s));
+              return;
+            }
+          }
+        }
+        // Unify each one with the output.
         for slot in cases {
           force_equivalence(slots, g, errors, *output, *slot);
         }
       }
-      Function{ function, args, return_type } => {
+      Function{ function, args, return_type, receiver, closure_args } => {
         if let Some(t) = slots.get(*function) {
           if let Some(mut sig) = t.sig_builder() {
             if sig.args().len() == args.len() {
@@ -307,6 +450,15 @@ impl <'a> Inference<'a> {
             }
           }
         }
+        if let Some((call, value)) = receiver {
+          self.fit_receiver(slots, g, errors, *call, *function, *value, args[0]);
+        }
+        for a in closure_args {
+          self.fit_closure_arg(slots, g, errors, a, args[a.index]);
+        }
+      }
+      ShortCircuit { node, name, function, left, right, result } => {
+        self.fit_short_circuit(slots, g, errors, *node, name, *function, *left, *right, *result);
       }
       Constructor { def_slot, fields } => {
         if let Some(t) = slots.get(*def_slot) {
@@ -337,11 +489,12 @@ impl <'a> Inference<'a> {
                   errors.push(e);
                 }
               }
-              TypeKind::Union => {
+              TypeKind::Union | TypeKind::TaggedUnion => {
                 if let [(Some(sym), slot)] = fields.as_slice() {
-                  if let Some((_, t)) = def.fields.iter().find(|(n, _)| n.name == sym.name) {
-                    let t = t.clone();
-                    slots.update_type(g, errors, *slot, &t);
+                  if let Some((_, field_type)) = def.fields.iter().find(|(n, _)| n.name == sym.name) {
+                    let mut field_type = field_type.clone();
+                    def.instance_type(&mut field_type, t.children.as_slice());
+                    slots.update_type(g, errors, *slot, &field_type);
                   }
                   else {
                     errors.push(error_raw(sym.loc, "field does not exist in this union"));
@@ -399,7 +552,11 @@ impl <'a> Inference<'a> {
             let resolved_type = resolved_symbol.resolved_type.clone();
             let id = resolved_symbol.id;
             self.register_def(*node, id);
-            slots.update_type(g, errors, *result, &resolved_type);
+            let r = slots.update_type(g, errors, *result, &resolved_type);
+            if r.unify_success {
+              let t = slots.get(*result).unwrap().clone();
+              self.refine_symbol(g, id, &t);
+            }
           }
           [] => {
             // Symbol will never be resolved. Report error, because at the moment it's the only guaranteed
@@ -425,17 +582,38 @@ impl <'a> Inference<'a> {
           while let Some(inner) = t.ptr() {
             t = inner;
           }
+          let access = VariantAccess::from_field(&field.name);
+          let mut tagged = false;
+          let bool_type : Type = PType::Bool.into();
           if let Def(name, unit_id) = &t.content {
             g.register_typedef(name, c);
             let def = self.t.get_type_def(&name, *unit_id);
-            let field_type = def.instanced_field_type(&field.name, t.children.as_slice());
+            tagged = def.kind == TypeKind::TaggedUnion;
+            // the variants of a tagged union can only be read by `match`
+            let field_type = match (tagged, access) {
+              (true, Some(VariantAccess::Test(v))) =>
+                Some(bool_type).filter(|_| def.fields.iter().any(|(f, _)| f.name.as_ref() == v)),
+              (true, Some(VariantAccess::Read(v))) =>
+                def.instanced_field_type(v, t.children.as_slice()),
+              (true, None) if def.fields.iter().any(|(f, _)| f.name == field.name) => {
+                let s = format!("the variants of tagged union '{}' can only be read with 'match'", t);
+                errors.push(error_raw(field.loc, s));
+                return;
+              }
+              (false, None) => def.instanced_field_type(&field.name, t.children.as_slice()),
+              _ => None,
+            };
             if let Some(t) = field_type {
               slots.update_type(g, errors, *result, &t);
               return;
             }
           }
           if t.is_concrete() {
-            let s = format!("type '{}' has no field '{}'", t, field.name);
+            let s = match access {
+              Some(_) if !tagged => format!("only tagged unions can be matched on, not '{}'", t),
+              Some(access) => format!("tagged union '{}' has no variant '{}'", t, access.variant()),
+              None => format!("type '{}' has no field '{}'", t, field.name),
+            };
             errors.push(error_raw(field.loc, s));
           }
         }
@@ -457,6 +635,37 @@ impl <'a> Inference<'a> {
       SizeOf { node, slot } => {
         if let Some(t) = slots.get(*slot) {
           if t.is_concrete() {
+            let field_query = match &self.nodes.node(*node).content {
+              Content::SizeOf{ query: LayoutQuery::Offset(field), .. } => Some((field, false)),
+              Content::SizeOf{ query: LayoutQuery::BitOffset(field), .. } => Some((field, true)),
+              _ => None,
+            };
+            if let Some((field, bit_offset)) = field_query {
+              let has_field = match &t.content {
+                Def(name, unit_id) => {
+                  let def = self.t.get_type_def(name, *unit_id);
+                  let is_bitfield = def.bitfield_width(&field.name).is_some();
+                  if is_bitfield && !bit_offset {
+                    let s = format!("bitfield '{}' has no offset", field.name);
+                    errors.push(error_raw(field.loc, s));
+                    return;
+                  }
+                  let has_field = def.fields.iter().any(|(f, _)| f.name == field.name);
+                  if has_field && bit_offset && !is_bitfield {
+                    let s = format!("'{}' is not a bitfield", field.name);
+                    errors.push(error_raw(field.loc, s));
+                    return;
+                  }
+                  has_field
+                }
+                _ => false,
+              };
+              if !has_field {
+                let s = format!("type '{}' has no field '{}'", t, field.name);
+                errors.push(error_raw(field.loc, s));
+                return;
+              }
+            }
             let t = t.clone().into();
             self.mapping.sizeof_info.insert(*node, t);
           }
@@ -465,6 +674,262 @@ impl <'a> Inference<'a> {
     }
   }
 
+  /// Decides how the receiver `x` of `x.f(...)` is passed to `f`, once the type of
+  /// the first parameter or of `x` is known. From then on the two types follow each
+  /// other, like they would if `x` was written as the first argument.
+  fn fit_receiver(
+    &mut self,
+    slots : &mut Slots,
+    g : &mut TypeGraph,
+    errors : &mut TypeErrors,
+    call : NodeId,
+    function : TypeSlot,
+    value : TypeSlot,
+    param : TypeSlot)
+  {
+    use MethodReceiver::*;
+    let receiver = match self.mapping.method_receivers.get(&call) {
+      Some(r) => *r,
+      None => {
+        let value_type = slots.get_or_any(value).clone();
+        let param_type = slots.get_or_any(param).clone();
+        let r = {
+          // a receiver that doesn't fit is passed as it is, so that the error is
+          // the same as for `f(x, ...)`
+          if !is_unknown(&param_type) {
+            match receiver_fit(&param_type, &value_type) {
+              Some(r) => r,
+              // it might fit once more is known about what it points to
+              None if points_to_unknown(&value_type) => return,
+              None => Value,
+            }
+          }
+          else if is_unknown(&value_type) { return }
+          else {
+            match self.fit_overloads(slots, call, function, &value_type) {
+              Some(r) => r,
+              None => return,
+            }
+          }
+        };
+        self.mapping.method_receivers.insert(call, r);
+        r
+      }
+    };
+    match receiver {
+      Value => force_equivalence(slots, g, errors, param, value),
+      Reference => {
+        if let Some(t) = slots.get(value) {
+          let t = t.clone().ptr_to();
+          slots.update_type(g, errors, param, &t);
+        }
+        if let Some(t) = slots.get(param).and_then(|t| t.ptr()) {
+          let t = t.clone();
+          slots.update_type(g, errors, value, &t);
+        }
+      }
+      Dereference(levels) => {
+        let pointee = slots.get(value).and_then(|t| (0..levels).try_fold(t, |t, _| t.ptr()));
+        if let Some(t) = pointee {
+          let t = t.clone();
+          slots.update_type(g, errors, param, &t);
+        }
+        if let Some(t) = slots.get(param) {
+          let mut t = t.clone();
+          for _ in 0..levels {
+            t = t.ptr_to();
+          }
+          slots.update_type(g, errors, value, &t);
+        }
+      }
+    }
+  }
+
+  /// Decides whether an argument of a call that might be to a C function is a
+  /// closure to pass as its bare function. It is if the call turns out to be to a
+  /// `cbind` that takes a function there. Otherwise the argument is passed as it
+  /// is. A closure that's made with a context right there is an error, and any
+  /// other closure is checked when it's passed (see `codegen_thinned_closure`).
+  fn fit_closure_arg(
+    &mut self,
+    slots : &mut Slots,
+    g : &mut TypeGraph,
+    errors : &mut TypeErrors,
+    a : &ClosureArg,
+    param : TypeSlot)
+  {
+    let thinned = match self.closure_arg_fits.get(&a.arg) {
+      Some(&thinned) => thinned,
+      None => {
+        let value_type = slots.get_or_any(a.value).clone();
+        let param_type = slots.get_or_any(param).clone();
+        let to_cbind = self.mapping.symbol_references.get(&a.callee).map(|&id| {
+          match self.t.get_symbol(id).initialiser {
+            SymbolInit::CBind => true,
+            _ => false,
+          }
+        });
+        let takes_function = is_unknown(&param_type) || param_type.sig().is_some();
+        let thinned = {
+          if to_cbind == Some(false) || !takes_function { false }
+          else if is_unknown(&value_type) { return }
+          else if closure_function(&value_type).is_none() { false }
+          else if to_cbind == Some(true) { true }
+          else { return }
+        };
+        if thinned && self.makes_closure_with_env(a.arg) {
+          let e = error_raw(self.nodes.node(a.arg).loc, "a closure with a context can't be passed to a C function");
+          errors.push(e);
+        }
+        self.closure_arg_fits.insert(a.arg, thinned);
+        if thinned {
+          self.mapping.thinned_args.insert(a.arg);
+        }
+        thinned
+      }
+    };
+    if !thinned {
+      return force_equivalence(slots, g, errors, param, a.value);
+    }
+    if let Some(f) = slots.get(a.value).and_then(closure_function) {
+      let f = f.clone();
+      slots.update_type(g, errors, param, &f);
+    }
+    let f = slots.get(param).cloned();
+    if let (Some(mut closure), Some(f)) = (slots.get(a.value).cloned(), f) {
+      closure.children[0] = f;
+      slots.update_type(g, errors, a.value, &closure);
+    }
+  }
+
+  /// Decides whether `&&` or `||` is built in, once the type of either operand is
+  /// known. It is for bools and options, where the result is one of the operands.
+  /// Anything else calls the overload of the operator that takes the operands,
+  /// which is found the way a reference to it would be.
+  fn fit_short_circuit(
+    &mut self,
+    slots : &mut Slots,
+    g : &mut TypeGraph,
+    errors : &mut TypeErrors,
+    node : NodeId,
+    name : &RefStr,
+    function : TypeSlot,
+    left : TypeSlot,
+    right : TypeSlot,
+    result : TypeSlot)
+  {
+    let overloaded = match self.short_circuit_fits.get(&node) {
+      Some(&overloaded) => overloaded,
+      None => {
+        let operand = [left, right].iter().filter_map(|s| slots.get(*s)).find(|t| !is_unknown(t));
+        let built_in = match operand.map(|t| &t.content) {
+          None => return,
+          Some(Prim(PType::Bool)) => true,
+          Some(Def(name, _)) => name.as_ref() == OPTION_TYPE_NAME,
+          Some(_) => false,
+        };
+        self.short_circuit_fits.insert(node, !built_in);
+        !built_in
+      }
+    };
+    if !overloaded {
+      force_equivalence(slots, g, errors, result, left);
+      force_equivalence(slots, g, errors, result, right);
+      // the operator gets the type that a function doing the same would have
+      if let Some(t) = slots.get(result) {
+        let mut sig = SignatureBuilder::new(t.clone());
+        sig.append_arg(t.clone());
+        sig.append_arg(t.clone());
+        slots.update_type(g, errors, function, &sig.into());
+      }
+      return;
+    }
+    let mut sig = SignatureBuilder::new(slots.get_or_any(result).clone());
+    sig.append_arg(slots.get_or_any(left).clone());
+    sig.append_arg(slots.get_or_any(right).clone());
+    slots.update_type(g, errors, function, &sig.into());
+    let function_node = match &self.nodes.node(node).content {
+      Content::ShortCircuit{ function, .. } => *function,
+      _ => panic!("COMPILER BUG: short circuit constraint without a short circuit node"),
+    };
+    let t = slots.get_or_any(function);
+    match self.t.find_symbol(name, t) {
+      [resolved_symbol] => {
+        let resolved_type = resolved_symbol.resolved_type.clone();
+        let id = resolved_symbol.id;
+        self.register_def(function_node, id);
+        let r = slots.update_type(g, errors, function, &resolved_type);
+        if r.unify_success {
+          let t = slots.get(function).unwrap().clone();
+          self.refine_symbol(g, id, &t);
+        }
+      }
+      [] => {
+        let (a, b) = (slots.get_or_any(left), slots.get_or_any(right));
+        let s = format!("'{}' needs bool or option operands, or an overload for '{}' and '{}'", name, a, b);
+        errors.push(error_raw(self.nodes.node(node).loc, s));
+        return;
+      }
+      syms => {
+        let mut t = types::type_intersection(&syms[0].resolved_type, &syms[1].resolved_type);
+        for sym in &syms[2..] {
+          t = types::type_intersection(&t, &sym.resolved_type);
+        }
+        slots.update_type(g, errors, function, &t);
+      }
+    }
+    // the operands and the result follow the overload's signature, like a call's do
+    if let Some(mut sig) = slots.get(function).and_then(|t| t.sig_builder()) {
+      if sig.args().len() == 2 {
+        slots.update_type_mut(g, errors, left, &mut sig.args()[0]);
+        slots.update_type_mut(g, errors, right, &mut sig.args()[1]);
+        slots.update_type_mut(g, errors, result, sig.return_type());
+        slots.update_type(g, errors, function, &sig.into());
+      }
+    }
+  }
+
+  /// True for `closure(f, env)`
+  fn makes_closure_with_env(&self, node : NodeId) -> bool {
+    match &self.nodes.node(node).content {
+      Content::FunctionCall{ function, args } if args.len() == 2 => match &self.nodes.node(*function).content {
+        Content::Reference{ name, refers_to: None } => name.as_ref() == CLOSURE_TYPE_NAME,
+        _ => false,
+      }
+      _ => false,
+    }
+  }
+
+  /// How the receiver of `x.f(...)` fits the overloads of `f` that could still be
+  /// called. It's passed as it is if any of them can take it that way. Returns
+  /// nothing if the overloads disagree about how to take it, or if none of them
+  /// fit yet but might once more is known about what `x` points to.
+  fn fit_overloads(&mut self, slots : &Slots, call : NodeId, function : TypeSlot, value_type : &Type)
+    -> Option<MethodReceiver>
+  {
+    let name = match &self.nodes.node(call).content {
+      Content::FunctionCall{ function, .. } => match &self.nodes.node(*function).content {
+        Content::Reference{ name, refers_to: None } => name.clone(),
+        // function values can't be overloaded
+        _ => return Some(MethodReceiver::Value),
+      }
+      _ => return Some(MethodReceiver::Value),
+    };
+    let t = slots.get_or_any(function).clone();
+    let fits : Vec<MethodReceiver> =
+      self.t.find_symbol(&name, &t).iter()
+      .filter_map(|rs| rs.resolved_type.sig().and_then(|sig| sig.args.first().cloned()))
+      .filter_map(|param_type| receiver_fit(&param_type, value_type))
+      .collect();
+    if fits.is_empty() && points_to_unknown(value_type) {
+      return None;
+    }
+    if fits.is_empty() || fits.contains(&MethodReceiver::Value) {
+      return Some(MethodReceiver::Value);
+    }
+    if fits.iter().all(|&r| r == fits[0]) { Some(fits[0]) } else { None }
+  }
+
   /// Tries to harden a type slot into a concrete type
   fn try_harden_slot(
     &mut self,
@@ -473,12 +938,153 @@ impl <'a> Inference<'a> {
     errors : &mut TypeErrors,
     slot : TypeSlot)
   {
-    if let Some(default) = slots.get(slot).unwrap().try_harden_literal() {
+    if let Some(default) = slots.get(slot).unwrap().try_harden_literal(&self.literal_defaults) {
       slots.update_type(g, errors, slot, &default);
     }
   }
 
-  fn infer(mut self, errors : &mut TypeErrors) {
+  /// Reports matches without a `_` arm that don't cover every variant of the
+  /// tagged union they match on. Matches outside of the code being checked (like
+  /// the rest of a polymorphic function's unit) have no types, so are skipped.
+  fn check_matches(&self, errors : &mut TypeErrors) {
+    for m in self.nodes.matches.iter().filter(|m| !m.has_default) {
+      let mut t = match self.mapping.node_type.get(&m.value) {
+        Some(t) => t,
+        None => continue,
+      };
+      while let Some(inner) = t.ptr() {
+        t = inner;
+      }
+      if let Def(name, unit_id) = &t.content {
+        let def = self.t.get_type_def(name, *unit_id);
+        let missing : Vec<_> =
+          def.fields.iter()
+          .filter(|(f, _)| !m.variants.iter().any(|v| v.name == f.name))
+          .map(|(f, _)| format!("'{}'", f.name)).collect();
+        if !missing.is_empty() {
+          let s = format!("match on '{}' doesn't cover {}", t, missing.join(", "));
+          errors.push(error_raw(m.loc, s));
+        }
+      }
+    }
+  }
+
+  /// Reports switch cases that don't fit in the type of the value being switched on
+  fn check_switches(&self, errors : &mut TypeErrors) {
+    for node in self.nodes.nodes.values() {
+      if let Content::Switch{ value, cases, case_locs, .. } = &node.content {
+        let t = match self.mapping.node_type.get(value) {
+          Some(t) => t,
+          None => continue,
+        };
+        let bits = t.int_bits().unwrap_or(64) as i128;
+        let (min, max) = {
+          if t.signed_int() { (-(1 << (bits - 1)), (1 << (bits - 1)) - 1) }
+          else { (0, (1 << bits) - 1) }
+        };
+        for (values, _) in cases.iter() {
+          for case in values.iter() {
+            if (*case as i128) < min || (*case as i128) > max {
+              let loc = case_locs.get(case).cloned().unwrap_or(node.loc);
+              errors.push(error_raw(loc, format!("case {} doesn't fit in '{}'", case, t)));
+            }
+          }
+        }
+      }
+    }
+  }
+
+  /// Reports type definitions that contain themselves without going through a
+  /// pointer, as they would have an infinite size
+  fn check_recursive_type_defs(&self, errors : &mut TypeErrors) {
+    let unit_id = self.t.new_unit_id;
+    let mut names : Vec<_> = self.t.types.get(&unit_id).unwrap().type_defs.keys().cloned().collect();
+    names.sort();
+    for name in names {
+      let def = self.t.get_type_def(&name, unit_id);
+      for (field, field_type) in def.fields.iter() {
+        let mut visited = HashSet::new();
+        let mut path = vec![field.name.clone()];
+        if self.type_contains_def(field_type, &name, unit_id, &mut visited, &mut path) {
+          let node = *self.mapping.type_def_nodes.get(&name).unwrap();
+          let e = error_raw(self.nodes.node(node).loc, format!(
+            "recursive type '{}' needs indirection, because it contains itself through '{}'\n   consider changing the field to `{} : ptr({})`",
+            name, path.join("."), field.name, field_type));
+          errors.push(e);
+          break;
+        }
+      }
+    }
+  }
+
+  /// Whether a value of type `t` directly contains the type definition `name`.
+  /// Pointers and functions are indirections, so they are never searched. Generic
+  /// types are searched once for each set of type arguments, since `pair(i64)`
+  /// and `pair(a)` store different elements.
+  fn type_contains_def(
+    &self, t : &Type, name : &RefStr, unit_id : UnitId,
+    visited : &mut HashSet<Type>, path : &mut Vec<RefStr>) -> bool
+  {
+    if let Def(def_name, def_unit) = &t.content {
+      if def_name == name && *def_unit == unit_id {
+        return true;
+      }
+      if !visited.insert(t.clone()) {
+        return false;
+      }
+      let def = self.t.get_type_def(def_name, *def_unit);
+      for (field, field_type) in def.fields.iter() {
+        let mut field_type = field_type.clone();
+        if t.children().len() == def.type_vars.len() {
+          def.instance_type(&mut field_type, t.children());
+        }
+        path.push(field.name.clone());
+        if self.type_contains_def(&field_type, name, unit_id, visited, path) {
+          return true;
+        }
+        path.pop();
+      }
+    }
+    false
+  }
+
+  /// Resolves the first overloaded reference (in lexical order) that has a single
+  /// best candidate, ranked by `overload_cost`. Returns false if there wasn't one.
+  fn rank_overloads(
+    &mut self,
+    slots : &mut Slots,
+    g : &mut TypeGraph,
+    errors : &mut TypeErrors) -> bool
+  {
+    for c in self.c.constraints.iter() {
+      if let ConstraintContent::SymbolReference{ node, name, result } = &c.content {
+        if self.mapping.symbol_references.contains_key(node) {
+          continue;
+        }
+        let t = slots.get_or_any(*result).clone();
+        let candidates : Vec<_> = self.t.find_symbol(&name, &t).iter().cloned().collect();
+        if candidates.len() < 2 {
+          continue;
+        }
+        let costs : Vec<_> = candidates.iter().map(|rs| {
+          let def = self.t.get_symbol(rs.id);
+          overload_cost(&t, &def.type_tag, &self.literal_defaults)
+        }).collect();
+        let best = *costs.iter().min().unwrap();
+        if costs.iter().filter(|&&c| c == best).count() == 1 {
+          let i = costs.iter().position(|&c| c == best).unwrap();
+          let chosen = &candidates[i];
+          self.register_def(*node, chosen.id);
+          slots.update_type(g, errors, *result, &chosen.resolved_type);
+          return true;
+        }
+      }
+    }
+    false
+  }
+
+  /// Returns the final state of the type slots
+  fn infer(mut self, errors : &mut TypeErrors) -> Slots<'a> {
     if DEBUG {
       println!("To resolve: {}", self.c.slots.len());
     }
@@ -491,19 +1097,26 @@ impl <'a> Inference<'a> {
     for a in self.c.assertions.iter() {
       self.process_assertion(&mut slots, &mut g, errors, a);
     }
+    self.check_recursive_type_defs(errors);
     let mut total_constrainslot_processed = 0;
     let mut active_edge_set = HashMap::new();
     let mut next_edge_set = HashMap::new();
     for c in self.c.constraints.iter() {
       next_edge_set.insert(c.id, c);
     }
+    let mut passes = 0;
     while (next_edge_set.len() > 0 || literals.len() > 0) && errors.is_empty() {
+      passes += 1;
       std::mem::swap(&mut next_edge_set, &mut active_edge_set);
       for (_, c) in active_edge_set.drain() {
         total_constrainslot_processed += 1;
         self.process_constraint(&mut slots, &mut g, errors, c);
       }
       g.find_boundary_constraints(&mut next_edge_set);
+      // If nothing was resolved, choose between any overloads that still match
+      while next_edge_set.is_empty() && self.rank_overloads(&mut slots, &mut g, errors) {
+        g.find_boundary_constraints(&mut next_edge_set);
+      }
       // If nothing was resolved, try to harden a literal (in lexical order)
       if next_edge_set.is_empty() && literals.len() > 0 {
         self.try_harden_slot(&mut slots, &mut g, errors, literals.pop_front().unwrap());
@@ -514,6 +1127,11 @@ impl <'a> Inference<'a> {
       println!("Unique constraints: {}\n", self.c.constraints.len());
       println!("Constraints processed (including duplicates): {}\n", total_constrainslot_processed);
     }
+    self.mapping.stats = InferenceStats {
+      constraints: self.c.constraints.len(),
+      constraints_processed: total_constrainslot_processed,
+      passes,
+    };
 
     // Look for errors
     if errors.is_empty() {
@@ -521,37 +1139,60 @@ impl <'a> Inference<'a> {
       active_edge_set.clear();
       slots.find_all_unresolved_constraints(&g, &mut active_edge_set);
       // Generate errors for unresolved constraints
-      let unresolved_count = active_edge_set.len();
-      for (_, c) in active_edge_set.drain() {
+      let mut unresolved : Vec<&Constraint> = active_edge_set.drain().map(|(_, c)| c).collect();
+      unresolved.sort_by_key(|c| c.id);
+      for c in unresolved.iter() {
         self.unresolved_constraint_error(errors, &mut slots, c);
       }
       // Generate errors if program has unresolved symbols
       for c in self.c.constraints.iter() {
-        if let ConstraintContent::SymbolReference{node, ..} = &c.content {
-          if !self.mapping.symbol_references.contains_key(node) {
-            self.unresolved_constraint_error(errors, &mut slots, c);
+        match &c.content {
+          ConstraintContent::SymbolReference{node, ..} => {
+            if !self.mapping.symbol_references.contains_key(node) {
+              self.unresolved_constraint_error(errors, &mut slots, c);
+            }
+          }
+          ConstraintContent::ShortCircuit{node, ..} => {
+            let resolved = match &self.nodes.node(*node).content {
+              Content::ShortCircuit{ function, .. } => self.mapping.symbol_references.contains_key(function),
+              _ => false,
+            };
+            if self.short_circuit_fits.get(node) == Some(&true) && !resolved {
+              self.unresolved_constraint_error(errors, &mut slots, c);
+            }
           }
+          _ => (),
         }
       }
-      if errors.is_empty() && unresolved_count > 0 {
-        panic!("Unresolved types found, but no errors generated!");
+      if errors.is_empty() && unresolved.len() > 0 {
+        let e = internal_error(self.nodes.root().loc,
+          "unresolved types found, but no errors generated", &unresolved);
+        errors.push(e);
       }
     }
 
     // Assign types to all of the nodes
     if errors.is_empty() {
       for (n, slot) in self.c.node_slots.iter() {
-        let t = slots.get(*slot).unwrap().clone();
+        let t = slots.get_or_any(*slot).clone();
         // Make sure the type isn't abstract
         if t.is_concrete() {
           self.mapping.node_type.insert(*n, t);
         }
         else {
-          panic!("abstract type but no error");
+          let loc = self.nodes.node(*n).loc;
+          let message = format!("expression has abstract type '{}', but no errors generated", t);
+          errors.push(internal_error(loc, &message, g.slot_constraints(*slot)));
+          break;
         }
       }
     }
 
+    if errors.is_empty() {
+      self.check_matches(errors);
+      self.check_switches(errors);
+    }
+
     // Find polymorphic definitions
     if errors.is_empty() {
       for (node_id, symbol_id) in self.mapping.symbol_references.iter() {
@@ -559,6 +1200,11 @@ impl <'a> Inference<'a> {
         if def.is_polymorphic() {
           if let SymbolInit::Function(_) = def.initialiser {
             let t = self.mapping.node_type.get(node_id).unwrap();
+            let loc = self.nodes.node(*node_id).loc;
+            let previous =
+              self.mapping.polymorphic_reference_locs
+              .entry((*symbol_id, t.clone())).or_insert(loc);
+            *previous = (*previous).min(loc);
             self.mapping.polymorphic_references.insert((*symbol_id, t.clone()));
           }
         }
@@ -569,6 +1215,69 @@ impl <'a> Inference<'a> {
     if !errors.is_empty() {
       errors.concrete_errors.sort_unstable_by_key(|e| e.location);
     }
+    slots
+  }
+}
+
+/// For solver states that should be impossible. These are reported like any other
+/// type error, so that a compiler bug doesn't take the live session down with it.
+pub(crate) fn internal_error(loc : TextLocation, message : &str, constraints : &[&Constraint]) -> Error {
+  let dump = constraints.iter().map(|c| format!("      {}", c)).join("\n");
+  error_raw(loc, format!("internal compiler error: {}\n   Constraints involved:\n{}", message, dump))
+}
+
+/// True for a type that nothing is known about yet
+fn is_unknown(t : &Type) -> bool {
+  t.content == Abstract(AbstractType::Any)
+}
+
+/// The type of the function in a `closure(F)` (see core/prelude.code)
+fn closure_function(t : &Type) -> Option<&Type> {
+  match &t.content {
+    Def(name, _) if name.as_ref() == CLOSURE_TYPE_NAME && t.children.len() == 1 => Some(&t.children[0]),
+    _ => None,
+  }
+}
+
+/// True for a pointer, or a pointer to pointers, to a type that isn't known yet
+fn points_to_unknown(t : &Type) -> bool {
+  let mut t = t;
+  while let Some(inner) = t.ptr() {
+    t = inner;
+  }
+  is_unknown(t)
+}
+
+/// How a receiver of type `value` can be passed to a parameter of type `param`.
+/// Passing it as it is comes first, then by reference, then through its pointers,
+/// fewest first. Pointers to types that aren't known yet are never dereferenced.
+fn receiver_fit(param : &Type, value : &Type) -> Option<MethodReceiver> {
+  if unify_types(param, value).is_some() {
+    return Some(MethodReceiver::Value);
+  }
+  if param.ptr().map(|p| unify_types(p, value).is_some()) == Some(true) {
+    return Some(MethodReceiver::Reference);
+  }
+  if points_to_unknown(value) {
+    return None;
+  }
+  let mut v = value;
+  let mut levels = 0;
+  while let Some(inner) = v.ptr() {
+    v = inner;
+    levels += 1;
+    if unify_types(param, v).is_some() {
+      return Some(MethodReceiver::Dereference(levels));
+    }
+  }
+  None
+}
+
+/// True for the type of a number literal that hasn't been hardened yet
+fn is_number_literal(t : &Type) -> bool {
+  match &t.content {
+    Abstract(AbstractType::Integer) | Abstract(AbstractType::Float) | Abstract(AbstractType::Number) => true,
+    _ => false,
   }
 }
 
@@ -580,10 +1289,52 @@ fn force_equivalence(
 {
   if let Some(t) = slots.get(a) {
     let t = t.clone();
-    slots.update_type(g, errors, b, &t);
+    slots.update_type_from(g, errors, b, &t, Some(a));
   }
   if let Some(t) = slots.get(b) {
     let t = t.clone();
-    slots.update_type(g, errors, a, &t);
+    slots.update_type_from(g, errors, a, &t, Some(b));
+  }
+}
+
+/// How well an overload's declared type matches the type a reference has been
+/// inferred to have so far. Lower is better. Each part of the type costs:
+///   - 0 for an exact match. A number literal exactly matches the type it would
+///     default to anyway (see `LiteralDefaults`).
+///   - 1 for a widening promotion, where a number literal becomes some other
+///     type of the same kind.
+///   - 2 for an abstract parameter (a type variable of a polymorphic function),
+///     which matches anything.
+/// So `foo(1)` prefers `foo(i64)` over `foo(i32)`, and prefers either of those
+/// over `foo(x : T)`. If two overloads have the same lowest cost the reference
+/// stays ambiguous.
+fn overload_cost(t : &Type, declared : &Type, defaults : &LiteralDefaults) -> u32 {
+  if let Polytype(_) = &declared.content {
+    return 2;
+  }
+  if let Abstract(a) = &t.content {
+    return match a.default_type(defaults) {
+      Some(d) if d == *declared => 0,
+      Some(_) => 1,
+      None => 0,
+    };
+  }
+  t.children().iter().zip(declared.children().iter())
+    .map(|(t, d)| overload_cost(t, d, defaults)).sum()
+}
+
+/// Levenshtein distance, counted in chars
+fn edit_distance(a : &str, b : &str) -> usize {
+  let b : Vec<char> = b.chars().collect();
+  let mut prev : Vec<usize> = (0..=b.len()).collect();
+  let mut row = vec![0; b.len() + 1];
+  for (i, ca) in a.chars().enumerate() {
+    row[0] = i + 1;
+    for (j, &cb) in b.iter().enumerate() {
+      let substitution = prev[j] + if ca == cb { 0 } else { 1 };
+      row[j + 1] = substitution.min(prev[j + 1] + 1).min(row[j] + 1);
+    }
+    std::mem::swap(&mut prev, &mut row);
   }
+  prev[b.len()]
 }
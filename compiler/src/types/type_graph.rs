@@ -2,7 +2,7 @@
 use crate::common::*;
 use crate::types::{types, constraints};
 
-use types::SymbolDefinition;
+use types::{SymbolDefinition, SymbolId};
 use constraints::{
   Constraint, ConstraintContent,
   Constraints, TypeSlot,
@@ -12,10 +12,13 @@ use std::collections::{HashMap, HashSet};
 #[derive(Default)]
 pub struct TypeGraph<'a> {
   symbol_map : HashMap<RefStr, Vec<&'a Constraint>>,
+  /// The constraint binding each symbol to its definition's type slot
+  symbol_def_map : HashMap<SymbolId, &'a Constraint>,
   typedef_map : HashMap<RefStr, HashMap<Uid, &'a Constraint>>,
   slot_map : HashMap<TypeSlot, Vec<&'a Constraint>>,
   dirty_slots : HashSet<TypeSlot>,
   dirty_symbols : HashSet<RefStr>,
+  dirty_symbol_defs : HashSet<SymbolId>,
 }
 
 impl <'a> TypeGraph<'a> {
@@ -35,8 +38,11 @@ impl <'a> TypeGraph<'a> {
     else { &[] }
   }
 
+  /// Marks every constraint that reads the symbol's type: the references to its
+  /// name, and its own definition
   pub fn symbol_updated(&mut self, def : &SymbolDefinition) {
     self.dirty_symbols.insert(def.name.clone());
+    self.dirty_symbol_defs.insert(def.id);
   }
 
   pub fn register_typedef(&mut self, name : &RefStr, c : &'a Constraint) {
@@ -57,6 +63,11 @@ impl <'a> TypeGraph<'a> {
         for &c in cs.iter() { edge_set.insert(c.id, c); }
       }
     }
+    for id in self.dirty_symbol_defs.drain() {
+      if let Some(&c) = self.symbol_def_map.get(&id) {
+        edge_set.insert(c.id, c);
+      }
+    }
     for slot in self.dirty_slots.drain() {
       if let Some(cs) = self.slot_map.get(&slot) {
         for &c in cs.iter() { edge_set.insert(c.id, c); }
@@ -84,7 +95,7 @@ impl <'a> TypeGraph<'a> {
         self.slot(a, c);
         self.slot(b, c);
       }
-      Branch{ output, cases } => {
+      Branch{ output, cases, .. } => {
         self.slot(output, c);
         for slot in cases {
           self.slot(slot, c);
@@ -106,12 +117,17 @@ impl <'a> TypeGraph<'a> {
         self.slot(def_slot, c);
         for (_, slot) in fields { self.slot(slot, c) }
       },
-      Function { function, args, return_type } => {
+      Function { function, args, return_type, receiver, closure_args } => {
         self.slot(function, c);
         for slot in args { self.slot(slot, c) }
         self.slot(return_type, c);
+        if let Some((_, slot)) = receiver { self.slot(slot, c) }
+        for a in closure_args { self.slot(&a.value, c) }
       },
-      SymbolDef { symbol_id:_, slot } => self.slot(slot, c),
+      SymbolDef { symbol_id, slot } => {
+        self.symbol_def_map.insert(*symbol_id, c);
+        self.slot(slot, c);
+      }
       SymbolReference { node:_, name, result } => {
         self.symbol(name, c);
         self.slot(result, c);
@@ -119,6 +135,13 @@ impl <'a> TypeGraph<'a> {
       SizeOf { node:_, slot } => {
         self.slot(slot, c);
       }
+      ShortCircuit { node:_, name, function, left, right, result } => {
+        self.symbol(name, c);
+        self.slot(function, c);
+        self.slot(left, c);
+        self.slot(right, c);
+        self.slot(result, c);
+      }
     }
   }
 }
\ No newline at end of file
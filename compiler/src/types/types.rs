@@ -3,13 +3,15 @@ use std::fmt;
 use itertools::Itertools;
 
 use crate::common::*;
+use crate::error::{Error, TextLocation, error_raw};
 use crate::structure::{
-  NodeId, TypeKind, Reference
+  NodeId, TypeKind, Reference, Pragma, CallingConvention, StructLayout,
 };
+use crate::intrinsics::is_tuple_type;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, BTreeMap};
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash, Ord, PartialOrd)]
 pub struct SymbolId {
   pub sid : Uid,
   pub uid : UnitId,
@@ -27,22 +29,55 @@ impl From<(Uid, UnitId)> for SymbolId {
   }
 }
 
-/// Provides all the type definitions for a particular unit
+/// Provides all the type definitions for a particular unit. These are ordered maps,
+/// so that codegen (and anything else iterating over them) is deterministic.
 pub struct TypeInfo {
-  pub type_defs : HashMap<RefStr, TypeDefinition>,
-  pub symbols : HashMap<SymbolId, SymbolDefinition>,
+  pub type_defs : BTreeMap<RefStr, TypeDefinition>,
+  pub symbols : BTreeMap<SymbolId, SymbolDefinition>,
   pub unit_id : UnitId,
 }
 
 /// Provides type information about nodes
 #[derive(Default)]
 pub struct TypeMapping {
-  pub node_type : HashMap<NodeId, Type>,
+  pub node_type : BTreeMap<NodeId, Type>,
   pub sizeof_info : HashMap<NodeId, Type>,
-  pub symbol_references : HashMap<NodeId, SymbolId>,
+  pub symbol_references : BTreeMap<NodeId, SymbolId>,
   pub polymorphic_references : HashSet<(SymbolId, Type)>,
+  /// The first place that each polymorphic reference is made, for diagnostics
+  pub polymorphic_reference_locs : HashMap<(SymbolId, Type), TextLocation>,
   pub symbol_def_nodes : HashMap<SymbolId, NodeId>,
   pub type_def_nodes : HashMap<RefStr, NodeId>,
+  /// How the receiver of each `x.f(...)` call is passed to `f`
+  pub method_receivers : HashMap<NodeId, MethodReceiver>,
+  /// The arguments that are closures passed to C functions, as their bare functions
+  pub thinned_args : HashSet<NodeId>,
+  pub stats : InferenceStats,
+}
+
+/// How the receiver `x` of a call `x.f(...)` becomes the first argument of `f`.
+/// `x` is passed as it is if it fits, then by reference, and then through as many
+/// of its pointers as it takes to fit. Field access also goes through any number
+/// of pointers, so `x.a.b` and `x.a.f()` work the same whether `x` is a value,
+/// a `ptr` or a `ptr(ptr(...))`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MethodReceiver {
+  /// `f(x, ...)`
+  Value,
+  /// `f(&x, ...)`
+  Reference,
+  /// `f(*x, ...)`, with one `*` for each pointer
+  Dereference(usize),
+}
+
+/// How much work inference did, for tracking compile times
+#[derive(Default, Clone, Copy, Debug)]
+pub struct InferenceStats {
+  pub constraints : usize,
+  /// Constraints processed, including repeats
+  pub constraints_processed : usize,
+  /// Passes of the solver over the active constraints
+  pub passes : usize,
 }
 
 impl TypeMapping {
@@ -53,10 +88,14 @@ impl TypeMapping {
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum PType {
   Void,
+  /// The type of expressions that never produce a value, like `return`
+  Never,
   F64, F32,
   I64, I32,
   U64, U32, U16, U8,
   Bool,
+  /// Four f32s, held in a SIMD register
+  Vec4F,
 }
 
 use PType::*;
@@ -73,6 +112,7 @@ impl PType {
       "u32" => U32,
       "u16" => U16,
       "u8" => U8,
+      "vec4f" => Vec4F,
       "()" => Void,
       _ => return None,
     };
@@ -90,7 +130,7 @@ pub struct Type {
 pub enum TypeContent {
   /// Primitive type (e.g. int, float, bool, etc)
   Prim(PType),
-  Fun,
+  Fun(CallingConvention),
   Def(RefStr, UnitId),
   Ptr,
   Abstract(AbstractType),
@@ -171,11 +211,16 @@ impl Into<Type> for AbstractType {
 
 pub struct SignatureBuilder {
   types : Vec<Type>,
+  convention : CallingConvention,
 }
 
 impl SignatureBuilder {
   pub fn new(return_type : Type) -> Self {
-    SignatureBuilder { types: vec![return_type] }
+    SignatureBuilder { types: vec![return_type], convention: CallingConvention::C }
+  }
+
+  pub fn set_convention(&mut self, convention : CallingConvention) {
+    self.convention = convention;
   }
 
   pub fn append_arg(&mut self, arg : Type) {
@@ -201,7 +246,7 @@ impl SignatureBuilder {
 
 impl Into<Type> for SignatureBuilder {
   fn into(self) -> Type {
-    Type::new(Fun, self.types)
+    Type::new(Fun(self.convention), self.types)
   }
 }
 
@@ -209,6 +254,7 @@ impl Into<Type> for SignatureBuilder {
 pub struct FunctionSignature<'a> {
   pub return_type : &'a Type,
   pub args : &'a [Type],
+  pub convention : CallingConvention,
 }
 
 impl Type {
@@ -222,8 +268,8 @@ impl Type {
   }
 
   pub fn sig(&self) -> Option<FunctionSignature> {
-    if self.content == Fun {
-      Some(FunctionSignature{return_type: &self.children[0], args: &self.children[1..]})
+    if let Fun(convention) = self.content {
+      Some(FunctionSignature{return_type: &self.children[0], args: &self.children[1..], convention})
     }
     else { None }
   }
@@ -232,16 +278,16 @@ impl Type {
     Type::new(Abstract(AbstractType::Def(s)), vec![])
   }
 
-  pub fn try_harden_literal(&self) -> Option<Type> {
+  pub fn try_harden_literal(&self, defaults : &LiteralDefaults) -> Option<Type> {
     if let Abstract(ab) = &self.content {
-      return ab.default_type();
+      return ab.default_type(defaults);
     }
     None
   }
 
   pub fn sig_builder(&self) -> Option<SignatureBuilder> {
-    if self.content == Fun {
-      Some(SignatureBuilder{types: self.children.iter().cloned().collect()})
+    if let Fun(convention) = self.content {
+      Some(SignatureBuilder{types: self.children.iter().cloned().collect(), convention})
     }
     else { None }
   }
@@ -269,7 +315,7 @@ impl Type {
     fn find(t : &Type, uids : &mut Vec<UnitId>) {
       match &t.content {
         Def(_, uid) => uids.push(*uid),
-        Prim(_) | Fun | Ptr | Polytype(_) => (),
+        Prim(_) | Fun(_) | Ptr | Polytype(_) => (),
         Abstract(_) =>
           panic!("units_referenced can't be called on abstract types. '{}' is abstract.", t),
       }
@@ -290,6 +336,9 @@ impl Type {
 pub enum AbstractType {
   Float,
   Integer,
+  /// An integer literal that may also become a float, so that the branches of an
+  /// `if` can agree on a type
+  Number,
   Any,
   Def(RefStr),
 }
@@ -299,6 +348,10 @@ impl AbstractType {
     match self {
       AbstractType::Float => t.float(),
       AbstractType::Integer => t.int(),
+      AbstractType::Number => match &t.content {
+        Abstract(AbstractType::Float) | Abstract(AbstractType::Integer) => true,
+        _ => t.int() || t.float(),
+      }
       AbstractType::Any => true,
       AbstractType::Def(name) => {
         if let Def(resolved_name, _) = &t.content {
@@ -309,16 +362,55 @@ impl AbstractType {
     }
   }
 
-  pub fn default_type(&self) -> Option<Type> {
+  pub fn default_type(&self, defaults : &LiteralDefaults) -> Option<Type> {
     match self {
-      AbstractType::Float => Some(PType::F64.into()),
-      AbstractType::Integer => Some(PType::I64.into()),
+      AbstractType::Float => Some(defaults.float.into()),
+      AbstractType::Integer | AbstractType::Number => Some(defaults.int.into()),
       AbstractType::Any => None,
       AbstractType::Def(_) => None,
     }
   }
 }
 
+/// The types that unannotated number literals become. A unit can change these
+/// with `pragma default_int(i32)` or `pragma default_float(f32)`.
+#[derive(Clone, Copy, Debug)]
+pub struct LiteralDefaults {
+  pub int : PType,
+  pub float : PType,
+}
+
+impl Default for LiteralDefaults {
+  fn default() -> Self {
+    LiteralDefaults { int: PType::I64, float: PType::F64 }
+  }
+}
+
+impl LiteralDefaults {
+  pub fn from_pragmas(pragmas : &[Pragma]) -> Result<Self, Error> {
+    let mut d = LiteralDefaults::default();
+    for p in pragmas {
+      let (slot, valid) : (&mut PType, fn(&Type) -> bool) = match p.name.as_ref() {
+        "default_int" => (&mut d.int, Type::int),
+        "default_float" => (&mut d.float, Type::float),
+        _ => continue,
+      };
+      let t = match p.args.as_slice() {
+        [arg] => PType::from_string(arg),
+        _ => None,
+      };
+      match t {
+        Some(t) if valid(&t.into()) => *slot = t,
+        _ => {
+          let s = format!("pragma {} expects a single {} type", p.name, &p.name["default_".len()..]);
+          return Err(error_raw(p.loc, s));
+        }
+      }
+    }
+    Ok(d)
+  }
+}
+
 #[derive(Clone, Debug)]
 pub struct TypeDefinition {
   pub name : RefStr,
@@ -326,6 +418,44 @@ pub struct TypeDefinition {
   pub kind : TypeKind,
   pub fields : Vec<(Reference, Type)>,
   pub type_vars : Vec<RefStr>,
+  pub layout : StructLayout,
+  /// The width in bits of each field that is a bitfield
+  pub bitfields : Vec<(RefStr, u32)>,
+  /// Where the type was defined (zero for intrinsics)
+  pub loc : TextLocation,
+  /// The `##` comment above the definition
+  pub doc : Option<RefStr>,
+}
+
+/// Where a field of a struct is stored in the generated code
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FieldPlacement {
+  /// The index of the field of the generated struct that holds it
+  pub index : u32,
+  /// For a bitfield, its bit offset and width within that field
+  pub bits : Option<(u32, u32)>,
+  /// For a bitfield laid out the System V way, the size in bytes of the field that
+  /// holds it, which is an array of bytes shared by the whole run of bitfields
+  pub storage_bytes : Option<u32>,
+}
+
+/// How C compilers lay out bitfields
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitfieldLayout {
+  /// A run of bitfields of the same type shares one field of that type, for as long
+  /// as they fit in it. A bitfield of a different type starts a new field.
+  Msvc,
+  /// Each bitfield goes in the next free bits, whatever its type, unless it would
+  /// cross a boundary of its type's size. Then it starts at the boundary.
+  SysV,
+}
+
+impl BitfieldLayout {
+  /// The layout of the C compilers of the platform that the compiler runs on, which
+  /// is the one that it generates code for
+  pub fn host() -> BitfieldLayout {
+    if cfg!(windows) { BitfieldLayout::Msvc } else { BitfieldLayout::SysV }
+  }
 }
 
 impl TypeDefinition {
@@ -334,6 +464,97 @@ impl TypeDefinition {
     self.type_vars.len() > 0
   }
 
+  pub fn bitfield_width(&self, name : &str) -> Option<u32> {
+    self.bitfields.iter().find(|(n, _)| n.as_ref() == name).map(|(_, w)| *w)
+  }
+
+  /// Where each field is stored. `field_layouts` has the size and alignment in
+  /// bytes of each field, which the System V layout needs to find out where each
+  /// run of bitfields starts. The types of bitfields are checked to be integers.
+  pub fn field_placements(&self, layout : BitfieldLayout, field_layouts : &[(u64, u64)]) -> Vec<FieldPlacement> {
+    if self.bitfields.is_empty() {
+      return (0..self.fields.len() as u32)
+        .map(|index| FieldPlacement { index, bits: None, storage_bytes: None }).collect();
+    }
+    let mut placements = vec![];
+    let mut next_index = 0;
+    // the type of the field being filled with bitfields, and the bits used so far
+    let mut filling : Option<(&Type, u32)> = None;
+    // for System V, the byte offset of each field, and of the run being filled
+    let mut offset = 0;
+    let mut run_start = 0;
+    for (i, (f, t)) in self.fields.iter().enumerate() {
+      let width = match self.bitfield_width(&f.name) {
+        Some(w) => w,
+        None => {
+          if layout == BitfieldLayout::SysV {
+            if let Some((_, used)) = filling {
+              offset = run_start + ((used + 7) / 8) as u64;
+            }
+            let (size, align) = field_layouts[i];
+            let align = if self.layout.packed { 1 } else { align };
+            offset = (offset + align - 1) / align * align + size;
+          }
+          placements.push(FieldPlacement { index: next_index, bits: None, storage_bytes: None });
+          next_index += 1;
+          filling = None;
+          continue;
+        }
+      };
+      let bits = t.int_bits().unwrap_or_else(|| panic!("COMPILER BUG: bitfield '{}' isn't an integer", f.name));
+      match (layout, filling) {
+        (BitfieldLayout::Msvc, Some((ft, used))) if ft == t && used + width <= bits => {
+          placements.push(FieldPlacement { index: next_index - 1, bits: Some((used, width)), storage_bytes: None });
+          filling = Some((ft, used + width));
+        }
+        (BitfieldLayout::Msvc, _) => {
+          placements.push(FieldPlacement { index: next_index, bits: Some((0, width)), storage_bytes: None });
+          next_index += 1;
+          filling = Some((t, width));
+        }
+        (BitfieldLayout::SysV, _) => {
+          let used = match filling {
+            Some((_, used)) => used,
+            None => {
+              run_start = offset;
+              next_index += 1;
+              0
+            }
+          };
+          // the boundaries are counted from the start of the struct
+          let start = run_start as u32 * 8 + used;
+          let start = if start / bits == (start + width - 1) / bits { start } else { (start / bits + 1) * bits };
+          let used = start - run_start as u32 * 8;
+          placements.push(FieldPlacement { index: next_index - 1, bits: Some((used, width)), storage_bytes: None });
+          filling = Some((t, used + width));
+        }
+      }
+    }
+    // each bitfield of a System V run knows how many bytes the run takes up
+    if layout == BitfieldLayout::SysV {
+      for i in 0..placements.len() {
+        if placements[i].bits.is_some() {
+          let end = placements.iter()
+            .filter(|p| p.index == placements[i].index)
+            .map(|p| p.bits.map(|(offset, width)| offset + width).unwrap_or(0))
+            .max().unwrap();
+          placements[i].storage_bytes = Some((end + 7) / 8);
+        }
+      }
+    }
+    placements
+  }
+
+  /// The alignment in bytes that the types of the bitfields give a System V struct,
+  /// because the bytes that hold them don't have any alignment of their own
+  pub fn bitfield_alignment(&self) -> u64 {
+    self.fields.iter()
+      .filter(|(f, _)| self.bitfield_width(&f.name).is_some())
+      .filter_map(|(_, t)| t.int_bits())
+      .map(|bits| bits as u64 / 8)
+      .max().unwrap_or(1)
+  }
+
   pub fn instanced_fields(&self, type_var_instances : &[Type]) -> Vec<Type> {
     let mut fields = vec![];
     for (_, t) in self.fields.iter() {
@@ -371,6 +592,8 @@ impl TypeDefinition {
 pub enum SymbolInit {
   Function(FunctionInit),
   Expression(NodeId),
+  /// A `lazy static`. The node is the body of the function that initialises it.
+  Lazy(NodeId),
   Intrinsic,
   CBind,
 }
@@ -390,13 +613,17 @@ pub struct SymbolDefinition {
   pub type_tag : Type,
   pub initialiser : SymbolInit,
   pub type_vars : Vec<RefStr>,
+  /// Where the symbol was defined (zero for intrinsics)
+  pub loc : TextLocation,
+  /// The `##` comment above the definition
+  pub doc : Option<RefStr>,
 }
 
 impl SymbolDefinition {
   pub fn codegen_name(&self) -> Option<&str> {
     match &self.initialiser {
       SymbolInit::Function(f) => Some(&f.name_for_codegen),
-      SymbolInit::CBind | SymbolInit::Expression(_) => Some(&self.name),
+      SymbolInit::CBind | SymbolInit::Expression(_) | SymbolInit::Lazy(_) => Some(&self.name),
       _ => None,
     }
   }
@@ -427,10 +654,17 @@ impl  fmt::Display for AbstractType {
 impl  fmt::Display for Type {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     match &self.content {
-      Fun => {
+      Fun(_) => {
         let sig = self.sig().unwrap();
         write!(f, "fun({}) => {}", 
-          sig.args.iter().join(", "), sig.return_type)
+          sig.args.iter().join(", "), sig.return_type)?;
+        if sig.convention != CallingConvention::C {
+          write!(f, " with convention({})", sig.convention)?;
+        }
+        Ok(())
+      }
+      Def(name, _) if is_tuple_type(name) => {
+        write!(f, "({})", self.children.iter().join(", "))
       }
       Def(name, _) => {
         write!(f, "{}", name)?;
@@ -473,7 +707,7 @@ impl  Type {
   pub fn to_concrete(&mut self) -> Result<(), ()> {
     match &self.content {
       Abstract(at) => {
-        if let Some(t) = at.default_type() {
+        if let Some(t) = at.default_type(&LiteralDefaults::default()) {
           *self = t;
         }
         else {
@@ -522,12 +756,23 @@ impl  Type {
     self.signed_int() || self.unsigned_int()
   }
 
+  /// The width in bits of an integer type
+  pub fn int_bits(&self) -> Option<u32> {
+    match self.content {
+      Prim(I64) | Prim(U64) => Some(64),
+      Prim(I32) | Prim(U32) => Some(32),
+      Prim(U16) => Some(16),
+      Prim(U8) => Some(8),
+      _ => None,
+    }
+  }
+
   pub fn number(&self) -> bool {
     self.int() || self.float()
   }
 
   pub fn pointer(&self) -> bool {
-    match self.content { Ptr | Fun => true, _ => false }
+    match self.content { Ptr | Fun(_) => true, _ => false }
   }
 }
 
@@ -571,6 +816,16 @@ fn incremental_unify_internal(old_mono : &Type, new : &mut Type, result : &mut U
 {
   if let Polytype(_) = &new.content { return Ok(()) }
   if let Polytype(_) = &old_mono.content { return Ok(()) }
+  // Control never reaches the end of an expression with the never type, so it
+  // is compatible with everything. Any other type replaces it.
+  if new.content == Prim(Never) && old_mono.content != Prim(Never) && old_mono.content != Abstract(AbstractType::Any) {
+    *new = old_mono.clone();
+    result.mutable_type_changed = true;
+    return Ok(());
+  }
+  if old_mono.content == Prim(Never) && new.content != Abstract(AbstractType::Any) {
+    return Ok(());
+  }
   if old_mono.content != new.content {
     if let Abstract(abs_old) = &old_mono.content {
       if abs_old.contains_type(new) {
@@ -641,8 +896,8 @@ pub fn unify_types(a : &Type, b : &Type) -> Option<Type> {
 impl TypeInfo {
   pub fn new(unit_id : UnitId) -> TypeInfo {
     TypeInfo {
-      type_defs: HashMap::new(),
-      symbols: HashMap::new(),
+      type_defs: BTreeMap::new(),
+      symbols: BTreeMap::new(),
       unit_id,
     }
   }
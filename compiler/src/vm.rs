@@ -0,0 +1,424 @@
+// A tree-walking interpreter for typechecked units, used by the `Vm` backend.
+//
+// It runs a unit's nodes directly, so it works without LLVM, but it only knows
+// about plain values: the primitive types, locals, statics, control flow, and calls
+// to the unit's own functions and to the arithmetic intrinsics. Anything involving
+// memory (pointers, structs, arrays, strings) or other units (including the prelude
+// and `cbind`) is an error for now. See the TODO list for what parity would take.
+//
+// Each node that it evaluates counts as a step against the step budget of sandboxed
+// units (see metering.rs).
+//
+// The old bytecode VM in `legacy/` ran a dynamically-typed predecessor of the
+// language, so there was nothing in it to reuse.
+
+use crate::common::*;
+use crate::error::{Error, error, error_raw, TextLocation};
+use crate::structure::{TOP_LEVEL_FUNCTION_NAME, Nodes, NodeId, Content, PrimitiveVal, ReferenceId, LabelId, VarScope, GlobalType, ShortCircuitOp};
+use crate::types::{TypeMapping, TypeContent, PType, SymbolId, MethodReceiver};
+use crate::code_store::CodeStore;
+use crate::compiler::Val;
+use crate::metering;
+
+use std::collections::{HashMap, BTreeMap};
+
+/// Calls can't go deeper than this, so that runaway recursion is an error rather
+/// than a stack overflow in the host
+const MAX_CALL_DEPTH : usize = 256;
+
+/// Why evaluation stopped early
+enum Interrupt {
+  Break(LabelId, Val),
+  Failed(Error),
+}
+
+impl From<Error> for Interrupt {
+  fn from(e : Error) -> Self { Interrupt::Failed(e) }
+}
+
+type Eval = Result<Val, Interrupt>;
+
+struct Vm<'l> {
+  nodes : &'l Nodes,
+  mapping : &'l TypeMapping,
+  unit_id : UnitId,
+  intrinsics : UnitId,
+  code_store : &'l CodeStore,
+  /// The symbols defined by each node
+  def_symbols : HashMap<NodeId, SymbolId>,
+  globals : Globals,
+  depth : usize,
+  calls : CallCounts,
+}
+
+/// How many times each function was called, for profiling
+pub type CallCounts = BTreeMap<SymbolId, u64>;
+
+/// The values of a unit's statics
+pub type Globals = HashMap<SymbolId, Val>;
+
+/// Runs a unit's top-level code and then its `init { ... }` blocks, in order, so
+/// that they share its statics. Returns the value of the top-level code, the number
+/// of times that each function was called, and the statics that it left behind.
+pub fn run_unit(code_store : &CodeStore, intrinsics : UnitId, unit_id : UnitId)
+  -> Result<(Val, CallCounts, Globals), Error>
+{
+  let mut vm = Vm::new(code_store, intrinsics, unit_id, HashMap::new());
+  let nodes = vm.nodes;
+  let find = |name : &str| {
+    code_store.types(unit_id).symbols.values().find(|def| def.name.as_ref() == name)
+    .ok_or_else(|| error_raw(nodes.root().loc, format!("no function called '{}'", name)))
+  };
+  let val = vm.call(find(TOP_LEVEL_FUNCTION_NAME)?.id, vec![], nodes.root().loc)?;
+  for name in nodes.init_functions.iter() {
+    let def = find(name)?;
+    match &def.type_tag.sig().unwrap().return_type.content {
+      TypeContent::Prim(PType::Void) | TypeContent::Prim(PType::Never) => (),
+      t => return error(def.loc, format!("init blocks shouldn't return a value, but this one returns {:?}", t)),
+    }
+    vm.call(def.id, vec![], def.loc)?;
+  }
+  Ok((val, vm.calls, vm.globals))
+}
+
+/// Calls one of a unit's functions that takes no arguments, such as a test, with
+/// the statics that running the unit left behind. Changes to them are kept.
+pub fn run_function(code_store : &CodeStore, intrinsics : UnitId, symbol : SymbolId, globals : &mut Globals)
+  -> Result<Val, Error>
+{
+  let mut vm = Vm::new(code_store, intrinsics, symbol.uid, std::mem::take(globals));
+  let loc = code_store.symbol_def(symbol).loc;
+  let result = vm.call(symbol, vec![], loc);
+  *globals = vm.globals;
+  result
+}
+
+fn unsupported<T>(loc : TextLocation, what : &str) -> Result<T, Interrupt> {
+  Err(error_raw(loc, format!("the vm backend doesn't support {} yet", what)).into())
+}
+
+macro_rules! int_op {
+  ($a:expr, $b:expr, $f:ident, $($v:ident),*) => {
+    match ($a, $b) {
+      $((Val::$v(a), Val::$v(b)) => Some(Val::$v(a.$f(*b))),)*
+      _ => None,
+    }
+  }
+}
+
+macro_rules! float_op {
+  ($a:expr, $b:expr, $op:tt) => {
+    match ($a, $b) {
+      (Val::F64(a), Val::F64(b)) => Some(Val::F64(a $op b)),
+      (Val::F32(a), Val::F32(b)) => Some(Val::F32(a $op b)),
+      _ => None,
+    }
+  }
+}
+
+macro_rules! compare {
+  ($a:expr, $b:expr, $op:tt) => {
+    match ($a, $b) {
+      (Val::I64(a), Val::I64(b)) => Some(a $op b),
+      (Val::I32(a), Val::I32(b)) => Some(a $op b),
+      (Val::U64(a), Val::U64(b)) => Some(a $op b),
+      (Val::U32(a), Val::U32(b)) => Some(a $op b),
+      (Val::U16(a), Val::U16(b)) => Some(a $op b),
+      (Val::U8(a), Val::U8(b)) => Some(a $op b),
+      (Val::F64(a), Val::F64(b)) => Some(a $op b),
+      (Val::F32(a), Val::F32(b)) => Some(a $op b),
+      (Val::Bool(a), Val::Bool(b)) => Some(a $op b),
+      _ => None,
+    }
+  }
+}
+
+fn is_zero(v : &Val) -> bool {
+  match v {
+    Val::I64(0) | Val::I32(0) | Val::U64(0) | Val::U32(0) | Val::U16(0) | Val::U8(0) => true,
+    _ => false,
+  }
+}
+
+fn binary_intrinsic(name : &str, a : &Val, b : &Val) -> Option<Val> {
+  match name {
+    "+" => int_op!(a, b, wrapping_add, I64, I32, U64, U32, U16, U8).or_else(|| float_op!(a, b, +)),
+    "-" => int_op!(a, b, wrapping_sub, I64, I32, U64, U32, U16, U8).or_else(|| float_op!(a, b, -)),
+    "*" => int_op!(a, b, wrapping_mul, I64, I32, U64, U32, U16, U8).or_else(|| float_op!(a, b, *)),
+    "/" => int_op!(a, b, wrapping_div, I64, I32, U64, U32, U16, U8).or_else(|| float_op!(a, b, /)),
+    "%" => int_op!(a, b, wrapping_rem, I64, I32, U64, U32, U16, U8).or_else(|| float_op!(a, b, %)),
+    "==" => compare!(a, b, ==).map(Val::Bool),
+    "!=" => compare!(a, b, !=).map(Val::Bool),
+    "<" => compare!(a, b, <).map(Val::Bool),
+    ">" => compare!(a, b, >).map(Val::Bool),
+    "<=" => compare!(a, b, <=).map(Val::Bool),
+    ">=" => compare!(a, b, >=).map(Val::Bool),
+    _ => None,
+  }
+}
+
+fn unary_intrinsic(name : &str, v : &Val) -> Option<Val> {
+  let v = match (name, v) {
+    ("!", Val::Bool(b)) => Val::Bool(!b),
+    ("-", Val::I64(a)) => Val::I64(a.wrapping_neg()),
+    ("-", Val::I32(a)) => Val::I32(a.wrapping_neg()),
+    ("-", Val::F64(a)) => Val::F64(-a),
+    ("-", Val::F32(a)) => Val::F32(-a),
+    ("sqrt", Val::F64(a)) => Val::F64(a.sqrt()),
+    ("sqrt", Val::F32(a)) => Val::F32(a.sqrt()),
+    ("floor", Val::F64(a)) => Val::F64(a.floor()),
+    ("floor", Val::F32(a)) => Val::F32(a.floor()),
+    ("cos", Val::F64(a)) => Val::F64(a.cos()),
+    ("cos", Val::F32(a)) => Val::F32(a.cos()),
+    ("sin", Val::F64(a)) => Val::F64(a.sin()),
+    ("sin", Val::F32(a)) => Val::F32(a.sin()),
+    ("log", Val::F64(a)) => Val::F64(a.ln()),
+    ("log", Val::F32(a)) => Val::F32(a.ln()),
+    _ => return None,
+  };
+  Some(v)
+}
+
+/// Converts between primitive types the way `as` does in compiled code
+fn convert(v : &Val, t : PType) -> Option<Val> {
+  use PType::*;
+  macro_rules! from {
+    ($x:expr) => {
+      match t {
+        I64 => Val::I64($x as i64), I32 => Val::I32($x as i32),
+        U64 => Val::U64($x as u64), U32 => Val::U32($x as u32),
+        U16 => Val::U16($x as u16), U8 => Val::U8($x as u8),
+        F64 => Val::F64($x as f64), F32 => Val::F32($x as f32),
+        _ => return None,
+      }
+    }
+  }
+  let v = match *v {
+    Val::I64(x) => from!(x), Val::I32(x) => from!(x),
+    Val::U64(x) => from!(x), Val::U32(x) => from!(x),
+    Val::U16(x) => from!(x), Val::U8(x) => from!(x),
+    Val::F64(x) => from!(x), Val::F32(x) => from!(x),
+    Val::Bool(b) if t == Bool => Val::Bool(b),
+    _ => return None,
+  };
+  Some(v)
+}
+
+impl <'l> Vm<'l> {
+
+  fn new(code_store : &'l CodeStore, intrinsics : UnitId, unit_id : UnitId, globals : Globals) -> Self {
+    let nodes = code_store.nodes(unit_id);
+    let mapping = code_store.type_mappings.get(&unit_id).unwrap();
+    let def_symbols = mapping.symbol_def_nodes.iter().map(|(&s, &n)| (n, s)).collect();
+    Vm {
+      nodes, mapping, unit_id, intrinsics, code_store, def_symbols,
+      globals, depth: 0, calls: BTreeMap::new(),
+    }
+  }
+  fn prim_type(&self, id : NodeId) -> Option<PType> {
+    match self.mapping.node_type.get(&id).map(|t| &t.content) {
+      Some(TypeContent::Prim(p)) => Some(*p),
+      _ => None,
+    }
+  }
+
+  fn call(&mut self, symbol : SymbolId, args : Vec<Val>, loc : TextLocation) -> Result<Val, Error> {
+    if symbol.uid != self.unit_id {
+      let name = &self.code_store.symbol_def(symbol).name;
+      return error(loc, format!("the vm backend can only call functions in the same unit, and '{}' isn't", name));
+    }
+    let nodes = self.nodes;
+    let def_node = nodes.node(*self.mapping.symbol_def_nodes.get(&symbol).unwrap());
+    let (arg_refs, body) = match &def_node.content {
+      Content::FunctionDefinition{ args, body, .. } => (args, *body),
+      _ => return error(loc, "the vm backend can only call functions by name"),
+    };
+    if self.depth >= MAX_CALL_DEPTH {
+      return error(loc, format!("calls nested more than {} deep in the vm backend", MAX_CALL_DEPTH));
+    }
+    *self.calls.entry(symbol).or_insert(0) += 1;
+    let mut locals = HashMap::new();
+    for ((r, _), v) in arg_refs.iter().zip(args) {
+      locals.insert(r.id, v);
+    }
+    self.depth += 1;
+    let result = self.eval(body, &mut locals);
+    self.depth -= 1;
+    match result {
+      Ok(v) => Ok(v),
+      Err(Interrupt::Failed(e)) => Err(e),
+      Err(Interrupt::Break(..)) => panic!("COMPILER BUG: break escaped a function in the vm"),
+    }
+  }
+
+  fn eval(&mut self, id : NodeId, locals : &mut HashMap<ReferenceId, Val>) -> Eval {
+    let nodes = self.nodes;
+    let node = nodes.node(id);
+    let loc = node.loc;
+    if metering::step() {
+      return Err(error_raw(loc, "ran out of steps").into());
+    }
+    match &node.content {
+      Content::Literal(v) => {
+        let t = self.prim_type(id);
+        let v = match (v, t) {
+          (PrimitiveVal::Void, _) => Some(Val::Void),
+          (PrimitiveVal::Bool(b), _) => Some(Val::Bool(*b)),
+          (PrimitiveVal::Int(i), Some(t)) => convert(&Val::I64(*i), t),
+          (PrimitiveVal::Float(f), Some(t)) => convert(&Val::F64(*f), t),
+          (PrimitiveVal::String(_), _) => return unsupported(loc, "strings"),
+          _ => None,
+        };
+        match v {
+          Some(v) => Ok(v),
+          None => unsupported(loc, "this literal"),
+        }
+      }
+      Content::VariableInitialise{ name, value, var_scope, .. } => {
+        let v = self.eval(*value, locals)?;
+        match var_scope {
+          VarScope::Local => { locals.insert(name.id, v); }
+          VarScope::Global(GlobalType::Normal) => {
+            let symbol = *self.def_symbols.get(&id).unwrap();
+            self.globals.insert(symbol, v);
+          }
+          VarScope::Global(_) => return unsupported(loc, "lazy statics or cbind globals"),
+        }
+        Ok(Val::Void)
+      }
+      Content::Assignment{ assignee, value } => {
+        let v = self.eval(*value, locals)?;
+        match &self.nodes.node(*assignee).content {
+          Content::Reference{ refers_to: Some(r), .. } => { locals.insert(*r, v); }
+          Content::Reference{ refers_to: None, .. } => {
+            let symbol = *self.mapping.symbol_references.get(assignee).unwrap();
+            if symbol.uid != self.unit_id {
+              return unsupported(loc, "assigning to statics in other units");
+            }
+            self.globals.insert(symbol, v);
+          }
+          _ => return unsupported(loc, "assigning to anything but a variable"),
+        }
+        Ok(Val::Void)
+      }
+      Content::IfThen{ condition, then_branch } => {
+        if let Val::Bool(true) = self.eval(*condition, locals)? {
+          self.eval(*then_branch, locals)?;
+        }
+        Ok(Val::Void)
+      }
+      Content::IfThenElse{ condition, then_branch, else_branch } => {
+        match self.eval(*condition, locals)? {
+          Val::Bool(true) => self.eval(*then_branch, locals),
+          _ => self.eval(*else_branch, locals),
+        }
+      }
+      Content::Switch{ value, cases, default, .. } => {
+        let v = match convert(&self.eval(*value, locals)?, PType::I64) {
+          Some(Val::I64(v)) => v,
+          _ => return unsupported(loc, "switching on this value"),
+        };
+        match cases.iter().find(|(cs, _)| cs.contains(&v)).map(|(_, b)| b).or(default.as_ref()) {
+          Some(b) if default.is_some() => self.eval(*b, locals),
+          Some(b) => { self.eval(*b, locals)?; Ok(Val::Void) }
+          None => Ok(Val::Void),
+        }
+      }
+      Content::ShortCircuit{ op, left, right, function } => {
+        let a = self.eval(*left, locals)?;
+        if let Some(&symbol) = self.mapping.symbol_references.get(function) {
+          // an overload of the operator
+          let b = self.eval(*right, locals)?;
+          return Ok(self.call(symbol, vec![a, b], loc)?);
+        }
+        match (op, a) {
+          (ShortCircuitOp::And, Val::Bool(false)) => Ok(Val::Bool(false)),
+          (ShortCircuitOp::Or, Val::Bool(true)) => Ok(Val::Bool(true)),
+          (_, Val::Bool(_)) => self.eval(*right, locals),
+          _ => unsupported(loc, "structs"),
+        }
+      }
+      Content::Block(ns) => {
+        let mut v = Val::Void;
+        for &n in ns.iter() {
+          v = self.eval(n, locals)?;
+        }
+        Ok(v)
+      }
+      Content::Reference{ name, refers_to } => {
+        if let Some(r) = refers_to {
+          return Ok(locals.get(r).cloned().unwrap_or(Val::Void));
+        }
+        let symbol = *self.mapping.symbol_references.get(&id).unwrap();
+        match self.globals.get(&symbol) {
+          Some(v) => Ok(v.clone()),
+          None if symbol.uid == self.unit_id =>
+            Err(error_raw(loc, format!("static '{}' was read before it was initialised", name)).into()),
+          None => unsupported(loc, &format!("reading '{}' from another unit", name)),
+        }
+      }
+      Content::FunctionDefinition{..} | Content::TypeDefinition{..} |
+      Content::CBind{..} | Content::TypeAlias{..} => Ok(Val::Void),
+      Content::FunctionCall{ function, args } => {
+        if let Some(MethodReceiver::Reference) | Some(MethodReceiver::Dereference(_)) = self.mapping.method_receivers.get(&id) {
+          return unsupported(loc, "passing a receiver by reference");
+        }
+        let mut vals = vec![];
+        for &a in args.iter() {
+          vals.push(self.eval(a, locals)?);
+        }
+        let symbol = match self.mapping.symbol_references.get(function) {
+          Some(s) => *s,
+          None => return unsupported(loc, "calling function values"),
+        };
+        if symbol.uid == self.intrinsics {
+          let code_store = self.code_store;
+          let name = &code_store.symbol_def(symbol).name;
+          let v = match vals.as_slice() {
+            [a] => unary_intrinsic(name, a),
+            [a, b] => {
+              if (name.as_ref() == "/" || name.as_ref() == "%") && is_zero(b) {
+                return Err(error_raw(loc, "division by zero").into());
+              }
+              binary_intrinsic(name, a, b)
+            }
+            _ => None,
+          };
+          return v.ok_or_else(|| error_raw(loc, format!("the vm backend doesn't support the intrinsic '{}' yet", name)).into());
+        }
+        Ok(self.call(symbol, vals, loc)?)
+      }
+      Content::While{ condition, body } => {
+        while let Val::Bool(true) = self.eval(*condition, locals)? {
+          self.eval(*body, locals)?;
+        }
+        Ok(Val::Void)
+      }
+      Content::Convert{ from_value, .. } => {
+        let v = self.eval(*from_value, locals)?;
+        match self.prim_type(id).and_then(|t| convert(&v, t)) {
+          Some(v) => Ok(v),
+          None => unsupported(loc, "this conversion"),
+        }
+      }
+      Content::Label{ label, body } => {
+        match self.eval(*body, locals) {
+          Err(Interrupt::Break(l, v)) if l == *label => Ok(v),
+          r => r,
+        }
+      }
+      Content::BreakToLabel{ label, return_value } => {
+        let v = match return_value {
+          Some(n) => self.eval(*n, locals)?,
+          None => Val::Void,
+        };
+        Err(Interrupt::Break(*label, v))
+      }
+      Content::Quote(_) => unsupported(loc, "quotes"),
+      Content::TypeConstructor{..} => unsupported(loc, "structs"),
+      Content::FieldAccess{..} => unsupported(loc, "field access"),
+      Content::ArrayLiteral(_) => unsupported(loc, "arrays"),
+      Content::SizeOf{..} => unsupported(loc, "sizeof"),
+    }
+  }
+}
@@ -0,0 +1,48 @@
+// A watchdog for code that runs under a time budget, so that an infinite loop
+// typed halfway through an edit doesn't freeze the session.
+//
+// There's no way to unwind out of JIT-compiled code, so instead the budget running
+// out cancels loops at their safepoints (see safepoint.rs). Every loop exits early
+// from then until the guarded call returns, and the caller reports the interruption.
+//
+// Only the outermost guard on a thread has a timer. Guards inside it run under
+// its budget.
+
+use std::cell::Cell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+thread_local! {
+  /// Set by the timer thread when the budget runs out
+  static INTERRUPTED : Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+  static GUARDED : Cell<bool> = Cell::new(false);
+}
+
+/// True if the budget of the guard on this thread has run out
+pub fn interrupted() -> bool {
+  INTERRUPTED.with(|i| i.load(Ordering::Relaxed))
+}
+
+/// Runs `f`, and interrupts its loops if it takes longer than the budget. Also
+/// returns whether it was interrupted. With no budget `f` just runs.
+pub fn guard<T>(budget : Option<Duration>, f : impl FnOnce() -> T) -> (T, bool) {
+  let budget = match budget {
+    Some(b) if !GUARDED.with(|g| g.replace(true)) => b,
+    _ => return (f(), false),
+  };
+  let interrupted = INTERRUPTED.with(|i| i.clone());
+  let (cancel, cancelled) = channel::<()>();
+  let timer = thread::spawn(move || {
+    if let Err(RecvTimeoutError::Timeout) = cancelled.recv_timeout(budget) {
+      interrupted.store(true, Ordering::Relaxed);
+    }
+  });
+  let v = f();
+  drop(cancel);
+  timer.join().unwrap();
+  GUARDED.with(|g| g.set(false));
+  (v, INTERRUPTED.with(|i| i.swap(false, Ordering::Relaxed)))
+}
@@ -4,16 +4,22 @@ use std::time::Duration;
 use std::thread;
 use std::default::Default;
 
-use std::io::{BufReader, BufRead};
+use std::io::{BufReader, BufRead, Write};
 use std::str;
+use std::path::Path;
 
 use subprocess::{Popen, PopenConfig, Redirection};
 
+use crate::interpret::Prelude;
+use crate::shutdown;
+use crate::project::{Project, split_glob, matches_any};
+use crate::formatter::{self, FormatOptions};
+
 pub fn run_process(path : &str) -> Popen {
   let exe = std::env::current_exe().unwrap();
   let exe = exe.to_str().unwrap();
-  let mut p = Popen::create(&[exe, "run", path], PopenConfig {
-      stdout: Redirection::Pipe, ..Default::default()
+  let mut p = Popen::create(&[exe, "serve", path], PopenConfig {
+      stdout: Redirection::Pipe, stdin: Redirection::Pipe, ..Default::default()
   }).unwrap();
   let stdout = p.stdout.take().unwrap();
   thread::spawn(|| {
@@ -40,13 +46,78 @@ fn spawn_stdin_channel() -> Receiver<String> {
     let (tx, rx) = mpsc::channel::<String>();
     thread::spawn(move || loop {
         let mut buffer = String::new();
-        io::stdin().read_line(&mut buffer).unwrap();
-        tx.send(buffer).unwrap();
+        // stop at EOF or a broken pipe, so the hotkeys are disabled rather than crashing
+        match io::stdin().read_line(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => (),
+        }
+        if tx.send(buffer).is_err() {
+            break;
+        }
     });
     rx
 }
 
-pub fn watch(path : &str) {
+/// Sends a line to the running program. If it has exited, its stdin is a broken
+/// pipe, and it will be reported when it's polled, so the line is dropped.
+fn send_line(p : &mut Popen, line : &str) {
+  if let Some(stdin) = p.stdin.as_mut() {
+    let _ = writeln!(stdin, "{}", line).and_then(|_| stdin.flush());
+  }
+}
+
+fn is_code(path : &Path) -> bool {
+  path.extension().map(|e| e == "code").unwrap_or(false)
+}
+
+/// Runs the program at `path`, and restarts it whenever its code changes. Files
+/// that change in the asset directories are reported to the running program
+/// instead (as `asset_changed` events), so that it can reload them without a restart.
+pub fn watch(path : &str, asset_dirs : &[&str]) {
+  // the program is restarted when its prelude changes too
+  let prelude = Prelude::for_program(path).unwrap_or_else(|_| Prelude::default_core());
+  let mut code_paths = vec![path.to_string()];
+  code_paths.extend(prelude.modules);
+  let asset_dirs : Vec<String> = asset_dirs.iter().map(|d| d.to_string()).collect();
+  watch_paths(path, &code_paths, &asset_dirs, None)
+}
+
+/// Runs a project, and restarts it whenever its code changes. The manifest is
+/// passed to the served process, so that it loads the project's libraries and
+/// compile options. Code files are formatted when they are saved, if the manifest
+/// asks for that.
+pub fn watch_project(manifest_path : &str, project : &Project) {
+  let format = if project.format_on_save { Some(project.format) } else { None };
+  watch_paths(manifest_path, &project.code_paths(), &project.assets, format)
+}
+
+/// Formats a code file that was saved. Returns true if the file was rewritten.
+/// Code that doesn't parse is left alone, and the program reports the error.
+fn format_file(path : &Path, options : FormatOptions) -> bool {
+  let code = match std::fs::read_to_string(path) {
+    Ok(code) => code,
+    Err(_) => return false,
+  };
+  match formatter::format(&code, options) {
+    Ok(formatted) if formatted != code => {
+      match std::fs::write(path, formatted) {
+        Ok(()) => {
+          println!("formatted '{}'", path.display());
+          true
+        }
+        Err(e) => {
+          println!("failed to format '{}': {}", path.display(), e);
+          false
+        }
+      }
+    }
+    _ => false,
+  }
+}
+
+/// The code paths and asset directories can be path patterns (see `project.rs`)
+fn watch_paths(path : &str, code_paths : &[String], asset_dirs : &[String], format : Option<FormatOptions>) {
+  shutdown::install_handler();
   let mut process = Some(run_process(path));
 
   // Create a channel to receive the events.
@@ -58,14 +129,23 @@ pub fn watch(path : &str) {
   // The notification back-end is selected based on the platform.
   let mut watcher = watcher(tx, Duration::from_millis(500)).unwrap();
 
-  // Add a path to be watched. All files and directories at that path and
-  // below will be monitored for changes.
-  watcher.watch(path, RecursiveMode::Recursive).unwrap();
-  for &path in &["code/core/prelude.code", "code/core/list.code", "code/core/compiler.code"] {
-    watcher.watch(path, RecursiveMode::Recursive).unwrap();
+  // Add the paths to be watched. All files and directories at each path and
+  // below will be monitored for changes. Patterns with wildcards are watched from
+  // the directory before the first one, and other changes under it are ignored.
+  for code_path in code_paths {
+    let (root, _) = split_glob(code_path);
+    if let Err(e) = watcher.watch(&root, RecursiveMode::Recursive) {
+      println!("failed to watch '{}': {}", code_path, e);
+    }
+  }
+  for dir in asset_dirs {
+    let (root, _) = split_glob(dir);
+    if let Err(e) = watcher.watch(&root, RecursiveMode::Recursive) {
+      println!("failed to watch asset directory '{}': {}", dir, e);
+    }
   }
 
-  loop {
+  while !shutdown::requested() {
     if let Some(mut p) = process {
       // check if the process is still alive
       let exit_status = p.poll();
@@ -85,12 +165,24 @@ pub fn watch(path : &str) {
       }
     }
 
-    // Read stdin, to restart the process from the terminal
+    // Read stdin, to restart the process from the terminal, or to pass
+    // commands (like `:test name`) to the running process
     if let Some(c) = &stdin_channel {
       match c.try_recv() {
-        Ok(_input_line) => {
-          if process.is_none() {
-            process = Some(run_process(path));
+        Ok(input_line) => {
+          match &mut process {
+            Some(p) if input_line.trim().starts_with(':') => {
+              send_line(p, input_line.trim_end());
+            }
+            Some(p) => {
+              // the process stays alive to serve commands, so restart it
+              p.kill().unwrap();
+              println!("Child process killed");
+              process = Some(run_process(path));
+            }
+            None => {
+              process = Some(run_process(path));
+            }
           }
         }
         Err(TryRecvError::Empty) => (),
@@ -101,16 +193,32 @@ pub fn watch(path : &str) {
       }
     }
 
-    // Read watch events, to restart the process
+    // Read watch events, to restart the process or report asset changes
     match rx.try_recv() {
       Ok(event) => {
         match event {
-          DebouncedEvent::Write(_) => {
-            if let Some(p) = &mut process {
-              p.kill().unwrap();
-              println!("Child process killed");
+          DebouncedEvent::Write(changed) | DebouncedEvent::Create(changed)
+            if matches_any(code_paths, &changed) || matches_any(asset_dirs, &changed) =>
+          {
+            if !is_code(&changed) {
+              if let Some(p) = &mut process {
+                send_line(p, &format!(":asset-changed {}", changed.display()));
+              }
+            }
+            else if format.map(|options| format_file(&changed, options)) == Some(true) {
+              // writing the formatted file causes another event, which restarts the program
+            }
+            else {
+              if let Some(p) = &mut process {
+                p.kill().unwrap();
+                println!("Child process killed");
+              }
+              // the tests of the units that changed are run once the program has
+              // finished loading
+              let mut p = run_process(path);
+              send_line(&mut p, &format!(":tests-affected-by {}", changed.display()));
+              process = Some(p);
             }
-            process = Some(run_process(path));
           }
           _ => {}
         }
@@ -123,4 +231,18 @@ pub fn watch(path : &str) {
     }
     thread::sleep(Duration::from_millis(10));
   }
+
+  // Ctrl-C goes to the child process too, so give it a chance to shut down on its
+  // own. Dropping the file watcher stops its thread.
+  drop(watcher);
+  if let Some(mut p) = process {
+    match p.wait_timeout(Duration::from_secs(5)) {
+      Ok(Some(_)) => (),
+      _ => {
+        println!("the program didn't shut down, so it was killed");
+        p.kill().unwrap();
+      }
+    }
+  }
+  println!("watcher shut down");
 }
\ No newline at end of file